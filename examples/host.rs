@@ -0,0 +1,120 @@
+//! Minimal dbusmenu host for poking at a running daemon without a desktop.
+//!
+//! ```text
+//! cargo run --example host -- [--bus NAME] [--path PATH] [tree [ID] | click ID]
+//! ```
+//!
+//! `tree` prints the menu below item `ID` (the root by default) and `click`
+//! sends a `clicked` event to an item, as a host does when it is chosen.
+use std::collections::HashMap;
+
+use zbus::dbus_proxy;
+use zbus::zvariant::{OwnedValue, Value};
+
+type Layout = (i32, HashMap<String, OwnedValue>, Vec<OwnedValue>);
+
+#[dbus_proxy(interface = "com.canonical.dbusmenu")]
+trait DbusMenu {
+  fn get_layout(
+    &self,
+    parent_id: i32,
+    recursion_depth: i32,
+    property_names: &[&str],
+  ) -> zbus::Result<(u32, Layout)>;
+
+  fn event(&self, id: i32, event_id: &str, data: &Value<'_>, timestamp: u32) -> zbus::Result<()>;
+}
+
+const DEFAULT_BUS: &str = "org.wsl.AppMenuDbusMenu";
+const DEFAULT_PATH: &str = "/org/ayatana/NotificationItem/wslAppMenuDbusMenu/Menu";
+
+fn prop<'a, T>(props: &'a HashMap<String, OwnedValue>, name: &str) -> Option<T>
+where
+  T: TryFrom<&'a OwnedValue>,
+{
+  props.get(name).and_then(|value| T::try_from(value).ok())
+}
+
+fn print_layout(layout: Layout, depth: usize) {
+  let (id, props, children) = layout;
+  let label: String = prop::<&str>(&props, "label")
+    .unwrap_or_default()
+    .to_string();
+  let mut notes = Vec::new();
+  if prop::<bool>(&props, "visible") == Some(false) {
+    notes.push("hidden");
+  }
+  if prop::<bool>(&props, "enabled") == Some(false) {
+    notes.push("disabled");
+  }
+  match prop::<i32>(&props, "toggle-state") {
+    Some(1) => notes.push("checked"),
+    Some(0) => notes.push("unchecked"),
+    _ => {},
+  }
+  if prop::<&str>(&props, "type") == Some("separator") {
+    notes.push("separator");
+  }
+  let notes = if notes.is_empty() {
+    String::new()
+  } else {
+    format!(" ({})", notes.join(", "))
+  };
+  println!("{}[{}] {}{}", "  ".repeat(depth), id, label, notes);
+  for child in children {
+    match Layout::try_from(child) {
+      Ok(child) => print_layout(child, depth + 1),
+      Err(err) => eprintln!("Skipping malformed layout entry: {}", err),
+    }
+  }
+}
+
+fn usage() -> ! {
+  eprintln!("usage: host [--bus NAME] [--path PATH] [tree [ID] | click ID]");
+  std::process::exit(2);
+}
+
+#[tokio::main]
+async fn main() -> zbus::Result<()> {
+  let mut bus = DEFAULT_BUS.to_string();
+  let mut path = DEFAULT_PATH.to_string();
+  let mut command = Vec::new();
+  let mut args = std::env::args().skip(1);
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--bus" => bus = args.next().unwrap_or_else(|| usage()),
+      "--path" => path = args.next().unwrap_or_else(|| usage()),
+      "-h" | "--help" => usage(),
+      _ => command.push(arg),
+    }
+  }
+  let id = |arg: Option<&String>| -> i32 {
+    arg
+      .map_or(Ok(0), |id| id.parse())
+      .unwrap_or_else(|_| usage())
+  };
+
+  let connection = zbus::Connection::session().await?;
+  let menu = DbusMenuProxy::builder(&connection)
+    .destination(bus)?
+    .path(path)?
+    .build()
+    .await?;
+  match command.first().map(String::as_str) {
+    None | Some("tree") => {
+      let (revision, layout) = menu.get_layout(id(command.get(1)), -1, &[]).await?;
+      println!("Revision {}", revision);
+      print_layout(layout, 0);
+    },
+    Some("click") if command.len() == 2 => {
+      let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32);
+      menu
+        .event(id(command.get(1)), "clicked", &Value::new(0), timestamp)
+        .await?;
+    },
+    _ => usage(),
+  }
+  Ok(())
+}
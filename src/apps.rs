@@ -0,0 +1,114 @@
+//! `org.wsl.AppMenu.Apps`: the application database for frontends that
+//! don't speak dbusmenu, such as rofi scripts and keyboard launchers.
+use std::collections::HashMap;
+
+use log::{info, warn};
+use zbus::{dbus_interface, SignalContext};
+
+use crate::constants;
+use crate::control::ControlError;
+
+#[derive(Default)]
+pub struct AppMenuApps;
+
+#[dbus_interface(name = "org.wsl.AppMenu.Apps")]
+impl AppMenuApps {
+  /// Query method
+  async fn query(
+    &self,
+    text: &str,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+  ) -> Result<Vec<(i32, String)>, ControlError> {
+    let menu = server
+      .interface::<_, crate::AppMenuDbusMenu>(
+        crate::util::init::get_object_names().menu_path.as_str(),
+      )
+      .await?;
+    let results = menu.get().await.query(text);
+    Ok(results)
+  }
+
+  /// ListApps method
+  async fn list_apps(
+    &self,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+  ) -> Result<Vec<HashMap<String, zbus::zvariant::OwnedValue>>, ControlError> {
+    let menu = server
+      .interface::<_, crate::AppMenuDbusMenu>(
+        crate::util::init::get_object_names().menu_path.as_str(),
+      )
+      .await?;
+    let apps = menu.get().await.list_apps();
+    Ok(apps)
+  }
+
+  /// LaunchApp method
+  async fn launch_app(
+    &self,
+    desktop_id: &str,
+    uris: Vec<String>,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+  ) -> Result<(), ControlError> {
+    let menu = server
+      .interface::<_, crate::AppMenuDbusMenu>(
+        crate::util::init::get_object_names().menu_path.as_str(),
+      )
+      .await?;
+    let connection = menu.signal_context().connection();
+    if !menu.get().await.launch_app(connection, desktop_id, &uris) {
+      return Err(ControlError::NotFound(format!(
+        "No active entry for '{}'",
+        desktop_id
+      )));
+    }
+    info!("Launched {} over D-Bus", desktop_id);
+    Ok(())
+  }
+
+  /// AppsChanged signal
+  #[dbus_interface(signal)]
+  pub async fn apps_changed(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+}
+
+/// Broadcasts `AppsChanged` so frontends list the applications again.
+pub async fn notify_apps_changed(connection: &zbus::Connection) {
+  let ctxt = match SignalContext::new(connection, constants::CONTROL_PATH) {
+    Ok(ctxt) => ctxt,
+    Err(err) => {
+      warn!("Failed to create apps signal context: {}", err);
+      return;
+    },
+  };
+  if let Err(err) = AppMenuApps::apps_changed(&ctxt).await {
+    warn!("Failed to signal app changes: {}", err);
+  }
+}
+
+/// Scores `text` against `query` as a case-insensitive subsequence, favoring
+/// characters at word starts and runs of adjacent ones. `None` if some
+/// character of the query doesn't occur in order.
+pub fn fuzzy_score(query: &str, text: &str) -> Option<i64> {
+  let text: Vec<char> = text.chars().flat_map(char::to_lowercase).collect();
+  let mut score = 0;
+  let mut next = 0;
+  let mut prev: Option<usize> = None;
+  for q in query
+    .chars()
+    .filter(|c| !c.is_whitespace())
+    .flat_map(char::to_lowercase)
+  {
+    let found = next + text[next..].iter().position(|c| *c == q)?;
+    score += 1;
+    if found == 0 || !text[found - 1].is_alphanumeric() {
+      score += 8;
+    }
+    match prev {
+      Some(prev) if prev + 1 == found => score += 4,
+      Some(prev) => score -= (found - prev - 1).min(4) as i64,
+      None => score -= found.min(4) as i64,
+    }
+    prev = Some(found);
+    next = found + 1;
+  }
+  Some(score)
+}
@@ -0,0 +1,78 @@
+//! Companion window for sessions without any tray, such as bare X11 or WSLg
+//! RDP windows: a small always-on-top button in the bottom-left corner that
+//! opens the menu. GTK renders it from the `org.gtk.Menus` export of the
+//! GMenu bridge, like any other GMenu host, so it shows the same model the
+//! tray does.
+use gtk::prelude::*;
+use gtk::{gdk, gio, glib};
+use log::{info, warn};
+
+use crate::{config, constants};
+
+/// Action group prefix the GMenu bridge's items expect.
+const ACTION_PREFIX: &str = "menu";
+
+/// Shows the companion window from its own thread, as GTK needs a main loop
+/// of its own.
+pub fn spawn(bus_name: String) {
+  let spawned = std::thread::Builder::new()
+    .name("companion".to_string())
+    .spawn(move || run(&bus_name));
+  if let Err(err) = spawned {
+    warn!("Failed to start the companion window: {}", err);
+  }
+}
+
+fn run(bus_name: &str) {
+  if let Err(err) = gtk::init() {
+    warn!("Failed to initialize GTK for the companion window: {}", err);
+    return;
+  }
+  let connection = match gio::bus_get_sync(gio::BusType::Session, gio::Cancellable::NONE) {
+    Ok(connection) => connection,
+    Err(err) => {
+      warn!(
+        "Failed to connect the companion window to the session bus: {}",
+        err
+      );
+      return;
+    },
+  };
+  let model = gio::DBusMenuModel::get(&connection, Some(bus_name), constants::GMENU_PATH);
+  let actions = gio::DBusActionGroup::get(&connection, Some(bus_name), constants::GMENU_PATH);
+
+  let title = config::get().title.clone();
+  let window = gtk::Window::new(gtk::WindowType::Toplevel);
+  window.set_title(&title);
+  window.set_decorated(false);
+  window.set_keep_above(true);
+  window.set_skip_taskbar_hint(true);
+  window.set_skip_pager_hint(true);
+  window.set_type_hint(gdk::WindowTypeHint::Utility);
+  window.stick();
+  window.insert_action_group(ACTION_PREFIX, Some(&actions));
+
+  let button = gtk::MenuButton::new();
+  button.set_menu_model(Some(&model));
+  button.set_image(Some(&gtk::Image::from_icon_name(
+    Some(&config::get().icon_name),
+    gtk::IconSize::LargeToolbar,
+  )));
+  button.set_always_show_image(true);
+  button.set_tooltip_text(Some(&title));
+  window.add(&button);
+
+  // Without decorations there's nothing to close it with but the window
+  // manager, and the menu shouldn't go away with it.
+  window.connect_delete_event(|_, _| glib::Propagation::Stop);
+  window.set_gravity(gdk::Gravity::SouthWest);
+  let workarea = gdk::Display::default()
+    .and_then(|display| display.primary_monitor().or_else(|| display.monitor(0)))
+    .map(|monitor| monitor.workarea());
+  if let Some(workarea) = workarea {
+    window.move_(workarea.x(), workarea.y() + workarea.height());
+  }
+  window.show_all();
+  info!("Showing the companion window");
+  gtk::main();
+}
@@ -0,0 +1,629 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::constants;
+
+/// Replacement label and icon for one desktop-file ID.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EntryOverride {
+  pub label: Option<String>,
+  /// Themed icon name or path to an icon file.
+  pub icon: Option<String>,
+}
+
+/// A menu item defined in the config rather than by a desktop file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CustomEntry {
+  /// ID the entry is known by, e.g. in `favorites` as `x-custom-<id>`, so
+  /// that it survives relabelling. Defaults to the entry's position.
+  pub id: Option<String>,
+  pub label: String,
+  /// Themed icon name or path to an icon file.
+  pub icon: Option<String>,
+  /// Command line, quoted like a desktop file's `Exec`.
+  pub exec: String,
+  /// Command and arguments, used instead of `exec` when set.
+  pub argv: Vec<String>,
+  /// Variables added to the environment of the command.
+  pub env: HashMap<String, String>,
+  /// Working directory of the command.
+  pub cwd: Option<PathBuf>,
+  /// Category the entry is listed under, e.g. `Utility`.
+  pub category: Option<String>,
+  pub terminal: bool,
+}
+
+/// A top-level submenu and the categories listed in it.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MenuCategory {
+  pub label: String,
+  pub icon: Option<String>,
+  /// Built-in categories (`Games`, `Audio/Video`, ...) or raw `Categories`
+  /// values, which take precedence, listed in this submenu.
+  pub include: Vec<String>,
+}
+
+/// A submenu filled from a script's output whenever it is opened.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PipeMenu {
+  pub label: String,
+  pub icon: Option<String>,
+  /// Command printing the items as JSON, quoted like a desktop file's `Exec`.
+  pub command: String,
+}
+
+/// A view of the menu listing only some entries, switched between by
+/// scrolling on the tray icon. Filters left unset match every entry.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MenuProfile {
+  pub name: String,
+  /// Only list the pinned favorites.
+  pub favorites_only: bool,
+  /// Only list Windows applications, or only Linux ones when false.
+  pub windows: Option<bool>,
+  /// Built-in categories or raw `Categories` values to list.
+  pub categories: Vec<String>,
+}
+
+/// Lists entries under `target`, overriding the built-in category mapping.
+/// Patterns are globs (`*`, `?`), or regular expressions when prefixed `re:`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CategoryRule {
+  /// Desktop-file ID pattern, e.g. `org.gnome.*`.
+  pub id: Option<String>,
+  /// Pattern for a raw `Categories` value, e.g. `X-*`.
+  pub category: Option<String>,
+  /// Category matching entries are listed under, e.g. `Development`.
+  pub target: String,
+}
+
+/// A `CategoryRule` with its patterns compiled and target resolved.
+#[derive(Debug, Clone)]
+pub struct CompiledRule {
+  pub id: Option<regex::Regex>,
+  pub category: Option<regex::Regex>,
+  pub target: constants::Category,
+}
+
+fn compile_pattern(pattern: &str) -> Result<regex::Regex, regex::Error> {
+  if let Some(re) = pattern.strip_prefix("re:") {
+    return regex::Regex::new(re);
+  }
+  let mut re = String::from("^");
+  for c in pattern.chars() {
+    match c {
+      '*' => re.push_str(".*"),
+      '?' => re.push('.'),
+      c => re.push_str(&regex::escape(&c.to_string())),
+    }
+  }
+  re.push('$');
+  regex::Regex::new(&re)
+}
+
+impl CategoryRule {
+  fn compile(&self) -> Result<CompiledRule, String> {
+    let compile = |pattern: &Option<String>| {
+      pattern
+        .as_deref()
+        .map(compile_pattern)
+        .transpose()
+        .map_err(|e| e.to_string())
+    };
+    let target = constants::category_by_name(&self.target)
+      .ok_or_else(|| format!("unknown target category '{}'", self.target))?;
+    Ok(CompiledRule {
+      id: compile(&self.id)?,
+      category: compile(&self.category)?,
+      target,
+    })
+  }
+}
+
+/// Settings from `$XDG_CONFIG_HOME/xdg-app-ksni/config.toml`, with the older
+/// environment variables layered on top.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+  /// Tray item title.
+  pub title: String,
+  /// Themed icon name of the tray item.
+  pub icon_name: String,
+  /// StatusNotifierItem Id; a custom one also derives the bus name and paths.
+  pub id: Option<String>,
+  pub bus_name: Option<String>,
+  pub item_path: Option<String>,
+  pub menu_path: Option<String>,
+  /// StatusNotifierItem category.
+  pub category: String,
+  /// Extra application directories, ranked above the system ones; later entries win.
+  pub app_dirs: Vec<PathBuf>,
+  /// Application directories left unscanned, toggled from the "Sources" submenu.
+  pub disabled_dirs: Vec<PathBuf>,
+  /// Desktop names matched against `OnlyShowIn`/`NotShowIn`; empty uses `$XDG_CURRENT_DESKTOP`.
+  pub desktops: Vec<String>,
+  /// Seconds file system events are debounced for before the menu updates.
+  pub debounce_secs: u64,
+  /// Whether icon files are rendered into an icon theme directory that hosts
+  /// load them from by name through `IconThemePath`, instead of embedding
+  /// them in every layout.
+  pub export_icons: bool,
+  /// Whether rendered menu icons are kept in `$XDG_CACHE_HOME` so restarts
+  /// and rescans don't render them again.
+  pub icon_cache: bool,
+  /// Whether icon files are only rendered once the submenu listing them is
+  /// first shown, rather than all of them at startup.
+  pub lazy_icons: bool,
+  /// Edge length menu icons are scaled to before being embedded; 0 keeps
+  /// the size of raster icons.
+  pub icon_size: u32,
+  /// Device pixel ratio of the panel: menu icons are rendered this many times
+  /// larger, and the tray icon gets scaled variants of every size.
+  pub icon_scale: u32,
+  /// Edge lengths the tray icon is rendered at for `IconPixmap`.
+  pub tray_icon_sizes: Vec<u32>,
+  /// Most entries listed in the "Recently added" submenu; 0 leaves it out.
+  pub recent_count: usize,
+  /// Days an entry installed while running stays in "Recently added".
+  pub recent_days: u64,
+  /// Most entries listed in the "Frequently used" submenu; 0 leaves it out
+  /// and stops counting launches.
+  pub frequent_count: usize,
+  /// Days after which a launch counts half as much; 0 never decays.
+  pub frequent_half_life_days: u64,
+  /// Longest label, in characters as displayed, before it is cut short with
+  /// an ellipsis; 0 keeps labels whole.
+  pub max_label_length: usize,
+  /// Desktop-file IDs left out of the menu.
+  pub hidden: Vec<String>,
+  /// Categories whose entries are left out of the menu, e.g. `Science`.
+  pub hidden_categories: Vec<String>,
+  /// Desktop-file IDs shown even if `NoDisplay` or `OnlyShowIn` would hide them.
+  pub force_show: Vec<String>,
+  pub log_level: String,
+  pub menu_only: bool,
+  pub launch_strictness: String,
+  /// What clicking the tray icon does: `MENU` opens the menu and never
+  /// launches anything; `FAVORITE` launches the top favorite; `COMMAND` runs
+  /// `activate_command`; `NONE` ignores it.
+  pub activate: String,
+  /// Command line run by the `COMMAND` activate action, e.g. `rofi -show drun`.
+  pub activate_command: String,
+  /// Desktop-file IDs of favorite applications, pinned to the top of the
+  /// menu in this order; the first installed one is launched by Activate.
+  pub favorites: Vec<String>,
+  pub sandbox: bool,
+  /// Whether to show a small always-on-top button that opens the menu, for
+  /// sessions without any tray. Needs the `companion` build feature.
+  pub companion: bool,
+  /// Whether launches that fail to start raise a desktop notification.
+  pub notify_failures: bool,
+  /// Whether themed icons switch to their dark variants while the settings
+  /// portal reports a dark color scheme.
+  pub follow_color_scheme: bool,
+  /// Icon theme themed icons are looked up in, then its parents and hicolor;
+  /// empty uses `gtk-icon-theme-name` from the GTK settings.
+  pub icon_theme: String,
+  pub show_about: bool,
+  /// Whether to end the root menu with "Options", "Refresh" and "Quit" items.
+  pub show_controls: bool,
+  /// Whether to list entries marked `NoDisplay` or meant for other desktops.
+  pub show_hidden: bool,
+  /// Whether to list entries run in a terminal.
+  pub show_terminal: bool,
+  /// Whether to add a "Sources" submenu for toggling application directories.
+  pub show_sources: bool,
+  /// Whether to serve the menu as JSON on a UNIX socket in `$XDG_RUNTIME_DIR`.
+  pub socket: bool,
+  pub merge_threshold: usize,
+  /// Whether entries of categories below `merge_threshold` are listed in the
+  /// root menu itself rather than the "Other" submenu.
+  pub flatten_sparse: bool,
+  /// Seconds between anonymous usage summaries logged with journal fields
+  /// (entries per category, launches and scan times); 0 logs none.
+  pub usage_summary_secs: u64,
+  pub network_guard: bool,
+  /// Whether to register the tray item by a per-instance
+  /// `org.kde.StatusNotifierItem-<pid>-<n>` name, as the spec has it, rather
+  /// than by object path.
+  pub sni_unique_name: bool,
+  /// Whether `GetDesktopEntry` may return raw desktop files over D-Bus.
+  pub expose_desktop_entries: bool,
+  /// Terminal to use per desktop-file ID for `Terminal=true` entries.
+  pub terminal_overrides: HashMap<String, String>,
+  /// Label and icon replacements per desktop-file ID.
+  pub overrides: HashMap<String, EntryOverride>,
+  /// Extra menu items, listed alongside the scanned desktop entries.
+  pub entries: Vec<CustomEntry>,
+  /// Script-generated submenus added to the root menu.
+  pub pipe_menus: Vec<PipeMenu>,
+  /// Menu profiles cycled through by scrolling on the tray icon, starting
+  /// with the first.
+  pub profiles: Vec<MenuProfile>,
+  /// Top-level submenus in display order; empty uses one per built-in
+  /// category. Entries nothing includes go to the one including
+  /// `Uncategorized`, else the last.
+  pub categories: Vec<MenuCategory>,
+  /// How entries are grouped into top-level submenus: `CATEGORY`, `SOURCE`
+  /// (System, Flatpak, User or Windows) or `INITIAL` (one submenu per
+  /// initial letter of the shown name, A to Z then `#` for the rest, empty
+  /// ones hidden).
+  pub group_by: String,
+  /// Icon names of category submenus by built-in category, replacing the
+  /// standard `applications-*` theme icons; empty names show no icon.
+  pub category_icons: HashMap<String, String>,
+  /// Fewest entries of a category sharing an additional category, such as
+  /// `WebBrowser`, that get their own submenu inside it; 0 never nests.
+  pub subcategory_threshold: usize,
+  /// Most entries a category lists directly before they are split into
+  /// "A–E" style submenus by initial; 0 never splits.
+  pub page_size: usize,
+  /// Category mapping rules, first match wins.
+  pub category_rules: Vec<CategoryRule>,
+  /// Extra `Categories` values mapped to a built-in category, e.g.
+  /// `Internet = "Network"`; matched ignoring case and punctuation.
+  pub category_aliases: HashMap<String, String>,
+  #[serde(skip)]
+  pub compiled_rules: Vec<CompiledRule>,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Config {
+      title: "Apps".to_string(),
+      icon_name: "starred".to_string(),
+      id: None,
+      bus_name: None,
+      item_path: None,
+      menu_path: None,
+      category: "ApplicationStatus".to_string(),
+      app_dirs: vec![],
+      disabled_dirs: vec![],
+      desktops: vec![],
+      debounce_secs: 10,
+      export_icons: false,
+      icon_cache: true,
+      lazy_icons: true,
+      icon_size: constants::MENU_ICON_SIZE,
+      icon_scale: 1,
+      tray_icon_sizes: constants::TRAY_PIXMAP_SIZES.to_vec(),
+      recent_count: 0,
+      recent_days: 7,
+      frequent_count: 0,
+      frequent_half_life_days: 14,
+      max_label_length: 0,
+      hidden: vec![],
+      hidden_categories: vec![],
+      force_show: vec![],
+      log_level: "INFO".to_string(),
+      menu_only: false,
+      launch_strictness: "WARN".to_string(),
+      activate: "MENU".to_string(),
+      activate_command: String::new(),
+      favorites: vec![],
+      sandbox: false,
+      companion: false,
+      notify_failures: true,
+      follow_color_scheme: true,
+      icon_theme: String::new(),
+      show_about: false,
+      show_controls: true,
+      show_hidden: false,
+      show_terminal: true,
+      show_sources: false,
+      socket: false,
+      merge_threshold: 0,
+      flatten_sparse: false,
+      usage_summary_secs: 0,
+      network_guard: false,
+      sni_unique_name: false,
+      expose_desktop_entries: false,
+      terminal_overrides: HashMap::new(),
+      overrides: HashMap::new(),
+      entries: vec![],
+      pipe_menus: vec![],
+      profiles: vec![],
+      categories: vec![],
+      group_by: "CATEGORY".to_string(),
+      category_icons: HashMap::new(),
+      subcategory_threshold: 0,
+      page_size: 0,
+      category_rules: vec![],
+      category_aliases: HashMap::new(),
+      compiled_rules: vec![],
+    }
+  }
+}
+
+static CONFIG: RwLock<Option<Arc<Config>>> = RwLock::new(None);
+
+fn env_var(name: &str) -> Option<String> {
+  std::env::var(name).ok().filter(|var| !var.is_empty())
+}
+
+fn env_flag(name: &str, flag: &mut bool) {
+  if let Some(var) = env_var(name) {
+    *flag = matches!(var.to_lowercase().as_str(), "1" | "true" | "yes" | "on");
+  }
+}
+
+/// Location of the config file, if one exists.
+pub fn config_path() -> Option<PathBuf> {
+  xdg::BaseDirectories::with_prefix("xdg-app-ksni")
+    .ok()?
+    .find_config_file("config.toml")
+}
+
+/// Where the user's config file lives, whether or not it exists yet.
+pub fn user_config_path() -> Option<PathBuf> {
+  Some(
+    xdg::BaseDirectories::with_prefix("xdg-app-ksni")
+      .ok()?
+      .get_config_file("config.toml"),
+  )
+}
+
+impl Config {
+  /// Reads the config file, defaulting if there is none.
+  pub fn try_load() -> Result<Config, String> {
+    match config_path() {
+      Some(path) => {
+        let data = std::fs::read_to_string(&path)
+          .map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+        let config = toml::from_str(&data)
+          .map_err(|e| format!("Invalid configuration in {:?}: {}", path, e))?;
+        info!("Loaded configuration from {:?}", path);
+        Ok(config)
+      },
+      None => Ok(Config::default()),
+    }
+  }
+
+  /// Reads the config file, falling back to defaults if it is missing or
+  /// invalid, then applies environment overrides.
+  pub fn load() -> Config {
+    let mut config = Config::try_load().unwrap_or_else(|err| {
+      warn!("{}, using defaults", err);
+      Config::default()
+    });
+    Choices::load().apply(&mut config);
+    config.apply_env();
+    config.apply_args(std::env::args().skip(1));
+    config.compiled_rules = config
+      .category_rules
+      .iter()
+      .filter_map(|rule| match rule.compile() {
+        Ok(rule) => Some(rule),
+        Err(err) => {
+          warn!("Ignoring invalid category rule {:?}: {}", rule, err);
+          None
+        },
+      })
+      .collect();
+    config.category_aliases.retain(|alias, target| {
+      let known = constants::category_by_name(target).is_some();
+      if !known {
+        warn!(
+          "Ignoring category alias {} for unknown category {}",
+          alias, target
+        );
+      }
+      known
+    });
+    config
+  }
+
+  /// Applies the `--id`, `--title`, `--icon-name` and `--category` options,
+  /// given as `--title=Apps` or `--title Apps`, over the environment.
+  fn apply_args(&mut self, mut args: impl Iterator<Item = String>) {
+    while let Some(arg) = args.next() {
+      let (flag, inline) = match arg.split_once('=') {
+        Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+        None => (arg, None),
+      };
+      if !["--id", "--title", "--icon-name", "--category"].contains(&flag.as_str()) {
+        continue;
+      }
+      let value = match inline.or_else(|| args.next()) {
+        Some(value) => value,
+        None => {
+          warn!("Ignoring {} without a value", flag);
+          continue;
+        },
+      };
+      match flag.as_str() {
+        "--id" => self.id = Some(value),
+        "--title" => self.title = value,
+        "--icon-name" => self.icon_name = value,
+        _ => self.category = value,
+      }
+    }
+  }
+
+  fn apply_env(&mut self) {
+    if let Some(level) = env_var("LOG_LEVEL") {
+      self.log_level = level;
+    }
+    if let Some(desktops) = env_var("ONLY_SHOW") {
+      self.desktops = desktops
+        .split(':')
+        .filter(|d| !d.is_empty())
+        .map(str::to_string)
+        .collect();
+    }
+    env_flag("MENU_ONLY", &mut self.menu_only);
+    if let Some(strictness) = env_var("LAUNCH_STRICTNESS") {
+      self.launch_strictness = strictness;
+    }
+    if let Some(group_by) = env_var("GROUP_BY") {
+      self.group_by = group_by;
+    }
+    if let Some(theme) = env_var("ICON_THEME") {
+      self.icon_theme = theme;
+    }
+    if let Some(activate) = env_var("ACTIVATE") {
+      self.activate = activate;
+    }
+    if let Some(command) = env_var("ACTIVATE_COMMAND") {
+      self.activate_command = command;
+    }
+    env_flag("SANDBOX", &mut self.sandbox);
+    env_flag("COMPANION", &mut self.companion);
+    env_flag("NOTIFY_FAILURES", &mut self.notify_failures);
+    env_flag("FOLLOW_COLOR_SCHEME", &mut self.follow_color_scheme);
+    env_flag("EXPORT_ICONS", &mut self.export_icons);
+    env_flag("ICON_CACHE", &mut self.icon_cache);
+    env_flag("LAZY_ICONS", &mut self.lazy_icons);
+    env_flag("SHOW_ABOUT", &mut self.show_about);
+    env_flag("SHOW_CONTROLS", &mut self.show_controls);
+    env_flag("SOCKET", &mut self.socket);
+    if let Some(size) = env_var("ICON_SIZE") {
+      match size.parse() {
+        Ok(size) => self.icon_size = size,
+        Err(_) => warn!("Invalid icon size '{}' passed in, ignoring it", size),
+      }
+    }
+    if let Some(threshold) = env_var("MERGE_CATEGORY_THRESHOLD") {
+      match threshold.parse() {
+        Ok(threshold) => self.merge_threshold = threshold,
+        Err(_) => warn!(
+          "Invalid category merge threshold '{}' passed in, ignoring it",
+          threshold
+        ),
+      }
+    }
+    if let Some(category) = env_var("SNI_CATEGORY") {
+      self.category = category;
+    }
+    env_flag("FLATTEN_SPARSE", &mut self.flatten_sparse);
+    env_flag("NETWORK_GUARD", &mut self.network_guard);
+    env_flag("SNI_UNIQUE_NAME", &mut self.sni_unique_name);
+    self.id = env_var("ITEM_ID").or(self.id.take());
+    self.bus_name = env_var("BUS_NAME").or(self.bus_name.take());
+    self.item_path = env_var("ITEM_PATH").or(self.item_path.take());
+    self.menu_path = env_var("MENU_PATH").or(self.menu_path.take());
+    if let Some(overrides) = env_var("TERMINAL_OVERRIDES") {
+      for (id, terminal) in overrides.split(';').filter_map(|pair| pair.split_once('=')) {
+        self
+          .terminal_overrides
+          .insert(id.trim().to_string(), terminal.trim().to_string());
+      }
+    }
+  }
+}
+
+/// Settings changed at runtime from the menu or the control interface. They
+/// are kept in a state file and applied over the config file, which is never
+/// rewritten.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Choices {
+  flags: HashMap<String, bool>,
+  /// Values appended to string lists such as `favorites`, by list.
+  added: HashMap<String, Vec<String>>,
+  /// Values taken out of string lists, by list.
+  removed: HashMap<String, Vec<String>>,
+}
+
+impl Choices {
+  fn load() -> Choices {
+    crate::util::state::load(constants::CHOICES_STATE_FILE).unwrap_or_default()
+  }
+
+  /// `values` with the values added to or removed from list `key` applied.
+  fn apply_listed(&self, key: &str, values: &mut Vec<String>) {
+    if let Some(removed) = self.removed.get(key) {
+      values.retain(|v| !removed.contains(v));
+    }
+    for value in self.added.get(key).into_iter().flatten() {
+      if !values.contains(value) {
+        values.push(value.clone());
+      }
+    }
+  }
+
+  fn apply(&self, config: &mut Config) {
+    for (key, value) in self.flags.iter() {
+      match key.as_str() {
+        "show_hidden" => config.show_hidden = *value,
+        "show_terminal" => config.show_terminal = *value,
+        _ => warn!("Ignoring unknown saved flag {}", key),
+      }
+    }
+    self.apply_listed("favorites", &mut config.favorites);
+    let mut disabled_dirs = config
+      .disabled_dirs
+      .iter()
+      .map(|dir| dir.to_string_lossy().to_string())
+      .collect();
+    self.apply_listed("disabled_dirs", &mut disabled_dirs);
+    config.disabled_dirs = disabled_dirs.into_iter().map(PathBuf::from).collect();
+  }
+}
+
+/// Records whether `dir` is scanned, over the config file's `disabled_dirs`.
+pub fn set_dir_disabled(dir: &Path, disabled: bool) -> Result<(), String> {
+  set_listed("disabled_dirs", &dir.to_string_lossy(), disabled)
+}
+
+/// Adds a desktop-file ID to the end of the favorites, or removes it, over
+/// the config file's `favorites`.
+pub fn set_favorite(appid: &str, favorite: bool) -> Result<(), String> {
+  set_listed("favorites", appid, favorite)
+}
+
+/// Records a flag such as `show_hidden`, over the config file's setting.
+pub fn set_flag(key: &str, value: bool) -> Result<(), String> {
+  let mut choices = Choices::load();
+  choices.flags.insert(key.to_string(), value);
+  crate::util::state::store(constants::CHOICES_STATE_FILE, &choices)
+}
+
+/// Adds `value` to the end of the string list `key`, or removes it from
+/// there.
+fn set_listed(key: &str, value: &str, listed: bool) -> Result<(), String> {
+  let mut choices = Choices::load();
+  let (to, from) = match listed {
+    true => (&mut choices.added, &mut choices.removed),
+    false => (&mut choices.removed, &mut choices.added),
+  };
+  from
+    .entry(key.to_string())
+    .or_default()
+    .retain(|v| v != value);
+  let to = to.entry(key.to_string()).or_default();
+  to.retain(|v| v != value);
+  to.push(value.to_string());
+  crate::util::state::store(constants::CHOICES_STATE_FILE, &choices)
+}
+
+/// Re-reads the configuration, replacing the one returned by `get`.
+pub fn reload() -> Arc<Config> {
+  let config = Arc::new(Config::load());
+  if let Ok(mut current) = CONFIG.write() {
+    *current = Some(config.clone());
+  }
+  config
+}
+
+/// The current configuration, loaded on first use.
+pub fn get() -> Arc<Config> {
+  if let Some(config) = CONFIG.read().ok().and_then(|config| config.clone()) {
+    return config;
+  }
+  let config = Arc::new(Config::load());
+  if let Ok(mut current) = CONFIG.write() {
+    *current = Some(config.clone());
+  }
+  config
+}
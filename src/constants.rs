@@ -1,4 +1,231 @@
-#[derive(Debug, Clone, Copy, enum_iterator::Sequence, Hash, PartialEq)]
+use std::time::Duration;
+
+/// Delay before the first re-render of an icon that failed to load.
+pub const ICON_RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+/// Number of re-render attempts before an icon is given up on.
+pub const ICON_RETRY_MAX_ATTEMPTS: u32 = 6;
+/// How often the retry queue is checked for due icons.
+pub const ICON_RETRY_TICK: Duration = Duration::from_secs(1);
+
+/// Menu item ID of the "Options" submenu holding the display toggles.
+pub const OPTIONS_MENU_IDX: i32 = 5;
+/// Menu item ID of the "Show hidden apps" toggle.
+pub const SHOW_HIDDEN_ITEM_IDX: i32 = 6;
+/// Menu item ID of the "Show terminal apps" toggle.
+pub const SHOW_TERMINAL_ITEM_IDX: i32 = 7;
+/// Menu item ID of the "Refresh" item rescanning all application directories.
+pub const REFRESH_ITEM_IDX: i32 = 10;
+/// Menu item ID of the "Quit" item.
+pub const QUIT_ITEM_IDX: i32 = 11;
+/// Menu item ID of the optional "About" entry at the bottom of the root menu.
+pub const ABOUT_ITEM_IDX: i32 = 12;
+/// Menu item ID of the "Other" submenu collecting entries of sparse categories.
+pub const OTHER_CATEGORY_IDX: i32 = 13;
+/// Menu item ID of the "Sources" submenu toggling application directories.
+pub const SOURCES_MENU_IDX: i32 = 14;
+/// Menu item ID of the separator below the pinned favorites.
+pub const FAVORITES_SEPARATOR_IDX: i32 = 15;
+/// Menu item ID of the separator above pipe menus, "Sources" and "About".
+pub const CONTROLS_SEPARATOR_IDX: i32 = 16;
+/// Menu item ID of the "Recently added" submenu.
+pub const RECENT_MENU_IDX: i32 = 17;
+/// Menu item ID of the "Frequently used" submenu.
+pub const FREQUENT_MENU_IDX: i32 = 18;
+/// Menu item ID of the first application directory in the "Sources" submenu;
+/// directories past `FIRST_PIPE_MENU_IDX` aren't listed.
+pub const FIRST_SOURCE_IDX: i32 = 20;
+/// Menu item ID of the first pipe menu; menus past `FIRST_SECTION_IDX` aren't shown.
+pub const FIRST_PIPE_MENU_IDX: i32 = 60;
+/// Menu item ID of the first category submenu; categories past
+/// `FIRST_LAUNCHER_IDX` aren't shown.
+pub const FIRST_SECTION_IDX: i32 = 100;
+/// First menu item ID handed out to pipe menu items, which get fresh IDs on
+/// every refresh so clicks on stale layouts can't hit the wrong item.
+pub const FIRST_TRANSIENT_IDX: i32 = 1 << 24;
+/// First menu item ID handed out to submenus generated inside categories.
+pub const FIRST_SUBMENU_IDX: i32 = 1 << 23;
+/// First menu item ID of launcher copies listed outside their section, like
+/// pinned favorites; see `copy_idx`.
+pub const FIRST_COPY_IDX: i32 = 1 << 22;
+/// Number of IDs reserved for each list of launcher copies; launcher IDs are
+/// assumed to stay below it.
+pub const COPY_SPAN: i32 = 1 << 19;
+
+/// Lists showing copies of launchers, each with its own span of item IDs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CopyList {
+  Pinned,
+  Recent,
+  Frequent,
+}
+
+/// Menu item ID of the copy of launcher `idx` in `list`, stable for as long
+/// as the launcher's own.
+pub fn copy_idx(list: CopyList, idx: i32) -> i32 {
+  FIRST_COPY_IDX + list as i32 * COPY_SPAN + idx
+}
+
+/// Whether a menu item ID belongs to a copy of a launcher in `list`.
+pub fn is_copy_idx(list: CopyList, id: i32) -> bool {
+  let first = FIRST_COPY_IDX + list as i32 * COPY_SPAN;
+  (first..first + COPY_SPAN).contains(&id)
+}
+
+/// Whether a menu item ID belongs to a launcher, copies excluded.
+pub fn is_launcher_idx(id: i32) -> bool {
+  (FIRST_LAUNCHER_IDX as i32..FIRST_COPY_IDX).contains(&id)
+}
+
+/// Launcher a menu item ID stands for, resolving copies to the launcher they
+/// were made from.
+pub fn launcher_idx(id: i32) -> i32 {
+  if (FIRST_COPY_IDX..FIRST_SUBMENU_IDX).contains(&id) {
+    (id - FIRST_COPY_IDX) % COPY_SPAN
+  } else {
+    id
+  }
+}
+
+/// Longest a pipe menu script may run before its submenu is left empty.
+pub const PIPE_MENU_TIMEOUT: Duration = Duration::from_secs(2);
+/// First menu item ID handed out to launchers; lower IDs are reserved for built-in items.
+pub const FIRST_LAUNCHER_IDX: usize = 1000;
+
+/// StatusNotifierItem Id used unless one is configured.
+pub const DEFAULT_ITEM_ID: &str = "WSLAppMenu";
+/// Bus name claimed unless one is configured or derived from a custom Id.
+pub const DEFAULT_BUS_NAME: &str = "org.wsl.AppMenuDbusMenu";
+/// Parent of the StatusNotifierItem object path derived from a custom Id.
+pub const ITEM_PATH_PREFIX: &str = "/org/ayatana/NotificationItem";
+/// Object path of the StatusNotifierItem unless configured or derived from a custom Id.
+pub const DEFAULT_ITEM_PATH: &str = "/org/ayatana/NotificationItem/wslAppMenuDbusMenu";
+/// Edge length menu icons are scaled to unless configured otherwise.
+pub const MENU_ICON_SIZE: u32 = 24;
+/// Largest icon file read, so a huge one can't stall rendering.
+pub const MAX_ICON_FILE_BYTES: u64 = 16 * 1024 * 1024;
+/// Longest edge of an icon decoded or rendered.
+pub const MAX_ICON_DIMENSION: u32 = 4096;
+/// Most memory an image decoder may allocate for one icon.
+pub const MAX_ICON_ALLOC: u64 = 128 * 1024 * 1024;
+/// Largest `icon-data` sent, keeping layouts far below the D-Bus message
+/// size limit; bigger icons are downscaled until they fit.
+pub const MAX_ICON_DATA_BYTES: usize = 256 * 1024;
+/// Largest total size of the ARGB32 pixmaps listed in `IconPixmap` or
+/// `OverlayIconPixmap`; bigger sizes are left out, smallest first kept.
+pub const MAX_PIXMAP_BYTES: usize = 4 * 1024 * 1024;
+/// How long a cached icon render may go unused before it is removed.
+pub const ICON_CACHE_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Prefix of the names exported icons are loaded by.
+pub const EXPORTED_ICON_PREFIX: &str = "xdg-app-ksni-";
+/// Sizes the tray icon is rendered at for hosts that can't load themed icons,
+/// unless configured otherwise.
+pub const TRAY_PIXMAP_SIZES: [u32; 3] = [22, 32, 48];
+/// Most icon themes looked up for an icon, the active one and its parents,
+/// bounding `Inherits` chains that loop or fan out.
+pub const MAX_ICON_THEME_DEPTH: usize = 16;
+/// Color symbolic icons are drawn in for pixmaps with a dark color scheme,
+/// as their own fills are meant for light panels.
+pub const SYMBOLIC_DARK_FOREGROUND: [u8; 3] = [0xee, 0xee, 0xec];
+/// Icon the tray item switches to while something needs the user's attention.
+pub const ATTENTION_ICON_NAME: &str = "dialog-warning";
+/// Most failures listed in the tooltip at once, dropping the oldest.
+pub const MAX_ALERTS: usize = 3;
+/// Title of the tray item's tooltip.
+pub const TOOL_TIP_TITLE: &str = "Applications";
+/// Object path hosts expect the item at when it registers by service name.
+pub const SNI_ITEM_PATH: &str = "/StatusNotifierItem";
+/// Prefix of the per-instance bus name the spec has items register with,
+/// followed by the process ID and an instance number.
+pub const SNI_NAME_PREFIX: &str = "org.kde.StatusNotifierItem";
+/// Vendor menu item properties only returned when asked for by name.
+pub const REQUESTED_ONLY_PROPS: [&str; 1] = ["x-categories"];
+
+/// Values the StatusNotifierItem spec allows for the Category property.
+pub const SNI_CATEGORIES: [&str; 4] = [
+  "ApplicationStatus",
+  "Communications",
+  "SystemServices",
+  "Hardware",
+];
+
+/// Object path serving the `org.wsl.AppMenu.Control` interface.
+pub const CONTROL_PATH: &str = "/org/wsl/AppMenu";
+/// Object path serving the menu as `org.gtk.Menus` and `org.gtk.Actions`.
+#[cfg(feature = "gmenu")]
+pub const GMENU_PATH: &str = "/org/wsl/AppMenu/GMenu";
+/// How often GMenu subscribers are checked for menu changes to be sent.
+#[cfg(feature = "gmenu")]
+pub const GMENU_SYNC_TICK: Duration = Duration::from_secs(1);
+/// How often menu socket subscribers are checked for menu changes to be sent.
+pub const SOCKET_SYNC_TICK: Duration = Duration::from_secs(1);
+
+/// Largest desktop file read; bigger ones are rejected unparsed.
+pub const MAX_ENTRY_FILE_SIZE: u64 = 256 * 1024;
+/// Most keys, localized variants included, a desktop file may define.
+pub const MAX_ENTRY_KEYS: usize = 4096;
+/// Longest value a single desktop file key may hold.
+pub const MAX_ENTRY_VALUE_LEN: usize = 16 * 1024;
+
+/// Program that opens the URL of `Type=Link` entries.
+pub const LINK_OPENER: &str = "xdg-open";
+
+/// Desktop-file ID prefix of menu entries defined in the config.
+pub const CUSTOM_ENTRY_PREFIX: &str = "x-custom-";
+
+/// Delay before the first registration retry while no watcher accepts the
+/// tray item, doubled after each failed attempt.
+pub const REGISTRATION_RETRY: Duration = Duration::from_secs(1);
+/// Longest delay between registration attempts.
+pub const REGISTRATION_RETRY_MAX: Duration = Duration::from_secs(60);
+/// How often the menu checks whether its prefetched layouts are stale.
+pub const WARMUP_TICK: Duration = Duration::from_secs(2);
+/// GetLayout depths prefetched for the root menu, with all properties.
+pub const WARM_LAYOUT_DEPTHS: [i32; 2] = [1, -1];
+/// Most GetLayout results kept for the current revision before starting over.
+pub const MAX_CACHED_LAYOUTS: usize = 64;
+/// State file recording when entries were first seen installed.
+pub const INSTALLS_STATE_FILE: &str = "installed.json";
+/// State file holding the launch scores behind "Frequently used".
+pub const FREQUENCY_STATE_FILE: &str = "frequency.json";
+/// State file holding when the menu was last opened with new entries in it.
+pub const SEEN_STATE_FILE: &str = "seen.json";
+/// State file holding the menu snapshot taken on exit.
+pub const SNAPSHOT_STATE_FILE: &str = "snapshot.json";
+/// State file holding settings changed from the menu or control interface.
+pub const CHOICES_STATE_FILE: &str = "choices.json";
+/// Decayed launch score below which an application is forgotten.
+pub const FREQUENT_MIN_SCORE: f64 = 0.25;
+/// How often entries are checked for ageing out of "Recently added".
+pub const RECENT_EXPIRY_TICK: Duration = Duration::from_secs(600);
+/// Scroll events closer than this to the last profile switch are ignored, so
+/// one flick of the wheel moves a single profile.
+pub const SCROLL_DEBOUNCE: Duration = Duration::from_millis(300);
+/// How often `PATH` directories are checked for changes affecting `TryExec`.
+pub const TRY_EXEC_POLL: Duration = Duration::from_secs(30);
+
+/// Deepest submenu nesting `GetLayout` will descend into.
+pub const MAX_LAYOUT_DEPTH: usize = 32;
+
+/// Human-readable build description: crate version, git hash and enabled features.
+pub fn build_info() -> String {
+  let features: Vec<&str> = [
+    ("sandbox", cfg!(feature = "sandbox")),
+    ("gmenu", cfg!(feature = "gmenu")),
+  ]
+  .iter()
+  .filter(|(_, enabled)| *enabled)
+  .map(|(name, _)| *name)
+  .collect();
+  format!(
+    "{} {} ({}) [{}]",
+    env!("CARGO_PKG_NAME"),
+    env!("CARGO_PKG_VERSION"),
+    env!("GIT_HASH"),
+    features.join(", ")
+  )
+}
+
+#[derive(Debug, Clone, Copy, enum_iterator::Sequence, Hash, PartialEq, Eq)]
 pub enum Category {
   AudioVideo,
   Development,
@@ -28,22 +255,90 @@ pub fn category_string(cat: Category) -> &'static str {
     Category::Uncategorized => "Uncategorized",
   }
 }
-pub fn category_idx(cat: Category) -> usize {
+
+/// Main categories of the freedesktop menu specification; the other names
+/// in `CATEGORY_MAP` are additional categories.
+pub const MAIN_CATEGORIES: [&str; 13] = [
+  "AudioVideo",
+  "Audio",
+  "Video",
+  "Development",
+  "Education",
+  "Game",
+  "Graphics",
+  "Network",
+  "Office",
+  "Science",
+  "Settings",
+  "System",
+  "Utility",
+];
+
+/// Whether `name` is a registered additional category, such as `WebBrowser`.
+pub fn is_additional_category(name: &str) -> bool {
+  CATEGORY_MAP.contains_key(name) && !MAIN_CATEGORIES.contains(&name)
+}
+
+/// Standard theme icon of a category submenu.
+pub fn category_icon(cat: Category) -> &'static str {
   match cat {
-    Category::AudioVideo => 1,
-    Category::Development => 2,
-    Category::EducationScience => 3,
-    Category::Games => 4,
-    Category::Graphics => 5,
-    Category::Network => 6,
-    Category::Office => 7,
-    Category::Settings => 8,
-    Category::System => 9,
-    Category::Utility => 10,
-    Category::Uncategorized => 11,
+    Category::AudioVideo => "applications-multimedia",
+    Category::Development => "applications-development",
+    Category::EducationScience => "applications-science",
+    Category::Games => "applications-games",
+    Category::Graphics => "applications-graphics",
+    Category::Network => "applications-internet",
+    Category::Office => "applications-office",
+    Category::Settings => "preferences-system",
+    Category::System => "applications-system",
+    Category::Utility => "applications-utilities",
+    Category::Uncategorized => "applications-other",
   }
 }
 
+/// Looks a category up by a freedesktop category name or its menu label.
+pub fn category_by_name(name: &str) -> Option<Category> {
+  CATEGORY_MAP
+    .get(name)
+    .cloned()
+    .or_else(|| enum_iterator::all::<Category>().find(|c| category_string(*c) == name))
+}
+
+/// Category names seen in the wild that aren't registered, keyed by their
+/// normalized form (see `normalize_category`).
+pub static CATEGORY_ALIASES: phf::Map<&'static str, Category> = phf::phf_map! {
+  "multimedia" => Category::AudioVideo,
+  "sound" => Category::AudioVideo,
+  "programming" => Category::Development,
+  "editor" => Category::Development,
+  "educational" => Category::EducationScience,
+  "gaming" => Category::Games,
+  "graphic" => Category::Graphics,
+  "photo" => Category::Graphics,
+  "internet" => Category::Network,
+  "web" => Category::Network,
+  "webbrowser" => Category::Network,
+  "browser" => Category::Network,
+  "communication" => Category::Network,
+  "productivity" => Category::Office,
+  "preferences" => Category::Settings,
+  "administration" => Category::System,
+  "terminal" => Category::System,
+  "accessories" => Category::Utility,
+  "utilities" => Category::Utility,
+  "tools" => Category::Utility,
+};
+
+/// Lowercases a category name and drops everything but letters and digits,
+/// so `Text-Editor` and `texteditor` compare equal.
+pub fn normalize_category(name: &str) -> String {
+  name
+    .chars()
+    .filter(|c| c.is_alphanumeric())
+    .flat_map(char::to_lowercase)
+    .collect()
+}
+
 pub static CATEGORY_MAP: phf::Map<&'static str, Category> = phf::phf_map! {
   "Audio" => Category::AudioVideo,
   "Video" => Category::AudioVideo,
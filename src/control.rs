@@ -0,0 +1,319 @@
+use log::{info, warn};
+use zbus::{dbus_interface, DBusError, SignalContext};
+
+use crate::constants;
+use crate::registration::RegistrationState;
+
+/// Desktop-file ids touched by a single menu mutation.
+#[derive(Debug, Default)]
+pub struct MenuChanges {
+  pub added: Vec<String>,
+  pub removed: Vec<String>,
+  pub updated: Vec<String>,
+}
+
+impl MenuChanges {
+  pub fn is_empty(&self) -> bool {
+    self.added.is_empty() && self.removed.is_empty() && self.updated.is_empty()
+  }
+}
+
+#[derive(DBusError, Debug)]
+#[dbus_error(prefix = "org.wsl.AppMenu.Control")]
+pub enum ControlError {
+  #[dbus_error(zbus_error)]
+  ZBus(zbus::Error),
+  InvalidArgument(String),
+  NotFound(String),
+  NotEnabled(String),
+  TrayDisabled,
+}
+
+#[derive(Default)]
+pub struct AppMenuControl {
+  pub registration: RegistrationState,
+  pub registration_transitions: u32,
+  /// Feeds the file watcher loop, which config reloads go through so that
+  /// application directories are watched again as well.
+  pub events: Option<std::sync::mpsc::Sender<notify::DebouncedEvent>>,
+}
+
+#[dbus_interface(name = "org.wsl.AppMenu.Control")]
+impl AppMenuControl {
+  /// Version property
+  #[dbus_interface(property)]
+  async fn version(&self) -> String {
+    constants::build_info()
+  }
+
+  /// RegistrationState property
+  #[dbus_interface(property)]
+  async fn registration_state(&self) -> String {
+    self.registration.to_string()
+  }
+
+  /// RegistrationTransitions property
+  #[dbus_interface(property)]
+  async fn registration_transitions(&self) -> u32 {
+    self.registration_transitions
+  }
+
+  /// Reload method
+  async fn reload(
+    &self,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+  ) -> Result<(), ControlError> {
+    let menu = server
+      .interface::<_, crate::AppMenuDbusMenu>(
+        crate::util::init::get_object_names().menu_path.as_str(),
+      )
+      .await?;
+    info!("Reloading application entries");
+    menu.get_mut().await.rescan(menu.signal_context()).await;
+    Ok(())
+  }
+
+  /// Rescan method
+  async fn rescan(
+    &self,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+  ) -> Result<(), ControlError> {
+    self.reload(server).await
+  }
+
+  /// ReloadConfig method
+  async fn reload_config(&self) -> Result<(), ControlError> {
+    let path = crate::config::user_config_path()
+      .ok_or_else(|| ControlError::NotFound("Failed to locate the config file".to_string()))?;
+    let events = self
+      .events
+      .as_ref()
+      .ok_or_else(|| ControlError::NotEnabled("No file watcher running".to_string()))?;
+    info!("Config reload requested");
+    events
+      .send(notify::DebouncedEvent::Write(path))
+      .map_err(|_| ControlError::NotEnabled("File watcher stopped".to_string()))
+  }
+
+  /// SetLogLevel method
+  async fn set_log_level(&self, level: &str) -> Result<(), ControlError> {
+    if !crate::util::init::set_log_level(level) {
+      return Err(ControlError::InvalidArgument(format!(
+        "Unknown log level '{}', expected error, warn, info, debug or trace",
+        level
+      )));
+    }
+    info!("Log level set to {}", level);
+    Ok(())
+  }
+
+  /// GetStats method
+  async fn get_stats(
+    &self,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+  ) -> Result<std::collections::HashMap<String, zbus::zvariant::OwnedValue>, ControlError> {
+    let menu = server
+      .interface::<_, crate::AppMenuDbusMenu>(
+        crate::util::init::get_object_names().menu_path.as_str(),
+      )
+      .await?;
+    let stats = menu.get().await.stats();
+    Ok(stats)
+  }
+
+  /// GetDesktopEntry method
+  async fn get_desktop_entry(
+    &self,
+    appid: &str,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+  ) -> Result<String, ControlError> {
+    if !crate::config::get().expose_desktop_entries {
+      return Err(ControlError::NotEnabled(
+        "Set expose_desktop_entries in the config to read desktop entries".to_string(),
+      ));
+    }
+    let menu = server
+      .interface::<_, crate::AppMenuDbusMenu>(
+        crate::util::init::get_object_names().menu_path.as_str(),
+      )
+      .await?;
+    let path = menu
+      .get()
+      .await
+      .active_entry_path(appid)
+      .ok_or_else(|| ControlError::NotFound(format!("No active entry for '{}'", appid)))?;
+    crate::desktop::raw_entry(&path).map_err(ControlError::NotFound)
+  }
+
+  /// Pin method
+  async fn pin(
+    &self,
+    appid: &str,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+  ) -> Result<(), ControlError> {
+    set_favorite(server, appid, true).await
+  }
+
+  /// Unpin method
+  async fn unpin(
+    &self,
+    appid: &str,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+  ) -> Result<(), ControlError> {
+    set_favorite(server, appid, false).await
+  }
+
+  /// HideApp method
+  async fn hide_app(
+    &self,
+    appid: &str,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+  ) -> Result<(), ControlError> {
+    let menu = server
+      .interface::<_, crate::AppMenuDbusMenu>(
+        crate::util::init::get_object_names().menu_path.as_str(),
+      )
+      .await?;
+    let (id, path) = menu
+      .get()
+      .await
+      .active_entry(appid)
+      .map(|(id, launcher)| (id.clone(), launcher.path.clone()))
+      .ok_or_else(|| ControlError::NotFound(format!("No active entry for '{}'", appid)))?;
+    if crate::desktop::is_custom_id(&id) {
+      return Err(ControlError::InvalidArgument(format!(
+        "'{}' is defined in the config, remove it there instead",
+        appid
+      )));
+    }
+    let target =
+      crate::desktop::write_hidden_override(&id, &path).map_err(ControlError::InvalidArgument)?;
+    info!("Hid {} with {:?}", appid, target);
+    Ok(())
+  }
+
+  /// SetTrayCategory method
+  async fn set_tray_category(
+    &self,
+    category: &str,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+  ) -> Result<(), ControlError> {
+    if !constants::SNI_CATEGORIES.contains(&category) {
+      return Err(ControlError::InvalidArgument(format!(
+        "Unknown category '{}', expected one of {:?}",
+        category,
+        constants::SNI_CATEGORIES
+      )));
+    }
+    let item = tray_item(server).await?;
+    let mut iface = item.get_mut().await;
+    if iface.category != category {
+      info!("Tray category changed to {}", category);
+      iface.category = category.to_string();
+      iface.category_changed(item.signal_context()).await?;
+    }
+    Ok(())
+  }
+
+  /// SetTrayTitle method
+  async fn set_tray_title(
+    &self,
+    title: &str,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+  ) -> Result<(), ControlError> {
+    let item = tray_item(server).await?;
+    let mut iface = item.get_mut().await;
+    if iface.title != title {
+      info!("Tray title changed to {}", title);
+      iface.title = title.to_string();
+      crate::AppMenuStatusNotifierItem::new_title(item.signal_context()).await?;
+    }
+    Ok(())
+  }
+
+  /// SetTrayIcon method
+  async fn set_tray_icon(
+    &self,
+    icon_name: &str,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+  ) -> Result<(), ControlError> {
+    let item = tray_item(server).await?;
+    let mut iface = item.get_mut().await;
+    if iface.icon_name != icon_name {
+      info!("Tray icon changed to {}", icon_name);
+      iface.set_icon(icon_name);
+      crate::AppMenuStatusNotifierItem::new_icon(item.signal_context()).await?;
+    }
+    Ok(())
+  }
+
+  /// MenuChanged signal
+  #[dbus_interface(signal)]
+  pub async fn menu_changed(
+    ctxt: &SignalContext<'_>,
+    added: &[String],
+    removed: &[String],
+    updated: &[String],
+  ) -> zbus::Result<()>;
+}
+
+/// The tray item, unless running in menu-only mode.
+async fn tray_item(
+  server: &zbus::ObjectServer,
+) -> Result<zbus::InterfaceRef<crate::AppMenuStatusNotifierItem>, ControlError> {
+  server
+    .interface::<_, crate::AppMenuStatusNotifierItem>(
+      crate::util::init::get_object_names().item_path.as_str(),
+    )
+    .await
+    .map_err(|_| ControlError::TrayDisabled)
+}
+
+/// Pins or unpins a desktop-file ID, saving the choice and updating the menu
+/// right away.
+async fn set_favorite(
+  server: &zbus::ObjectServer,
+  appid: &str,
+  favorite: bool,
+) -> Result<(), ControlError> {
+  let menu = server
+    .interface::<_, crate::AppMenuDbusMenu>(
+      crate::util::init::get_object_names().menu_path.as_str(),
+    )
+    .await?;
+  if favorite && menu.get().await.active_entry(appid).is_none() {
+    return Err(ControlError::NotFound(format!(
+      "No active entry for '{}'",
+      appid
+    )));
+  }
+  crate::config::set_favorite(appid, favorite).map_err(ControlError::InvalidArgument)?;
+  info!("{} {}", if favorite { "Pinned" } else { "Unpinned" }, appid);
+  crate::config::reload();
+  menu
+    .get_mut()
+    .await
+    .refresh_copies(menu.signal_context())
+    .await;
+  Ok(())
+}
+
+/// Broadcasts `MenuChanged` on the control object, if anything changed.
+pub async fn notify_menu_changed(connection: &zbus::Connection, changes: &MenuChanges) {
+  if changes.is_empty() {
+    return;
+  }
+  let ctxt = match SignalContext::new(connection, constants::CONTROL_PATH) {
+    Ok(ctxt) => ctxt,
+    Err(err) => {
+      warn!("Failed to create control signal context: {}", err);
+      return;
+    },
+  };
+  let sig_res =
+    AppMenuControl::menu_changed(&ctxt, &changes.added, &changes.removed, &changes.updated).await;
+  if let Err(err) = sig_res {
+    warn!("Failed to signal menu changes: {}", err);
+  }
+  crate::apps::notify_apps_changed(connection).await;
+}
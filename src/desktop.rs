@@ -1,7 +1,10 @@
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use freedesktop_desktop_entry::DesktopEntry;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 
 use crate::constants;
 use crate::util;
@@ -11,32 +14,207 @@ use crate::util;
 pub struct Launcher {
   pub path: PathBuf,
   pub name: String,
+  /// Localized `GenericName`, such as "Web Browser".
+  pub generic_name: Option<String>,
+  /// Localized `Comment`, a tooltip-style description.
+  pub comment: Option<String>,
+  /// Localized `Keywords`, extra terms to search by.
+  pub keywords: Vec<String>,
   pub categories: Vec<constants::Category>,
-  pub exec: String,
+  pub category_names: Vec<String>,
+  pub exec: Vec<String>,
+  /// File or URL field code of `Exec` and the index in `exec` it stood at,
+  /// where URIs given to a launch go.
+  pub file_code: Option<(char, usize)>,
+  pub working_dir: Option<PathBuf>,
+  /// Variables added to the environment of the command.
+  pub env: Vec<(String, String)>,
   pub icon: Option<String>,
   pub display: bool,
+  pub terminal: bool,
+  pub runnable: bool,
+  pub try_exec: Option<String>,
+  pub try_exec_missing: bool,
+  pub tombstone: bool,
+  /// Tombstone of a removed file rather than of `Hidden=true`, which doesn't
+  /// hide copies in lower-priority directories.
+  pub deleted: bool,
 }
 
-pub fn tombstone_launcher(path: PathBuf, name: String) -> Launcher {
+/// Placeholder for an entry that is gone, either `deleted` or hidden by
+/// `Hidden=true`.
+pub fn tombstone_launcher(path: PathBuf, name: String, deleted: bool) -> Launcher {
   Launcher {
     path,
     name,
+    generic_name: None,
+    comment: None,
+    keywords: vec![],
     categories: vec![],
-    exec: String::new(),
+    category_names: vec![],
+    exec: vec![],
+    file_code: None,
+    working_dir: None,
+    env: vec![],
     icon: None,
     display: false,
+    terminal: false,
+    runnable: true,
+    try_exec: None,
+    try_exec_missing: false,
+    tombstone: true,
+    deleted,
   }
 }
 
+/// Launcher for a command line from the config, quoted like a desktop
+/// file's `Exec`, or `None` if it doesn't parse.
+pub fn command_launcher(command: &str) -> Option<Launcher> {
+  let exec = util::xdg::exec_tokenize(command).filter(|args| !args.is_empty())?;
+  Some(Launcher {
+    path: crate::config::user_config_path().unwrap_or_default(),
+    name: command.to_string(),
+    generic_name: None,
+    comment: None,
+    keywords: vec![],
+    categories: vec![],
+    category_names: vec![],
+    exec,
+    file_code: None,
+    working_dir: None,
+    env: vec![],
+    icon: None,
+    display: true,
+    terminal: false,
+    runnable: true,
+    try_exec: None,
+    try_exec_missing: false,
+    tombstone: false,
+    deleted: false,
+  })
+}
+
+/// Maps raw category names to menu categories, consulting the configured
+/// category rules and aliases before the built-in mapping.
 fn category_str_convert(vec: Vec<String>) -> Vec<constants::Category> {
+  let config = crate::config::get();
   vec
     .iter()
-    .filter_map(|s| constants::CATEGORY_MAP.get(&s).cloned())
+    .filter_map(|s| {
+      config
+        .compiled_rules
+        .iter()
+        .find(|rule| rule.id.is_none() && rule.category.as_ref().is_some_and(|re| re.is_match(s)))
+        .map(|rule| rule.target)
+        .or_else(|| aliased_category(s, &config.category_aliases))
+        .or_else(|| constants::CATEGORY_MAP.get(s).cloned())
+        .or_else(|| normalized_category(s))
+    })
     .collect()
 }
 
-use zbus::zvariant::{DeserializeDict, OwnedValue, SerializeDict, Type, Value};
-#[derive(SerializeDict, DeserializeDict, Type, Debug, Clone, Value, OwnedValue)]
+/// Maps a category name through the configured aliases, ignoring case and
+/// punctuation.
+fn aliased_category(name: &str, aliases: &HashMap<String, String>) -> Option<constants::Category> {
+  let normalized = constants::normalize_category(name);
+  aliases
+    .iter()
+    .find(|(alias, _)| constants::normalize_category(alias) == normalized)
+    .and_then(|(_, target)| constants::category_by_name(target))
+}
+
+/// Maps a category name the built-in mapping doesn't know exactly: ignoring
+/// case and punctuation, through the registered names and then the built-in
+/// aliases.
+fn normalized_category(name: &str) -> Option<constants::Category> {
+  let normalized = constants::normalize_category(name);
+  let category = constants::CATEGORY_MAP
+    .entries()
+    .find(|(known, _)| constants::normalize_category(known) == normalized)
+    .map(|(_, category)| *category)
+    .or_else(|| {
+      constants::CATEGORY_ALIASES
+        .get(normalized.as_str())
+        .cloned()
+    });
+  if category.is_none() {
+    debug!("Unmapped category {:?}", name);
+  }
+  category
+}
+
+/// Category forced on a desktop-file ID by a configured rule, if any.
+fn id_category(id: &std::ffi::OsStr, category_names: &[String]) -> Option<constants::Category> {
+  let id = id.to_string_lossy();
+  crate::config::get()
+    .compiled_rules
+    .iter()
+    .find(|rule| {
+      rule.id.as_ref().is_some_and(|re| re.is_match(&id))
+        && rule
+          .category
+          .as_ref()
+          .is_none_or(|re| category_names.iter().any(|name| re.is_match(name)))
+    })
+    .map(|rule| rule.target)
+}
+
+use zbus::zvariant::{DeserializeDict, OwnedValue, SerializeDict, Signature, Type, Value};
+
+/// Encoded icon bytes, shared by the menu items showing the same icon file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IconData(std::sync::Arc<[u8]>);
+
+impl std::ops::Deref for IconData {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    &self.0
+  }
+}
+
+impl From<Vec<u8>> for IconData {
+  fn from(data: Vec<u8>) -> Self {
+    IconData(data.into())
+  }
+}
+
+impl Type for IconData {
+  fn signature() -> Signature<'static> {
+    <Vec<u8>>::signature()
+  }
+}
+
+impl serde::Serialize for IconData {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(self.0.iter())
+  }
+}
+
+impl<'de> serde::Deserialize<'de> for IconData {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    Vec::<u8>::deserialize(deserializer).map(IconData::from)
+  }
+}
+
+/// Builds the byte array straight from the shared bytes, without copying
+/// them to a `Vec` first. The array still holds a `Value` per byte, which
+/// `MAX_ICON_DATA_BYTES` keeps bounded per item.
+impl From<IconData> for Value<'_> {
+  fn from(data: IconData) -> Self {
+    Value::Array(zbus::zvariant::Array::from(&*data))
+  }
+}
+
+impl TryFrom<Value<'_>> for IconData {
+  type Error = zbus::zvariant::Error;
+
+  fn try_from(value: Value<'_>) -> Result<Self, Self::Error> {
+    Vec::<u8>::try_from(value).map(IconData::from)
+  }
+}
+
+#[derive(SerializeDict, DeserializeDict, Type, Debug, Clone, PartialEq, Value, OwnedValue)]
 #[zvariant(signature = "a{sv}")]
 pub struct MenuProps {
   pub label: String,
@@ -45,116 +223,989 @@ pub struct MenuProps {
   #[zvariant(rename = "icon-name")]
   pub icon_name: String,
   #[zvariant(rename = "icon-data")]
-  pub icon_data: Vec<u8>,
+  pub icon_data: IconData,
   #[zvariant(rename = "type")]
   pub entry_type: String,
   #[zvariant(rename = "children-display")]
   pub children_display: String,
+  #[zvariant(rename = "accessible-desc")]
+  pub accessible_desc: String,
+  #[zvariant(rename = "x-categories")]
+  pub x_categories: Vec<String>,
+  #[zvariant(rename = "toggle-type")]
+  pub toggle_type: String,
+  #[zvariant(rename = "toggle-state")]
+  pub toggle_state: i32,
+}
+
+impl MenuProps {
+  /// Names the properties are sent under.
+  const NAMES: [&'static str; 11] = [
+    "label",
+    "visible",
+    "enabled",
+    "icon-name",
+    "icon-data",
+    "type",
+    "children-display",
+    "accessible-desc",
+    "x-categories",
+    "toggle-type",
+    "toggle-state",
+  ];
+
+  /// The property sent under `name`, if it is one of `NAMES`.
+  pub fn value_of(&self, name: &str) -> Option<Value<'_>> {
+    let value = match name {
+      "label" => Value::from(self.label.as_str()),
+      "visible" => Value::from(self.visible),
+      "enabled" => Value::from(self.enabled),
+      "icon-name" => Value::from(self.icon_name.as_str()),
+      "icon-data" => Value::from(&*self.icon_data),
+      "type" => Value::from(self.entry_type.as_str()),
+      "children-display" => Value::from(self.children_display.as_str()),
+      "accessible-desc" => Value::from(self.accessible_desc.as_str()),
+      "x-categories" => Value::from(self.x_categories.clone()),
+      "toggle-type" => Value::from(self.toggle_type.as_str()),
+      "toggle-state" => Value::from(self.toggle_state),
+      _ => return None,
+    };
+    Some(value)
+  }
+
+  /// The properties `wanted` picks by name, as sent in layouts and
+  /// property updates.
+  pub fn to_dict(&self, wanted: impl Fn(&str) -> bool) -> HashMap<String, OwnedValue> {
+    MenuProps::NAMES
+      .iter()
+      .filter(|name| wanted(name))
+      .filter_map(|name| Some((name.to_string(), self.value_of(name)?.into())))
+      .collect()
+  }
+}
+
+/// Configured override for a desktop-file ID, keyed with or without the
+/// `.desktop` suffix.
+fn entry_override(id: &std::ffi::OsStr) -> Option<crate::config::EntryOverride> {
+  let id = id.to_string_lossy();
+  let overrides = &crate::config::get().overrides;
+  overrides
+    .get(id.as_ref())
+    .or_else(|| overrides.get(id.strip_suffix(".desktop")?))
+    .cloned()
 }
 
-pub fn launcher_props(launcher: &Launcher) -> MenuProps {
+/// Icon reference of a launcher, after overrides.
+pub fn launcher_icon(id: &std::ffi::OsStr, launcher: &Launcher) -> Option<String> {
+  entry_override(id)
+    .and_then(|o| o.icon)
+    .or_else(|| launcher.icon.clone())
+}
+
+/// Name of a launcher after overrides, before truncation.
+pub fn full_name(id: &std::ffi::OsStr, launcher: &Launcher) -> String {
+  entry_override(id)
+    .and_then(|o| o.label)
+    .unwrap_or_else(|| launcher.name.clone())
+}
+
+/// Shortens `name` to the configured maximum length, cutting between
+/// grapheme clusters so combined characters and emoji stay intact.
+fn truncate_label(name: &str) -> Option<String> {
+  use unicode_segmentation::UnicodeSegmentation;
+  let max = crate::config::get().max_label_length;
+  if max == 0 || name.graphemes(true).nth(max).is_none() {
+    return None;
+  }
+  let kept: String = name.graphemes(true).take(max.saturating_sub(1)).collect();
+  Some(format!("{}…", kept.trim_end()))
+}
+
+/// Menu label of a launcher after overrides and truncation.
+pub fn launcher_label(id: &std::ffi::OsStr, launcher: &Launcher) -> String {
+  let name = full_name(id, launcher);
+  truncate_label(&name).unwrap_or(name)
+}
+
+pub fn launcher_props(id: &std::ffi::OsStr, launcher: &Launcher) -> MenuProps {
   let mut props = MenuProps {
-    label: launcher.name.clone(),
-    visible: launcher.display,
+    label: launcher_label(id, launcher),
+    visible: launcher_visible(launcher) && in_profile(id, launcher),
     icon_name: String::new(),
     entry_type: "standard".to_string(),
     children_display: String::new(),
-    icon_data: vec![],
+    icon_data: IconData::default(),
     enabled: true,
+    accessible_desc: String::new(),
+    x_categories: launcher.category_names.clone(),
+    toggle_type: String::new(),
+    toggle_state: -1,
   };
 
-  if launcher.icon.is_some() {
-    let icon_ref = launcher.icon.as_ref().unwrap();
-    if icon_ref.contains("/") {
-      let icon_path = std::path::Path::new(icon_ref);
-      if icon_path.exists() && icon_path.is_file() && icon_path.extension().is_some() {
-        let ext = icon_path.extension().unwrap();
-        if ext == "svg" {
-          let mut svg_opts = usvg::Options::default();
-          svg_opts.resources_dir = std::fs::canonicalize(icon_path)
-            .ok()
-            .and_then(|p| p.parent().map(|p| p.to_path_buf()));
-          svg_opts.fontdb.load_system_fonts();
-          let svg_data = std::fs::read(icon_path).unwrap();
-          let rtree = usvg::Tree::from_data(&svg_data, &svg_opts.to_ref());
-          if rtree.is_err() {
-            let err = rtree.err();
-            error!("Failed to parse SVG {:?}: {:?}", icon_path, err);
-          } else {
-            let rtree = rtree.unwrap();
-            let pixmap_size = rtree.svg_node().size.to_screen_size();
-            let pixmap = tiny_skia::Pixmap::new(pixmap_size.width(), pixmap_size.height());
-            if pixmap.is_none() {
-              error!("Failed to make skia bitmap");
-            } else {
-              let mut pixmap = pixmap.unwrap();
-              let render = resvg::render(
-                &rtree,
-                usvg::FitTo::Original,
-                tiny_skia::Transform::default(),
-                pixmap.as_mut(),
-              );
-              if render.is_none() {
-                error!("Failed to render SVG");
-              } else {
-                let png_data = pixmap.encode_png();
-                if png_data.is_err() {
-                  let err = png_data.err().unwrap();
-                  error!("Failed to convert {:?} to PNG: {:?}", icon_path, err);
-                } else {
-                  let png_data = png_data.unwrap();
-                  props.icon_data = png_data;
-                }
-              }
-            }
-          }
-        } else {
-          use image::io::Reader as ImageReader;
-          use std::io::Cursor;
-          let data = ImageReader::open(icon_path);
-          if data.is_err() {
-            let err = data.err().unwrap();
-            error!("Failed to read image at {:?}: {}", icon_path, err);
-          } else {
-            let data = data.unwrap().decode();
-            if data.is_err() {
-              let err = data.err().unwrap();
-              error!("Failed to parse image at {:?}: {}", icon_path, err);
-            } else {
-              let data = data.unwrap();
-              let mut png_bytes: Vec<u8> = Vec::new();
-              let decode_res = data.write_to(
-                &mut Cursor::new(&mut png_bytes),
-                image::ImageOutputFormat::Png,
-              );
-              if decode_res.is_err() {
-                let err = decode_res.err().unwrap();
-                error!("Failed to convert image at {:?}: {}", icon_path, err);
-              } else {
-                props.icon_data = png_bytes;
-              }
+  if !launcher.runnable {
+    props.enabled = false;
+    props.accessible_desc = format!(
+      "{} is installed for other users; you lack permission to run it",
+      launcher.name
+    );
+  } else {
+    let name = full_name(id, launcher);
+    let truncated = truncate_label(&name).is_some().then_some(name);
+    props.accessible_desc = truncated
+      .into_iter()
+      .chain(launcher.comment.clone())
+      .collect::<Vec<_>>()
+      .join(": ");
+  }
+
+  // Icon files are rendered by the menu, once the entry is about to be shown.
+  if let Some(icon_ref) = launcher_icon(id, launcher).filter(|i| !i.contains('/')) {
+    props.icon_name = icon_ref;
+  }
+  props
+}
+
+/// Shows the icon file `icon_ref` on a menu item, by the name it was
+/// exported under or else as embedded PNG data. Returns whether it rendered.
+pub fn set_file_icon(props: &mut MenuProps, icon_ref: &str) -> bool {
+  (props.icon_name, props.icon_data) = file_icon(icon_ref);
+  !props.icon_name.is_empty() || !props.icon_data.is_empty()
+}
+
+/// The `icon-name` and `icon-data` showing icon file `icon_ref`, both empty
+/// if it can't be rendered.
+pub fn file_icon(icon_ref: &str) -> (String, IconData) {
+  match export_icon(Path::new(icon_ref)) {
+    Some(name) => (name, IconData::default()),
+    None => (String::new(), shared_icon_data(icon_ref)),
+  }
+}
+
+type IconKey = (PathBuf, Option<SystemTime>, Option<u32>);
+
+/// Icon files rendered for menu items still around, by path, modification
+/// time and size.
+static ICON_STORE: std::sync::Mutex<Option<HashMap<IconKey, std::sync::Weak<[u8]>>>> =
+  std::sync::Mutex::new(None);
+
+/// Renders an icon file like `icon_file_data`, sharing the bytes with every
+/// other menu item showing it.
+fn shared_icon_data(icon_ref: &str) -> IconData {
+  let path = PathBuf::from(icon_ref);
+  let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+  let key = (path, modified, util::init::get_menu_icon_size());
+  let lookup = |store: &mut Option<HashMap<IconKey, std::sync::Weak<[u8]>>>| {
+    store.get_or_insert_with(HashMap::new).get(&key)?.upgrade()
+  };
+  if let Some(data) = lookup(&mut ICON_STORE.lock().unwrap()) {
+    return IconData(data);
+  }
+  let data = match icon_file_data(icon_ref) {
+    Some(data) => IconData::from(data),
+    None => return IconData::default(),
+  };
+  let mut store = ICON_STORE.lock().unwrap();
+  let store = store.get_or_insert_with(HashMap::new);
+  store.retain(|_, data| data.strong_count() > 0);
+  store.insert(key, std::sync::Arc::downgrade(&data.0));
+  data
+}
+
+/// Icon size the exported theme was last set up for.
+static EXPORTED_SIZE: std::sync::Mutex<Option<u32>> = std::sync::Mutex::new(None);
+
+/// Sets up the exported hicolor theme in `theme` for icons of `size`: writes
+/// the `index.theme` hosts need to find them, and removes icons exported at
+/// other sizes, which would otherwise be kept forever.
+fn prepare_icon_theme(theme: &Path, size: u32) -> Result<(), String> {
+  let mut exported_size = EXPORTED_SIZE.lock().unwrap();
+  if *exported_size == Some(size) {
+    return Ok(());
+  }
+  std::fs::create_dir_all(theme).map_err(|err| err.to_string())?;
+  let size_dir = format!("{0}x{0}", size);
+  for entry in std::fs::read_dir(theme)
+    .map_err(|err| err.to_string())?
+    .flatten()
+  {
+    if entry.file_name() == *size_dir || !entry.path().is_dir() {
+      continue;
+    }
+    if let Err(err) = std::fs::remove_dir_all(entry.path()) {
+      warn!(
+        "Failed to remove stale exported icons {:?}: {}",
+        entry.path(),
+        err
+      );
+    }
+  }
+  let index = format!(
+    "[Icon Theme]\nName=hicolor\nComment=Icons exported by xdg-app-ksni\n\
+     Directories={0}/apps\n\n[{0}/apps]\nSize={1}\nType=Fixed\n",
+    size_dir, size
+  );
+  std::fs::write(theme.join("index.theme"), index).map_err(|err| err.to_string())?;
+  *exported_size = Some(size);
+  Ok(())
+}
+
+/// Renders an icon file into the exported icon theme, unless an up to date
+/// copy is there already, returning the name hosts can load it by.
+fn export_icon(icon_path: &Path) -> Option<String> {
+  let dir = util::init::get_icon_theme_dir()?;
+  let size = util::init::get_menu_icon_size().unwrap_or(constants::MENU_ICON_SIZE);
+  if let Err(err) = prepare_icon_theme(&dir.join("hicolor"), size) {
+    warn!(
+      "Failed to set up the exported icon theme in {:?}: {}",
+      dir, err
+    );
+    return None;
+  }
+  let hash = stable_hash(icon_path.as_os_str().as_encoded_bytes());
+  let name = format!("{}{:016x}", constants::EXPORTED_ICON_PREFIX, hash);
+  let target = dir
+    .join("hicolor")
+    .join(format!("{0}x{0}", size))
+    .join("apps")
+    .join(format!("{}.png", name));
+  let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+  if modified(&target).is_some_and(|exported| modified(icon_path) <= Some(exported)) {
+    return Some(name);
+  }
+  let image = icon_image(icon_path, Some(size))?;
+  let saved = std::fs::create_dir_all(target.parent()?)
+    .map_err(|err| err.to_string())
+    .and_then(|_| image.save(&target).map_err(|err| err.to_string()));
+  if let Err(err) = saved {
+    warn!(
+      "Failed to export icon {:?} to {:?}: {}",
+      icon_path, target, err
+    );
+    return None;
+  }
+  Some(name)
+}
+
+/// Copies of a launcher with `uris` passed the way its `Exec` field code
+/// asks: local paths for `%f`/`%F`, and one launch per URI for `%f`/`%u`.
+/// Launchers without a field code, or taking files when none of `uris` is
+/// local, are launched once, ignoring `uris`.
+pub fn with_uris(launcher: &Launcher, uris: &[String]) -> Vec<Launcher> {
+  let (code, at) = match launcher.file_code {
+    Some(file_code) if !uris.is_empty() => file_code,
+    _ => return vec![launcher.clone()],
+  };
+  let args: Vec<String> = if code.eq_ignore_ascii_case(&'f') {
+    uris
+      .iter()
+      .filter_map(|uri| util::xdg::uri_path(uri))
+      .collect()
+  } else {
+    uris.to_vec()
+  };
+  if args.is_empty() {
+    warn!(
+      "{} only opens local files, launching it without {:?}",
+      launcher.name, uris
+    );
+    return vec![launcher.clone()];
+  }
+  let groups = if code.is_ascii_uppercase() {
+    vec![args]
+  } else {
+    args.into_iter().map(|arg| vec![arg]).collect()
+  };
+  groups
+    .into_iter()
+    .map(|group| {
+      let mut copy = launcher.clone();
+      copy.exec.splice(at..at, group);
+      copy
+    })
+    .collect()
+}
+
+/// Whether a launcher is listed, given the display options.
+pub fn launcher_visible(launcher: &Launcher) -> bool {
+  (launcher.display || util::init::get_show_hidden())
+    && (!launcher.terminal || util::init::get_show_terminal())
+    && !launcher.try_exec_missing
+    && !launcher.tombstone
+}
+
+/// Whether a launcher is listed in the active menu profile.
+pub fn in_profile(id: &std::ffi::OsStr, launcher: &Launcher) -> bool {
+  let profile = match util::init::get_profile() {
+    Some(profile) => profile,
+    None => return true,
+  };
+  let id = id.to_string_lossy();
+  let favorite = || {
+    crate::config::get()
+      .favorites
+      .iter()
+      .any(|f| *f == id || format!("{}.desktop", f) == id)
+  };
+  let in_category = |name: &String| {
+    launcher.category_names.contains(name)
+      || constants::category_by_name(name).is_some_and(|c| launcher.categories.contains(&c))
+  };
+  (!profile.favorites_only || favorite())
+    && profile
+      .windows
+      .is_none_or(|windows| crate::sections::is_windows(launcher) == windows)
+    && (profile.categories.is_empty() || profile.categories.iter().any(in_category))
+}
+
+/// Whether the icon reference is a file that didn't make it into `icon-data`.
+pub fn icon_render_failed(icon: Option<&str>, props: &MenuProps) -> bool {
+  props.icon_data.is_empty()
+    && props.icon_name.is_empty()
+    && icon.is_some_and(|icon_ref| icon_ref.contains('/'))
+}
+
+/// FNV-1a, so names derived from it stay the same across builds and restarts.
+fn stable_hash(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+    (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+  })
+}
+
+/// Where the PNG rendered from an icon file at a size is cached, named after
+/// its path, modification time and the size so that stale renders are never
+/// picked up.
+fn cached_icon_path(icon_path: &Path, size: Option<u32>) -> Option<PathBuf> {
+  let dir = util::init::get_icon_cache_dir()?;
+  let modified = std::fs::metadata(icon_path)
+    .and_then(|m| m.modified())
+    .ok()?;
+  let modified = modified.duration_since(std::time::UNIX_EPOCH).ok()?;
+  let mut key = icon_path.as_os_str().as_encoded_bytes().to_vec();
+  key.extend_from_slice(&modified.as_nanos().to_le_bytes());
+  key.extend_from_slice(&size.unwrap_or(0).to_le_bytes());
+  Some(dir.join(format!("{:016x}.png", stable_hash(&key))))
+}
+
+/// Renders an icon file to PNG bytes, reusing an earlier render if cached.
+pub fn icon_file_data(icon_ref: &str) -> Option<Vec<u8>> {
+  use std::io::Cursor;
+  let icon_path = std::path::Path::new(icon_ref);
+  let size = util::init::get_menu_icon_size();
+  let cached = cached_icon_path(icon_path, size);
+  let cached_bytes = cached.as_ref().and_then(|path| std::fs::read(path).ok());
+  if let Some(png_bytes) = cached_bytes.filter(|b| b.len() <= constants::MAX_ICON_DATA_BYTES) {
+    // Marks the render as used, so pruning the cache keeps it.
+    if let Some(file) = cached.and_then(|path| std::fs::File::options().write(true).open(path).ok())
+    {
+      let _ = file.set_modified(std::time::SystemTime::now());
+    }
+    return Some(png_bytes);
+  }
+  let mut image = icon_image(icon_path, size)?;
+  let png_bytes = loop {
+    let mut png_bytes: Vec<u8> = Vec::new();
+    let encode_res = image.write_to(
+      &mut Cursor::new(&mut png_bytes),
+      image::ImageOutputFormat::Png,
+    );
+    if let Err(err) = encode_res {
+      error!("Failed to convert image at {:?}: {}", icon_path, err);
+      return None;
+    }
+    if png_bytes.len() <= constants::MAX_ICON_DATA_BYTES {
+      break png_bytes;
+    }
+    if image.width().max(image.height()) <= constants::MENU_ICON_SIZE {
+      warn!(
+        "Dropping icon {:?}: it takes {} bytes even at {}x{}",
+        icon_path,
+        png_bytes.len(),
+        image.width(),
+        image.height()
+      );
+      return None;
+    }
+    warn!(
+      "Icon {:?} at {}x{} takes {} bytes, downscaling it",
+      icon_path,
+      image.width(),
+      image.height(),
+      png_bytes.len()
+    );
+    image = image::imageops::resize(
+      &image,
+      (image.width() / 2).max(1),
+      (image.height() / 2).max(1),
+      image::imageops::FilterType::Lanczos3,
+    );
+  };
+  if let Some(path) = cached {
+    // Unique per render, as the same icon may be rendered on several threads.
+    static RENDERS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let render = RENDERS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let tmp = path.with_extension(format!("{}.{}.tmp", std::process::id(), render));
+    let written = std::fs::write(&tmp, &png_bytes).and_then(|_| std::fs::rename(&tmp, &path));
+    if let Err(err) = written {
+      warn!(
+        "Failed to cache icon {:?} at {:?}: {}",
+        icon_path, path, err
+      );
+    }
+  }
+  Some(png_bytes)
+}
+
+/// The leading `sizes` whose square ARGB32 pixmaps fit together within
+/// `MAX_PIXMAP_BYTES`, so a pixmap list can't outgrow a D-Bus message.
+fn pixmap_sizes(sizes: &[u32]) -> &[u32] {
+  let mut total = 0usize;
+  let fitting = sizes
+    .iter()
+    .take_while(|size| {
+      total = total.saturating_add((**size as usize).pow(2).saturating_mul(4));
+      total <= constants::MAX_PIXMAP_BYTES
+    })
+    .count();
+  if fitting < sizes.len() {
+    debug!(
+      "Skipping icon sizes {:?} over the pixmap size limit",
+      &sizes[fitting..]
+    );
+  }
+  &sizes[..fitting]
+}
+
+/// Renders the tray icon, a themed name or a file, as `IconPixmap` ARGB32
+/// pixmaps at each of `sizes` that fits `pixmap_sizes`, skipping sizes it
+/// can't be found or rendered at. Symbolic icons are recolored to a light
+/// foreground while a dark color scheme is followed.
+pub fn icon_pixmaps(icon_ref: &str, sizes: &[u32]) -> Vec<(i32, i32, Vec<u8>)> {
+  pixmap_sizes(sizes)
+    .iter()
+    .filter_map(|size| {
+      let path = if icon_ref.contains('/') {
+        PathBuf::from(icon_ref)
+      } else {
+        find_themed_icon(icon_ref, *size)?
+      };
+      let mut image = icon_image(&path, Some(*size))?;
+      let symbolic = path
+        .file_stem()
+        .is_some_and(|stem| stem.to_string_lossy().ends_with("-symbolic"));
+      if symbolic && util::appearance::prefers_dark() {
+        let [r, g, b] = constants::SYMBOLIC_DARK_FOREGROUND;
+        for image::Rgba([pr, pg, pb, _]) in image.pixels_mut() {
+          (*pr, *pg, *pb) = (r, g, b);
+        }
+      }
+      Some(argb_pixmap(&image))
+    })
+    .collect()
+}
+
+/// Converts an image to an SNI pixmap: width, height and ARGB32 data in
+/// network byte order.
+fn argb_pixmap(image: &image::RgbaImage) -> (i32, i32, Vec<u8>) {
+  let argb = image
+    .pixels()
+    .flat_map(|image::Rgba([r, g, b, a])| [*a, *r, *g, *b])
+    .collect();
+  (image.width() as i32, image.height() as i32, argb)
+}
+
+/// 3x5 glyphs for badge text, one row of three bits per entry.
+fn badge_glyph(c: char) -> [u8; 5] {
+  match c {
+    '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+    '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+    '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+    '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+    '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+    '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+    '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+    '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+    '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+    '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+    _ => [0b000, 0b010, 0b111, 0b010, 0b000],
+  }
+}
+
+/// Renders a badge with `count`, or "9+" above nine, in the bottom right
+/// corner of otherwise transparent `OverlayIconPixmap` pixmaps at `sizes`,
+/// as far as they fit `pixmap_sizes`.
+pub fn badge_pixmaps(count: usize, sizes: &[u32]) -> Vec<(i32, i32, Vec<u8>)> {
+  let text = if count > 9 {
+    "9+".to_string()
+  } else {
+    count.to_string()
+  };
+  pixmap_sizes(sizes)
+    .iter()
+    .filter_map(|size| {
+      let mut pixmap = tiny_skia::Pixmap::new(*size, *size)?;
+      let mut paint = tiny_skia::Paint {
+        anti_alias: true,
+        ..Default::default()
+      };
+      let radius = *size as f32 * 0.3;
+      let center = *size as f32 - radius;
+      paint.set_color_rgba8(0xd3, 0x2f, 0x2f, 0xff);
+      pixmap.fill_path(
+        &tiny_skia::PathBuilder::from_circle(center, center, radius)?,
+        &paint,
+        tiny_skia::FillRule::Winding,
+        tiny_skia::Transform::identity(),
+        None,
+      )?;
+      // Glyphs are three cells wide with one between them, and fit inside
+      // the circle both ways. Cells are whole pixels to keep digits crisp.
+      let columns = text.len() as f32 * 4.0 - 1.0;
+      let cell = (radius * 1.2 / 5.0)
+        .min(radius * 1.6 / columns)
+        .floor()
+        .max(1.0);
+      let left = (center - columns * cell / 2.0).round();
+      let top = (center - 2.5 * cell).round();
+      paint.anti_alias = false;
+      paint.set_color_rgba8(0xff, 0xff, 0xff, 0xff);
+      for (i, c) in text.chars().enumerate() {
+        for (row, bits) in badge_glyph(c).iter().enumerate() {
+          for col in (0..3).filter(|col| bits & (0b100 >> col) != 0) {
+            let x = left + (i * 4 + col) as f32 * cell;
+            let y = top + row as f32 * cell;
+            if let Some(rect) = tiny_skia::Rect::from_xywh(x, y, cell, cell) {
+              pixmap.fill_rect(rect, &paint, tiny_skia::Transform::identity(), None);
             }
           }
         }
-      } else {
-        warn!("Icon at {:?} not found", icon_path);
       }
-    } else {
-      props.icon_name = icon_ref.clone();
+      let rgba = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+          let color = pixel.demultiply();
+          [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+      Some(argb_pixmap(&image::RgbaImage::from_raw(
+        *size, *size, rgba,
+      )?))
+    })
+    .collect()
+}
+
+/// Names of the themes icons are looked up in, in order: the active theme,
+/// the themes it inherits from, breadth first, then hicolor.
+fn icon_theme_chain(data_dirs: &[PathBuf]) -> Vec<String> {
+  let mut chain: Vec<String> = util::init::get_icon_theme().into_iter().collect();
+  let mut next = 0;
+  while next < chain.len() && chain.len() < constants::MAX_ICON_THEME_DEPTH {
+    let index = data_dirs
+      .iter()
+      .map(|data_dir| {
+        data_dir
+          .join("icons")
+          .join(&chain[next])
+          .join("index.theme")
+      })
+      .find_map(|path| std::fs::read_to_string(path).ok())
+      .unwrap_or_default();
+    let parents = index.lines().find_map(|line| {
+      let (key, value) = line.split_once('=')?;
+      (key.trim() == "Inherits").then(|| value.to_string())
+    });
+    for parent in parents.iter().flat_map(|parents| parents.split(',')) {
+      let parent = parent.trim();
+      if !parent.is_empty() && !chain.iter().any(|theme| theme == parent) {
+        chain.push(parent.to_string());
+      }
     }
+    next += 1;
   }
-  props
+  chain.retain(|theme| theme != "hicolor");
+  chain.push("hicolor".to_string());
+  chain
+}
+
+/// Finds the file for a themed icon name in the active icon theme, its
+/// parents and hicolor, then `pixmaps`. Within a theme, prefers the
+/// smallest size of at least `size`, scalable icons counting as the
+/// largest, then the largest smaller one.
+pub fn find_themed_icon(name: &str, size: u32) -> Option<PathBuf> {
+  let data_dirs = xdg::BaseDirectories::new()
+    .map(|dirs| {
+      let mut data_dirs = vec![dirs.get_data_home()];
+      data_dirs.extend(dirs.get_data_dirs());
+      data_dirs
+    })
+    .unwrap_or_default();
+  for theme in icon_theme_chain(&data_dirs) {
+    let found = data_dirs
+      .iter()
+      .find_map(|data_dir| find_in_theme(&data_dir.join("icons").join(&theme), name, size));
+    if found.is_some() {
+      return found;
+    }
+  }
+  data_dirs.iter().find_map(|data_dir| {
+    ["svg", "png", "xpm"]
+      .iter()
+      .map(|ext| data_dir.join("pixmaps").join(format!("{}.{}", name, ext)))
+      .find(|path| path.is_file())
+  })
+}
+
+/// Looks two directory levels deep, covering both `<size>/<context>` and
+/// `<context>/<size>` theme layouts.
+fn find_in_theme(theme: &Path, name: &str, size: u32) -> Option<PathBuf> {
+  let subdirs = |dir: &Path| -> Vec<PathBuf> {
+    std::fs::read_dir(dir)
+      .into_iter()
+      .flatten()
+      .flatten()
+      .map(|entry| entry.path())
+      .filter(|path| path.is_dir())
+      .collect()
+  };
+  // Scalable icons rank as size u32::MAX, above every fixed size, and
+  // `48x48@2` directories as 96 pixels.
+  let dir_size = |dir: &Path| -> Option<u32> {
+    let dir_name = dir.file_name()?.to_str()?;
+    if dir_name == "scalable" {
+      return Some(u32::MAX);
+    }
+    let (dims, scale) = dir_name.split_once('@').unwrap_or((dir_name, "1"));
+    let size: u32 = dims.split('x').next()?.parse().ok()?;
+    Some(size * scale.parse::<u32>().ok()?)
+  };
+  let mut candidates: Vec<(u32, PathBuf)> = Vec::new();
+  for outer in subdirs(theme) {
+    for inner in subdirs(&outer) {
+      let icon_size = match dir_size(&outer).or_else(|| dir_size(&inner)) {
+        Some(icon_size) => icon_size,
+        None => continue,
+      };
+      for ext in ["svg", "png"] {
+        let path = inner.join(format!("{}.{}", name, ext));
+        if path.is_file() {
+          candidates.push((icon_size, path));
+        }
+      }
+    }
+  }
+  candidates
+    .iter()
+    .filter(|(icon_size, _)| *icon_size >= size)
+    .min_by_key(|(icon_size, _)| *icon_size)
+    .or_else(|| candidates.iter().max_by_key(|(icon_size, _)| *icon_size))
+    .map(|(_, path)| path.clone())
+}
+
+/// Decodes an icon file, scaled to `size` pixels square if given.
+fn icon_image(icon_path: &Path, size: Option<u32>) -> Option<image::RgbaImage> {
+  if util::faults::icon_corrupt() {
+    warn!("Injected icon failure for {:?}", icon_path);
+    return None;
+  }
+  if !icon_path.is_file() || icon_path.extension().is_none() {
+    warn!("Icon at {:?} not found", icon_path);
+    return None;
+  }
+  let file_size = std::fs::metadata(icon_path).map_or(0, |m| m.len());
+  if file_size > constants::MAX_ICON_FILE_BYTES {
+    warn!(
+      "Ignoring icon {:?}: {} bytes is larger than icons get",
+      icon_path, file_size
+    );
+    return None;
+  }
+  let size = size.map(|size| size.min(constants::MAX_ICON_DIMENSION));
+  let extension = icon_path
+    .extension()
+    .map(|ext| ext.to_string_lossy().to_lowercase());
+  match extension.as_deref() {
+    Some("svg") => svg_icon_image(icon_path, size),
+    Some("xpm") => xpm_icon_image(icon_path, size),
+    Some("ico") => ico_icon_image(icon_path, size),
+    _ => raster_icon_image(icon_path, size),
+  }
+}
+
+fn xpm_icon_image(icon_path: &Path, size: Option<u32>) -> Option<image::RgbaImage> {
+  let source = match std::fs::read(icon_path) {
+    Ok(source) => source,
+    Err(err) => {
+      error!("Failed to read XPM {:?}: {}", icon_path, err);
+      return None;
+    },
+  };
+  match util::xpm::decode(&String::from_utf8_lossy(&source)) {
+    Ok(image) => Some(fit_image(image.into(), size)),
+    Err(err) => {
+      error!("Failed to parse XPM {:?}: {}", icon_path, err);
+      None
+    },
+  }
+}
+
+/// Decodes the frame of an ICO file closest to `size`: the smallest at least
+/// that large, else the largest, preferring more colors among equal sizes.
+fn ico_icon_image(icon_path: &Path, size: Option<u32>) -> Option<image::RgbaImage> {
+  let data = match std::fs::read(icon_path) {
+    Ok(data) => data,
+    Err(err) => {
+      error!("Failed to read ICO {:?}: {}", icon_path, err);
+      return None;
+    },
+  };
+  let count = match data.get(..6) {
+    Some([0, 0, 1, 0, lo, hi]) => u16::from_le_bytes([*lo, *hi]) as usize,
+    _ => {
+      error!("Failed to parse ICO {:?}: invalid header", icon_path);
+      return None;
+    },
+  };
+  // Directory entries are 16 bytes, with a width and height of 0 meaning 256.
+  let frames: Vec<(u32, u16, &[u8])> = (0..count)
+    .filter_map(|i| data.get(6 + i * 16..6 + (i + 1) * 16))
+    .map(|entry| {
+      let edge = |b: u8| if b == 0 { 256 } else { b as u32 };
+      let bpp = u16::from_le_bytes([entry[6], entry[7]]);
+      (edge(entry[0]).max(edge(entry[1])), bpp, entry)
+    })
+    .collect();
+  let wanted = size.unwrap_or(u32::MAX);
+  let best = frames
+    .iter()
+    .filter(|(edge, _, _)| *edge >= wanted)
+    .min_by_key(|(edge, bpp, _)| (*edge, std::cmp::Reverse(*bpp)))
+    .or_else(|| frames.iter().max_by_key(|(edge, bpp, _)| (*edge, *bpp)));
+  let entry = match best {
+    Some((_, _, entry)) => entry,
+    None => {
+      error!("Failed to parse ICO {:?}: no frames", icon_path);
+      return None;
+    },
+  };
+  // Rewrites the file with just that frame for the decoder, which otherwise
+  // always picks the largest.
+  let length = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as usize;
+  let offset = u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]) as usize;
+  let frame = data.get(offset..offset.saturating_add(length))?;
+  let mut single = vec![0, 0, 1, 0, 1, 0];
+  single.extend_from_slice(&entry[..12]);
+  single.extend_from_slice(&22u32.to_le_bytes());
+  single.extend_from_slice(frame);
+  let mut reader =
+    image::io::Reader::with_format(std::io::Cursor::new(single), image::ImageFormat::Ico);
+  reader.limits(icon_limits());
+  match reader.decode() {
+    Ok(image) => Some(fit_image(image, size)),
+    Err(err) => {
+      error!("Failed to parse ICO {:?}: {}", icon_path, err);
+      None
+    },
+  }
+}
+
+fn svg_icon_image(icon_path: &Path, size: Option<u32>) -> Option<image::RgbaImage> {
+  let mut svg_opts = usvg::Options {
+    resources_dir: std::fs::canonicalize(icon_path)
+      .ok()
+      .and_then(|p| p.parent().map(|p| p.to_path_buf())),
+    ..usvg::Options::default()
+  };
+  svg_opts.fontdb.load_system_fonts();
+  let svg_data = match std::fs::read(icon_path) {
+    Ok(svg_data) => svg_data,
+    Err(err) => {
+      error!("Failed to read SVG {:?}: {}", icon_path, err);
+      return None;
+    },
+  };
+  let rtree = match usvg::Tree::from_data(&svg_data, &svg_opts.to_ref()) {
+    Ok(rtree) => rtree,
+    Err(err) => {
+      error!("Failed to parse SVG {:?}: {:?}", icon_path, err);
+      return None;
+    },
+  };
+  // Vector icons have no size of their own worth keeping: documents without
+  // a width and height are sized by their viewBox, and scaled to fit.
+  let size = size.unwrap_or(constants::MENU_ICON_SIZE);
+  let fit_to = usvg::FitTo::Size(size, size);
+  let pixmap_size = match fit_to.fit_to(rtree.svg_node().size.to_screen_size()) {
+    Some(pixmap_size) => pixmap_size,
+    None => {
+      error!("Failed to size SVG {:?}", icon_path);
+      return None;
+    },
+  };
+  let mut pixmap = match tiny_skia::Pixmap::new(pixmap_size.width(), pixmap_size.height()) {
+    Some(pixmap) => pixmap,
+    None => {
+      error!("Failed to make skia bitmap");
+      return None;
+    },
+  };
+  let render = resvg::render(
+    &rtree,
+    fit_to,
+    tiny_skia::Transform::default(),
+    pixmap.as_mut(),
+  );
+  if render.is_none() {
+    error!("Failed to render SVG");
+    return None;
+  }
+  let rgba = pixmap
+    .pixels()
+    .iter()
+    .flat_map(|pixel| {
+      let color = pixel.demultiply();
+      [color.red(), color.green(), color.blue(), color.alpha()]
+    })
+    .collect();
+  image::RgbaImage::from_raw(pixmap.width(), pixmap.height(), rgba)
 }
 
-pub fn category_props(c: constants::Category) -> MenuProps {
+fn raster_icon_image(icon_path: &Path, size: Option<u32>) -> Option<image::RgbaImage> {
+  use image::io::Reader as ImageReader;
+  let mut data = match ImageReader::open(icon_path) {
+    Ok(data) => data,
+    Err(err) => {
+      error!("Failed to read image at {:?}: {}", icon_path, err);
+      return None;
+    },
+  };
+  data.limits(icon_limits());
+  let data = match data.decode() {
+    Ok(data) => data,
+    Err(err) => {
+      error!("Failed to parse image at {:?}: {}", icon_path, err);
+      return None;
+    },
+  };
+  Some(fit_image(data, size))
+}
+
+/// Decoder limits rejecting pathological images before they are allocated.
+fn icon_limits() -> image::io::Limits {
+  let mut limits = image::io::Limits::default();
+  limits.max_image_width = Some(constants::MAX_ICON_DIMENSION);
+  limits.max_image_height = Some(constants::MAX_ICON_DIMENSION);
+  limits.max_alloc = Some(constants::MAX_ICON_ALLOC);
+  limits
+}
+
+/// Fits the longer edge of an image to `size`, keeping the aspect ratio.
+fn fit_image(image: image::DynamicImage, size: Option<u32>) -> image::RgbaImage {
+  let image = match size {
+    Some(size) if image.width().max(image.height()) != size => {
+      image.resize(size, size, image::imageops::FilterType::Lanczos3)
+    },
+    _ => image,
+  };
+  image.into_rgba8()
+}
+
+pub fn other_category_props() -> MenuProps {
+  MenuProps {
+    label: "Other".to_string(),
+    visible: false,
+    icon_name: util::appearance::themed_icon(&crate::sections::category_icon(
+      constants::Category::Uncategorized,
+    )),
+    entry_type: "standard".to_string(),
+    children_display: "submenu".to_string(),
+    icon_data: IconData::default(),
+    enabled: true,
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: String::new(),
+    toggle_state: -1,
+  }
+}
+
+/// Submenu listing the entries installed most recently, hidden while empty.
+pub fn recent_props() -> MenuProps {
+  MenuProps {
+    label: "Recently added".to_string(),
+    visible: false,
+    icon_name: "document-open-recent".to_string(),
+    entry_type: "standard".to_string(),
+    children_display: "submenu".to_string(),
+    icon_data: IconData::default(),
+    enabled: true,
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: String::new(),
+    toggle_state: -1,
+  }
+}
+
+/// Submenu listing the entries launched most, hidden while empty.
+pub fn frequent_props() -> MenuProps {
   MenuProps {
-    label: constants::category_string(c).to_string(),
+    label: "Frequently used".to_string(),
+    visible: false,
+    icon_name: "starred".to_string(),
+    entry_type: "standard".to_string(),
+    children_display: "submenu".to_string(),
+    icon_data: IconData::default(),
+    enabled: true,
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: String::new(),
+    toggle_state: -1,
+  }
+}
+
+/// Submenu grouping the entries of an additional category, labelled with its
+/// name split into words, e.g. "Text Editor".
+pub fn subcategory_props(name: &str) -> MenuProps {
+  let mut label = String::new();
+  let mut prev_lower = false;
+  for c in name.chars() {
+    if c.is_uppercase() && prev_lower {
+      label.push(' ');
+    }
+    prev_lower = c.is_lowercase();
+    label.push(c);
+  }
+  submenu_props(label)
+}
+
+/// Submenu generated inside a category.
+pub fn submenu_props(label: String) -> MenuProps {
+  MenuProps {
+    label,
     visible: true,
     icon_name: String::new(),
     entry_type: "standard".to_string(),
     children_display: "submenu".to_string(),
-    icon_data: vec![],
+    icon_data: IconData::default(),
     enabled: true,
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: String::new(),
+    toggle_state: -1,
+  }
+}
+
+pub fn separator_props() -> MenuProps {
+  MenuProps {
+    label: String::new(),
+    visible: true,
+    icon_name: String::new(),
+    entry_type: "separator".to_string(),
+    children_display: String::new(),
+    icon_data: IconData::default(),
+    enabled: true,
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: String::new(),
+    toggle_state: -1,
   }
 }
 
@@ -165,54 +1216,585 @@ pub fn root_props() -> MenuProps {
     icon_name: String::new(),
     entry_type: "standard".to_string(),
     children_display: "submenu".to_string(),
-    icon_data: vec![],
+    icon_data: IconData::default(),
+    enabled: true,
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: String::new(),
+    toggle_state: -1,
+  }
+}
+
+pub fn sources_props() -> MenuProps {
+  MenuProps {
+    label: "Sources".to_string(),
+    visible: true,
+    icon_name: "folder".to_string(),
+    entry_type: "standard".to_string(),
+    children_display: "submenu".to_string(),
+    icon_data: IconData::default(),
+    enabled: true,
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: String::new(),
+    toggle_state: -1,
+  }
+}
+
+/// Checkable item for an application directory, ticked while it is scanned.
+pub fn source_props(dir: &Path) -> MenuProps {
+  MenuProps {
+    label: dir.display().to_string(),
+    visible: true,
+    icon_name: String::new(),
+    entry_type: "standard".to_string(),
+    children_display: String::new(),
+    icon_data: IconData::default(),
+    enabled: true,
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: "checkmark".to_string(),
+    toggle_state: (!util::init::is_dir_disabled(dir)) as i32,
+  }
+}
+
+pub fn about_props() -> MenuProps {
+  MenuProps {
+    label: format!("About: {}", constants::build_info()),
+    visible: true,
+    icon_name: "help-about".to_string(),
+    entry_type: "standard".to_string(),
+    children_display: String::new(),
+    icon_data: IconData::default(),
+    enabled: false,
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: String::new(),
+    toggle_state: -1,
+  }
+}
+
+/// Submenu holding the display option toggles.
+pub fn options_props() -> MenuProps {
+  MenuProps {
+    label: "Options".to_string(),
+    visible: true,
+    icon_name: "preferences-system".to_string(),
+    entry_type: "standard".to_string(),
+    children_display: "submenu".to_string(),
+    icon_data: IconData::default(),
+    enabled: true,
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: String::new(),
+    toggle_state: -1,
+  }
+}
+
+/// Checkable item for a display option, ticked while it is on.
+pub fn option_props(label: &str, on: bool) -> MenuProps {
+  MenuProps {
+    label: label.to_string(),
+    visible: true,
+    icon_name: String::new(),
+    entry_type: "standard".to_string(),
+    children_display: String::new(),
+    icon_data: IconData::default(),
+    enabled: true,
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: "checkmark".to_string(),
+    toggle_state: on as i32,
+  }
+}
+
+/// Item rescanning all application directories.
+pub fn refresh_props() -> MenuProps {
+  MenuProps {
+    label: "Refresh".to_string(),
+    visible: true,
+    icon_name: "view-refresh".to_string(),
+    entry_type: "standard".to_string(),
+    children_display: String::new(),
+    icon_data: IconData::default(),
     enabled: true,
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: String::new(),
+    toggle_state: -1,
   }
 }
 
-pub fn launcher_for_entry(p: PathBuf, locale: &str) -> Option<Launcher> {
+/// Item shutting the daemon down.
+pub fn quit_props() -> MenuProps {
+  MenuProps {
+    label: "Quit".to_string(),
+    visible: true,
+    icon_name: "application-exit".to_string(),
+    entry_type: "standard".to_string(),
+    children_display: String::new(),
+    icon_data: IconData::default(),
+    enabled: true,
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: String::new(),
+    toggle_state: -1,
+  }
+}
+
+/// Remembers desktop entries that failed to read or parse, keyed by their
+/// modification time, so unchanged broken files aren't retried and re-warned.
+#[derive(Debug, Default)]
+pub struct ParseFailures {
+  failed: HashMap<PathBuf, Option<SystemTime>>,
+}
+
+impl ParseFailures {
+  fn mtime(p: &Path) -> Option<SystemTime> {
+    std::fs::metadata(p).and_then(|m| m.modified()).ok()
+  }
+
+  fn unchanged(&self, p: &Path) -> bool {
+    match self.failed.get(p) {
+      Some(mtime) => *mtime == Self::mtime(p),
+      None => false,
+    }
+  }
+
+  fn record(&mut self, p: &Path) {
+    self.failed.insert(p.to_path_buf(), Self::mtime(p));
+  }
+
+  fn clear(&mut self, p: &Path) {
+    self.failed.remove(p);
+  }
+
+  /// Number of files currently failing to parse.
+  pub fn count(&self) -> usize {
+    self.failed.len()
+  }
+}
+
+/// Applies `OnlyShowIn` and `NotShowIn` against the current desktop names.
+fn shown_in_desktops(
+  only_show_in: Option<&str>,
+  not_show_in: Option<&str>,
+  desktops: &[String],
+) -> bool {
+  let matches = |list: Option<&str>| {
+    let list = util::xdg::split(list.unwrap_or(""));
+    let hit = list
+      .iter()
+      .any(|d| desktops.iter().any(|c| c.eq_ignore_ascii_case(d)));
+    (!list.is_empty()).then_some(hit)
+  };
+  matches(only_show_in).unwrap_or(true) && !matches(not_show_in).unwrap_or(false)
+}
+
+/// Desktop-file ID of `path` inside the applications directory `dir`: the
+/// relative path with `/` replaced by `-`, e.g. `kde4-foo.desktop`.
+pub fn desktop_id(dir: &Path, path: &Path) -> Option<OsString> {
+  let relative = path.strip_prefix(dir).ok()?;
+  let mut id = OsString::new();
+  for (i, part) in relative.iter().enumerate() {
+    if i > 0 {
+      id.push("-");
+    }
+    id.push(part);
+  }
+  (!id.is_empty()).then_some(id)
+}
+
+/// Whether the desktop-file ID is configured to be left out of the menu.
+pub fn is_hidden_id(id: &std::ffi::OsStr) -> bool {
+  crate::config::get()
+    .hidden
+    .iter()
+    .any(|hidden| std::ffi::OsStr::new(hidden) == id)
+}
+
+/// Applies the configured category exclusions, forced entries and per-ID
+/// category rules, returning `None` if the launcher should be left out of the menu.
+pub fn filter_launcher(id: &std::ffi::OsStr, mut launcher: Launcher) -> Option<Launcher> {
+  if launcher.tombstone {
+    return Some(launcher);
+  }
+  let config = crate::config::get();
+  if launcher.category_names.iter().any(|name| {
+    config
+      .hidden_categories
+      .iter()
+      .any(|hidden| hidden.eq_ignore_ascii_case(name))
+  }) {
+    debug!("Excluding {:?} by category", id);
+    return None;
+  }
+  if config
+    .force_show
+    .iter()
+    .any(|shown| std::ffi::OsStr::new(shown) == id)
+  {
+    launcher.display = true;
+  }
+  if let Some(category) = id_category(id, &launcher.category_names) {
+    launcher.categories.retain(|c| *c != category);
+    launcher.categories.insert(0, category);
+  }
+  Some(launcher)
+}
+
+/// Whether the desktop-file ID belongs to an entry defined in the config.
+pub fn is_custom_id(id: &std::ffi::OsStr) -> bool {
+  id.to_string_lossy()
+    .starts_with(constants::CUSTOM_ENTRY_PREFIX)
+}
+
+/// Launchers for the `entries` defined in the config, keyed by their `id`,
+/// or their position without one. Entries without a label or with an ID
+/// taken already are skipped.
+pub fn custom_launchers() -> Vec<(OsString, Launcher)> {
+  let source = crate::config::user_config_path().unwrap_or_default();
+  let mut launchers: Vec<(OsString, Launcher)> = Vec::new();
+  for (i, entry) in crate::config::get().entries.iter().enumerate() {
+    if entry.label.trim().is_empty() {
+      warn!("Ignoring custom entry {} without a label", i);
+      continue;
+    }
+    let id = OsString::from(format!(
+      "{}{}",
+      constants::CUSTOM_ENTRY_PREFIX,
+      entry.id.clone().unwrap_or_else(|| i.to_string())
+    ));
+    if launchers.iter().any(|(taken, _)| *taken == id) {
+      warn!(
+        "Ignoring custom entry '{}' with duplicate ID {:?}",
+        entry.label, id
+      );
+      continue;
+    }
+    let exec = match util::xdg::command_argv(&entry.exec, &entry.argv) {
+      Some(exec) => exec,
+      None => {
+        warn!("Ignoring custom entry '{}' with invalid exec", entry.label);
+        continue;
+      },
+    };
+    let category_names: Vec<String> = entry.category.iter().cloned().collect();
+    launchers.push((
+      id,
+      Launcher {
+        path: source.clone(),
+        name: entry.label.clone(),
+        generic_name: None,
+        comment: None,
+        keywords: vec![],
+        categories: category_str_convert(category_names.clone()),
+        category_names,
+        exec,
+        file_code: None,
+        working_dir: entry.cwd.clone(),
+        env: entry
+          .env
+          .iter()
+          .map(|(k, v)| (k.clone(), v.clone()))
+          .collect(),
+        icon: entry.icon.clone(),
+        display: true,
+        terminal: entry.terminal,
+        runnable: true,
+        try_exec: None,
+        try_exec_missing: false,
+        tombstone: false,
+        deleted: false,
+      },
+    ));
+  }
+  launchers
+}
+
+/// Lists every file below `dir`, descending into subdirectories once each.
+pub fn scan_dir(dir: &Path) -> Vec<PathBuf> {
+  let mut files = Vec::new();
+  let mut visited = HashSet::new();
+  let mut pending = vec![dir.to_path_buf()];
+  while let Some(dir) = pending.pop() {
+    if !visited.insert(dir.canonicalize().unwrap_or_else(|_| dir.clone())) {
+      continue;
+    }
+    let entries = match dir.read_dir() {
+      Ok(entries) => entries,
+      Err(e) => {
+        warn!("Failed to read {:?}: {}", dir, e);
+        // Directories removed while scanning aren't worth raising.
+        if e.kind() != std::io::ErrorKind::NotFound {
+          util::alerts::report(format!("Failed to read {}: {}", dir.display(), e));
+        }
+        continue;
+      },
+    };
+    for e in entries {
+      match e {
+        Ok(entry) => {
+          let p = entry.path();
+          if p.is_dir() {
+            pending.push(p);
+          } else {
+            files.push(p);
+          }
+        },
+        Err(e) => {
+          warn!("Failed while reading {:?}: {}", dir, e);
+        },
+      }
+    }
+  }
+  files
+}
+
+/// Reads a desktop file, refusing ones over `MAX_ENTRY_FILE_SIZE`.
+fn read_limited(p: &Path) -> std::io::Result<String> {
+  use std::io::Read;
+  let mut data = String::new();
+  std::fs::File::open(p)?
+    .take(constants::MAX_ENTRY_FILE_SIZE + 1)
+    .read_to_string(&mut data)?;
+  if data.len() as u64 > constants::MAX_ENTRY_FILE_SIZE {
+    return Err(std::io::Error::other(format!(
+      "larger than {} bytes",
+      constants::MAX_ENTRY_FILE_SIZE
+    )));
+  }
+  Ok(data)
+}
+
+/// Checks a parsed entry against the key count and value length limits.
+fn check_limits(desk: &DesktopEntry) -> Result<(), String> {
+  let mut keys = 0;
+  for (key, (value, locales)) in desk.groups.values().flat_map(|keys| keys.iter()) {
+    keys += 1 + locales.len();
+    if keys > constants::MAX_ENTRY_KEYS {
+      return Err(format!("more than {} keys", constants::MAX_ENTRY_KEYS));
+    }
+    if std::iter::once(value)
+      .chain(locales.values())
+      .any(|value| value.len() > constants::MAX_ENTRY_VALUE_LEN)
+    {
+      return Err(format!(
+        "{} is longer than {} bytes",
+        key,
+        constants::MAX_ENTRY_VALUE_LEN
+      ));
+    }
+  }
+  Ok(())
+}
+
+/// Renders every group and key of a desktop file as parsed, localized keys
+/// included, headed by the file's path.
+pub fn raw_entry(p: &Path) -> Result<String, String> {
+  let data = read_limited(p).map_err(|e| format!("Failed to read {:?}: {}", p, e))?;
+  let desk =
+    DesktopEntry::decode(p, &data).map_err(|e| format!("Failed to parse {:?}: {}", p, e))?;
+  let mut out = format!("# {}\n", p.display());
+  for (group, keys) in desk.groups.iter() {
+    out.push_str(&format!("[{}]\n", group));
+    for (key, (value, locales)) in keys.iter() {
+      out.push_str(&format!("{}={}\n", key, value));
+      for (locale, value) in locales.iter() {
+        out.push_str(&format!("{}[{}]={}\n", key, locale, value));
+      }
+    }
+  }
+  Ok(out)
+}
+
+/// Writes a copy of the entry at `p` marked `NoDisplay=true` to the user's
+/// applications directory, where it takes precedence over the original.
+pub fn write_hidden_override(id: &std::ffi::OsStr, p: &Path) -> Result<PathBuf, String> {
+  let data = read_limited(p).map_err(|e| format!("Failed to read {:?}: {}", p, e))?;
+  let mut out = String::new();
+  let mut in_entry = false;
+  for line in data.lines() {
+    let trimmed = line.trim();
+    if trimmed.starts_with('[') {
+      in_entry = trimmed == "[Desktop Entry]";
+      out.push_str(line);
+      out.push('\n');
+      if in_entry {
+        out.push_str("NoDisplay=true\n");
+      }
+      continue;
+    }
+    if in_entry && trimmed.split('=').next().map(str::trim) == Some("NoDisplay") {
+      continue;
+    }
+    out.push_str(line);
+    out.push('\n');
+  }
+  let dir = xdg::BaseDirectories::new()
+    .map_err(|e| e.to_string())
+    .and_then(|dirs| {
+      dirs
+        .create_data_directory("applications")
+        .map_err(|e| e.to_string())
+    })
+    .map_err(|e| format!("Failed to create the applications directory: {}", e))?;
+  let target = dir.join(id);
+  let tmp = dir.join(format!(".{}.tmp", id.to_string_lossy()));
+  std::fs::write(&tmp, out)
+    .and_then(|_| std::fs::rename(&tmp, &target))
+    .map_err(|e| format!("Failed to write {:?}: {}", target, e))?;
+  Ok(target)
+}
+
+/// Whether a `TryExec` binary is given but can't be found or run.
+pub fn try_exec_missing(try_exec: Option<&str>) -> bool {
+  try_exec.is_some_and(|binary| {
+    util::launch::resolve_program(binary)
+      .is_none_or(|program| !util::launch::executable_by_user(&program))
+  })
+}
+
+pub fn launcher_for_entry(
+  p: PathBuf,
+  locale: &str,
+  failures: &mut ParseFailures,
+) -> Option<Launcher> {
   let ext = p.extension().unwrap_or_default().to_str();
   let name = p.file_stem().unwrap_or_default();
   if p.is_file() && ext == Some("desktop") && !name.is_empty() {
-    match std::fs::read_to_string(&p) {
+    if failures.unchanged(&p) {
+      debug!("Skipping unchanged broken entry {:?}", p);
+      return None;
+    }
+    match read_limited(&p) {
       Ok(data) => match DesktopEntry::decode(&p, &data) {
         Ok(desk) => {
+          if let Err(err) = check_limits(&desk) {
+            warn!("Rejecting desktop entry {:?}: {}", p, err);
+            failures.record(&p);
+            return None;
+          }
+          failures.clear(&p);
           let entry_name = desk
-            .name(Some(&locale))
-            .or(desk.generic_name(Some(&locale)))
-            .or(Some(std::borrow::Cow::from(desk.appid)))
-            .unwrap();
+            .name(Some(locale))
+            .or_else(|| desk.generic_name(Some(locale)))
+            .unwrap_or_else(|| std::borrow::Cow::from(desk.appid));
           info!("Entry: {} ({})", entry_name, desk.no_display());
-          let entry_type = desk.type_().unwrap_or("Application");
-          let entry_exec = desk.exec();
-          if entry_exec.is_none() {
-            info!("{} ({:?}) lacks exec key", entry_name, &p);
-            return None;
+          if desk.desktop_entry("Hidden") == Some("true") {
+            info!("{} ({:?}) is hidden, treating as deleted", entry_name, &p);
+            return Some(tombstone_launcher(p.clone(), desk.appid.to_string(), false));
           }
-          if entry_type == "Application" {
-            let only_show_in = util::xdg::split(desk.only_show_in().unwrap_or(""));
-            let icon = desk.icon().map(|s| util::xdg::unescape(s, false));
-            let name = util::xdg::unescape(&entry_name, false);
-            return Some(Launcher {
-              categories: category_str_convert(util::xdg::split(desk.categories().unwrap_or(""))),
-              exec: util::xdg::exec_substitute(
-                &util::xdg::unescape(entry_exec.unwrap(), false),
-                icon.clone(),
-                &name,
-                &p,
+          let entry_type = desk.type_().unwrap_or("Application");
+          let icon = desk.icon().map(|s| util::xdg::unescape(s, false));
+          let name = util::xdg::unescape(&entry_name, false);
+          let generic_name = desk
+            .generic_name(Some(locale))
+            .map(|s| util::xdg::unescape(&s, false));
+          let comment = desk
+            .comment(Some(locale))
+            .map(|s| util::xdg::unescape(&s, false))
+            .filter(|s| !s.is_empty());
+          let keywords = util::xdg::split(
+            &desk
+              .desktop_entry_localized("Keywords", Some(locale))
+              .unwrap_or_default(),
+          );
+          let (exec, file_code, terminal, try_exec) = match entry_type {
+            "Application" => {
+              let entry_exec = match desk.exec() {
+                Some(entry_exec) => entry_exec,
+                None => {
+                  info!("{} ({:?}) lacks exec key", entry_name, &p);
+                  return None;
+                },
+              };
+              let exec_args =
+                match util::xdg::exec_tokenize(&util::xdg::unescape(entry_exec, false)) {
+                  Some(exec_args) => exec_args,
+                  None => {
+                    warn!("Malformed Exec quoting in {:?}", p);
+                    failures.record(&p);
+                    return None;
+                  },
+                };
+              let file_code = util::xdg::file_code(&exec_args).map(|(code, i)| {
+                let before = exec_args[..i].to_vec();
+                (
+                  code,
+                  util::xdg::exec_expand(before, icon.as_deref(), &name, &p).len(),
+                )
+              });
+              (
+                util::xdg::exec_expand(exec_args, icon.as_deref(), &name, &p),
+                file_code,
+                desk.terminal(),
+                desk
+                  .desktop_entry("TryExec")
+                  .map(|s| util::xdg::unescape(s, false)),
+              )
+            },
+            "Link" => match desk.desktop_entry("URL") {
+              Some(url) => (
+                vec![
+                  constants::LINK_OPENER.to_string(),
+                  util::xdg::unescape(url, false),
+                ],
+                None,
+                false,
+                None,
               ),
-              name: name,
-              icon: icon,
-              display: !desk.no_display()
-                && !desk.terminal()
-                && (only_show_in.is_empty() || only_show_in.contains(&util::init::get_only_show())),
-              path: p,
-            });
-          }
+              None => {
+                info!("{} ({:?}) lacks URL key", entry_name, &p);
+                return None;
+              },
+            },
+            _ => return None,
+          };
+          let category_names = util::xdg::split(desk.categories().unwrap_or(""));
+          let runnable = exec
+            .first()
+            .and_then(|program| util::launch::resolve_program(program))
+            .is_none_or(|program| util::launch::executable_by_user(&program));
+          return Some(Launcher {
+            try_exec_missing: try_exec_missing(try_exec.as_deref()),
+            try_exec,
+            categories: category_str_convert(category_names.clone()),
+            category_names,
+            exec,
+            file_code,
+            working_dir: desk
+              .desktop_entry("Path")
+              .filter(|dir| !dir.is_empty())
+              .map(|dir| PathBuf::from(util::xdg::unescape(dir, false))),
+            env: vec![],
+            name,
+            generic_name,
+            comment,
+            keywords,
+            icon,
+            display: !desk.no_display()
+              && shown_in_desktops(
+                desk.only_show_in(),
+                desk.desktop_entry("NotShowIn"),
+                &util::init::get_current_desktops(),
+              ),
+            terminal,
+            runnable,
+            path: p,
+            tombstone: false,
+            deleted: false,
+          });
         },
-        Err(e) => warn!("Failed to parse {:?}: {}", p, e),
+        Err(e) => {
+          warn!("Failed to parse {:?}: {}", p, e);
+          failures.record(&p);
+        },
+      },
+      Err(e) => {
+        warn!("Failed to read desktop entry {:?}: {}", p, e);
+        failures.record(&p);
       },
-      Err(e) => warn!("Failed to read desktop entry {:?}: {}", p, e),
     }
   }
   None
@@ -0,0 +1,69 @@
+//! Fatal errors and the exit codes they end the process with, so service
+//! managers and scripts can tell failures apart:
+//!
+//! | Code | Meaning                                                  |
+//! |------|----------------------------------------------------------|
+//! | 0    | Clean exit, including being replaced by another instance |
+//! | 1    | Fatal runtime error or panic                             |
+//! | 2    | Invalid configuration file                               |
+//! | 3    | Another instance already owns the bus name               |
+//! | 4    | Session bus unavailable or objects couldn't be served    |
+//! | 5    | Watching the application directories failed              |
+use log::error;
+
+pub const EXIT_RUNTIME: i32 = 1;
+pub const EXIT_CONFIG: i32 = 2;
+pub const EXIT_ALREADY_RUNNING: i32 = 3;
+pub const EXIT_BUS: i32 = 4;
+pub const EXIT_WATCHER: i32 = 5;
+
+#[derive(Debug)]
+pub enum Fatal {
+  Runtime(String),
+  Config(String),
+  NameTaken(String),
+  Bus(zbus::Error),
+  Watcher(notify::Error),
+}
+
+impl std::fmt::Display for Fatal {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Fatal::Runtime(err) => write!(f, "Fatal error: {}", err),
+      Fatal::Config(err) => write!(f, "Configuration error: {}", err),
+      Fatal::NameTaken(name) => write!(f, "Bus name {} is already taken", name),
+      Fatal::Bus(err) => write!(f, "Session bus error: {}", err),
+      Fatal::Watcher(err) => write!(f, "Failed to watch application directories: {}", err),
+    }
+  }
+}
+
+impl From<zbus::Error> for Fatal {
+  fn from(err: zbus::Error) -> Self {
+    Fatal::Bus(err)
+  }
+}
+
+impl From<notify::Error> for Fatal {
+  fn from(err: notify::Error) -> Self {
+    Fatal::Watcher(err)
+  }
+}
+
+impl Fatal {
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      Fatal::Runtime(_) => EXIT_RUNTIME,
+      Fatal::Config(_) => EXIT_CONFIG,
+      Fatal::NameTaken(_) => EXIT_ALREADY_RUNNING,
+      Fatal::Bus(_) => EXIT_BUS,
+      Fatal::Watcher(_) => EXIT_WATCHER,
+    }
+  }
+
+  /// Logs the error and exits with its code.
+  pub fn exit(self) -> ! {
+    error!("{}", self);
+    std::process::exit(self.exit_code())
+  }
+}
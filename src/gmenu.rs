@@ -0,0 +1,333 @@
+//! Mirrors the dbusmenu tree as `org.gtk.Menus` and `org.gtk.Actions` for
+//! hosts that only speak GMenu. Each submenu becomes a group numbered after
+//! its dbusmenu item ID; menu 0 of a group lists one section per run of items
+//! between separators, and sections are menus 1 and up. Items activate the
+//! `activate` action with their item ID as target, so hosts should insert the
+//! action group under the `menu` prefix.
+use std::collections::HashMap;
+
+use log::{debug, warn};
+use log_err::*;
+use zbus::zvariant::{OwnedValue, Signature, StructureBuilder, Value};
+use zbus::{dbus_interface, DBusError, SignalContext};
+
+use crate::constants;
+use crate::desktop::MenuProps;
+
+const ACTION_NAME: &str = "activate";
+
+type MenuItem = HashMap<String, OwnedValue>;
+type MenuContents = (u32, u32, Vec<MenuItem>);
+
+#[derive(DBusError, Debug)]
+#[dbus_error(prefix = "org.wsl.AppMenu.GMenu")]
+pub enum GMenuError {
+  #[dbus_error(zbus_error)]
+  ZBus(zbus::Error),
+  UnknownAction(String),
+  InvalidParameter,
+  NotStateful,
+}
+
+/// Serialized GIcon for an item, preferring the theme name.
+fn item_icon(props: &MenuProps) -> Option<OwnedValue> {
+  let (kind, data) = if !props.icon_name.is_empty() {
+    ("themed", Value::new(vec![props.icon_name.clone()]))
+  } else if !props.icon_data.is_empty() {
+    ("bytes", Value::new(props.icon_data.clone()))
+  } else {
+    return None;
+  };
+  let icon = StructureBuilder::new()
+    .add_field(kind)
+    .append_field(Value::Value(Box::new(data)))
+    .build();
+  Some(Value::from(icon).into())
+}
+
+fn menu_item(id: i32, props: &MenuProps) -> MenuItem {
+  let mut item = MenuItem::new();
+  item.insert("label".into(), Value::new(props.label.clone()).into());
+  if let Some(icon) = item_icon(props) {
+    item.insert("icon".into(), icon);
+  }
+  if props.children_display == "submenu" {
+    item.insert(":submenu".into(), Value::from((id as u32, 0u32)).into());
+  } else if props.enabled {
+    item.insert(
+      "action".into(),
+      Value::new(format!("menu.{}", ACTION_NAME)).into(),
+    );
+    item.insert("target".into(), Value::new(id).into());
+  }
+  item
+}
+
+/// Menus making up the group of submenu `group`, empty if it doesn't exist.
+fn group_menus(
+  group: u32,
+  children: &HashMap<i32, Vec<i32>>,
+  props: &HashMap<i32, MenuProps>,
+) -> Vec<MenuContents> {
+  let ids = match children.get(&(group as i32)) {
+    Some(ids) => ids,
+    None => return Vec::new(),
+  };
+  let mut sections: Vec<Vec<MenuItem>> = vec![Vec::new()];
+  for id in ids {
+    let item_props = match props.get(id) {
+      Some(item_props) if item_props.visible => item_props,
+      _ => continue,
+    };
+    if item_props.entry_type == "separator" {
+      sections.push(Vec::new());
+    } else {
+      sections
+        .last_mut()
+        .log_expect("Section list unexpectedly empty")
+        .push(menu_item(*id, item_props));
+    }
+  }
+  sections.retain(|section| !section.is_empty());
+
+  let mut menus = vec![(
+    group,
+    0,
+    (1..=sections.len() as u32)
+      .map(|section| {
+        let mut item = MenuItem::new();
+        item.insert(":section".into(), Value::from((group, section)).into());
+        item
+      })
+      .collect(),
+  )];
+  menus.extend(
+    sections
+      .into_iter()
+      .zip(1u32..)
+      .map(|(items, section)| (group, section, items)),
+  );
+  menus
+}
+
+/// Contents of the subscribed `groups` in `menu`, keyed by group and menu.
+fn snapshot(groups: &[u32], menu: &crate::AppMenuDbusMenu) -> HashMap<(u32, u32), Vec<MenuItem>> {
+  let mut current = HashMap::new();
+  for group in groups {
+    for (group, menu_idx, items) in group_menus(*group, &menu.children, &menu.props) {
+      current.insert((group, menu_idx), items);
+    }
+  }
+  current
+}
+
+#[derive(Default)]
+pub struct GtkMenus {
+  /// Subscription count per group.
+  subscriptions: HashMap<u32, u32>,
+  /// Contents last sent to subscribers, keyed by group and menu.
+  sent: HashMap<(u32, u32), Vec<MenuItem>>,
+}
+
+impl GtkMenus {
+  fn remember(&mut self, menus: &[MenuContents]) {
+    for (group, menu, items) in menus {
+      self.sent.insert((*group, *menu), items.clone());
+    }
+  }
+
+  fn groups(&self) -> Vec<u32> {
+    self.subscriptions.keys().copied().collect()
+  }
+
+  /// Brings the subscribed groups up to date with `current`, returning the
+  /// changes as `(group, menu, position, removed, added)`, each replacing a
+  /// whole menu. Comparing contents rather than the menu revision also picks
+  /// up props that change in place, like labels, toggles and badges.
+  fn sync(
+    &mut self,
+    mut current: HashMap<(u32, u32), Vec<MenuItem>>,
+  ) -> Vec<(u32, u32, u32, u32, Vec<MenuItem>)> {
+    current.retain(|key, _| self.subscriptions.contains_key(&key.0));
+    let mut changes = Vec::new();
+    for (key, old) in self.sent.iter() {
+      if !current.contains_key(key) {
+        changes.push((key.0, key.1, 0, old.len() as u32, Vec::new()));
+      }
+    }
+    for (key, items) in current.iter() {
+      let old = self.sent.get(key);
+      if old != Some(items) {
+        let removed = old.map_or(0, |old| old.len() as u32);
+        changes.push((key.0, key.1, 0, removed, items.clone()));
+      }
+    }
+    self.sent = current;
+    changes
+  }
+}
+
+#[dbus_interface(name = "org.gtk.Menus")]
+impl GtkMenus {
+  /// Start method
+  async fn start(
+    &mut self,
+    subscriptions: Vec<u32>,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+  ) -> Result<Vec<MenuContents>, GMenuError> {
+    let menu = server
+      .interface::<_, crate::AppMenuDbusMenu>(
+        crate::util::init::get_object_names().menu_path.as_str(),
+      )
+      .await?;
+    tokio::spawn(crate::show_submenu_icons(
+      menu.signal_context().connection().clone(),
+      subscriptions.iter().map(|group| *group as i32).collect(),
+    ));
+    let menu = menu.get().await;
+    let mut out = Vec::new();
+    for group in subscriptions {
+      *self.subscriptions.entry(group).or_default() += 1;
+      let menus = group_menus(group, &menu.children, &menu.props);
+      self.remember(&menus);
+      out.extend(menus);
+    }
+    Ok(out)
+  }
+
+  /// End method
+  async fn end(&mut self, subscriptions: Vec<u32>) {
+    for group in subscriptions {
+      if let Some(count) = self.subscriptions.get_mut(&group) {
+        *count -= 1;
+        if *count == 0 {
+          self.subscriptions.remove(&group);
+          self.sent.retain(|key, _| key.0 != group);
+        }
+      }
+    }
+  }
+
+  /// Changed signal
+  #[dbus_interface(signal)]
+  async fn changed(
+    ctxt: &SignalContext<'_>,
+    changes: &[(u32, u32, u32, u32, Vec<MenuItem>)],
+  ) -> zbus::Result<()>;
+}
+
+#[derive(Default)]
+pub struct GtkActions;
+
+fn action_description() -> (bool, Signature<'static>, Vec<OwnedValue>) {
+  (true, Signature::from_static_str_unchecked("i"), Vec::new())
+}
+
+#[dbus_interface(name = "org.gtk.Actions")]
+impl GtkActions {
+  /// List method
+  async fn list(&self) -> Vec<String> {
+    vec![ACTION_NAME.to_string()]
+  }
+
+  /// Describe method
+  async fn describe(
+    &self,
+    action_name: &str,
+  ) -> Result<(bool, Signature<'static>, Vec<OwnedValue>), GMenuError> {
+    if action_name != ACTION_NAME {
+      return Err(GMenuError::UnknownAction(action_name.to_string()));
+    }
+    Ok(action_description())
+  }
+
+  /// DescribeAll method
+  async fn describe_all(&self) -> HashMap<String, (bool, Signature<'static>, Vec<OwnedValue>)> {
+    HashMap::from([(ACTION_NAME.to_string(), action_description())])
+  }
+
+  /// Activate method
+  async fn activate(
+    &self,
+    action_name: &str,
+    parameter: Vec<OwnedValue>,
+    _platform_data: HashMap<String, OwnedValue>,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+  ) -> Result<(), GMenuError> {
+    if action_name != ACTION_NAME {
+      return Err(GMenuError::UnknownAction(action_name.to_string()));
+    }
+    let item_id = match parameter.first().map(|value| i32::try_from(value.clone())) {
+      Some(Ok(item_id)) => item_id,
+      _ => return Err(GMenuError::InvalidParameter),
+    };
+    let menu = server
+      .interface::<_, crate::AppMenuDbusMenu>(
+        crate::util::init::get_object_names().menu_path.as_str(),
+      )
+      .await?;
+    let ctxt = menu.signal_context().clone();
+    menu
+      .get()
+      .await
+      .event(item_id, "clicked", Value::new(0), 0, ctxt)
+      .await;
+    Ok(())
+  }
+
+  /// SetState method
+  async fn set_state(
+    &self,
+    _action_name: &str,
+    _value: OwnedValue,
+    _platform_data: HashMap<String, OwnedValue>,
+  ) -> Result<(), GMenuError> {
+    Err(GMenuError::NotStateful)
+  }
+}
+
+/// Serves the GMenu mirror and keeps subscribers up to date with the menu.
+pub async fn serve(connection: &zbus::Connection, menu_path: &str) {
+  let server = connection.object_server();
+  let served = futures_util::try_join!(
+    server.at(constants::GMENU_PATH, GtkMenus::default()),
+    server.at(constants::GMENU_PATH, GtkActions),
+  );
+  if let Err(err) = served {
+    warn!("Failed to serve GMenu bridge: {}", err);
+    return;
+  }
+
+  let connection = connection.clone();
+  let menu_path = menu_path.to_string();
+  tokio::spawn(async move {
+    let server = connection.object_server();
+    let menu_ref = server
+      .interface::<_, crate::AppMenuDbusMenu>(menu_path.as_str())
+      .await
+      .log_expect("Failed to get reference to menu interface for GMenu bridge");
+    let gtk_ref = server
+      .interface::<_, GtkMenus>(constants::GMENU_PATH)
+      .await
+      .log_expect("Failed to get reference to GMenu interface");
+    let mut ticker = tokio::time::interval(constants::GMENU_SYNC_TICK);
+    loop {
+      ticker.tick().await;
+      // `Start` locks the menu while holding this interface, so never hold
+      // the menu while waiting for it here.
+      let groups = gtk_ref.get().await.groups();
+      if groups.is_empty() {
+        continue;
+      }
+      let current = snapshot(&groups, &*menu_ref.get().await);
+      let changes = gtk_ref.get_mut().await.sync(current);
+      if changes.is_empty() {
+        continue;
+      }
+      debug!("Signalling {} GMenu changes", changes.len());
+      if let Err(err) = GtkMenus::changed(gtk_ref.signal_context(), &changes).await {
+        warn!("Failed to signal GMenu changes: {}", err);
+      }
+    }
+  });
+}
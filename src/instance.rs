@@ -0,0 +1,88 @@
+use futures_util::StreamExt;
+use log::{error, info, warn};
+use zbus::fdo::{RequestNameFlags, RequestNameReply};
+use zbus::names::{BusName, WellKnownName};
+
+use crate::constants;
+use crate::error::Fatal;
+
+/// Command-line flag taking the bus name over from a running instance.
+pub const REPLACE_ARG: &str = "--replace";
+
+/// Claims the well-known bus name, leaving it replaceable by a later
+/// `--replace` start. If another instance already holds it, asks that
+/// instance to reload and exits with `error::EXIT_ALREADY_RUNNING`.
+pub async fn claim_name(connection: &zbus::Connection, bus_name: &str, replace: bool) {
+  let dbus = zbus::fdo::DBusProxy::new(connection)
+    .await
+    .unwrap_or_else(|err| {
+      error!("Failed to get bus proxy");
+      Fatal::from(err).exit()
+    });
+  let name = WellKnownName::try_from(bus_name).unwrap_or_else(|err| {
+    error!("Invalid bus name {}", bus_name);
+    Fatal::Bus(err.into()).exit()
+  });
+  let mut flags = RequestNameFlags::AllowReplacement | RequestNameFlags::DoNotQueue;
+  if replace {
+    flags |= RequestNameFlags::ReplaceExisting;
+  }
+  match dbus.request_name(name.clone(), flags).await {
+    Ok(RequestNameReply::PrimaryOwner) | Ok(RequestNameReply::AlreadyOwner) => {
+      info!("Acquired bus name {}", bus_name);
+    },
+    Ok(_) => {
+      let owner = dbus
+        .get_connection_unix_process_id(BusName::from(name.clone()))
+        .await
+        .map_or_else(|_| "unknown".to_string(), |pid| pid.to_string());
+      error!(
+        "Another instance (PID {}) already owns {}; pass {} to take over",
+        owner, bus_name, REPLACE_ARG
+      );
+      forward_reload(connection, bus_name).await;
+      Fatal::NameTaken(bus_name.to_string()).exit();
+    },
+    Err(err) => {
+      error!("Failed to request bus name {}", bus_name);
+      Fatal::Bus(err.into()).exit();
+    },
+  }
+
+  let mut lost = dbus.receive_name_lost().await.unwrap_or_else(|err| {
+    error!("Failed to watch for bus name loss");
+    Fatal::from(err).exit()
+  });
+  let bus_name = bus_name.to_string();
+  tokio::spawn(async move {
+    while let Some(signal) = lost.next().await {
+      if signal
+        .args()
+        .is_ok_and(|args| args.name().as_str() == bus_name)
+      {
+        info!(
+          "Bus name {} taken over by another instance, exiting",
+          bus_name
+        );
+        std::process::exit(0);
+      }
+    }
+  });
+}
+
+/// Asks the instance owning `bus_name` to rescan its application directories.
+async fn forward_reload(connection: &zbus::Connection, bus_name: &str) {
+  let reply = connection
+    .call_method(
+      Some(bus_name),
+      constants::CONTROL_PATH,
+      Some("org.wsl.AppMenu.Control"),
+      "Reload",
+      &(),
+    )
+    .await;
+  match reply {
+    Ok(_) => info!("Asked the running instance to reload"),
+    Err(err) => warn!("Failed to forward reload to the running instance: {}", err),
+  }
+}
@@ -1,33 +1,153 @@
 use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
 
-use log::{error, info, trace, warn};
+use log::{debug, error, info, trace, warn};
 use log_err::*;
 use notify::{watcher, RecursiveMode, Watcher};
 use std::sync::mpsc::channel;
 use std::time::Duration;
-use tokio;
 use zbus::{dbus_interface, SignalContext};
 
+use error::Fatal;
+
+mod apps;
+#[cfg(feature = "companion")]
+mod companion;
+mod config;
 mod constants;
+mod control;
 mod desktop;
+mod error;
+#[cfg(feature = "gmenu")]
+mod gmenu;
+mod instance;
+mod pipe_menu;
 mod proxy_types;
+mod registration;
+mod sections;
+mod setup;
+mod socket;
+mod sources;
 mod util;
 
-struct AppMenuStatusNotifierItem {}
+struct AppMenuStatusNotifierItem {
+  id: String,
+  menu_path: String,
+  category: String,
+  title: String,
+  icon_name: String,
+  /// Icon shown for `icon_name`, its dark variant with a dark color scheme.
+  shown_icon: String,
+  /// `shown_icon` rendered for hosts without access to the icon theme.
+  icon_pixmap: Vec<(i32, i32, Vec<u8>)>,
+  tool_tip: String,
+  /// Failures raised since the user last opened the menu, oldest first.
+  alerts: Vec<String>,
+  /// `ATTENTION_ICON_NAME` rendered, once the first alert came in.
+  attention_pixmap: Vec<(i32, i32, Vec<u8>)>,
+  /// Whether the first scan is still running, leaving the menu empty.
+  scanning: bool,
+  /// Entries installed since the menu was last opened.
+  unseen: usize,
+  /// When scrolling last switched profiles.
+  last_scroll: Option<std::time::Instant>,
+  /// Badge with `unseen`, drawn over the icon while it is above zero.
+  overlay_pixmap: Vec<(i32, i32, Vec<u8>)>,
+}
 
 #[dbus_interface(name = "org.kde.StatusNotifierItem")]
 impl AppMenuStatusNotifierItem {
   /// Activate method
-  async fn activate(&self, _x: i32, _y: i32) {}
+  async fn activate(
+    &self,
+    _x: i32,
+    _y: i32,
+    #[zbus(object_server)] server: &zbus::ObjectServer,
+    #[zbus(signal_context)] ctxt: SignalContext<'_>,
+  ) {
+    match util::init::get_activate_action() {
+      util::init::ActivateAction::Nothing => return,
+      util::init::ActivateAction::Command => {
+        let command = config::get().activate_command.clone();
+        match desktop::command_launcher(&command) {
+          Some(launcher) => {
+            info!("Running {} on activation", command);
+            tokio::spawn(launch_entry(
+              ctxt.connection().clone(),
+              command.into(),
+              launcher,
+              None,
+            ));
+          },
+          None => warn!("Invalid activate_command '{}'", command),
+        }
+        return;
+      },
+      // ItemIsMenu has hosts open the menu themselves; one activating the
+      // item anyway is left to show it on its own.
+      util::init::ActivateAction::Menu => {
+        debug!("Ignoring activation, the menu opens on its own");
+        return;
+      },
+      util::init::ActivateAction::Favorite => {},
+    }
+    let menu = match server
+      .interface::<_, AppMenuDbusMenu>(self.menu_path.as_str())
+      .await
+    {
+      Ok(menu) => menu,
+      Err(err) => {
+        warn!("Failed to get menu interface for activation: {}", err);
+        return;
+      },
+    };
+    if !menu
+      .get()
+      .await
+      .launch_favorite(menu.signal_context().connection())
+    {
+      info!("Ignoring activation, no favorite application is installed");
+    }
+  }
 
   /// ContextMenu method
   async fn context_menu(&self, _x: i32, _y: i32) {}
 
   /// Scroll method
-  async fn scroll(&self, _delta: i32, _orientation: &str) {}
+  async fn scroll(
+    &mut self,
+    delta: i32,
+    orientation: &str,
+    #[zbus(signal_context)] ctxt: SignalContext<'_>,
+  ) {
+    if delta == 0 || !orientation.eq_ignore_ascii_case("vertical") {
+      return;
+    }
+    if self
+      .last_scroll
+      .is_some_and(|at| at.elapsed() < constants::SCROLL_DEBOUNCE)
+    {
+      return;
+    }
+    let profile = match util::init::cycle_profile(delta.signum() as isize) {
+      Some(profile) => profile,
+      None => return,
+    };
+    self.last_scroll = Some(std::time::Instant::now());
+    info!("Switched to menu profile {}", profile.name);
+    tokio::spawn(apply_profile(ctxt.connection().clone()));
+  }
 
   /// SecondaryActivate method
-  async fn secondary_activate(&self, _x: i32, _y: i32) {}
+  async fn secondary_activate(
+    &self,
+    _x: i32,
+    _y: i32,
+    #[zbus(signal_context)] ctxt: SignalContext<'_>,
+  ) {
+    info!("Rescanning on secondary activation");
+    tokio::spawn(refresh(ctxt.connection().clone()));
+  }
 
   /// NewAttentionIcon signal
   #[dbus_interface(signal)]
@@ -60,13 +180,13 @@ impl AppMenuStatusNotifierItem {
   /// AttentionIconName property
   #[dbus_interface(property)]
   async fn attention_icon_name(&self) -> &str {
-    ""
+    constants::ATTENTION_ICON_NAME
   }
 
   /// AttentionIconPixmap property
   #[dbus_interface(property)]
   async fn attention_icon_pixmap(&self) -> Vec<(i32, i32, Vec<u8>)> {
-    vec![]
+    self.attention_pixmap.clone()
   }
 
   /// AttentionMovieName property
@@ -78,19 +198,19 @@ impl AppMenuStatusNotifierItem {
   /// Category property
   #[dbus_interface(property)]
   async fn category(&self) -> &str {
-    "ApplicationStatus"
+    &self.category
   }
 
   /// IconName property
   #[dbus_interface(property)]
   async fn icon_name(&self) -> &str {
-    "starred"
+    &self.shown_icon
   }
 
   /// IconPixmap property
   #[dbus_interface(property)]
   async fn icon_pixmap(&self) -> Vec<(i32, i32, Vec<u8>)> {
-    vec![]
+    self.icon_pixmap.clone()
   }
 
   /// IconThemePath property
@@ -102,22 +222,20 @@ impl AppMenuStatusNotifierItem {
   /// Id property
   #[dbus_interface(property)]
   async fn id(&self) -> &str {
-    "WSLAppMenu"
+    &self.id
   }
 
   /// ItemIsMenu property
   #[dbus_interface(property)]
   async fn item_is_menu(&self) -> bool {
-    true
+    util::init::get_activate_action() == util::init::ActivateAction::Menu
   }
 
   /// Menu property
   #[dbus_interface(property)]
   async fn menu(&self) -> zbus::zvariant::OwnedObjectPath {
-    zbus::zvariant::OwnedObjectPath::try_from(
-      "/org/ayatana/NotificationItem/wslAppMenuDbusMenu/Menu",
-    )
-    .log_expect("Failed to parse menu path")
+    zbus::zvariant::OwnedObjectPath::try_from(self.menu_path.as_str())
+      .log_expect("Failed to parse menu path")
   }
 
   /// OverlayIconName property
@@ -129,518 +247,3325 @@ impl AppMenuStatusNotifierItem {
   /// OverlayIconPixmap property
   #[dbus_interface(property)]
   async fn overlay_icon_pixmap(&self) -> Vec<(i32, i32, Vec<u8>)> {
-    vec![]
+    self.overlay_pixmap.clone()
   }
 
   /// Status property
   #[dbus_interface(property)]
   async fn status(&self) -> &str {
-    "Active"
+    self.current_status()
   }
 
   /// Title property
   #[dbus_interface(property)]
   async fn title(&self) -> &str {
-    "Apps"
+    &self.title
   }
 
-  /*
-    /// ToolTip property
-    #[dbus_interface(property)]
-    async fn tool_tip(&self) -> ZbusResult<(String, Vec<(i32, i32, Vec<u8>)>)>;
+  /// ToolTip property
+  #[dbus_interface(property)]
+  async fn tool_tip(&self) -> (String, Vec<(i32, i32, Vec<u8>)>, String, String) {
+    if self.alerts.is_empty() {
+      return (
+        self.icon_name.clone(),
+        vec![],
+        constants::TOOL_TIP_TITLE.to_string(),
+        self.tool_tip.clone(),
+      );
+    }
+    (
+      constants::ATTENTION_ICON_NAME.to_string(),
+      vec![],
+      constants::TOOL_TIP_TITLE.to_string(),
+      format!("{}\n\n{}", self.tool_tip, self.alerts.join("\n")),
+    )
+  }
 
+  /*
     /// WindowId property
     #[dbus_interface(property)]
     async fn window_id(&self) -> ZbusResult<i32>;
   */
 }
 
+impl AppMenuStatusNotifierItem {
+  /// Switches to another icon, rendering its pixmaps at the configured sizes.
+  fn set_icon(&mut self, icon_name: &str) {
+    self.icon_name = icon_name.to_string();
+    self.shown_icon = util::appearance::themed_icon(icon_name);
+    self.icon_pixmap = desktop::icon_pixmaps(&self.shown_icon, &util::init::get_tray_icon_sizes());
+  }
+
+  /// Renders the badge for `unseen` new entries again.
+  fn render_badge(&mut self) {
+    self.overlay_pixmap = if self.unseen > 0 {
+      desktop::badge_pixmaps(self.unseen, &util::init::get_tray_icon_sizes())
+    } else {
+      vec![]
+    };
+  }
+
+  fn current_status(&self) -> &'static str {
+    if !self.alerts.is_empty() {
+      "NeedsAttention"
+    } else if self.scanning {
+      "Passive"
+    } else {
+      "Active"
+    }
+  }
+
+  /// Adds a failure to the tooltip, or clears them all once resolved.
+  fn apply_alert(&mut self, alert: util::alerts::Alert) {
+    match alert {
+      util::alerts::Alert::Failed(summary) => {
+        if self.attention_pixmap.is_empty() {
+          self.attention_pixmap = desktop::icon_pixmaps(
+            constants::ATTENTION_ICON_NAME,
+            &util::init::get_tray_icon_sizes(),
+          );
+        }
+        self.alerts.push(summary);
+        let excess = self.alerts.len().saturating_sub(constants::MAX_ALERTS);
+        self.alerts.drain(..excess);
+      },
+      util::alerts::Alert::Resolved => self.alerts.clear(),
+    }
+  }
+}
+
+/// Swaps the tray and submenu icons for their dark variants, or back, as
+/// the color scheme preference changes.
+async fn follow_color_scheme(connection: zbus::Connection, item_path: String) {
+  let apply = || async {
+    if let Ok(item_ref) = connection
+      .object_server()
+      .interface::<_, AppMenuStatusNotifierItem>(item_path.as_str())
+      .await
+    {
+      let mut item = item_ref.get_mut().await;
+      let icon_name = item.icon_name.clone();
+      item.set_icon(&icon_name);
+      if let Err(err) = AppMenuStatusNotifierItem::new_icon(item_ref.signal_context()).await {
+        warn!("Failed to signal tray icon change: {}", err);
+      }
+    }
+    let menu_ref = match connection
+      .object_server()
+      .interface::<_, AppMenuDbusMenu>(util::init::get_object_names().menu_path.as_str())
+      .await
+    {
+      Ok(menu_ref) => menu_ref,
+      Err(err) => {
+        warn!("Failed to get menu interface for the color scheme: {}", err);
+        return;
+      },
+    };
+    menu_ref
+      .get_mut()
+      .await
+      .refresh_section_icons(menu_ref.signal_context())
+      .await;
+  };
+  util::appearance::follow(connection.clone(), apply).await;
+}
+
+/// Shows alerts on the tray item as they come in, switching it to
+/// `NeedsAttention` until they are resolved.
+async fn show_alerts(
+  connection: zbus::Connection,
+  item_path: String,
+  mut alerts: tokio::sync::mpsc::UnboundedReceiver<util::alerts::Alert>,
+) {
+  while let Some(alert) = alerts.recv().await {
+    let item_ref = match connection
+      .object_server()
+      .interface::<_, AppMenuStatusNotifierItem>(item_path.as_str())
+      .await
+    {
+      Ok(item_ref) => item_ref,
+      Err(_) => continue,
+    };
+    let mut item = item_ref.get_mut().await;
+    let was_alerting = !item.alerts.is_empty();
+    let status = item.current_status();
+    item.apply_alert(alert);
+    // Take whatever else is queued, so a failing scan signals only once.
+    while let Ok(alert) = alerts.try_recv() {
+      item.apply_alert(alert);
+    }
+    if !was_alerting && item.alerts.is_empty() {
+      continue;
+    }
+    let ctxt = item_ref.signal_context();
+    if item.current_status() != status {
+      info!("Tray status changed to {}", item.current_status());
+      let sig_res = futures_util::try_join!(
+        AppMenuStatusNotifierItem::new_status(ctxt, item.current_status()),
+        AppMenuStatusNotifierItem::new_attention_icon(ctxt),
+      );
+      if let Err(err) = sig_res {
+        warn!("Failed to signal tray status change: {}", err);
+      }
+    }
+    if let Err(err) = AppMenuStatusNotifierItem::new_tool_tip(ctxt).await {
+      warn!("Failed to signal tooltip update: {}", err);
+    }
+  }
+}
+
 pub type DbusMenuLayoutEntry = (
   i32,
   std::collections::HashMap<String, zbus::zvariant::OwnedValue>,
   Vec<zbus::zvariant::OwnedValue>,
 );
 
-fn get_layout(
-  root: i32,
-  children: &HashMap<i32, Vec<i32>>,
-  props: &HashMap<i32, desktop::MenuProps>,
-  property_names: &Vec<&str>,
-  recursion_depth: i32,
-) -> DbusMenuLayoutEntry {
-  let root_props = props
-    .get(&root)
-    .log_expect("Failed to get props in layout fetch");
-
-  let ctxt = zbus::zvariant::EncodingContext::<byteorder::LE>::new_dbus(0);
-  let encoded =
-    zbus::zvariant::to_bytes(ctxt, root_props).log_expect("Failed to encode properties");
-  let mut root_props: std::collections::HashMap<String, zbus::zvariant::OwnedValue> =
-    zbus::zvariant::from_slice(&encoded, ctxt).log_expect("Failed to decode properties");
-  let root_props = root_props
-    .drain()
-    .filter(|(k, _)| property_names.is_empty() || property_names.contains(&k.as_str()))
-    .collect();
-  let mut entry = (root, root_props, Vec::new());
-  let next_depth = if recursion_depth > 0 {
-    recursion_depth - 1
+/// Whether property `name` belongs in a reply asking for `property_names`,
+/// where an empty list means all but the requested-only properties.
+fn property_requested(name: &str, property_names: &[&str]) -> bool {
+  if property_names.is_empty() {
+    !constants::REQUESTED_ONLY_PROPS.contains(&name)
   } else {
-    recursion_depth
+    property_names.contains(&name)
+  }
+}
+
+fn layout_props(
+  id: i32,
+  props: &HashMap<i32, desktop::MenuProps>,
+  property_names: &[&str],
+) -> std::collections::HashMap<String, zbus::zvariant::OwnedValue> {
+  props
+    .get(&id)
+    .log_expect("Failed to get props in layout fetch")
+    .to_dict(|k| property_requested(k, property_names))
+}
+
+/// Signals tray item changes after the configuration was reloaded.
+async fn apply_tray_config(connection: &zbus::Connection, item_path: &str) {
+  let item_ref = match connection
+    .object_server()
+    .interface::<_, AppMenuStatusNotifierItem>(item_path)
+    .await
+  {
+    Ok(item_ref) => item_ref,
+    Err(_) => return,
   };
-  if next_depth != 0 {
-    if let Some(node_children) = children.get(&root) {
-      for child in node_children.iter() {
-        if let Some(_) = props.get(child) {
-          let child = get_layout(*child, children, &props, property_names, next_depth);
-          let variant = zbus::zvariant::OwnedValue::from(zbus::zvariant::Value::new(child));
-          entry.2.push(variant);
-        }
-      }
+  let mut item = item_ref.get_mut().await;
+  let ctxt = item_ref.signal_context();
+  let category = util::init::get_sni_category();
+  if item.category != category {
+    item.category = category;
+    if let Err(err) = item.category_changed(ctxt).await {
+      warn!("Failed to signal tray category change: {}", err);
     }
   }
-  entry
+  item.title = config::get().title.clone();
+  item.set_icon(&config::get().icon_name);
+  item.render_badge();
+  let sig_res = futures_util::try_join!(
+    AppMenuStatusNotifierItem::new_icon(ctxt),
+    AppMenuStatusNotifierItem::new_overlay_icon(ctxt),
+    AppMenuStatusNotifierItem::new_title(ctxt),
+    AppMenuStatusNotifierItem::new_tool_tip(ctxt),
+  );
+  if let Err(err) = sig_res {
+    warn!("Failed to signal tray updates after config reload: {}", err);
+  }
 }
 
-fn update_category_props(
-  children: &mut HashMap<i32, Vec<i32>>,
-  props: &mut HashMap<i32, desktop::MenuProps>,
+/// Badges the tray icon with the number of entries installed since the menu
+/// was last opened.
+async fn show_unseen(connection: zbus::Connection, unseen: usize) {
+  let item_ref = match connection
+    .object_server()
+    .interface::<_, AppMenuStatusNotifierItem>(util::init::get_object_names().item_path.as_str())
+    .await
+  {
+    Ok(item_ref) => item_ref,
+    Err(_) => return,
+  };
+  let mut item = item_ref.get_mut().await;
+  if item.unseen == unseen {
+    return;
+  }
+  item.unseen = unseen;
+  item.render_badge();
+  if let Err(err) = AppMenuStatusNotifierItem::new_overlay_icon(item_ref.signal_context()).await {
+    warn!("Failed to signal tray badge update: {}", err);
+  }
+}
+
+/// Treats the menu as seen once it is opened, clearing alerts and the badge.
+async fn menu_opened(connection: zbus::Connection) {
+  util::alerts::resolve();
+  let iface_ref = match connection
+    .object_server()
+    .interface::<_, AppMenuDbusMenu>(util::init::get_object_names().menu_path.as_str())
+    .await
+  {
+    Ok(iface_ref) => iface_ref,
+    Err(err) => {
+      warn!("Failed to get menu interface to mark it seen: {}", err);
+      return;
+    },
+  };
+  iface_ref.get_mut().await.mark_seen();
+  show_unseen(connection, 0).await;
+}
+
+/// Regenerates pipe menu `id` from `menu`'s script, run without holding the
+/// menu so it can't stall other calls, and updates its items if they changed.
+async fn refresh_pipe_menu(connection: zbus::Connection, id: i32, menu: config::PipeMenu) {
+  let items = match pipe_menu::run_script(&menu).await {
+    Ok(items) => items,
+    Err(err) => {
+      warn!("Failed to refresh pipe menu '{}': {}", menu.label, err);
+      vec![]
+    },
+  };
+  let iface_ref = match connection
+    .object_server()
+    .interface::<_, AppMenuDbusMenu>(util::init::get_object_names().menu_path.as_str())
+    .await
+  {
+    Ok(iface_ref) => iface_ref,
+    Err(err) => {
+      warn!(
+        "Failed to get menu interface to refresh a pipe menu: {}",
+        err
+      );
+      return;
+    },
+  };
+  let ctxt = iface_ref.signal_context().clone();
+  iface_ref
+    .get_mut()
+    .await
+    .set_pipe_items(id, items, &ctxt)
+    .await;
+}
+
+/// Reads every entry in the enabled application directories `dirs`, plus the
+/// config's custom entries, for the menu to start out with.
+fn initial_scan(
+  dirs: &[(usize, std::path::PathBuf)],
+  locale: &str,
+) -> (
+  HashMap<std::ffi::OsString, BTreeMap<usize, desktop::Launcher>>,
+  desktop::ParseFailures,
 ) {
-  for i in 1..12 {
-    props
-      .get_mut(&i)
-      .log_expect("Failed to get category ref for update")
-      .visible = !children
-      .get(&i)
-      .log_expect("Failed to get children ref for update")
-      .is_empty();
+  let mut cache: HashMap<std::ffi::OsString, BTreeMap<usize, desktop::Launcher>> = HashMap::new();
+  let mut parse_failures = desktop::ParseFailures::default();
+  let scan_started = std::time::Instant::now();
+  for (prio_idx, dir) in dirs {
+    for p in desktop::scan_dir(dir) {
+      let name = match desktop::desktop_id(dir, &p) {
+        Some(name) if !desktop::is_hidden_id(&name) => name,
+        _ => continue,
+      };
+      if let Some(launcher) = desktop::launcher_for_entry(p, locale, &mut parse_failures)
+        .and_then(|launcher| desktop::filter_launcher(&name, launcher))
+      {
+        let prio_cache = cache.entry(name).or_default();
+        prio_cache.insert(*prio_idx, launcher);
+      }
+    }
+  }
+  for (name, launcher) in desktop::custom_launchers() {
+    cache.entry(name).or_default().insert(0, launcher);
+  }
+  info!("Loaded {} menu entries", cache.len());
+  util::usage::record_scan(scan_started.elapsed());
+  (cache, parse_failures)
+}
 
-    children
-      .get_mut(&i)
-      .log_expect("Failed to get children for sorting")
-      .sort_by_key(|k| {
-        props
-          .get(k)
-          .log_expect("Failed to get properties for sorting")
-          .label
-          .clone()
+/// Switches the tray item from `Passive` to `Active` once the first scan
+/// filled the menu.
+async fn finish_scanning(connection: &zbus::Connection, item_path: &str) {
+  let item_ref = match connection
+    .object_server()
+    .interface::<_, AppMenuStatusNotifierItem>(item_path)
+    .await
+  {
+    Ok(item_ref) => item_ref,
+    Err(_) => return,
+  };
+  let mut item = item_ref.get_mut().await;
+  item.scanning = false;
+  let sig_res =
+    AppMenuStatusNotifierItem::new_status(item_ref.signal_context(), item.current_status()).await;
+  if let Err(err) = sig_res {
+    warn!("Failed to signal tray status change: {}", err);
+  }
+}
+
+/// Items a layout of `root` nested `depth` levels deep lists, `root` included.
+fn layout_ids(root: i32, depth: i32, children: &HashMap<i32, Vec<i32>>) -> Vec<i32> {
+  let mut seen = std::collections::HashSet::from([root]);
+  let mut level = vec![root];
+  let mut depth = depth;
+  for _ in 0..constants::MAX_LAYOUT_DEPTH {
+    if depth == 0 || level.is_empty() {
+      break;
+    }
+    level = level
+      .iter()
+      .flat_map(|id| children.get(id).into_iter().flatten().copied())
+      .filter(|id| seen.insert(*id))
+      .collect();
+    depth -= 1;
+  }
+  seen.into_iter().collect()
+}
+
+/// Renders the deferred icons of the items listed in submenus `ids`, for
+/// hosts that don't ask for layouts.
+#[cfg(feature = "gmenu")]
+async fn show_submenu_icons(connection: zbus::Connection, ids: Vec<i32>) {
+  let iface_ref = match connection
+    .object_server()
+    .interface::<_, AppMenuDbusMenu>(util::init::get_object_names().menu_path.as_str())
+    .await
+  {
+    Ok(iface_ref) => iface_ref,
+    Err(err) => {
+      warn!("Failed to get menu interface to show icons: {}", err);
+      return;
+    },
+  };
+  let mut iface = iface_ref.get_mut().await;
+  let ids = ids
+    .into_iter()
+    .flat_map(|id| layout_ids(id, 1, &iface.children))
+    .collect();
+  iface.render_icons(ids, iface_ref.signal_context());
+}
+
+/// Renders icon files as `icon-name` and `icon-data` by path, spreading them
+/// over one blocking task per CPU.
+async fn render_file_icons(
+  icons: impl IntoIterator<Item = String>,
+) -> HashMap<String, (String, desktop::IconData)> {
+  let icons: Vec<String> = icons
+    .into_iter()
+    .collect::<std::collections::HashSet<_>>()
+    .into_iter()
+    .collect();
+  let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+  let chunk_size = icons.len().div_ceil(workers).max(1);
+  let tasks: Vec<_> = icons
+    .chunks(chunk_size)
+    .map(|chunk| {
+      let chunk = chunk.to_vec();
+      tokio::task::spawn_blocking(move || {
+        chunk
+          .into_iter()
+          .map(|icon| {
+            let rendered = desktop::file_icon(&icon);
+            (icon, rendered)
+          })
+          .collect::<Vec<_>>()
       })
+    })
+    .collect();
+  let mut rendered = HashMap::new();
+  for task in tasks {
+    match task.await {
+      Ok(chunk) => rendered.extend(chunk),
+      Err(err) => warn!("Failed to render icons: {}", err),
+    }
   }
+  rendered
 }
 
-fn launcher_updated(orig: &desktop::Launcher, new: &desktop::Launcher) -> bool {
-  orig.categories.iter().next() != new.categories.iter().next()
-    || orig.display != new.display
-    || orig.icon != new.icon
-    || orig.name != new.name
+/// Renders deferred icons off the async runtime, then shows them in the menu.
+async fn render_icons(connection: zbus::Connection, icons: Vec<(i32, String)>) {
+  let rendered = render_file_icons(icons.iter().map(|(_, icon)| icon.clone())).await;
+  let rendered = icons
+    .into_iter()
+    .filter_map(|(menu_idx, icon)| {
+      let file_icon = rendered.get(&icon)?.clone();
+      Some((menu_idx, icon, file_icon))
+    })
+    .collect();
+  let iface_ref = match connection
+    .object_server()
+    .interface::<_, AppMenuDbusMenu>(util::init::get_object_names().menu_path.as_str())
+    .await
+  {
+    Ok(iface_ref) => iface_ref,
+    Err(err) => {
+      warn!("Failed to get menu interface to show icons: {}", err);
+      return;
+    },
+  };
+  iface_ref
+    .get_mut()
+    .await
+    .show_icons(rendered, iface_ref.signal_context())
+    .await;
 }
 
-#[derive(Debug)]
-struct AppMenuDbusMenu {
-  revision: u32,
-  children: HashMap<i32, Vec<i32>>,
-  props: HashMap<i32, desktop::MenuProps>,
-  cache: HashMap<std::ffi::OsString, BTreeMap<usize, desktop::Launcher>>,
-  path_map: bimap::BiMap<usize, std::path::PathBuf>,
-  counter: LauncherCounter,
+/// Takes the deferred icons of menu items `ids` and renders them, for
+/// callers that can't take the menu mutably themselves.
+async fn render_layout_icons(connection: zbus::Connection, ids: Vec<i32>) {
+  let iface_ref = match connection
+    .object_server()
+    .interface::<_, AppMenuDbusMenu>(util::init::get_object_names().menu_path.as_str())
+    .await
+  {
+    Ok(iface_ref) => iface_ref,
+    Err(err) => {
+      warn!("Failed to get menu interface to render icons: {}", err);
+      return;
+    },
+  };
+  let ctxt = iface_ref.signal_context().clone();
+  iface_ref.get_mut().await.render_icons(ids, &ctxt);
 }
 
-use zbus::DBusError;
-#[derive(DBusError, Debug)]
-#[dbus_error(prefix = "org.wsl.AppMenuDbusMenu")]
-enum MenuError {
-  #[dbus_error(zbus_error)]
-  ZBus(zbus::Error),
-  LauncherIndexNotFound,
-  PropertyNotFound,
+/// Layouts built for one menu revision, reused until it changes.
+#[derive(Debug, Default)]
+struct WarmLayouts {
+  revision: Option<u32>,
+  /// Layouts by `layout_key`.
+  layouts: HashMap<(i32, i32, u64), DbusMenuLayoutEntry>,
 }
 
-#[dbus_interface(name = "com.canonical.dbusmenu")]
-impl AppMenuDbusMenu {
-  /// AboutToShow method
-  async fn about_to_show(&self, _id: i32) -> bool {
-    false
+impl WarmLayouts {
+  /// The cached layouts, dropped first if built for another revision.
+  fn at(&mut self, revision: u32) -> &mut HashMap<(i32, i32, u64), DbusMenuLayoutEntry> {
+    if self.revision != Some(revision) {
+      self.layouts.clear();
+      self.revision = Some(revision);
+    }
+    &mut self.layouts
   }
 
-  /// AboutToShowGroup method
-  async fn about_to_show_group(&self, _ids: Vec<i32>) -> (Vec<i32>, Vec<i32>) {
-    (vec![], vec![])
+  /// Drops the cached layouts, for when props changed without a new revision.
+  fn invalidate(&mut self) {
+    self.revision = None;
   }
+}
 
-  /// Event method
-  async fn event(
-    &mut self,
-    item_id: i32,
-    event_id: &str,
-    _data: zbus::zvariant::Value<'_>,
-    timestamp: u32,
-    #[zbus(signal_context)] ctxt: SignalContext<'_>,
-  ) {
-    match event_id {
-      "clicked" => {
-        let sig_res = AppMenuDbusMenu::item_activation_requested(&ctxt, &item_id, &timestamp).await;
-        if let Err(err) = sig_res {
-          warn!("Failed to signal activation for {}: {}", item_id, err);
-        }
-        let target_path = self.counter.get_path(&(item_id as usize));
-        if target_path.is_some() {
-          let target_path = target_path.unwrap();
-          let target_entry = self
-            .cache
-            .get(target_path)
-            .log_expect(format!("Failed to find BTree for {:?}", target_path).as_str())
-            .iter()
-            .next()
-            .log_expect(format!("Failed to get BTree entry for {:?}", target_path).as_str());
-          let exec = &target_entry.1.exec;
-          let mut exec_vec = exec.split(" ").collect::<std::collections::VecDeque<_>>();
-          if exec_vec.is_empty() {
-            warn!("Exec for {:?} is empty!", target_path);
-          } else {
-            let mut cmd = std::process::Command::new(exec_vec.pop_front().unwrap());
-            let spawn_result = cmd.args(exec_vec).spawn();
-            if let Err(err) = spawn_result {
-              error!("Failed to exec {:?}: {}", target_path, err);
-            }
-            return;
-          }
+/// A partially built layout node on the `get_layout` work stack.
+struct LayoutFrame {
+  entry: DbusMenuLayoutEntry,
+  pending: Vec<i32>,
+  depth: i32,
+}
+
+/// Cache key of a GetLayout request: parent, depth and a hash of the
+/// requested property names.
+fn layout_key(parent_id: i32, recursion_depth: i32, property_names: &[&str]) -> (i32, i32, u64) {
+  let mut hasher = std::collections::hash_map::DefaultHasher::new();
+  property_names.hash(&mut hasher);
+  (
+    parent_id,
+    recursion_depth,
+    std::hash::Hasher::finish(&hasher),
+  )
+}
+
+/// Builds the layout below `root` without recursion, skipping any child that
+/// would close a cycle or nest deeper than `constants::MAX_LAYOUT_DEPTH`.
+fn get_layout(
+  root: i32,
+  children: &HashMap<i32, Vec<i32>>,
+  props: &HashMap<i32, desktop::MenuProps>,
+  property_names: &[&str],
+  recursion_depth: i32,
+) -> DbusMenuLayoutEntry {
+  let new_frame = |id: i32, depth: i32| {
+    let next_depth = if depth > 0 { depth - 1 } else { depth };
+    let visible = props.get(&id).is_some_and(|p| p.visible);
+    let mut pending = if depth != 0 && visible {
+      prune_separators(children.get(&id).map_or(&[], Vec::as_slice), props)
+    } else {
+      Vec::new()
+    };
+    pending.reverse();
+    LayoutFrame {
+      entry: (id, layout_props(id, props, property_names), Vec::new()),
+      pending,
+      depth: next_depth,
+    }
+  };
+
+  let mut on_path = std::collections::HashSet::from([root]);
+  let mut stack = vec![new_frame(root, recursion_depth)];
+  loop {
+    let nesting = stack.len();
+    let top = stack
+      .last_mut()
+      .log_expect("Layout stack unexpectedly empty");
+    if let Some(child) = top.pending.pop() {
+      if !props.contains_key(&child) {
+        continue;
+      }
+      if on_path.contains(&child) {
+        warn!(
+          "Skipping menu item {} under {}: it would form a cycle",
+          child, top.entry.0
+        );
+        continue;
+      }
+      if nesting >= constants::MAX_LAYOUT_DEPTH {
+        warn!(
+          "Skipping menu item {} under {}: layout is nested deeper than {} levels",
+          child,
+          top.entry.0,
+          constants::MAX_LAYOUT_DEPTH
+        );
+        continue;
+      }
+      let depth = top.depth;
+      on_path.insert(child);
+      stack.push(new_frame(child, depth));
+    } else {
+      let done = stack.pop().log_expect("Layout stack unexpectedly empty");
+      on_path.remove(&done.entry.0);
+      match stack.last_mut() {
+        Some(parent) => parent.entry.2.push(zbus::zvariant::OwnedValue::from(
+          zbus::zvariant::Value::new(done.entry),
+        )),
+        None => return done.entry,
+      }
+    }
+  }
+}
+
+/// Drops separators that would show up first, last or next to another one
+/// among the visible items.
+fn prune_separators(ids: &[i32], props: &HashMap<i32, desktop::MenuProps>) -> Vec<i32> {
+  let mut out = Vec::new();
+  let mut separator = None;
+  let mut shown_before = false;
+  for id in ids {
+    match props.get(id) {
+      Some(p) if p.visible && p.entry_type == "separator" => {
+        if shown_before && separator.is_none() {
+          separator = Some(*id);
         }
-        warn!("Got activation request for nonexistent entry: {}", item_id);
       },
-      "hovered" => trace!("Ignoring hover"),
-      "opened" => trace!("Ignoring open"),
-      "closed" => trace!("Ignoring close"),
-      _ => info!("Ignoring unknown event: {}", event_id),
+      Some(p) if p.visible => {
+        out.extend(separator.take());
+        shown_before = true;
+        out.push(*id);
+      },
+      _ => out.push(*id),
     }
   }
+  out
+}
 
-  /// GetGroupProperties method
-  async fn get_group_properties(
-    &self,
-    item_ids: Vec<i32>,
-    property_names: Vec<&str>,
-  ) -> Vec<(
-    i32,
-    std::collections::HashMap<String, zbus::zvariant::OwnedValue>,
-  )> {
-    let mut out = Vec::new();
-    for i in item_ids.iter() {
-      let props = self.props.get(i);
-      if let Some(props) = props {
-        let ctxt = zbus::zvariant::EncodingContext::<byteorder::LE>::new_dbus(0);
-        let encoded =
-          zbus::zvariant::to_bytes(ctxt, props).log_expect("Failed to encode properties");
-        let mut props: std::collections::HashMap<String, zbus::zvariant::OwnedValue> =
-          zbus::zvariant::from_slice(&encoded, ctxt).log_expect("Failed to decode properties");
-        let props = props
-          .drain()
-          .filter(|(k, _)| property_names.is_empty() || property_names.contains(&k.as_str()))
-          .collect();
-        out.push((*i, props));
-      }
+/// Menu structure before an update: every submenu's children and the
+/// properties of the items other than launchers, which change without their
+/// own property signals.
+struct MenuShape {
+  children: HashMap<i32, Vec<i32>>,
+  props: HashMap<i32, desktop::MenuProps>,
+}
+
+impl MenuShape {
+  fn of(children: &HashMap<i32, Vec<i32>>, props: &HashMap<i32, desktop::MenuProps>) -> Self {
+    MenuShape {
+      children: children.clone(),
+      props: props
+        .iter()
+        .filter(|(id, _)| !constants::is_launcher_idx(**id))
+        .map(|(id, p)| (*id, p.clone()))
+        .collect(),
     }
-    out
   }
 
-  /// GetLayout method
-  async fn get_layout(
+  /// Deepest submenu holding everything that changed since the shape was
+  /// taken, along with `item`, for LayoutUpdated. Falls back to the root.
+  fn changed_parent(
     &self,
-    parent_id: i32,
-    recursion_depth: i32,
-    property_names: Vec<&str>,
-  ) -> Result<(u32, DbusMenuLayoutEntry), MenuError> {
-    if let Some(_) = self.props.get(&parent_id) {
-      let layout = get_layout(
-        parent_id,
-        &self.children,
-        &self.props,
-        &property_names,
-        recursion_depth,
+    children: &HashMap<i32, Vec<i32>>,
+    props: &HashMap<i32, desktop::MenuProps>,
+    item: i32,
+  ) -> i32 {
+    let mut changed: Vec<i32> = children
+      .keys()
+      .chain(self.children.keys())
+      .filter(|id| children.get(id) != self.children.get(id))
+      .copied()
+      .collect();
+    let mut moved: Vec<i32> = props
+      .iter()
+      .filter(|(id, _)| !constants::is_launcher_idx(**id))
+      .filter(|(id, p)| self.props.get(id) != Some(p))
+      .map(|(id, _)| *id)
+      .chain(
+        self
+          .props
+          .keys()
+          .filter(|id| !props.contains_key(id))
+          .copied(),
+      )
+      .collect();
+    moved.push(item);
+    for lists in [children, &self.children] {
+      changed.extend(
+        lists
+          .iter()
+          .filter(|(_, list)| list.iter().any(|id| moved.contains(id)))
+          .map(|(parent, _)| *parent),
       );
-      return Ok((self.revision, layout));
     }
-    Err(MenuError::LauncherIndexNotFound)
-  }
 
-  /// GetProperty method
-  async fn get_property(
-    &self,
+    let mut parents = HashMap::new();
+    for lists in [children, &self.children] {
+      for (parent, list) in lists {
+        for id in list {
+          parents.entry(*id).or_insert(*parent);
+        }
+      }
+    }
+    let ancestors = |id: i32| {
+      let mut chain = vec![id];
+      while let Some(parent) = parents.get(chain.last().unwrap()) {
+        if chain.contains(parent) || chain.len() > constants::MAX_LAYOUT_DEPTH {
+          break;
+        }
+        chain.push(*parent);
+      }
+      chain
+    };
+    let mut common: Option<Vec<i32>> = None;
+    for id in changed {
+      let chain = ancestors(id);
+      common = Some(match common {
+        Some(common) => common.into_iter().filter(|i| chain.contains(i)).collect(),
+        None => chain,
+      });
+    }
+    common.and_then(|c| c.first().copied()).unwrap_or(0)
+  }
+}
+
+/// Refreshes category visibility and ordering. Categories with fewer than
+/// `merge_threshold` visible entries are hidden and their entries shown in
+/// the combined "Other" submenu, or the root menu, instead. Only the
+/// `affected` sections are laid out again, relying on their members being
+/// kept sorted; `None` re-sorts and lays out all of them, for when labels may
+/// have changed.
+fn update_category_props(
+  children: &mut HashMap<i32, Vec<i32>>,
+  props: &mut HashMap<i32, desktop::MenuProps>,
+  sections: &mut sections::Sections,
+  merge_threshold: usize,
+  affected: Option<&[i32]>,
+) {
+  if affected.is_none() {
+    sections.sort_members(props);
+  }
+  let mut merged = Vec::new();
+  for i in sections.ids().collect::<Vec<_>>() {
+    let members = sections.members(i);
+    let shown = members
+      .iter()
+      .filter(|k| props.get(k).is_some_and(|p| p.visible))
+      .count();
+    let merge = shown > 0 && shown < merge_threshold;
+    if merge {
+      merged.extend_from_slice(members);
+    }
+    props
+      .get_mut(&i)
+      .log_expect("Failed to get category ref for update")
+      .visible = !members.is_empty() && !merge;
+    if affected.is_none_or(|affected| affected.contains(&i)) {
+      layout_section(children, props, sections, i);
+    }
+  }
+
+  merged.sort_by(|a, b| label_of(props, a).cmp(label_of(props, b)));
+  let root = children
+    .get_mut(&0)
+    .log_expect("Failed to get root children");
+  // Launchers are only ever listed in the root when flattened there.
+  root.retain(|k| !constants::is_launcher_idx(*k));
+  if util::init::get_flatten_sparse() {
+    let at = root
+      .iter()
+      .position(|k| *k == constants::OTHER_CATEGORY_IDX)
+      .unwrap_or(root.len());
+    root.splice(at..at, merged.drain(..));
+  }
+  props
+    .get_mut(&constants::OTHER_CATEGORY_IDX)
+    .log_expect("Failed to get other category ref for update")
+    .visible = !merged.is_empty();
+  children.insert(constants::OTHER_CATEGORY_IDX, merged);
+}
+
+/// Makes a copy of the launcher for a desktop-file ID in `list`, returning
+/// its ID, unless the launcher isn't shown.
+fn copy_launcher(
+  props: &mut HashMap<i32, desktop::MenuProps>,
+  counter: &LauncherCounter,
+  appid: &str,
+  list: constants::CopyList,
+) -> Option<i32> {
+  let idx = counter.index_of(appid)? as i32;
+  let launcher_props = props.get(&idx).filter(|p| p.visible)?.clone();
+  let copy_idx = constants::copy_idx(list, idx);
+  props.insert(copy_idx, launcher_props);
+  Some(copy_idx)
+}
+
+/// Brings the copies of the launchers in `updated` in line with them, adding
+/// the same property changes for each copy listed.
+fn with_copies<'a>(
+  props: &mut HashMap<i32, desktop::MenuProps>,
+  mut updated: Vec<(i32, HashMap<String, zbus::zvariant::Value<'a>>)>,
+) -> Vec<(i32, HashMap<String, zbus::zvariant::Value<'a>>)> {
+  let mut copies = Vec::new();
+  for (idx, changes) in updated.iter() {
+    let launcher_props = match props.get(idx) {
+      Some(launcher_props) if constants::is_launcher_idx(*idx) => launcher_props.clone(),
+      _ => continue,
+    };
+    for list in [
+      constants::CopyList::Pinned,
+      constants::CopyList::Recent,
+      constants::CopyList::Frequent,
+    ] {
+      let copy_idx = constants::copy_idx(list, *idx);
+      if let Some(copy_props) = props.get_mut(&copy_idx) {
+        *copy_props = launcher_props.clone();
+        copies.push((copy_idx, changes.clone()));
+      }
+    }
+  }
+  updated.extend(copies);
+  updated
+}
+
+/// Lists copies of the favorites at the top of the root menu, followed by a
+/// separator if there are any, and copies of the entries installed most
+/// recently and launched most in the "Recently added" and "Frequently used"
+/// submenus.
+fn update_copies(
+  children: &mut HashMap<i32, Vec<i32>>,
+  props: &mut HashMap<i32, desktop::MenuProps>,
+  counter: &LauncherCounter,
+  installed: &HashMap<String, u64>,
+) {
+  let root = children
+    .get_mut(&0)
+    .log_expect("Failed to get root children");
+  root.retain(|k| {
+    let pinned = constants::is_copy_idx(constants::CopyList::Pinned, *k);
+    if pinned {
+      props.remove(k);
+    }
+    !pinned && *k != constants::FAVORITES_SEPARATOR_IDX
+  });
+  let mut pinned = Vec::new();
+  for appid in config::get().favorites.iter() {
+    if let Some(idx) = copy_launcher(props, counter, appid, constants::CopyList::Pinned) {
+      if !pinned.contains(&idx) {
+        pinned.push(idx);
+      }
+    }
+  }
+  if !pinned.is_empty() {
+    pinned.push(constants::FAVORITES_SEPARATOR_IDX);
+  }
+  root.splice(0..0, pinned);
+
+  for old in children
+    .remove(&constants::RECENT_MENU_IDX)
+    .unwrap_or_default()
+  {
+    props.remove(&old);
+  }
+  let max_age = config::get().recent_days * 24 * 60 * 60;
+  let now = util::state::unix_now();
+  let mut recent: Vec<(&String, &u64)> = installed
+    .iter()
+    .filter(|(_, at)| now.saturating_sub(**at) < max_age)
+    .collect();
+  recent.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+  let recent: Vec<i32> = recent
+    .into_iter()
+    .filter_map(|(appid, _)| copy_launcher(props, counter, appid, constants::CopyList::Recent))
+    .take(config::get().recent_count)
+    .collect();
+  if let Some(recent_props) = props.get_mut(&constants::RECENT_MENU_IDX) {
+    recent_props.visible = !recent.is_empty();
+  }
+  children.insert(constants::RECENT_MENU_IDX, recent);
+
+  for old in children
+    .remove(&constants::FREQUENT_MENU_IDX)
+    .unwrap_or_default()
+  {
+    props.remove(&old);
+  }
+  let frequent_count = config::get().frequent_count;
+  let frequent: Vec<i32> = if frequent_count > 0 {
+    util::frequency::ranked()
+      .iter()
+      .filter_map(|appid| copy_launcher(props, counter, appid, constants::CopyList::Frequent))
+      .take(frequent_count)
+      .collect()
+  } else {
+    Vec::new()
+  };
+  if let Some(frequent_props) = props.get_mut(&constants::FREQUENT_MENU_IDX) {
+    frequent_props.visible = !frequent.is_empty();
+  }
+  children.insert(constants::FREQUENT_MENU_IDX, frequent);
+}
+
+fn label_of<'a>(props: &'a HashMap<i32, desktop::MenuProps>, id: &i32) -> &'a str {
+  props
+    .get(id)
+    .log_expect("Failed to get properties for sorting")
+    .label
+    .as_str()
+}
+
+/// Lists a section's members, nesting entries sharing an additional category
+/// with at least `subcategory_threshold` others in a submenu for it.
+fn layout_section(
+  children: &mut HashMap<i32, Vec<i32>>,
+  props: &mut HashMap<i32, desktop::MenuProps>,
+  sections: &mut sections::Sections,
+  section: i32,
+) {
+  let nest_threshold = config::get().subcategory_threshold;
+  let members = sections.members(section).to_vec();
+  let mut counts: HashMap<String, usize> = HashMap::new();
+  if nest_threshold > 0 {
+    for k in members.iter() {
+      match props.get(k) {
+        Some(p) if p.visible => {
+          for name in p.x_categories.iter() {
+            if constants::is_additional_category(name) {
+              *counts.entry(name.clone()).or_default() += 1;
+            }
+          }
+        },
+        _ => {},
+      }
+    }
+  }
+  let mut direct = Vec::new();
+  let mut nested: HashMap<String, Vec<i32>> = HashMap::new();
+  for k in members {
+    let subcategory = props.get(&k).and_then(|p| {
+      p.x_categories
+        .iter()
+        .find(|name| counts.get(*name).is_some_and(|n| *n >= nest_threshold))
+    });
+    match subcategory {
+      Some(name) => nested.entry(name.clone()).or_default().push(k),
+      None => direct.push(k),
+    }
+  }
+  for sub in sections.submenus_of(section).collect::<Vec<_>>() {
+    children.insert(sub, Vec::new());
+    if let Some(sub_props) = props.get_mut(&sub) {
+      sub_props.visible = false;
+    }
+  }
+  let mut subs = Vec::new();
+  for (name, entries) in nested {
+    let sub = sections.submenu_id(section, &name);
+    children.insert(sub, entries);
+    props.insert(sub, desktop::subcategory_props(&name));
+    subs.push(sub);
+  }
+  subs.sort_by(|a, b| label_of(props, a).cmp(label_of(props, b)));
+  let page_size = config::get().page_size;
+  if page_size > 0 && direct.len() > page_size {
+    for (i, page) in pages(props, direct, page_size).into_iter().enumerate() {
+      let sub = sections.submenu_id(section, &format!("page {}", i));
+      let first = initial(label_of(props, &page[0]));
+      let last = initial(label_of(props, &page[page.len() - 1]));
+      let label = if first == last {
+        first.to_string()
+      } else {
+        format!("{}–{}", first, last)
+      };
+      children.insert(sub, page);
+      props.insert(sub, desktop::submenu_props(label));
+      subs.push(sub);
+    }
+  } else {
+    subs.extend(direct);
+  }
+  children.insert(section, subs);
+}
+
+fn initial(label: &str) -> char {
+  label
+    .chars()
+    .next()
+    .map_or('#', |c| c.to_uppercase().next().unwrap_or(c))
+}
+
+/// Splits entries sorted by label into pages of at most `page_size`, keeping
+/// entries with the same initial together even if that overfills a page.
+fn pages(
+  props: &HashMap<i32, desktop::MenuProps>,
+  mut entries: Vec<i32>,
+  page_size: usize,
+) -> Vec<Vec<i32>> {
+  // Labels sort case-sensitively; bring lowercase initials in with the rest.
+  entries.sort_by_key(|k| initial(label_of(props, k)));
+  let mut groups: Vec<Vec<i32>> = Vec::new();
+  for k in entries {
+    match groups.last_mut() {
+      Some(group) if initial(label_of(props, &group[0])) == initial(label_of(props, &k)) => {
+        group.push(k)
+      },
+      _ => groups.push(vec![k]),
+    }
+  }
+  let mut pages: Vec<Vec<i32>> = Vec::new();
+  for group in groups {
+    match pages.last_mut() {
+      Some(page) if page.len() + group.len() <= page_size => page.extend(group),
+      _ => pages.push(group),
+    }
+  }
+  pages
+}
+
+/// Flips whether an application directory is scanned, saving the choice and
+/// applying it right away.
+async fn toggle_source(connection: zbus::Connection, dir: std::path::PathBuf) {
+  let disabled = !util::init::is_dir_disabled(&dir);
+  if let Err(err) = config::set_dir_disabled(&dir, disabled) {
+    warn!("Failed to save source toggle for {:?}: {}", dir, err);
+    return;
+  }
+  info!(
+    "{} application directory {:?}",
+    if disabled { "Disabled" } else { "Enabled" },
+    dir
+  );
+  config::reload();
+  let iface_ref = match connection
+    .object_server()
+    .interface::<_, AppMenuDbusMenu>(util::init::get_object_names().menu_path.as_str())
+    .await
+  {
+    Ok(iface_ref) => iface_ref,
+    Err(err) => {
+      warn!(
+        "Failed to get menu interface to apply source toggle: {}",
+        err
+      );
+      return;
+    },
+  };
+  let mut iface = iface_ref.get_mut().await;
+  iface.apply_config(iface_ref.signal_context()).await;
+}
+
+/// Config key and current state of a display option item, `None` for any
+/// other item.
+fn display_option(idx: i32) -> Option<(&'static str, bool)> {
+  match idx {
+    constants::SHOW_HIDDEN_ITEM_IDX => Some(("show_hidden", util::init::get_show_hidden())),
+    constants::SHOW_TERMINAL_ITEM_IDX => Some(("show_terminal", util::init::get_show_terminal())),
+    _ => None,
+  }
+}
+
+/// Saves a display option to the config and shows or hides entries by it.
+async fn toggle_option(connection: zbus::Connection, key: &'static str, on: bool) {
+  if let Err(err) = config::set_flag(key, on) {
+    warn!("Failed to save {}: {}", key, err);
+    return;
+  }
+  info!("Set {} to {}", key, on);
+  config::reload();
+  let iface_ref = match connection
+    .object_server()
+    .interface::<_, AppMenuDbusMenu>(util::init::get_object_names().menu_path.as_str())
+    .await
+  {
+    Ok(iface_ref) => iface_ref,
+    Err(err) => {
+      warn!("Failed to get menu interface to apply {}: {}", key, err);
+      return;
+    },
+  };
+  let mut iface = iface_ref.get_mut().await;
+  iface
+    .apply_display_options(iface_ref.signal_context())
+    .await;
+}
+
+/// Shows the entries in the menu profile switched to, then the tooltip
+/// summing them up. Neither interface is locked while waiting for the other.
+async fn apply_profile(connection: zbus::Connection) {
+  let names = util::init::get_object_names();
+  let server = connection.object_server();
+  let menu_ref = match server
+    .interface::<_, AppMenuDbusMenu>(names.menu_path.as_str())
+    .await
+  {
+    Ok(menu_ref) => menu_ref,
+    Err(err) => {
+      warn!("Failed to get menu interface to switch profiles: {}", err);
+      return;
+    },
+  };
+  let tool_tip = {
+    let mut iface = menu_ref.get_mut().await;
+    iface.apply_profile(menu_ref.signal_context()).await;
+    iface.tool_tip()
+  };
+  let item_ref = match server
+    .interface::<_, AppMenuStatusNotifierItem>(names.item_path.as_str())
+    .await
+  {
+    Ok(item_ref) => item_ref,
+    Err(err) => {
+      warn!("Failed to get tray item to update its tooltip: {}", err);
+      return;
+    },
+  };
+  item_ref.get_mut().await.tool_tip = tool_tip;
+  if let Err(err) = AppMenuStatusNotifierItem::new_tool_tip(item_ref.signal_context()).await {
+    warn!("Failed to signal tooltip update: {}", err);
+  }
+}
+
+/// Rescans all application directories and logs what changed, for the
+/// "Refresh" item and secondary activation.
+async fn refresh(connection: zbus::Connection) {
+  let iface_ref = match connection
+    .object_server()
+    .interface::<_, AppMenuDbusMenu>(util::init::get_object_names().menu_path.as_str())
+    .await
+  {
+    Ok(iface_ref) => iface_ref,
+    Err(err) => {
+      warn!("Failed to get menu interface to refresh: {}", err);
+      return;
+    },
+  };
+  info!("Refreshing application entries");
+  let mut iface = iface_ref.get_mut().await;
+  iface.rescan_all(iface_ref.signal_context()).await;
+}
+
+/// Writes the menu model as pretty JSON to the runtime directory, or to the
+/// log if that fails.
+async fn dump_state(connection: &zbus::Connection) {
+  let iface_ref = match connection
+    .object_server()
+    .interface::<_, AppMenuDbusMenu>(util::init::get_object_names().menu_path.as_str())
+    .await
+  {
+    Ok(iface_ref) => iface_ref,
+    Err(err) => {
+      warn!("Failed to get menu interface to dump state: {}", err);
+      return;
+    },
+  };
+  let state = iface_ref.get().await.dump();
+  let pretty = match serde_json::to_string_pretty(&state) {
+    Ok(pretty) => pretty,
+    Err(err) => {
+      warn!("Failed to serialize state dump: {}", err);
+      return;
+    },
+  };
+  if let Some(path) = util::init::get_dump_path() {
+    match std::fs::write(&path, &pretty) {
+      Ok(()) => {
+        info!("Dumped state to {:?}", path);
+        return;
+      },
+      Err(err) => warn!("Failed to write state dump to {:?}: {}", path, err),
+    }
+  }
+  info!("State dump:\n{}", pretty);
+}
+
+/// Saves the snapshot, takes the tray item off the bus so the watcher drops
+/// it, and exits.
+async fn shut_down(connection: zbus::Connection) {
+  let names = util::init::get_object_names();
+  let server = connection.object_server();
+  if let Ok(iface_ref) = server
+    .interface::<_, AppMenuDbusMenu>(names.menu_path.as_str())
+    .await
+  {
+    util::snapshot::stage(iface_ref.get().await.snapshot());
+  }
+  util::snapshot::save();
+  util::systemd::notify("STOPPING=1");
+  if let Err(err) = server
+    .remove::<AppMenuStatusNotifierItem, _>(names.item_path.as_str())
+    .await
+  {
+    debug!("No tray item to remove: {}", err);
+  }
+  if let Err(err) = connection.release_name(names.bus_name.as_str()).await {
+    warn!("Failed to release {}: {}", names.bus_name, err);
+  }
+  std::process::exit(0);
+}
+
+/// Adds a launch of `appid` to its score, then updates "Frequently used".
+/// Only listed desktop-file IDs are counted, not pipe menu items or the
+/// activation command.
+async fn count_launch(connection: &zbus::Connection, appid: &std::ffi::OsStr) {
+  if config::get().frequent_count == 0 {
+    return;
+  }
+  let iface_ref = match connection
+    .object_server()
+    .interface::<_, AppMenuDbusMenu>(util::init::get_object_names().menu_path.as_str())
+    .await
+  {
+    Ok(iface_ref) => iface_ref,
+    Err(err) => {
+      warn!("Failed to get menu interface to count a launch: {}", err);
+      return;
+    },
+  };
+  let mut iface = iface_ref.get_mut().await;
+  if !iface.cache.contains_key(appid) {
+    return;
+  }
+  util::frequency::record(&appid.to_string_lossy());
+  iface.refresh_frequent(iface_ref.signal_context()).await;
+}
+
+/// Tells the user a launcher failed to start through a desktop notification,
+/// unless disabled in the config.
+async fn notify_launch_failure(
+  connection: &zbus::Connection,
+  launcher: &desktop::Launcher,
+  error: &str,
+) {
+  if !config::get().notify_failures {
+    return;
+  }
+  let proxy = match proxy_types::NotificationsProxy::new(connection).await {
+    Ok(proxy) => proxy,
+    Err(err) => {
+      warn!("Failed to create notifications proxy: {}", err);
+      return;
+    },
+  };
+  let sent = proxy
+    .notify(
+      &config::get().title,
+      0,
+      launcher.icon.as_deref().unwrap_or_default(),
+      &format!("Failed to launch {}", launcher.name),
+      error,
+      &[],
+      HashMap::new(),
+      -1,
+    )
+    .await;
+  if let Err(err) = sent {
+    warn!(
+      "Failed to notify about {} not launching: {}",
+      launcher.name, err
+    );
+  }
+}
+
+/// Runs a launcher's command, checking network reachability and launch
+/// policy first.
+async fn launch_entry(
+  connection: zbus::Connection,
+  target_path: std::ffi::OsString,
+  launcher: desktop::Launcher,
+  reachability: Option<std::sync::Arc<tokio::sync::Mutex<util::network::ReachabilityCache>>>,
+) {
+  let exec = &launcher.exec;
+  if let (Some(reachability), Some(host)) = (reachability, util::network::url_host(exec)) {
+    if !reachability.lock().await.reachable(&host).await {
+      warn!(
+        "Not launching {:?}: {} is unreachable, network appears offline",
+        target_path, host
+      );
+      util::alerts::report(format!(
+        "{} is offline: {} is unreachable",
+        launcher.name, host
+      ));
+      let error = format!("{} is unreachable, the network appears offline", host);
+      notify_launch_failure(&connection, &launcher, &error).await;
+      return;
+    }
+  }
+  let (program, exec_args) = match exec.split_first() {
+    Some(split) => split,
+    None => {
+      warn!("Exec for {:?} is empty!", target_path);
+      util::alerts::report(format!("{} has no command to run", launcher.name));
+      return;
+    },
+  };
+  if !util::launch::permitted(
+    exec,
+    program,
+    &launcher.path,
+    util::init::get_launch_strictness(),
+  ) {
+    util::alerts::report(format!("Refused to launch {}", launcher.name));
+    return;
+  }
+  let mut cmd = if launcher.terminal {
+    match util::launch::terminal_prefix(&target_path.to_string_lossy()) {
+      Some(mut prefix) => {
+        let mut cmd = tokio::process::Command::new(prefix.remove(0));
+        cmd.args(prefix).arg(program);
+        cmd
+      },
+      None => {
+        error!(
+          "No terminal emulator found to run {:?}, set $TERMINAL",
+          target_path
+        );
+        util::alerts::report(format!(
+          "No terminal emulator found to run {}",
+          launcher.name
+        ));
+        let error = "No terminal emulator found, set $TERMINAL";
+        notify_launch_failure(&connection, &launcher, error).await;
+        return;
+      },
+    }
+  } else {
+    tokio::process::Command::new(program)
+  };
+  if let Some(dir) = &launcher.working_dir {
+    if dir.is_dir() {
+      cmd.current_dir(dir);
+    } else {
+      warn!(
+        "Working directory {:?} of {:?} does not exist, ignoring it",
+        dir, target_path
+      );
+    }
+  }
+  cmd.envs(launcher.env.iter().map(|(k, v)| (k, v)));
+  let spawn_result = util::sandbox::spawn(cmd.args(exec_args)).await;
+  match spawn_result {
+    Ok(_) => {
+      util::usage::record_launch();
+      count_launch(&connection, &target_path).await;
+    },
+    Err(err) => {
+      error!("Failed to exec {:?}: {}", target_path, err);
+      util::alerts::report(format!("Failed to launch {}: {}", launcher.name, err));
+      notify_launch_failure(&connection, &launcher, &err.to_string()).await;
+    },
+  }
+}
+
+fn launcher_updated(orig: &desktop::Launcher, new: &desktop::Launcher) -> bool {
+  orig.categories.first() != new.categories.first()
+    || orig.display != new.display
+    || orig.terminal != new.terminal
+    || orig.runnable != new.runnable
+    || orig.try_exec_missing != new.try_exec_missing
+    || orig.icon != new.icon
+    || orig.name != new.name
+    || orig.category_names != new.category_names
+    || orig.comment != new.comment
+    || orig.generic_name != new.generic_name
+    || orig.keywords != new.keywords
+}
+
+/// Finds the applications directory holding `p`, returning its priority and
+/// the entry's desktop-file ID.
+fn locate_entry(
+  app_dirs: &sources::SourceSet,
+  p: &std::path::Path,
+) -> Option<(usize, std::ffi::OsString)> {
+  let (prio_idx, dir) = app_dirs.locate(p)?;
+  Some((prio_idx, desktop::desktop_id(dir, p)?))
+}
+
+#[derive(Debug)]
+struct AppMenuDbusMenu {
+  revision: u32,
+  children: HashMap<i32, Vec<i32>>,
+  props: HashMap<i32, desktop::MenuProps>,
+  cache: HashMap<std::ffi::OsString, BTreeMap<usize, desktop::Launcher>>,
+  app_dirs: sources::SourceSet,
+  counter: LauncherCounter,
+  icon_retries: IconRetryQueue,
+  lazy_icons: LazyIcons,
+  /// Bumped whenever icons change without a new revision.
+  icon_updates: u32,
+  parse_failures: desktop::ParseFailures,
+  merge_threshold: usize,
+  reachability: Option<std::sync::Arc<tokio::sync::Mutex<util::network::ReachabilityCache>>>,
+  /// Behind a lock of its own so GetLayout can fill it without taking the
+  /// menu mutably.
+  warm_layouts: std::sync::Mutex<WarmLayouts>,
+  pipe_menus: HashMap<i32, config::PipeMenu>,
+  /// Items last printed by each pipe menu's script.
+  pipe_items: HashMap<i32, Vec<pipe_menu::PipeItem>>,
+  sections: sections::Sections,
+  /// When entries were first seen installed, as Unix time by desktop-file ID.
+  installed: HashMap<String, u64>,
+  /// When the menu was last opened with new entries in it, as Unix time.
+  seen_at: u64,
+  /// Launchers of the items currently listed in pipe menus.
+  transient: HashMap<i32, desktop::Launcher>,
+  next_transient: i32,
+}
+
+use zbus::DBusError;
+#[derive(DBusError, Debug)]
+#[dbus_error(prefix = "org.wsl.AppMenuDbusMenu")]
+enum MenuError {
+  #[dbus_error(zbus_error)]
+  ZBus(zbus::Error),
+  LauncherIndexNotFound,
+  PropertyNotFound,
+}
+
+#[dbus_interface(name = "com.canonical.dbusmenu")]
+impl AppMenuDbusMenu {
+  /// AboutToShow method
+  async fn about_to_show(
+    &mut self,
+    id: i32,
+    #[zbus(signal_context)] ctxt: SignalContext<'_>,
+  ) -> bool {
+    if id == 0 {
+      tokio::spawn(menu_opened(ctxt.connection().clone()));
+    }
+    self.render_icons(layout_ids(id, 1, &self.children), &ctxt);
+    if let Some(menu) = self.pipe_menus.get(&id) {
+      tokio::spawn(refresh_pipe_menu(
+        ctxt.connection().clone(),
+        id,
+        menu.clone(),
+      ));
+    }
+    false
+  }
+
+  /// AboutToShowGroup method
+  async fn about_to_show_group(
+    &mut self,
+    ids: Vec<i32>,
+    #[zbus(signal_context)] ctxt: SignalContext<'_>,
+  ) -> (Vec<i32>, Vec<i32>) {
+    let mut id_errors = Vec::new();
+    for id in ids {
+      if !self.props.contains_key(&id) {
+        id_errors.push(id);
+        continue;
+      }
+      self.render_icons(layout_ids(id, 1, &self.children), &ctxt);
+      if let Some(menu) = self.pipe_menus.get(&id) {
+        tokio::spawn(refresh_pipe_menu(
+          ctxt.connection().clone(),
+          id,
+          menu.clone(),
+        ));
+      }
+    }
+    (vec![], id_errors)
+  }
+
+  /// Event method
+  async fn event(
+    &self,
+    item_id: i32,
+    event_id: &str,
+    _data: zbus::zvariant::Value<'_>,
+    timestamp: u32,
+    #[zbus(signal_context)] ctxt: SignalContext<'_>,
+  ) {
+    match event_id {
+      "clicked" => {
+        let sig_res = AppMenuDbusMenu::item_activation_requested(&ctxt, &item_id, &timestamp).await;
+        if let Err(err) = sig_res {
+          warn!("Failed to signal activation for {}: {}", item_id, err);
+        }
+        if let Some(launcher) = self.transient.get(&item_id) {
+          tokio::spawn(launch_entry(
+            ctxt.connection().clone(),
+            launcher.name.clone().into(),
+            launcher.clone(),
+            self.reachability.clone(),
+          ));
+          return;
+        }
+        match item_id {
+          constants::REFRESH_ITEM_IDX => {
+            tokio::spawn(refresh(ctxt.connection().clone()));
+            return;
+          },
+          constants::QUIT_ITEM_IDX => {
+            info!("Quitting");
+            tokio::spawn(shut_down(ctxt.connection().clone()));
+            return;
+          },
+          _ => {},
+        }
+        if let Some((key, on)) = display_option(item_id) {
+          tokio::spawn(toggle_option(ctxt.connection().clone(), key, !on));
+          return;
+        }
+        if let Some(dir) = self.source_dir(item_id) {
+          tokio::spawn(toggle_source(ctxt.connection().clone(), dir.clone()));
+          return;
+        }
+        let launcher_idx = constants::launcher_idx(item_id);
+        if let Some(target_path) = self.counter.get_path(&(launcher_idx as usize)) {
+          // Items restored from the snapshot have no launcher until the first scan is done.
+          let target_entry = match self
+            .cache
+            .get(target_path)
+            .and_then(|prio_cache| prio_cache.values().next())
+          {
+            Some(launcher) => launcher,
+            None => {
+              warn!("{:?} is not loaded yet, ignoring activation", target_path);
+              return;
+            },
+          };
+          // Launch outside the interface lock so lookups and spawns don't stall the menu.
+          tokio::spawn(launch_entry(
+            ctxt.connection().clone(),
+            target_path.clone(),
+            target_entry.clone(),
+            self.reachability.clone(),
+          ));
+          return;
+        }
+        warn!("Got activation request for nonexistent entry: {}", item_id);
+      },
+      "hovered" => trace!("Ignoring hover"),
+      "opened" if item_id == 0 => {
+        tokio::spawn(menu_opened(ctxt.connection().clone()));
+      },
+      "opened" => trace!("Ignoring open"),
+      "closed" => trace!("Ignoring close"),
+      _ => info!("Ignoring unknown event: {}", event_id),
+    }
+  }
+
+  /// GetGroupProperties method
+  async fn get_group_properties(
+    &self,
+    item_ids: Vec<i32>,
+    property_names: Vec<&str>,
+  ) -> Vec<(
+    i32,
+    std::collections::HashMap<String, zbus::zvariant::OwnedValue>,
+  )> {
+    let mut out = Vec::new();
+    for i in item_ids.iter() {
+      if self.props.contains_key(i) {
+        out.push((*i, layout_props(*i, &self.props, &property_names)));
+      }
+    }
+    out
+  }
+
+  /// GetLayout method
+  async fn get_layout(
+    &self,
+    parent_id: i32,
+    recursion_depth: i32,
+    property_names: Vec<&str>,
+    #[zbus(signal_context)] ctxt: SignalContext<'_>,
+  ) -> Result<(u32, DbusMenuLayoutEntry), MenuError> {
+    if !self.props.contains_key(&parent_id) {
+      return Err(MenuError::LauncherIndexNotFound);
+    }
+    // Icons are rendered for the level about to be shown, not for whole
+    // trees fetched up front.
+    let shown = if recursion_depth < 0 {
+      1
+    } else {
+      recursion_depth.min(1)
+    };
+    let ids = layout_ids(parent_id, shown, &self.children);
+    if self.lazy_icons.any_pending(&ids) {
+      tokio::spawn(render_layout_icons(ctxt.connection().clone(), ids));
+    }
+    let key = layout_key(parent_id, recursion_depth, &property_names);
+    let mut warm_layouts = self.warm_layouts.lock().unwrap();
+    let layouts = warm_layouts.at(self.revision);
+    if let Some(layout) = layouts.get(&key) {
+      return Ok((self.revision, layout.clone()));
+    }
+    let layout = get_layout(
+      parent_id,
+      &self.children,
+      &self.props,
+      &property_names,
+      recursion_depth,
+    );
+    if layouts.len() >= constants::MAX_CACHED_LAYOUTS {
+      layouts.clear();
+    }
+    layouts.insert(key, layout.clone());
+    Ok((self.revision, layout))
+  }
+
+  /// GetProperty method
+  async fn get_property(
+    &self,
     item_id: i32,
     name: &str,
   ) -> Result<zbus::zvariant::OwnedValue, MenuError> {
-    if let Some(item_props) = self.props.get(&item_id) {
-      match name {
-        "type" => Ok(zbus::zvariant::OwnedValue::from(
-          zbus::zvariant::Value::new("standard"),
-        )),
-        "label" => Ok(zbus::zvariant::OwnedValue::from(
-          zbus::zvariant::Value::new(&item_props.label),
-        )),
-        "enabled" => Ok(zbus::zvariant::OwnedValue::from(
-          zbus::zvariant::Value::new(item_props.enabled),
-        )),
-        "visible" => Ok(zbus::zvariant::OwnedValue::from(
-          zbus::zvariant::Value::new(item_props.visible),
-        )),
-        "icon-name" => Ok(zbus::zvariant::OwnedValue::from(
-          zbus::zvariant::Value::new(&item_props.icon_name),
-        )),
-        "icon-data" => Ok(zbus::zvariant::OwnedValue::from(
-          zbus::zvariant::Value::new(&item_props.icon_data),
-        )),
-        "shortcut" => Ok(zbus::zvariant::OwnedValue::from(
-          zbus::zvariant::Value::new(Vec::<String>::new()),
-        )),
-        "toggle-type" => Ok(zbus::zvariant::OwnedValue::from(
-          zbus::zvariant::Value::new(String::new()),
-        )),
-        "toggle-state" => Ok(zbus::zvariant::OwnedValue::from(
-          zbus::zvariant::Value::new(-1i32),
-        )),
-        "children-display" => Ok(zbus::zvariant::OwnedValue::from(
-          zbus::zvariant::Value::new(&item_props.children_display),
-        )),
-        _ => Err(MenuError::PropertyNotFound),
+    let item_props = self
+      .props
+      .get(&item_id)
+      .ok_or(MenuError::LauncherIndexNotFound)?;
+    match name {
+      "shortcut" => Ok(zbus::zvariant::OwnedValue::from(
+        zbus::zvariant::Value::new(Vec::<String>::new()),
+      )),
+      _ => item_props
+        .value_of(name)
+        .map(zbus::zvariant::OwnedValue::from)
+        .ok_or(MenuError::PropertyNotFound),
+    }
+  }
+
+  /// AddLauncherPath method
+  async fn add_launcher_path(
+    &mut self,
+    path: &str,
+    #[zbus(signal_context)] ctxt: SignalContext<'_>,
+  ) {
+    let locale = sys_locale::get_locale().unwrap_or_else(|| String::from("en-US"));
+    let p = std::path::PathBuf::from(path);
+    if p.is_file() {
+      if let Some(launcher) =
+        desktop::launcher_for_entry(p.clone(), &locale, &mut self.parse_failures)
+      {
+        let (prio_idx, cache_name) = match locate_entry(&self.app_dirs, &p) {
+          Some(located) => located,
+          None => {
+            warn!("Failed to find applications directory for {}", path);
+            return;
+          },
+        };
+        if desktop::is_hidden_id(&cache_name) {
+          debug!("Ignoring hidden entry {}", path);
+          return;
+        }
+        if self.in_disabled_dir(&p) {
+          debug!("Ignoring entry {} from disabled directory", path);
+          return;
+        }
+        let launcher = match desktop::filter_launcher(&cache_name, launcher) {
+          Some(launcher) => launcher,
+          None => {
+            debug!("Ignoring filtered entry {}", path);
+            // An edit can make a listed entry fail the filters.
+            let listed = self
+              .cache
+              .get(&cache_name)
+              .and_then(|prio_cache| prio_cache.get(&prio_idx))
+              .is_some_and(|launcher| !launcher.tombstone);
+            if listed {
+              self.remove_launcher_path(path, ctxt).await;
+            }
+            return;
+          },
+        };
+        self
+          .insert_launcher(cache_name, prio_idx, launcher, &ctxt)
+          .await;
+      } else {
+        debug!("No launcher produced for {}", path);
+      }
+    }
+  }
+
+  /// AddLauncherPath method
+  async fn remove_launcher_path(
+    &mut self,
+    path: &str,
+    #[zbus(signal_context)] ctxt: SignalContext<'_>,
+  ) {
+    let p = std::path::PathBuf::from(path);
+    let (prio_idx, cache_name) = match locate_entry(&self.app_dirs, &p) {
+      Some(located) => located,
+      None => {
+        warn!("Failed to find applications directory for {}", path);
+        return;
+      },
+    };
+    let menu_idx = self.counter.get_index(&cache_name);
+    let prio_cache = self.cache.entry(cache_name.clone()).or_default();
+
+    let entry = prio_cache.get(&prio_idx);
+    if entry.is_none() {
+      info!("Entry not found: {}", path);
+      return;
+    }
+    let entry = entry.unwrap().clone();
+    let was_active = prio_cache.keys().next() == Some(&prio_idx);
+    prio_cache.remove(&prio_idx);
+    if prio_cache.is_empty() {
+      prio_cache.insert(
+        prio_idx,
+        desktop::tombstone_launcher(p, (*cache_name.to_string_lossy()).to_string(), true),
+      );
+    }
+
+    // Removing a shadowed copy leaves the visible entry untouched.
+    if !was_active {
+      return;
+    }
+
+    let shape = MenuShape::of(&self.children, &self.props);
+    let old_section = self.sections.section_of(&cache_name, &entry);
+    self.sections.remove_member(old_section, menu_idx as i32);
+
+    let r_entry = prio_cache.iter().next().unwrap();
+    let mut remain = desktop::launcher_props(&cache_name, r_entry.1);
+    let icon = desktop::launcher_icon(&cache_name, r_entry.1);
+    let deferred = self
+      .lazy_icons
+      .fill(menu_idx as i32, icon.as_deref(), &mut remain);
+    let desktop_id = cache_name.to_string_lossy().to_string();
+    let changes = match (entry.tombstone, r_entry.1.tombstone) {
+      (false, true) => control::MenuChanges {
+        removed: vec![desktop_id],
+        ..Default::default()
+      },
+      (true, false) => control::MenuChanges {
+        added: vec![desktop_id],
+        ..Default::default()
+      },
+      (false, false) => control::MenuChanges {
+        updated: vec![desktop_id],
+        ..Default::default()
+      },
+      (true, true) => control::MenuChanges::default(),
+    };
+    let props = remain
+      .to_dict(|k| property_requested(k, &[]))
+      .into_iter()
+      .map(|(k, v)| (k, v.into()))
+      .collect();
+    if !deferred {
+      self
+        .icon_retries
+        .track(menu_idx as i32, icon.as_deref(), &remain);
+    }
+    self.props.insert(menu_idx as i32, remain);
+
+    let c = self.sections.section_of(&cache_name, r_entry.1);
+    self.sections.add_member(c, menu_idx as i32, &self.props);
+
+    self.revision += 1;
+
+    update_category_props(
+      &mut self.children,
+      &mut self.props,
+      &mut self.sections,
+      self.merge_threshold,
+      Some(&[old_section, c]),
+    );
+    update_copies(
+      &mut self.children,
+      &mut self.props,
+      &self.counter,
+      &self.installed,
+    );
+
+    let updated = with_copies(&mut self.props, vec![(menu_idx as i32, props)]);
+    let sig_res = AppMenuDbusMenu::items_properties_updated(&ctxt, &updated, &vec![]).await;
+    if let Err(err) = sig_res {
+      warn!("Failed to signal property updates for {}: {}", path, err);
+    }
+
+    let parent = shape.changed_parent(&self.children, &self.props, menu_idx as i32);
+    let sig_res = AppMenuDbusMenu::layout_updated(&ctxt, &self.revision, &parent).await;
+    if let Err(err) = sig_res {
+      warn!("Failed to signal layout updates for {}: {}", path, err);
+    }
+
+    control::notify_menu_changed(ctxt.connection(), &changes).await;
+  }
+
+  /// ItemActivationRequested signal
+  #[dbus_interface(signal)]
+  async fn item_activation_requested(
+    ctxt: &SignalContext<'_>,
+    iid: &i32,
+    timestamp: &u32,
+  ) -> zbus::Result<()>;
+
+  /// ItemsPropertiesUpdated signal
+  #[dbus_interface(signal)]
+  async fn items_properties_updated(
+    ctxt: &SignalContext<'_>,
+    updated: &Vec<(
+      i32,
+      std::collections::HashMap<String, zbus::zvariant::Value<'_>>,
+    )>,
+    removed: &Vec<(i32, Vec<String>)>,
+  ) -> zbus::Result<()>;
+
+  /// LayoutUpdated signal
+  #[dbus_interface(signal)]
+  async fn layout_updated(
+    ctxt: &SignalContext<'_>,
+    revision: &u32,
+    parent: &i32,
+  ) -> zbus::Result<()>;
+
+  /// IconThemePath property
+  #[dbus_interface(property)]
+  async fn icon_theme_path(&self) -> Vec<String> {
+    util::init::get_icon_theme_dir()
+      .map(|dir| dir.to_string_lossy().to_string())
+      .into_iter()
+      .collect()
+  }
+
+  /// Status property
+  #[dbus_interface(property)]
+  async fn status(&self) -> &str {
+    "normal"
+  }
+
+  /// TextDirection property
+  #[dbus_interface(property)]
+  async fn text_direction(&self) -> &str {
+    "ltr"
+  }
+
+  /// Version property
+  #[dbus_interface(property)]
+  async fn version(&self) -> u32 {
+    3
+  }
+}
+
+impl AppMenuDbusMenu {
+  /// Adds or replaces the launcher for a desktop-file ID at the given
+  /// priority, updating the menu if it becomes the active entry.
+  async fn insert_launcher(
+    &mut self,
+    cache_name: std::ffi::OsString,
+    prio_idx: usize,
+    launcher: desktop::Launcher,
+    ctxt: &SignalContext<'_>,
+  ) {
+    let desktop_id = cache_name.to_string_lossy().to_string();
+    let menu_idx = self.counter.get_index(&cache_name);
+    let prio_cache = self.cache.entry(cache_name.clone()).or_default();
+
+    let existing_launcher = prio_cache.iter().next();
+    let mut changes = control::MenuChanges::default();
+
+    // A removed file's tombstone only holds the ID's place, so copies found
+    // later in any directory replace it.
+    if existing_launcher.is_none()
+      || ((*existing_launcher.unwrap().0 >= prio_idx || existing_launcher.unwrap().1.deleted)
+        && launcher_updated(&launcher, existing_launcher.unwrap().1))
+    {
+      match existing_launcher {
+        Some((_, existing)) if !existing.tombstone => {
+          if launcher.tombstone {
+            changes.removed.push(desktop_id)
+          } else {
+            changes.updated.push(desktop_id)
+          }
+        },
+        _ if !launcher.tombstone => changes.added.push(desktop_id),
+        _ => {},
+      }
+      let shape = MenuShape::of(&self.children, &self.props);
+      let old_section =
+        existing_launcher.map(|(_, existing)| self.sections.section_of(&cache_name, existing));
+      if let Some(old_section) = old_section {
+        self.sections.remove_member(old_section, menu_idx as i32);
+      }
+      let mut entry_props = desktop::launcher_props(&cache_name, &launcher);
+      let icon = desktop::launcher_icon(&cache_name, &launcher);
+      let deferred = self
+        .lazy_icons
+        .fill(menu_idx as i32, icon.as_deref(), &mut entry_props);
+      let props = entry_props
+        .to_dict(|k| property_requested(k, &[]))
+        .into_iter()
+        .map(|(k, v)| (k, v.into()))
+        .collect();
+      if !deferred {
+        self
+          .icon_retries
+          .track(menu_idx as i32, icon.as_deref(), &entry_props);
+      }
+      self.props.insert(menu_idx as i32, entry_props);
+
+      let c = self.sections.section_of(&cache_name, &launcher);
+      self.sections.add_member(c, menu_idx as i32, &self.props);
+      let affected: Vec<i32> = old_section.into_iter().chain([c]).collect();
+
+      self.revision += 1;
+
+      update_category_props(
+        &mut self.children,
+        &mut self.props,
+        &mut self.sections,
+        self.merge_threshold,
+        Some(&affected),
+      );
+      update_copies(
+        &mut self.children,
+        &mut self.props,
+        &self.counter,
+        &self.installed,
+      );
+
+      let updated = with_copies(&mut self.props, vec![(menu_idx as i32, props)]);
+      let sig_res = AppMenuDbusMenu::items_properties_updated(ctxt, &updated, &vec![]).await;
+      if let Err(err) = sig_res {
+        warn!(
+          "Failed to signal property updates for {:?}: {}",
+          cache_name, err
+        );
+      }
+
+      let parent = shape.changed_parent(&self.children, &self.props, menu_idx as i32);
+      let sig_res = AppMenuDbusMenu::layout_updated(ctxt, &self.revision, &parent).await;
+      if let Err(err) = sig_res {
+        warn!(
+          "Failed to signal layout updates for {:?}: {}",
+          cache_name, err
+        );
       }
-    } else {
-      Err(MenuError::LauncherIndexNotFound)
     }
+
+    if !launcher.deleted {
+      prio_cache.retain(|_, existing| !existing.deleted);
+    }
+    prio_cache.insert(prio_idx, launcher);
+    control::notify_menu_changed(ctxt.connection(), &changes).await;
   }
 
-  /// AddLauncherPath method
-  async fn add_launcher_path(
+  /// Starts rendering the icon files left for the launchers behind `ids`.
+  fn render_icons(&mut self, ids: Vec<i32>, ctxt: &SignalContext<'_>) {
+    let icons = self.lazy_icons.take(ids);
+    if !icons.is_empty() {
+      debug!("Rendering {} deferred icons", icons.len());
+      tokio::spawn(render_icons(ctxt.connection().clone(), icons));
+    }
+  }
+
+  /// Shows icons rendered by `render_icons` on their launchers and copies of
+  /// them, unless the launcher's icon changed in the meantime.
+  async fn show_icons(
     &mut self,
-    path: &str,
-    #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    rendered: Vec<(i32, String, (String, desktop::IconData))>,
+    ctxt: &SignalContext<'_>,
   ) {
-    let locale = sys_locale::get_locale().unwrap_or_else(|| String::from("en-US"));
-    let p = std::path::PathBuf::from(path);
-    if p.is_file() {
-      if let Some(launcher) = desktop::launcher_for_entry(p.clone(), &locale) {
-        let cache_name = p.file_stem().unwrap_or_default().to_os_string();
-        let menu_idx = self.counter.get_index(&cache_name);
-        let prio_cache = self.cache.entry(cache_name).or_default();
-
-        let source = p.parent();
-        if source.is_none() {
-          warn!("Launcher has no parent: {}", path);
-          return;
+    let mut updates = Vec::new();
+    for (menu_idx, icon, (icon_name, icon_data)) in rendered {
+      let current = self
+        .counter
+        .get_path(&(menu_idx as usize))
+        .and_then(|name| desktop::launcher_icon(name, self.cache.get(name)?.values().next()?));
+      if current.as_deref() != Some(icon.as_str()) {
+        continue;
+      }
+      let copies = [
+        constants::CopyList::Pinned,
+        constants::CopyList::Recent,
+        constants::CopyList::Frequent,
+      ]
+      .map(|list| constants::copy_idx(list, menu_idx));
+      for id in std::iter::once(menu_idx).chain(copies) {
+        let props = match self.props.get_mut(&id) {
+          Some(props) => props,
+          None => continue,
+        };
+        props.icon_name = icon_name.clone();
+        props.icon_data = icon_data.clone();
+        if id == menu_idx {
+          self.icon_retries.track(menu_idx, Some(&icon), props);
         }
-        let source = source.unwrap();
+        updates.push((
+          id,
+          HashMap::from([
+            (
+              "icon-name".to_string(),
+              zbus::zvariant::Value::new(props.icon_name.clone()),
+            ),
+            (
+              "icon-data".to_string(),
+              zbus::zvariant::Value::new(props.icon_data.clone()),
+            ),
+          ]),
+        ));
+      }
+    }
+    if updates.is_empty() {
+      return;
+    }
+    // Properties changed without a new revision, so drop prefetched layouts.
+    self.warm_layouts.get_mut().unwrap().invalidate();
+    self.icon_updates = self.icon_updates.wrapping_add(1);
+    let sig_res = AppMenuDbusMenu::items_properties_updated(ctxt, &updates, &vec![]).await;
+    if let Err(err) = sig_res {
+      warn!("Failed to signal rendered icons: {}", err);
+    }
+  }
 
-        let prio_idx = self.path_map.get_by_right(&source.to_path_buf());
-        if prio_idx.is_none() {
-          warn!("Failed to find priority map entry for {:?}", source);
-          return;
+  /// Re-renders icons whose retry backoff has elapsed, publishing the ones that succeed.
+  async fn retry_icons(&mut self, ctxt: &SignalContext<'_>) {
+    for menu_idx in self.icon_retries.due() {
+      let icon = self
+        .counter
+        .get_path(&(menu_idx as usize))
+        .and_then(|name| {
+          let launcher = self.cache.get(name)?.values().next()?;
+          desktop::launcher_icon(name, launcher)
+        });
+      let icon = match icon {
+        Some(icon) => icon,
+        None => {
+          self.icon_retries.forget(menu_idx);
+          continue;
+        },
+      };
+      let props = match self.props.get_mut(&menu_idx) {
+        Some(props) => props,
+        None => {
+          self.icon_retries.forget(menu_idx);
+          continue;
+        },
+      };
+      if !desktop::set_file_icon(props, &icon) {
+        self.icon_retries.failed(menu_idx, &icon);
+        continue;
+      }
+      info!("Rendered icon {:?} after retry", icon);
+      self.icon_retries.forget(menu_idx);
+      // Properties changed without a new revision, so drop prefetched layouts.
+      self.warm_layouts.get_mut().unwrap().invalidate();
+      self.icon_updates = self.icon_updates.wrapping_add(1);
+      let props = HashMap::from([
+        (
+          "icon-name".to_string(),
+          zbus::zvariant::Value::new(props.icon_name.clone()),
+        ),
+        (
+          "icon-data".to_string(),
+          zbus::zvariant::Value::new(props.icon_data.clone()),
+        ),
+      ]);
+      let updated = with_copies(&mut self.props, vec![(menu_idx, props)]);
+      let sig_res = AppMenuDbusMenu::items_properties_updated(ctxt, &updated, &vec![]).await;
+      if let Err(err) = sig_res {
+        warn!("Failed to signal icon update for {:?}: {}", icon, err);
+      }
+    }
+  }
+}
+
+impl AppMenuDbusMenu {
+  /// File backing the highest-priority entry for a desktop-file ID, with or
+  /// without its `.desktop` suffix.
+  fn active_entry_path(&self, appid: &str) -> Option<std::path::PathBuf> {
+    self
+      .active_entry(appid)
+      .map(|(_, launcher)| launcher.path.clone())
+  }
+
+  /// Cache key and winning launcher of a desktop-file ID, unless it was removed.
+  fn active_entry(&self, appid: &str) -> Option<(&std::ffi::OsString, &desktop::Launcher)> {
+    let (name, prio_cache) = self
+      .cache
+      .get_key_value(std::ffi::OsStr::new(appid))
+      .or_else(|| {
+        self
+          .cache
+          .get_key_value(std::ffi::OsStr::new(&format!("{}.desktop", appid)))
+      })?;
+    prio_cache
+      .values()
+      .next()
+      .filter(|launcher| !launcher.tombstone)
+      .map(|launcher| (name, launcher))
+  }
+
+  /// Listed launchers whose name, generic name or keywords fuzzily match `text`, as
+  /// menu item IDs and names, best match first.
+  fn query(&self, text: &str) -> Vec<(i32, String)> {
+    if text.trim().is_empty() {
+      return vec![];
+    }
+    let mut matches: Vec<(i64, i32, String)> = self
+      .cache
+      .iter()
+      .filter_map(|(name, prio_cache)| {
+        let launcher = prio_cache
+          .values()
+          .next()
+          .filter(|launcher| !launcher.tombstone && desktop::launcher_visible(launcher))?;
+        let label = desktop::full_name(name, launcher);
+        let score = std::iter::once(label.as_str())
+          .chain(launcher.generic_name.as_deref())
+          .chain(launcher.keywords.iter().map(String::as_str))
+          .filter_map(|field| apps::fuzzy_score(text, field))
+          .max()?;
+        let idx = self.counter.index_of(&name.to_string_lossy())?;
+        Some((score, idx as i32, label))
+      })
+      .collect();
+    matches.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.2.cmp(&b.2)));
+    matches
+      .into_iter()
+      .map(|(_, idx, label)| (idx, label))
+      .collect()
+  }
+
+  /// Launches the first configured favorite with an active entry, returning
+  /// whether there was one.
+  fn launch_favorite(&self, connection: &zbus::Connection) -> bool {
+    config::get()
+      .favorites
+      .iter()
+      .any(|appid| self.launch_app(connection, appid, &[]))
+  }
+
+  /// Lays out the pinned favorites, recently added and frequently used
+  /// entries again.
+  async fn refresh_copies(&mut self, ctxt: &SignalContext<'_>) {
+    self.revision += 1;
+    update_copies(
+      &mut self.children,
+      &mut self.props,
+      &self.counter,
+      &self.installed,
+    );
+    let sig_res = AppMenuDbusMenu::layout_updated(ctxt, &self.revision, &0).await;
+    if let Err(err) = sig_res {
+      warn!(
+        "Failed to signal layout update for launcher copies: {}",
+        err
+      );
+    }
+  }
+
+  /// Launches a desktop-file ID with `uris`, returning whether it has an
+  /// active entry the menu lists, as `query` only offers those.
+  fn launch_app(&self, connection: &zbus::Connection, appid: &str, uris: &[String]) -> bool {
+    let listed = self
+      .active_entry(appid)
+      .filter(|(_, launcher)| desktop::launcher_visible(launcher));
+    match listed {
+      Some((target_path, launcher)) => {
+        for launcher in desktop::with_uris(launcher, uris) {
+          tokio::spawn(launch_entry(
+            connection.clone(),
+            target_path.clone(),
+            launcher,
+            self.reachability.clone(),
+          ));
         }
-        let prio_idx = prio_idx.unwrap();
+        true
+      },
+      None => false,
+    }
+  }
 
-        let existing_launcher = prio_cache.iter().next();
+  /// The whole menu model as JSON, for diagnosing missing or duplicate
+  /// entries. Icon pixmaps are reduced to their size.
+  fn dump(&self) -> serde_json::Value {
+    use serde_json::json;
+    let sorted = |ids: Vec<&i32>| {
+      let mut ids: Vec<i32> = ids.into_iter().copied().collect();
+      ids.sort();
+      ids
+    };
+    let children: serde_json::Map<String, serde_json::Value> =
+      sorted(self.children.keys().collect())
+        .into_iter()
+        .map(|id| (id.to_string(), json!(self.children[&id])))
+        .collect();
+    let props: serde_json::Map<String, serde_json::Value> = sorted(self.props.keys().collect())
+      .into_iter()
+      .map(|id| {
+        let p = &self.props[&id];
+        let entry = json!({
+          "label": p.label,
+          "visible": p.visible,
+          "enabled": p.enabled,
+          "type": p.entry_type,
+          "children-display": p.children_display,
+          "icon-name": p.icon_name,
+          "icon-data-bytes": p.icon_data.len(),
+          "accessible-desc": p.accessible_desc,
+          "toggle-type": p.toggle_type,
+          "toggle-state": p.toggle_state,
+        });
+        (id.to_string(), entry)
+      })
+      .collect();
+    let cache: std::collections::BTreeMap<String, serde_json::Value> = self
+      .cache
+      .iter()
+      .map(|(name, prio_cache)| {
+        let entries: Vec<serde_json::Value> = prio_cache
+          .iter()
+          .map(|(prio, launcher)| {
+            json!({
+              "priority": prio,
+              "path": launcher.path,
+              "name": launcher.name,
+              "display": launcher.display,
+              "tombstone": launcher.tombstone,
+              "try-exec-missing": launcher.try_exec_missing,
+              "runnable": launcher.runnable,
+            })
+          })
+          .collect();
+        (name.to_string_lossy().to_string(), json!(entries))
+      })
+      .collect();
+    let app_dirs: Vec<serde_json::Value> = self
+      .app_dirs
+      .iter()
+      .map(|(prio, dir)| {
+        json!({
+          "priority": prio,
+          "path": dir,
+          "disabled": util::init::is_dir_disabled(dir),
+        })
+      })
+      .collect();
+    let counter: std::collections::BTreeMap<String, usize> = self
+      .counter
+      .map
+      .iter()
+      .map(|(name, idx)| (name.to_string_lossy().to_string(), *idx))
+      .collect();
+    json!({
+      "revision": self.revision,
+      "children": children,
+      "props": props,
+      "cache": cache,
+      "app-dirs": app_dirs,
+      "counter": counter,
+      "next-launcher-id": self.counter.count,
+      "transient": sorted(self.transient.keys().collect()),
+    })
+  }
 
-        if existing_launcher.is_none()
-          || (existing_launcher.clone().unwrap().0 >= prio_idx
-            && launcher_updated(&launcher, existing_launcher.clone().unwrap().1))
-        {
-          if let Some(existing_launcher) = existing_launcher {
-            let c = existing_launcher
-              .1
-              .categories
-              .iter()
-              .next()
-              .unwrap_or(&constants::Category::Uncategorized);
-            self
-              .children
-              .get_mut(&(constants::category_idx(*c) as i32))
-              .log_expect("Failed to get category reference")
-              .retain(|i| *i != menu_idx as i32);
+  /// Entry counts, the last scan, parse errors and icons waiting to be
+  /// rendered again, for `GetStats`.
+  fn stats(&self) -> HashMap<String, zbus::zvariant::OwnedValue> {
+    use zbus::zvariant::Value;
+    let launchers: Vec<&desktop::Launcher> = self
+      .cache
+      .values()
+      .filter_map(|prio_cache| prio_cache.values().next())
+      .filter(|launcher| !launcher.tombstone)
+      .collect();
+    let visible = launchers
+      .iter()
+      .filter(|launcher| desktop::launcher_visible(launcher))
+      .count();
+    let files: usize = self.cache.values().map(|prio_cache| prio_cache.len()).sum();
+    let (last_scan_at, last_scan) = util::usage::last_scan();
+    [
+      ("entries", Value::from(launchers.len() as u32)),
+      ("visible-entries", Value::from(visible as u32)),
+      ("files", Value::from(files as u32)),
+      ("app-dirs", Value::from(self.app_dirs.iter().count() as u32)),
+      ("last-scan", Value::from(last_scan_at)),
+      ("last-scan-ms", Value::from(last_scan.as_millis() as u64)),
+      (
+        "parse-errors",
+        Value::from(self.parse_failures.count() as u32),
+      ),
+      (
+        "icon-retries-pending",
+        Value::from(self.icon_retries.pending.len() as u32),
+      ),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_string(), value.into()))
+    .collect()
+  }
+
+  /// Every active entry as a dictionary of its desktop-file ID, menu item ID
+  /// and parsed keys, sorted by ID.
+  fn list_apps(&self) -> Vec<HashMap<String, zbus::zvariant::OwnedValue>> {
+    use zbus::zvariant::{OwnedValue, Value};
+    let mut names: Vec<&std::ffi::OsString> = self.cache.keys().collect();
+    names.sort();
+    names
+      .into_iter()
+      .filter_map(|name| {
+        let launcher = self.cache[name]
+          .values()
+          .next()
+          .filter(|launcher| !launcher.tombstone)?;
+        let idx = self.counter.index_of(&name.to_string_lossy())?;
+        let mut app: HashMap<String, OwnedValue> = HashMap::from([
+          (
+            "id".to_string(),
+            Value::from(name.to_string_lossy().to_string()).into(),
+          ),
+          ("item".to_string(), Value::from(idx as i32).into()),
+          (
+            "name".to_string(),
+            Value::from(desktop::full_name(name, launcher)).into(),
+          ),
+          (
+            "categories".to_string(),
+            Value::from(launcher.category_names.clone()).into(),
+          ),
+          (
+            "keywords".to_string(),
+            Value::from(launcher.keywords.clone()).into(),
+          ),
+          (
+            "terminal".to_string(),
+            Value::from(launcher.terminal).into(),
+          ),
+          (
+            "visible".to_string(),
+            Value::from(desktop::launcher_visible(launcher)).into(),
+          ),
+          (
+            "path".to_string(),
+            Value::from(launcher.path.to_string_lossy().to_string()).into(),
+          ),
+        ]);
+        let optional = [
+          ("generic-name", launcher.generic_name.clone()),
+          ("comment", launcher.comment.clone()),
+          ("icon", desktop::launcher_icon(name, launcher)),
+        ];
+        for (key, value) in optional {
+          if let Some(value) = value {
+            app.insert(key.to_string(), Value::from(value).into());
           }
-          let entry_props = desktop::launcher_props(&launcher);
-          let enc_ctxt = zbus::zvariant::EncodingContext::<byteorder::LE>::new_dbus(0);
-          let encoded = zbus::zvariant::to_bytes(enc_ctxt, &entry_props)
-            .log_expect("Failed to encode properties");
-          let mut props: std::collections::HashMap<String, zbus::zvariant::OwnedValue> =
-            zbus::zvariant::from_slice(&encoded, enc_ctxt)
-              .log_expect("Failed to decode properties");
-          let props = props.drain().map(|(k, v)| (k, v.into())).collect();
-          self.props.insert(menu_idx as i32, entry_props);
-
-          let c = launcher
-            .categories
-            .iter()
-            .next()
-            .unwrap_or(&constants::Category::Uncategorized);
-          self
-            .children
-            .get_mut(&(constants::category_idx(*c) as i32))
-            .log_expect("Failed to get category reference")
-            .push(menu_idx as i32);
+        }
+        Some(app)
+      })
+      .collect()
+  }
+
+  /// Lists `items` in pipe menu `id` unless its script printed the same
+  /// items last time, signalling the layout change.
+  async fn set_pipe_items(
+    &mut self,
+    id: i32,
+    items: Vec<pipe_menu::PipeItem>,
+    ctxt: &SignalContext<'_>,
+  ) {
+    let label = match self.pipe_menus.get(&id) {
+      Some(menu) => menu.label.clone(),
+      None => return,
+    };
+    if self.pipe_items.get(&id) == Some(&items) {
+      return;
+    }
+    for old in self.children.remove(&id).unwrap_or_default() {
+      self.props.remove(&old);
+      self.transient.remove(&old);
+    }
+    let mut item_ids = Vec::new();
+    for item in &items {
+      let idx = self.next_transient;
+      self.next_transient = idx.checked_add(1).unwrap_or(constants::FIRST_TRANSIENT_IDX);
+      let mut props = pipe_menu::item_props(item);
+      match pipe_menu::item_launcher(item) {
+        Some(launcher) => {
+          self.transient.insert(idx, launcher);
+        },
+        None if !item.separator => props.enabled = false,
+        None => {},
+      }
+      self.props.insert(idx, props);
+      item_ids.push(idx);
+    }
+    debug!("Pipe menu '{}' has {} items", label, item_ids.len());
+    self.children.insert(id, item_ids);
+    self.pipe_items.insert(id, items);
+    self.revision += 1;
+    let sig_res = AppMenuDbusMenu::layout_updated(ctxt, &self.revision, &id).await;
+    if let Err(err) = sig_res {
+      warn!(
+        "Failed to signal layout update for pipe menu '{}': {}",
+        label, err
+      );
+    }
+  }
 
-          self.revision = self.revision + 1;
+  /// Application directory toggled by a "Sources" submenu item.
+  fn source_dir(&self, item_id: i32) -> Option<&std::path::PathBuf> {
+    if !(constants::FIRST_SOURCE_IDX..constants::FIRST_PIPE_MENU_IDX).contains(&item_id)
+      || !self.props.contains_key(&item_id)
+    {
+      return None;
+    }
+    self
+      .app_dirs
+      .by_slot((item_id - constants::FIRST_SOURCE_IDX) as usize)
+  }
 
-          update_category_props(&mut self.children, &mut self.props);
+  /// Whether `p` lies in an application directory switched off in "Sources".
+  fn in_disabled_dir(&self, p: &std::path::Path) -> bool {
+    self
+      .app_dirs
+      .locate(p)
+      .is_some_and(|(_, dir)| util::init::is_dir_disabled(dir))
+  }
 
-          let sig_res = AppMenuDbusMenu::items_properties_updated(
-            &ctxt,
-            &vec![(menu_idx as i32, props)],
-            &vec![],
-          )
+  /// Brings the application directories in line with the config, dropping
+  /// the entries of those no longer listed. Returns the directories added and
+  /// removed, so the caller can update what is watched; entries of the added
+  /// ones are read by the next rescan.
+  async fn sync_app_dirs(
+    &mut self,
+    ctxt: &SignalContext<'_>,
+  ) -> (Vec<std::path::PathBuf>, Vec<std::path::PathBuf>) {
+    let wanted = util::init::get_app_dirs();
+    let removed: Vec<std::path::PathBuf> = self
+      .app_dirs
+      .iter()
+      .map(|(_, dir)| dir)
+      .filter(|dir| !wanted.contains(dir))
+      .cloned()
+      .collect();
+    for dir in removed.iter() {
+      info!("Application directory {:?} removed", dir);
+      let paths: Vec<std::path::PathBuf> = self
+        .cache
+        .values()
+        .flat_map(|prio_cache| prio_cache.values())
+        .filter(|launcher| {
+          !launcher.tombstone
+            && self
+              .app_dirs
+              .locate(&launcher.path)
+              .is_some_and(|(_, holder)| holder == dir)
+        })
+        .map(|launcher| launcher.path.clone())
+        .collect();
+      for path in paths {
+        self
+          .remove_launcher_path(&path.to_string_lossy(), ctxt.clone())
           .await;
-          if let Err(err) = sig_res {
-            warn!("Failed to signal property updates for {}: {}", path, err);
+      }
+      if let Some(slot) = self.app_dirs.slot(dir) {
+        let idx = constants::FIRST_SOURCE_IDX + slot as i32;
+        self.props.remove(&idx);
+        if let Some(sources) = self.children.get_mut(&constants::SOURCES_MENU_IDX) {
+          sources.retain(|i| *i != idx);
+        }
+      }
+    }
+    let mut added: Vec<std::path::PathBuf> = Vec::new();
+    for dir in wanted.iter() {
+      if !self.app_dirs.contains(dir) && !added.contains(dir) {
+        info!("Application directory {:?} added", dir);
+        added.push(dir.clone());
+      }
+    }
+    let renumbered = self.app_dirs.sync(wanted);
+    self.renumber_entries(&renumbered, ctxt).await;
+    if self.children.contains_key(&constants::SOURCES_MENU_IDX) {
+      let mut sources = Vec::new();
+      for (_, dir) in self.app_dirs.iter() {
+        let idx = constants::FIRST_SOURCE_IDX + self.app_dirs.slot(dir).unwrap_or_default() as i32;
+        if idx < constants::FIRST_PIPE_MENU_IDX {
+          if added.contains(dir) {
+            self.props.insert(idx, desktop::source_props(dir));
           }
+          sources.push(idx);
+        }
+      }
+      self.children.insert(constants::SOURCES_MENU_IDX, sources);
+    }
+    (added, removed)
+  }
 
-          let sig_res = AppMenuDbusMenu::layout_updated(&ctxt, &self.revision, &0).await;
-          if let Err(err) = sig_res {
-            warn!("Failed to signal layout updates for {}: {}", path, err);
-          }
+  /// Moves cached entries to the new priorities of their directories, as
+  /// given by `SourceSet::sync`, showing the entries that win after
+  /// directories were reordered. Entries left without a directory, only
+  /// tombstones by now, rank below all others.
+  async fn renumber_entries(
+    &mut self,
+    renumbered: &HashMap<usize, usize>,
+    ctxt: &SignalContext<'_>,
+  ) {
+    let mut promoted = Vec::new();
+    for (name, prio_cache) in self.cache.iter_mut() {
+      if desktop::is_custom_id(name) {
+        continue;
+      }
+      let active = prio_cache
+        .values()
+        .next()
+        .map(|launcher| launcher.path.clone());
+      *prio_cache = std::mem::take(prio_cache)
+        .into_iter()
+        .map(|(prio, launcher)| {
+          (
+            renumbered.get(&prio).copied().unwrap_or(usize::MAX),
+            launcher,
+          )
+        })
+        .collect();
+      if let Some((prio, launcher)) = prio_cache.first_key_value() {
+        if Some(&launcher.path) != active.as_ref() {
+          promoted.push((name.clone(), *prio));
+        }
+      }
+    }
+    for (name, prio) in promoted {
+      let launcher = self
+        .cache
+        .get_mut(&name)
+        .and_then(|prio_cache| prio_cache.remove(&prio));
+      if let Some(launcher) = launcher {
+        self.insert_launcher(name, prio, launcher, ctxt).await;
+      }
+    }
+  }
+
+  /// Fills the empty menu with the launchers read by `initial_scan`, and
+  /// the icon files rendered for them up front, if any.
+  async fn populate(
+    &mut self,
+    cache: HashMap<std::ffi::OsString, BTreeMap<usize, desktop::Launcher>>,
+    parse_failures: desktop::ParseFailures,
+    icons: HashMap<String, (String, desktop::IconData)>,
+    ctxt: &SignalContext<'_>,
+  ) {
+    // Drops the snapshot's launchers served while scanning.
+    self
+      .props
+      .retain(|idx, _| !constants::is_launcher_idx(*idx));
+    self.sections.clear_members();
+    for (name, prio_cache) in cache.iter() {
+      let active_entry = prio_cache
+        .values()
+        .next()
+        .log_expect(format!("Failed to get initial entry for {:?}", name).as_str());
+      let mut entry_props = desktop::launcher_props(name, active_entry);
+      let idx = self.counter.get_index(name) as i32;
+      let icon = desktop::launcher_icon(name, active_entry);
+      if let Some((icon_name, icon_data)) = icon.as_ref().and_then(|icon| icons.get(icon)) {
+        entry_props.icon_name = icon_name.clone();
+        entry_props.icon_data = icon_data.clone();
+        self.icon_retries.track(idx, icon.as_deref(), &entry_props);
+      } else if !self.lazy_icons.fill(idx, icon.as_deref(), &mut entry_props) {
+        self.icon_retries.track(idx, icon.as_deref(), &entry_props);
+      }
+      self.props.insert(idx, entry_props);
+      let c = self.sections.section_of(name, active_entry);
+      self.sections.add_member(c, idx, &self.props);
+    }
+    self.cache = cache;
+    self.parse_failures = parse_failures;
+    update_category_props(
+      &mut self.children,
+      &mut self.props,
+      &mut self.sections,
+      self.merge_threshold,
+      None,
+    );
+    update_copies(
+      &mut self.children,
+      &mut self.props,
+      &self.counter,
+      &self.installed,
+    );
+    self.revision += 1;
+    let sig_res = AppMenuDbusMenu::layout_updated(ctxt, &self.revision, &0).await;
+    if let Err(err) = sig_res {
+      warn!(
+        "Failed to signal layout update after the first scan: {}",
+        err
+      );
+    }
+  }
+
+  /// Re-reads every enabled application directory, picking up entries the
+  /// watcher missed and dropping those whose file or directory is gone, then
+  /// syncs the config's custom entries.
+  async fn rescan(&mut self, ctxt: &SignalContext<'_>) {
+    let started = std::time::Instant::now();
+    let failures = util::alerts::failures();
+    let gone: Vec<std::path::PathBuf> = self
+      .cache
+      .iter()
+      .filter(|(name, _)| !desktop::is_custom_id(name))
+      .flat_map(|(_, prio_cache)| prio_cache.values())
+      .filter(|launcher| {
+        !launcher.tombstone && (!launcher.path.exists() || self.in_disabled_dir(&launcher.path))
+      })
+      .map(|launcher| launcher.path.clone())
+      .collect();
+    for path in gone {
+      self
+        .remove_launcher_path(&path.to_string_lossy(), ctxt.clone())
+        .await;
+    }
+    // Tombstones of removed files have been announced, so their items can go.
+    let deleted: Vec<std::ffi::OsString> = self
+      .cache
+      .iter()
+      .filter(|(_, prio_cache)| prio_cache.values().all(|launcher| launcher.deleted))
+      .map(|(name, _)| name.clone())
+      .collect();
+    for name in deleted {
+      self.forget_entry(&name);
+    }
+    let dirs: Vec<std::path::PathBuf> = self
+      .app_dirs
+      .iter()
+      .map(|(_, dir)| dir)
+      .filter(|dir| !util::init::is_dir_disabled(dir))
+      .cloned()
+      .collect();
+    for dir in dirs {
+      for path in desktop::scan_dir(&dir) {
+        self
+          .add_launcher_path(&path.to_string_lossy(), ctxt.clone())
+          .await;
+      }
+    }
+
+    let custom = desktop::custom_launchers();
+    let stale: Vec<(std::ffi::OsString, std::path::PathBuf)> = self
+      .cache
+      .iter()
+      .filter(|(name, _)| desktop::is_custom_id(name) && !custom.iter().any(|(id, _)| id == *name))
+      .filter_map(|(name, prio_cache)| {
+        let launcher = prio_cache.values().next()?;
+        (!launcher.tombstone).then(|| (name.clone(), launcher.path.clone()))
+      })
+      .collect();
+    for (name, path) in stale {
+      let tombstone = desktop::tombstone_launcher(path, name.to_string_lossy().to_string(), true);
+      self.insert_launcher(name, 0, tombstone, ctxt).await;
+    }
+    for (name, launcher) in custom {
+      self.insert_launcher(name, 0, launcher, ctxt).await;
+    }
+    util::usage::record_scan(started.elapsed());
+    if util::alerts::failures() == failures {
+      util::alerts::resolve();
+    }
+  }
+
+  /// Rescans, then logs what changed and has hosts fetch the whole layout
+  /// again.
+  async fn rescan_all(&mut self, ctxt: &SignalContext<'_>) {
+    let before = self.active_launchers();
+    self.rescan(ctxt).await;
+    let after = self.active_launchers();
+    let added = after.keys().filter(|id| !before.contains_key(*id)).count();
+    let removed = before.keys().filter(|id| !after.contains_key(*id)).count();
+    let updated = after
+      .iter()
+      .filter(|(id, new)| {
+        before
+          .get(*id)
+          .is_some_and(|orig| launcher_updated(orig, new))
+      })
+      .count();
+    info!(
+      "Rescan found {} added, {} removed and {} updated entries",
+      added, removed, updated
+    );
+    self.revision += 1;
+    let sig_res = AppMenuDbusMenu::layout_updated(ctxt, &self.revision, &0).await;
+    if let Err(err) = sig_res {
+      warn!("Failed to signal layout update after rescan: {}", err);
+    }
+  }
+
+  /// The launchers currently shown for each desktop-file ID.
+  fn active_launchers(&self) -> HashMap<std::ffi::OsString, desktop::Launcher> {
+    self
+      .cache
+      .iter()
+      .filter_map(|(name, prio_cache)| {
+        let launcher = prio_cache.values().next()?;
+        (!launcher.tombstone).then(|| (name.clone(), launcher.clone()))
+      })
+      .collect()
+  }
+
+  /// Visible entries per top-level submenu, for the usage summary.
+  fn section_counts(&self) -> Vec<(String, usize)> {
+    self
+      .sections
+      .items()
+      .iter()
+      .map(|(idx, section_props)| {
+        let shown = self
+          .sections
+          .members(*idx)
+          .iter()
+          .filter(|k| self.props.get(k).is_some_and(|p| p.visible))
+          .count();
+        (section_props.label.clone(), shown)
+      })
+      .filter(|(_, shown)| *shown > 0)
+      .collect()
+  }
+
+  /// Drops every launcher for `name` along with its menu item, returning the
+  /// one that was active. Callers signal the layout change.
+  fn forget_entry(&mut self, name: &std::ffi::OsString) -> Option<desktop::Launcher> {
+    let menu_idx = self.counter.get_index(name) as i32;
+    let launcher = self
+      .cache
+      .remove(name)
+      .and_then(|prio_cache| prio_cache.into_values().next());
+    if let Some(launcher) = &launcher {
+      let c = self.sections.section_of(name, launcher);
+      self.sections.remove_member(c, menu_idx);
+    }
+    for children in self.children.values_mut() {
+      children.retain(|i| *i != menu_idx);
+    }
+    self.props.remove(&menu_idx);
+    self.icon_retries.forget(menu_idx);
+    self.lazy_icons.forget(menu_idx);
+    launcher
+  }
+
+  /// Re-applies filters after the configuration changed: drops entries that
+  /// are now hidden and re-reads the rest so desktop filters take effect.
+  async fn apply_config(&mut self, ctxt: &SignalContext<'_>) {
+    self.merge_threshold = util::init::get_merge_threshold();
+    let hidden: Vec<std::ffi::OsString> = self
+      .cache
+      .iter()
+      .filter(|(name, prio_cache)| {
+        desktop::is_hidden_id(name)
+          || prio_cache
+            .values()
+            .next()
+            .is_some_and(|launcher| desktop::filter_launcher(name, launcher.clone()).is_none())
+      })
+      .map(|(name, _)| name.clone())
+      .collect();
+    let mut changes = control::MenuChanges::default();
+    for name in hidden {
+      if self
+        .forget_entry(&name)
+        .is_some_and(|launcher| !launcher.tombstone)
+      {
+        changes.removed.push(name.to_string_lossy().to_string());
+      }
+    }
+    self.rescan(ctxt).await;
+
+    // Overrides and truncation change props without touching the launchers themselves.
+    let mut overridden = Vec::new();
+    for (name, prio_cache) in self.cache.iter() {
+      let launcher = match prio_cache.values().next() {
+        Some(launcher) if !launcher.tombstone => launcher,
+        _ => continue,
+      };
+      let menu_idx = self.counter.get_index(name) as i32;
+      let mut fresh = desktop::launcher_props(name, launcher);
+      let icon = desktop::launcher_icon(name, launcher);
+      self.lazy_icons.fill(menu_idx, icon.as_deref(), &mut fresh);
+      if let Some(props) = self.props.get_mut(&menu_idx) {
+        if props.label != fresh.label
+          || props.icon_name != fresh.icon_name
+          || props.icon_data != fresh.icon_data
+          || props.accessible_desc != fresh.accessible_desc
+          || props.visible != fresh.visible
+        {
+          debug!("Overrides of {:?} changed", name);
+          props.label = fresh.label.clone();
+          props.icon_name = fresh.icon_name.clone();
+          props.icon_data = fresh.icon_data.clone();
+          props.accessible_desc = fresh.accessible_desc.clone();
+          props.visible = fresh.visible;
+          overridden.push((
+            menu_idx,
+            HashMap::from([
+              ("label".to_string(), zbus::zvariant::Value::new(fresh.label)),
+              (
+                "icon-name".to_string(),
+                zbus::zvariant::Value::new(fresh.icon_name),
+              ),
+              (
+                "icon-data".to_string(),
+                zbus::zvariant::Value::new(fresh.icon_data),
+              ),
+              (
+                "accessible-desc".to_string(),
+                zbus::zvariant::Value::new(fresh.accessible_desc),
+              ),
+              (
+                "visible".to_string(),
+                zbus::zvariant::Value::new(fresh.visible),
+              ),
+            ]),
+          ));
+          changes.updated.push(name.to_string_lossy().to_string());
+        }
+      }
+    }
+    let sources = self
+      .children
+      .get(&constants::SOURCES_MENU_IDX)
+      .cloned()
+      .unwrap_or_default();
+    let options = [
+      constants::SHOW_HIDDEN_ITEM_IDX,
+      constants::SHOW_TERMINAL_ITEM_IDX,
+    ];
+    for idx in sources.into_iter().chain(options) {
+      let state = match (self.source_dir(idx), display_option(idx)) {
+        (Some(dir), _) => (!util::init::is_dir_disabled(dir)) as i32,
+        (None, Some((_, on))) => on as i32,
+        _ => continue,
+      };
+      if let Some(props) = self.props.get_mut(&idx) {
+        if props.toggle_state != state {
+          props.toggle_state = state;
+          overridden.push((
+            idx,
+            HashMap::from([(
+              "toggle-state".to_string(),
+              zbus::zvariant::Value::new(state),
+            )]),
+          ));
         }
+      }
+    }
+    if !overridden.is_empty() {
+      let overridden = with_copies(&mut self.props, overridden);
+      let sig_res = AppMenuDbusMenu::items_properties_updated(ctxt, &overridden, &vec![]).await;
+      if let Err(err) = sig_res {
+        warn!("Failed to signal override updates: {}", err);
+      }
+    }
 
-        prio_cache.insert(*prio_idx, launcher);
-      } else {
-        warn!("Failed to parse {} as launcher", path);
+    self.rebuild_sections();
+    self.revision += 1;
+    update_category_props(
+      &mut self.children,
+      &mut self.props,
+      &mut self.sections,
+      self.merge_threshold,
+      None,
+    );
+    update_copies(
+      &mut self.children,
+      &mut self.props,
+      &self.counter,
+      &self.installed,
+    );
+    let sig_res = AppMenuDbusMenu::layout_updated(ctxt, &self.revision, &0).await;
+    if let Err(err) = sig_res {
+      warn!(
+        "Failed to signal layout update after config reload: {}",
+        err
+      );
+    }
+    control::notify_menu_changed(ctxt.connection(), &changes).await;
+  }
+
+  /// Lays out the top-level submenus again for icons that changed with the
+  /// color scheme.
+  async fn refresh_section_icons(&mut self, ctxt: &SignalContext<'_>) {
+    self.rebuild_sections();
+    self.revision += 1;
+    update_category_props(
+      &mut self.children,
+      &mut self.props,
+      &mut self.sections,
+      self.merge_threshold,
+      None,
+    );
+    let sig_res = AppMenuDbusMenu::layout_updated(ctxt, &self.revision, &0).await;
+    if let Err(err) = sig_res {
+      warn!(
+        "Failed to signal layout update after a color scheme change: {}",
+        err
+      );
+    }
+  }
+
+  /// Recreates the category submenus from the config and files every entry
+  /// into them again.
+  fn rebuild_sections(&mut self) {
+    let old: Vec<i32> = self.sections.ids().collect();
+    for idx in old.iter().copied().chain(self.sections.submenu_ids()) {
+      self.children.remove(&idx);
+      self.props.remove(&idx);
+    }
+    self.sections = sections::Sections::from_config();
+    let root = self.children.get(&0).cloned().unwrap_or_default();
+    // New sections go where the old ones started.
+    let at = root.iter().position(|i| old.contains(i)).unwrap_or(0);
+    let mut rest: Vec<i32> = root.into_iter().filter(|i| !old.contains(i)).collect();
+    let after = rest.split_off(at);
+    let root = rest
+      .into_iter()
+      .chain(self.sections.ids())
+      .chain(after)
+      .collect();
+    self.children.insert(0, root);
+    for (idx, props) in self.sections.items() {
+      self.props.insert(*idx, props.clone());
+      self.children.insert(*idx, Vec::new());
+    }
+    if let Some(other) = self.props.get_mut(&constants::OTHER_CATEGORY_IDX) {
+      other.icon_name =
+        util::appearance::themed_icon(&sections::category_icon(constants::Category::Uncategorized));
+    }
+    for (name, prio_cache) in self.cache.iter() {
+      if let Some(launcher) = prio_cache.values().next() {
+        let idx = self.counter.get_index(name) as i32;
+        let c = self.sections.section_of(name, launcher);
+        self.sections.add_member(c, idx, &self.props);
+      }
+    }
+  }
+
+  /// Menu item IDs of the launchers still around, with their properties and
+  /// the sections listing them, for the exit snapshot.
+  fn snapshot(&self) -> util::snapshot::Snapshot {
+    let ids: HashMap<String, usize> = self
+      .counter
+      .map
+      .iter()
+      .filter(|(name, _)| self.cache.contains_key(*name))
+      .filter_map(|(name, idx)| Some((name.to_str()?.to_string(), *idx)))
+      .collect();
+    let props = ids
+      .values()
+      .filter_map(|idx| Some((*idx as i32, self.props.get(&(*idx as i32))?.into())))
+      .collect();
+    util::snapshot::Snapshot {
+      revision: self.revision,
+      ids,
+      next_id: self.counter.count,
+      props,
+      children: self
+        .sections
+        .ids()
+        .map(|section| (section, self.sections.members(section).to_vec()))
+        .collect(),
+    }
+  }
+
+  /// Precomputes the root layouts hosts ask for when the menu first opens,
+  /// returning whether anything changed since the last warm-up.
+  fn warm_up(&mut self) -> bool {
+    let layouts = self.warm_layouts.get_mut().unwrap().at(self.revision);
+    let mut changed = false;
+    for depth in constants::WARM_LAYOUT_DEPTHS {
+      let key = layout_key(0, depth, &[]);
+      if let std::collections::hash_map::Entry::Vacant(entry) = layouts.entry(key) {
+        entry.insert(get_layout(0, &self.children, &self.props, &[], depth));
+        changed = true;
+      }
+    }
+    if changed {
+      debug!("Prefetched root layouts for revision {}", self.revision);
+    }
+    changed
+  }
+
+  /// Summary of the menu contents shown in the tray tooltip.
+  fn tool_tip(&self) -> String {
+    let shown = self
+      .props
+      .iter()
+      .filter(|(id, p)| {
+        constants::is_launcher_idx(**id) && !self.transient.contains_key(id) && p.visible
+      })
+      .count();
+    let count = match shown {
+      1 => "1 application".to_string(),
+      n => format!("{} applications", n),
+    };
+    let count = match util::init::get_profile() {
+      Some(profile) => format!("{}: {}", profile.name, count),
+      None => count,
+    };
+    let (last_scan_at, _) = util::usage::last_scan();
+    match Some(last_scan_at)
+      .filter(|at| *at > 0)
+      .and_then(util::state::local_clock)
+    {
+      Some(clock) => format!("{}\nLast scanned at {}", count, clock),
+      None => count,
+    }
+  }
+
+  /// Notes the entry at `p` as installed now unless it was seen before, for
+  /// the "Recently added" submenu.
+  async fn record_install(&mut self, p: &std::path::Path, ctxt: &SignalContext<'_>) {
+    let appid = match locate_entry(&self.app_dirs, p) {
+      Some((_, name)) if self.active_entry(&name.to_string_lossy()).is_some() => {
+        name.to_string_lossy().to_string()
+      },
+      _ => return,
+    };
+    if self.installed.contains_key(&appid) {
+      return;
+    }
+    let now = util::state::unix_now();
+    let max_age = config::get().recent_days * 24 * 60 * 60;
+    self
+      .installed
+      .retain(|_, at| now.saturating_sub(*at) < max_age);
+    self.installed.insert(appid, now);
+    tokio::spawn(show_unseen(
+      ctxt.connection().clone(),
+      self.unseen_installs(),
+    ));
+    if config::get().recent_count > 0 {
+      util::state::save(constants::INSTALLS_STATE_FILE, &self.installed);
+      self.refresh_copies(ctxt).await;
+    }
+  }
+
+  /// Active entries installed since the menu was last opened.
+  fn unseen_installs(&self) -> usize {
+    self
+      .installed
+      .iter()
+      .filter(|(appid, at)| **at > self.seen_at && self.active_entry(appid).is_some())
+      .count()
+  }
+
+  /// Notes that the user has seen the entries installed so far.
+  fn mark_seen(&mut self) {
+    if self.unseen_installs() == 0 {
+      return;
+    }
+    self.seen_at = util::state::unix_now();
+    util::state::save(constants::SEEN_STATE_FILE, &self.seen_at);
+  }
+
+  /// Reorders "Frequently used" after a launch, signalling a layout change
+  /// only if the list changed.
+  async fn refresh_frequent(&mut self, ctxt: &SignalContext<'_>) {
+    let listed = self.children.get(&constants::FREQUENT_MENU_IDX).cloned();
+    update_copies(
+      &mut self.children,
+      &mut self.props,
+      &self.counter,
+      &self.installed,
+    );
+    if self.children.get(&constants::FREQUENT_MENU_IDX) != listed.as_ref() {
+      self.revision += 1;
+      let sig_res = AppMenuDbusMenu::layout_updated(ctxt, &self.revision, &0).await;
+      if let Err(err) = sig_res {
+        warn!(
+          "Failed to signal layout update for frequent entries: {}",
+          err
+        );
+      }
+    }
+  }
+
+  /// Drops entries from "Recently added" once they are too old for it.
+  async fn expire_recent(&mut self, ctxt: &SignalContext<'_>) {
+    let listed = self.children.get(&constants::RECENT_MENU_IDX).cloned();
+    update_copies(
+      &mut self.children,
+      &mut self.props,
+      &self.counter,
+      &self.installed,
+    );
+    if self.children.get(&constants::RECENT_MENU_IDX) != listed.as_ref() {
+      self.revision += 1;
+      let sig_res = AppMenuDbusMenu::layout_updated(ctxt, &self.revision, &0).await;
+      if let Err(err) = sig_res {
+        warn!("Failed to signal layout update for recent entries: {}", err);
       }
     }
   }
 
-  /// AddLauncherPath method
-  async fn remove_launcher_path(
-    &mut self,
-    path: &str,
-    #[zbus(signal_context)] ctxt: SignalContext<'_>,
-  ) {
-    let p = std::path::PathBuf::from(path);
-    let cache_name = p.file_stem().unwrap_or_default().to_os_string();
-    let menu_idx = self.counter.get_index(&cache_name);
-    let prio_cache = self.cache.entry(cache_name.clone()).or_default();
+  /// Checks the display option items by the config and shows or hides the
+  /// entries they cover, without rescanning.
+  async fn apply_display_options(&mut self, ctxt: &SignalContext<'_>) {
+    let mut updated = Vec::new();
+    for idx in [
+      constants::SHOW_HIDDEN_ITEM_IDX,
+      constants::SHOW_TERMINAL_ITEM_IDX,
+    ] {
+      let state = display_option(idx).is_some_and(|(_, on)| on) as i32;
+      if let Some(props) = self.props.get_mut(&idx) {
+        if props.toggle_state != state {
+          props.toggle_state = state;
+          updated.push((
+            idx,
+            HashMap::from([(
+              "toggle-state".to_string(),
+              zbus::zvariant::Value::new(state),
+            )]),
+          ));
+        }
+      }
+    }
+    if !updated.is_empty() {
+      let sig_res = AppMenuDbusMenu::items_properties_updated(ctxt, &updated, &vec![]).await;
+      if let Err(err) = sig_res {
+        warn!("Failed to signal display option updates: {}", err);
+      }
+    }
+    self.apply_profile(ctxt).await;
+  }
 
-    let source = p.parent();
-    if source.is_none() {
-      warn!("Launcher has no parent: {}", path);
-      return;
+  /// Shows only the entries in the active menu profile.
+  async fn apply_profile(&mut self, ctxt: &SignalContext<'_>) {
+    let mut updated = Vec::new();
+    for (name, prio_cache) in self.cache.iter() {
+      let launcher = match prio_cache.values().next() {
+        Some(launcher) if !launcher.tombstone => launcher,
+        _ => continue,
+      };
+      let visible = desktop::launcher_visible(launcher) && desktop::in_profile(name, launcher);
+      let menu_idx = self.counter.get_index(name) as i32;
+      if let Some(props) = self.props.get_mut(&menu_idx) {
+        if props.visible != visible {
+          props.visible = visible;
+          updated.push((
+            menu_idx,
+            HashMap::from([("visible".to_string(), zbus::zvariant::Value::new(visible))]),
+          ));
+        }
+      }
+    }
+    if !updated.is_empty() {
+      let updated = with_copies(&mut self.props, updated);
+      let sig_res = AppMenuDbusMenu::items_properties_updated(ctxt, &updated, &vec![]).await;
+      if let Err(err) = sig_res {
+        warn!("Failed to signal profile visibility updates: {}", err);
+      }
+    }
+    self.revision += 1;
+    update_category_props(
+      &mut self.children,
+      &mut self.props,
+      &mut self.sections,
+      self.merge_threshold,
+      None,
+    );
+    update_copies(
+      &mut self.children,
+      &mut self.props,
+      &self.counter,
+      &self.installed,
+    );
+    let sig_res = AppMenuDbusMenu::layout_updated(ctxt, &self.revision, &0).await;
+    if let Err(err) = sig_res {
+      warn!("Failed to signal layout update for the profile: {}", err);
     }
-    let source = source.unwrap();
+  }
 
-    let prio_idx = self.path_map.get_by_right(&source.to_path_buf());
-    if prio_idx.is_none() {
-      warn!("Failed to find priority map entry for {:?}", source);
+  /// Re-checks `TryExec` binaries of active launchers, showing or hiding
+  /// entries whose binary appeared or disappeared.
+  async fn revalidate_try_exec(&mut self, ctxt: &SignalContext<'_>) {
+    let mut updated = Vec::new();
+    for (name, prio_cache) in self.cache.iter_mut() {
+      let launcher = match prio_cache.values_mut().next() {
+        Some(launcher) if launcher.try_exec.is_some() => launcher,
+        _ => continue,
+      };
+      let missing = desktop::try_exec_missing(launcher.try_exec.as_deref());
+      if missing == launcher.try_exec_missing {
+        continue;
+      }
+      info!(
+        "TryExec for {:?} is now {}",
+        launcher.path,
+        if missing { "missing" } else { "present" }
+      );
+      launcher.try_exec_missing = missing;
+      let menu_idx = self.counter.get_index(name) as i32;
+      if let Some(props) = self.props.get_mut(&menu_idx) {
+        props.visible = desktop::launcher_visible(launcher) && desktop::in_profile(name, launcher);
+        updated.push((
+          menu_idx,
+          HashMap::from([(
+            "visible".to_string(),
+            zbus::zvariant::Value::new(props.visible),
+          )]),
+        ));
+      }
+    }
+    if updated.is_empty() {
       return;
     }
-    let prio_idx = prio_idx.unwrap();
 
-    let entry = prio_cache.get(prio_idx);
-    if entry.is_none() {
-      info!("Entry not found: {}", path);
-      return;
+    self.revision += 1;
+    update_category_props(
+      &mut self.children,
+      &mut self.props,
+      &mut self.sections,
+      self.merge_threshold,
+      None,
+    );
+    update_copies(
+      &mut self.children,
+      &mut self.props,
+      &self.counter,
+      &self.installed,
+    );
+
+    let updated = with_copies(&mut self.props, updated);
+    let sig_res = AppMenuDbusMenu::items_properties_updated(ctxt, &updated, &vec![]).await;
+    if let Err(err) = sig_res {
+      warn!("Failed to signal TryExec visibility updates: {}", err);
     }
-    let entry = entry.unwrap().clone();
-    prio_cache.remove(prio_idx);
-    if prio_cache.is_empty() {
-      prio_cache.insert(
-        *prio_idx,
-        desktop::tombstone_launcher(p, (*cache_name.to_string_lossy()).to_string()),
+    let sig_res = AppMenuDbusMenu::layout_updated(ctxt, &self.revision, &0).await;
+    if let Err(err) = sig_res {
+      warn!(
+        "Failed to signal layout update after TryExec changes: {}",
+        err
       );
     }
+  }
+}
 
-    let c = entry
-      .categories
-      .iter()
-      .next()
-      .unwrap_or(&constants::Category::Uncategorized);
-    self
-      .children
-      .get_mut(&(constants::category_idx(*c) as i32))
-      .log_expect("Failed to get category reference")
-      .retain(|i| *i != menu_idx as i32);
-
-    let r_entry = prio_cache.iter().next().unwrap();
-    let remain = desktop::launcher_props(r_entry.1);
-    if r_entry.0 <= prio_idx && launcher_updated(r_entry.1, &entry) {
-      let enc_ctxt = zbus::zvariant::EncodingContext::<byteorder::LE>::new_dbus(0);
-      let encoded =
-        zbus::zvariant::to_bytes(enc_ctxt, &remain).log_expect("Failed to encode properties");
-      let mut props: std::collections::HashMap<String, zbus::zvariant::OwnedValue> =
-        zbus::zvariant::from_slice(&encoded, enc_ctxt).log_expect("Failed to decode properties");
-      let props = props.drain().map(|(k, v)| (k, v.into())).collect();
-      self.props.insert(menu_idx as i32, remain);
-
-      let c = r_entry
-        .1
-        .categories
-        .iter()
-        .next()
-        .unwrap_or(&constants::Category::Uncategorized);
-      self
-        .children
-        .get_mut(&(constants::category_idx(*c) as i32))
-        .log_expect("Failed to get category reference")
-        .push(menu_idx as i32);
+#[derive(Debug)]
+struct IconRetry {
+  attempts: u32,
+  next_attempt: std::time::Instant,
+}
 
-      self.revision = self.revision + 1;
+#[derive(Debug, Default)]
+struct IconRetryQueue {
+  pending: HashMap<i32, IconRetry>,
+}
 
-      update_category_props(&mut self.children, &mut self.props);
+impl IconRetryQueue {
+  fn track(&mut self, menu_idx: i32, icon: Option<&str>, props: &desktop::MenuProps) {
+    if desktop::icon_render_failed(icon, props) {
+      info!(
+        "Queueing icon render retry for {:?} in {:?}",
+        icon.unwrap_or_default(),
+        constants::ICON_RETRY_BASE_DELAY
+      );
+      self.pending.insert(
+        menu_idx,
+        IconRetry {
+          attempts: 0,
+          next_attempt: std::time::Instant::now() + constants::ICON_RETRY_BASE_DELAY,
+        },
+      );
+    } else {
+      self.pending.remove(&menu_idx);
+    }
+  }
 
-      let sig_res =
-        AppMenuDbusMenu::items_properties_updated(&ctxt, &vec![(menu_idx as i32, props)], &vec![])
-          .await;
-      if let Err(err) = sig_res {
-        warn!("Failed to signal property updates for {}: {}", path, err);
-      }
+  fn due(&self) -> Vec<i32> {
+    let now = std::time::Instant::now();
+    self
+      .pending
+      .iter()
+      .filter(|(_, retry)| retry.next_attempt <= now)
+      .map(|(idx, _)| *idx)
+      .collect()
+  }
 
-      let sig_res = AppMenuDbusMenu::layout_updated(&ctxt, &self.revision, &0).await;
-      if let Err(err) = sig_res {
-        warn!("Failed to signal layout updates for {}: {}", path, err);
+  fn failed(&mut self, menu_idx: i32, icon: &str) {
+    if let Some(retry) = self.pending.get_mut(&menu_idx) {
+      retry.attempts += 1;
+      if retry.attempts >= constants::ICON_RETRY_MAX_ATTEMPTS {
+        warn!(
+          "Giving up on icon {:?} after {} attempts",
+          icon, retry.attempts
+        );
+        self.pending.remove(&menu_idx);
+      } else {
+        let delay = constants::ICON_RETRY_BASE_DELAY * 2u32.pow(retry.attempts);
+        retry.next_attempt = std::time::Instant::now() + delay;
       }
     }
   }
 
-  /// ItemActivationRequested signal
-  #[dbus_interface(signal)]
-  async fn item_activation_requested(
-    ctxt: &SignalContext<'_>,
-    iid: &i32,
-    timestamp: &u32,
-  ) -> zbus::Result<()>;
-
-  /// ItemsPropertiesUpdated signal
-  #[dbus_interface(signal)]
-  async fn items_properties_updated(
-    ctxt: &SignalContext<'_>,
-    updated: &Vec<(
-      i32,
-      std::collections::HashMap<String, zbus::zvariant::Value<'_>>,
-    )>,
-    removed: &Vec<(i32, Vec<String>)>,
-  ) -> zbus::Result<()>;
+  fn forget(&mut self, menu_idx: i32) {
+    self.pending.remove(&menu_idx);
+  }
+}
 
-  /// LayoutUpdated signal
-  #[dbus_interface(signal)]
-  async fn layout_updated(
-    ctxt: &SignalContext<'_>,
-    revision: &u32,
-    parent: &i32,
-  ) -> zbus::Result<()>;
+/// Icon files of launchers that haven't been shown yet, rendered once a
+/// submenu listing them is about to be.
+#[derive(Debug, Default)]
+struct LazyIcons {
+  pending: HashMap<i32, String>,
+  /// Launchers shown before, whose icons are rendered right away on changes.
+  shown: std::collections::HashSet<i32>,
+}
 
-  /// IconThemePath property
-  #[dbus_interface(property)]
-  async fn icon_theme_path(&self) -> Vec<String> {
-    vec![]
+impl LazyIcons {
+  /// Renders the icon file of launcher `menu_idx` into `props`, or leaves it
+  /// for later if the launcher wasn't shown yet. Returns whether it did the latter.
+  fn fill(&mut self, menu_idx: i32, icon: Option<&str>, props: &mut desktop::MenuProps) -> bool {
+    self.pending.remove(&menu_idx);
+    let icon = match icon {
+      Some(icon) if icon.contains('/') => icon,
+      _ => return false,
+    };
+    if config::get().lazy_icons && !self.shown.contains(&menu_idx) {
+      self.pending.insert(menu_idx, icon.to_string());
+      return true;
+    }
+    desktop::set_file_icon(props, icon);
+    false
   }
 
-  /// Status property
-  #[dbus_interface(property)]
-  async fn status(&self) -> &str {
-    "normal"
+  /// Whether any launcher behind menu items `ids` has an icon left to render.
+  fn any_pending(&self, ids: &[i32]) -> bool {
+    ids
+      .iter()
+      .any(|id| self.pending.contains_key(&constants::launcher_idx(*id)))
   }
 
-  /// TextDirection property
-  #[dbus_interface(property)]
-  async fn text_direction(&self) -> &str {
-    "ltr"
+  /// Takes the icons left to render for the launchers behind menu items
+  /// `ids`, copies included.
+  fn take(&mut self, ids: impl IntoIterator<Item = i32>) -> Vec<(i32, String)> {
+    ids
+      .into_iter()
+      .map(constants::launcher_idx)
+      .filter_map(|idx| {
+        let icon = self.pending.remove(&idx)?;
+        self.shown.insert(idx);
+        Some((idx, icon))
+      })
+      .collect()
   }
 
-  /// Version property
-  #[dbus_interface(property)]
-  async fn version(&self) -> u32 {
-    3
+  fn forget(&mut self, menu_idx: i32) {
+    self.pending.remove(&menu_idx);
+    self.shown.remove(&menu_idx);
   }
 }
 
@@ -652,176 +3577,592 @@ struct LauncherCounter {
 
 impl LauncherCounter {
   fn get_index(&mut self, key: &std::ffi::OsString) -> usize {
-    let res = self.map.get_by_left(key);
-    if res.is_some() {
-      *res.unwrap()
+    if let Some(res) = self.map.get_by_left(key) {
+      *res
     } else {
       self.map.insert(key.clone(), self.count);
       let ret = self.count;
-      self.count = self.count + 1;
+      self.count += 1;
       ret
     }
   }
 
-  fn get_path(&mut self, index: &usize) -> Option<&std::ffi::OsString> {
+  fn get_path(&self, index: &usize) -> Option<&std::ffi::OsString> {
     self.map.get_by_right(index)
   }
+
+  /// Index handed out to a desktop-file ID, with or without its `.desktop` suffix.
+  fn index_of(&self, appid: &str) -> Option<usize> {
+    self
+      .map
+      .get_by_left(std::ffi::OsStr::new(appid))
+      .or_else(|| {
+        self
+          .map
+          .get_by_left(std::ffi::OsStr::new(&format!("{}.desktop", appid)))
+      })
+      .copied()
+  }
 }
-#[tokio::main]
-async fn main() {
+fn main() {
+  util::systemd::take_env();
   util::init::init_logging();
 
+  match std::env::args().nth(1).as_deref() {
+    Some(util::sandbox::BROKER_ARG) => {
+      util::sandbox::run_broker();
+      return;
+    },
+    Some("setup") => {
+      tokio::runtime::Runtime::new()
+        .log_expect("Failed to start async runtime")
+        .block_on(setup::run());
+      return;
+    },
+    _ => {},
+  }
+  if let Err(err) = config::Config::try_load() {
+    Fatal::Config(err).exit();
+  }
+  util::sandbox::enter();
+
+  tokio::runtime::Runtime::new()
+    .unwrap_or_else(|err| Fatal::Runtime(format!("Failed to start async runtime: {}", err)).exit())
+    .block_on(run());
+}
+
+async fn run() {
   let locale = sys_locale::get_locale().unwrap_or_else(|| String::from("en-US"));
+  // Subscribed before the first scan so its failures are shown as well.
+  let alerts = if util::init::get_menu_only() {
+    None
+  } else {
+    util::alerts::subscribe()
+  };
 
-  let app_dirs = util::init::get_app_dirs()
-    .drain(..)
-    .enumerate()
-    .collect::<bimap::BiMap<usize, std::path::PathBuf>>();
-  let mut launcher_counter = LauncherCounter {
-    count: 12,
-    map: bimap::BiMap::new(),
+  let app_dirs = sources::SourceSet::new(util::init::get_app_dirs());
+  let snapshot = util::snapshot::load().unwrap_or_default();
+  let launcher_counter = LauncherCounter {
+    count: snapshot.next_id.max(constants::FIRST_LAUNCHER_IDX),
+    map: snapshot
+      .ids
+      .iter()
+      .filter(|(_, idx)| **idx >= constants::FIRST_LAUNCHER_IDX && **idx < snapshot.next_id)
+      .map(|(appid, idx)| (appid.into(), *idx))
+      .collect(),
   };
 
   let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
   let mut props: HashMap<i32, desktop::MenuProps> = HashMap::new();
-  children.insert(0, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+  let mut sections = sections::Sections::from_config();
+  children.insert(
+    0,
+    [constants::RECENT_MENU_IDX, constants::FREQUENT_MENU_IDX]
+      .into_iter()
+      .chain(sections.ids())
+      .chain([
+        constants::OTHER_CATEGORY_IDX,
+        constants::CONTROLS_SEPARATOR_IDX,
+      ])
+      .collect(),
+  );
+  props.insert(
+    constants::OTHER_CATEGORY_IDX,
+    desktop::other_category_props(),
+  );
+  props.insert(constants::RECENT_MENU_IDX, desktop::recent_props());
+  props.insert(constants::FREQUENT_MENU_IDX, desktop::frequent_props());
+  let merge_threshold = util::init::get_merge_threshold();
   props.insert(0, desktop::root_props());
-  enum_iterator::all::<constants::Category>().for_each(|c| {
+  for idx in [
+    constants::FAVORITES_SEPARATOR_IDX,
+    constants::CONTROLS_SEPARATOR_IDX,
+  ] {
+    props.insert(idx, desktop::separator_props());
+  }
+  for (idx, section_props) in sections.items() {
+    props.insert(*idx, section_props.clone());
+    children.insert(*idx, Vec::new());
+  }
+  let mut pipe_menus = HashMap::new();
+  for (i, menu) in config::get().pipe_menus.iter().enumerate() {
+    let idx = constants::FIRST_PIPE_MENU_IDX + i as i32;
+    if idx >= constants::FIRST_SECTION_IDX {
+      warn!("Too many pipe menus, ignoring '{}'", menu.label);
+      continue;
+    }
+    children
+      .get_mut(&0)
+      .log_expect("Failed to get root children")
+      .push(idx);
+    children.insert(idx, Vec::new());
+    props.insert(idx, pipe_menu::menu_props(menu));
+    pipe_menus.insert(idx, menu.clone());
+  }
+  if util::init::get_show_sources() {
+    children
+      .get_mut(&0)
+      .log_expect("Failed to get root children")
+      .push(constants::SOURCES_MENU_IDX);
+    props.insert(constants::SOURCES_MENU_IDX, desktop::sources_props());
+    let mut sources = Vec::new();
+    for (_, dir) in app_dirs.iter() {
+      let idx = constants::FIRST_SOURCE_IDX + app_dirs.slot(dir).unwrap_or_default() as i32;
+      if idx < constants::FIRST_PIPE_MENU_IDX {
+        props.insert(idx, desktop::source_props(dir));
+        sources.push(idx);
+      }
+    }
+    children.insert(constants::SOURCES_MENU_IDX, sources);
+  }
+  if util::init::get_show_about() {
+    children
+      .get_mut(&0)
+      .log_expect("Failed to get root children")
+      .push(constants::ABOUT_ITEM_IDX);
+    props.insert(constants::ABOUT_ITEM_IDX, desktop::about_props());
+  }
+  if util::init::get_show_controls() {
+    children
+      .get_mut(&0)
+      .log_expect("Failed to get root children")
+      .extend([
+        constants::OPTIONS_MENU_IDX,
+        constants::REFRESH_ITEM_IDX,
+        constants::QUIT_ITEM_IDX,
+      ]);
+    children.insert(
+      constants::OPTIONS_MENU_IDX,
+      vec![
+        constants::SHOW_HIDDEN_ITEM_IDX,
+        constants::SHOW_TERMINAL_ITEM_IDX,
+      ],
+    );
+    props.insert(constants::OPTIONS_MENU_IDX, desktop::options_props());
     props.insert(
-      constants::category_idx(c) as i32,
-      desktop::category_props(c),
+      constants::SHOW_HIDDEN_ITEM_IDX,
+      desktop::option_props("Show hidden apps", util::init::get_show_hidden()),
     );
-  });
-  for i in 1..12 {
-    children.insert(i, Vec::new());
+    props.insert(
+      constants::SHOW_TERMINAL_ITEM_IDX,
+      desktop::option_props("Show terminal apps", util::init::get_show_terminal()),
+    );
+    props.insert(constants::REFRESH_ITEM_IDX, desktop::refresh_props());
+    props.insert(constants::QUIT_ITEM_IDX, desktop::quit_props());
   }
-  let mut cache: HashMap<std::ffi::OsString, BTreeMap<usize, desktop::Launcher>> = HashMap::new();
+  let scan_dirs: Vec<(usize, std::path::PathBuf)> = app_dirs
+    .iter()
+    .filter(|(_, dir)| !util::init::is_dir_disabled(dir))
+    .map(|(prio_idx, dir)| (prio_idx, dir.clone()))
+    .collect();
 
-  for dir in app_dirs.iter() {
-    match dir.1.read_dir() {
-      Ok(entries) => {
-        for e in entries {
-          match e {
-            Ok(entry) => {
-              let p = entry.path();
-              if let Some(launcher) = desktop::launcher_for_entry(p.clone(), &locale) {
-                let name = p.file_stem().unwrap_or_default().to_os_string();
-                let prio_cache = cache.entry(name).or_default();
-                prio_cache.insert(*dir.0, launcher);
-              }
-            },
-            Err(e) => {
-              warn!("Failed while reading {:?}: {}", dir, e);
-            },
-          }
-        }
-      },
-      Err(e) => {
-        warn!("Failed to read {:?}: {}", dir, e);
-      },
+  // Lay out the last run's menu, or an empty one, served while the first
+  // scan runs.
+  for (idx, item_props) in snapshot.props {
+    if launcher_counter.map.contains_right(&(idx as usize)) {
+      props.insert(idx, item_props.into());
     }
   }
-
-  for entry in cache.iter() {
-    let active_entry = entry
-      .1
-      .iter()
-      .next()
-      .log_expect(format!("Failed to get initial entry for {:?}", entry.0).as_str());
-    let entry_props = desktop::launcher_props(active_entry.1);
-    let idx = launcher_counter.get_index(entry.0);
-    props.insert(idx as i32, entry_props);
-    if active_entry.1.categories.is_empty() {
-      children
-        .get_mut(&(constants::category_idx(constants::Category::Uncategorized) as i32))
-        .log_expect("Failed to get category reference")
-        .push(idx as i32);
-    } else {
-      let c = active_entry
-        .1
-        .categories
-        .iter()
-        .next()
-        .log_expect("Failed to get first category");
-      children
-        .get_mut(&(constants::category_idx(*c) as i32))
-        .log_expect("Failed to get category reference")
-        .push(idx as i32);
+  let section_ids: Vec<i32> = sections.ids().collect();
+  for (section, members) in snapshot.children {
+    if !section_ids.contains(&section) {
+      continue;
+    }
+    for idx in members.into_iter().filter(|idx| props.contains_key(idx)) {
+      sections.add_member(section, idx, &props);
     }
   }
-
-  info!("Loaded {} menu entries", cache.len());
-
-  update_category_props(&mut children, &mut props);
+  update_category_props(
+    &mut children,
+    &mut props,
+    &mut sections,
+    merge_threshold,
+    None,
+  );
+  let installed: HashMap<String, u64> =
+    util::state::load(constants::INSTALLS_STATE_FILE).unwrap_or_default();
+  update_copies(&mut children, &mut props, &launcher_counter, &installed);
 
   let (tx, rx) = channel();
 
   // Create a watcher object, delivering debounced events.
   // The notification back-end is selected based on the platform.
-  let mut watcher = watcher(tx, Duration::from_secs(10)).unwrap();
+  let mut watcher = watcher(tx.clone(), Duration::from_secs(config::get().debounce_secs))
+    .unwrap_or_else(|err| Fatal::from(err).exit());
 
   // Add a path to be watched. All files and directories at that path and
   // below will be monitored for changes.
-  for dir in app_dirs.iter() {
-    watcher
-      .watch(dir.1, RecursiveMode::Recursive)
-      .log_expect(format!("Failed to watch {:?}", dir.1).as_str());
+  for (_, dir) in app_dirs.iter() {
+    if let Err(err) = watcher.watch(dir, RecursiveMode::Recursive) {
+      error!("Failed to watch {:?}", dir);
+      Fatal::from(err).exit();
+    }
+  }
+  let config_file = config::user_config_path();
+  if let Some(config_dir) = config_file.as_ref().and_then(|file| file.parent()) {
+    let watched = std::fs::create_dir_all(config_dir).and_then(|_| {
+      watcher
+        .watch(config_dir, RecursiveMode::NonRecursive)
+        .map_err(|err| std::io::Error::other(err.to_string()))
+    });
+    if let Err(err) = watched {
+      warn!("Not watching {:?} for config changes: {}", config_dir, err);
+    }
   }
 
   let menu_struct = AppMenuDbusMenu {
-    revision: 0,
+    revision: snapshot.revision,
     children,
     props,
-    cache,
-    path_map: app_dirs,
+    cache: HashMap::new(),
+    app_dirs,
     counter: launcher_counter,
+    icon_retries: IconRetryQueue::default(),
+    lazy_icons: LazyIcons::default(),
+    icon_updates: 0,
+    parse_failures: desktop::ParseFailures::default(),
+    merge_threshold,
+    reachability: util::init::get_network_guard().then(Default::default),
+    warm_layouts: Default::default(),
+    pipe_menus,
+    pipe_items: HashMap::new(),
+    sections,
+    installed,
+    seen_at: util::state::load(constants::SEEN_STATE_FILE).unwrap_or_else(util::state::unix_now),
+    transient: HashMap::new(),
+    next_transient: constants::FIRST_TRANSIENT_IDX,
   };
 
-  let dbus = zbus::ConnectionBuilder::session()
-    .log_expect("Failed to connect to DBUS session")
-    .name("org.wsl.AppMenuDbusMenu");
+  let names = util::init::get_object_names();
+  let dbus = zbus::ConnectionBuilder::session().unwrap_or_else(|err| Fatal::from(err).exit());
+  let menu_only = util::init::get_menu_only();
+  let mut dbus = dbus
+    .serve_at(names.menu_path.as_str(), menu_struct)
+    .and_then(|dbus| {
+      dbus.serve_at(
+        constants::CONTROL_PATH,
+        control::AppMenuControl {
+          events: Some(tx.clone()),
+          ..Default::default()
+        },
+      )
+    })
+    .and_then(|dbus| dbus.serve_at(constants::CONTROL_PATH, apps::AppMenuApps))
+    .unwrap_or_else(|err| Fatal::from(err).exit());
+  if !menu_only {
+    dbus = dbus
+      .serve_at(
+        names.item_path.as_str(),
+        AppMenuStatusNotifierItem {
+          id: names.id.clone(),
+          menu_path: names.menu_path.clone(),
+          category: util::init::get_sni_category(),
+          title: config::get().title.clone(),
+          icon_name: config::get().icon_name.clone(),
+          shown_icon: config::get().icon_name.clone(),
+          icon_pixmap: desktop::icon_pixmaps(
+            &config::get().icon_name,
+            &util::init::get_tray_icon_sizes(),
+          ),
+          tool_tip: String::new(),
+          alerts: vec![],
+          attention_pixmap: vec![],
+          scanning: true,
+          unseen: 0,
+          overlay_pixmap: vec![],
+          last_scroll: None,
+        },
+      )
+      .unwrap_or_else(|err| Fatal::from(err).exit());
+  }
   let connection = dbus
-    .log_expect("Failed to claim DBUS name")
-    .serve_at(
-      "/org/ayatana/NotificationItem/wslAppMenuDbusMenu/Menu",
-      menu_struct,
-    )
-    .log_expect("Failed to set up DBUS menu")
-    .serve_at(
-      "/org/ayatana/NotificationItem/wslAppMenuDbusMenu",
-      AppMenuStatusNotifierItem {},
-    )
-    .log_expect("Failed to set up icon")
     .build()
     .await
-    .log_expect("Failed to launch DBUS menu service");
+    .unwrap_or_else(|err| Fatal::from(err).exit());
+  #[cfg(feature = "gmenu")]
+  gmenu::serve(&connection, &names.menu_path).await;
+  let replace = std::env::args().any(|arg| arg == instance::REPLACE_ARG);
+  instance::claim_name(&connection, &names.bus_name, replace).await;
+  if util::init::get_companion() {
+    #[cfg(feature = "companion")]
+    companion::spawn(names.bus_name.clone());
+    #[cfg(not(feature = "companion"))]
+    warn!("COMPANION requested but this build lacks the companion feature");
+  }
+  if let Some(socket_path) = util::init::get_socket_path() {
+    socket::serve(&connection, &names.menu_path, socket_path).await;
+  }
 
-  let watcher_ref = proxy_types::StatusNotifierWatcherProxy::new(&connection)
-    .await
-    .log_expect("Failed to get watcher reference");
+  if menu_only {
+    info!("Running in menu-only mode, skipping tray icon registration");
+    registration::transition(&connection, registration::RegistrationState::Disabled).await;
+    util::systemd::notify("READY=1");
+  } else {
+    tokio::spawn(registration::maintain(
+      connection.clone(),
+      names.item_path.clone(),
+    ));
+  }
 
-  watcher_ref
-    .register_status_notifier_item("/org/ayatana/NotificationItem/wslAppMenuDbusMenu")
-    .await
-    .log_expect("Failed to register with watcher");
+  tokio::spawn(follow_color_scheme(
+    connection.clone(),
+    names.item_path.clone(),
+  ));
+
+  if let Some(alerts) = alerts {
+    tokio::spawn(show_alerts(
+      connection.clone(),
+      names.item_path.clone(),
+      alerts,
+    ));
+  }
 
   let object_server = connection.object_server();
   let iface_ref = object_server
-    .interface::<_, AppMenuDbusMenu>("/org/ayatana/NotificationItem/wslAppMenuDbusMenu/Menu")
+    .interface::<_, AppMenuDbusMenu>(names.menu_path.as_str())
     .await
     .log_expect("Failed to get reference to menu interface");
 
+  let (cache, parse_failures) = initial_scan(&scan_dirs, &locale);
+  let icons = if config::get().lazy_icons {
+    HashMap::new()
+  } else {
+    let icons = cache.iter().filter_map(|(name, prio_cache)| {
+      desktop::launcher_icon(name, prio_cache.values().next()?).filter(|i| i.contains('/'))
+    });
+    render_file_icons(icons).await
+  };
+  iface_ref
+    .get_mut()
+    .await
+    .populate(cache, parse_failures, icons, iface_ref.signal_context())
+    .await;
+  finish_scanning(&connection, &names.item_path).await;
+  let unseen = iface_ref.get().await.unseen_installs();
+  show_unseen(connection.clone(), unseen).await;
+
+  let retry_connection = connection.clone();
+  let retry_menu_path = names.menu_path.clone();
+  tokio::spawn(async move {
+    let iface_ref = retry_connection
+      .object_server()
+      .interface::<_, AppMenuDbusMenu>(retry_menu_path.as_str())
+      .await
+      .log_expect("Failed to get reference to menu interface for icon retries");
+    let mut ticker = tokio::time::interval(constants::ICON_RETRY_TICK);
+    loop {
+      ticker.tick().await;
+      // Only blocks readers of the menu when an icon is due.
+      if iface_ref.get().await.icon_retries.due().is_empty() {
+        continue;
+      }
+      let mut iface = iface_ref.get_mut().await;
+      iface.retry_icons(iface_ref.signal_context()).await;
+    }
+  });
+
+  let warmup_connection = connection.clone();
+  let warmup_names = names.clone();
+  tokio::spawn(async move {
+    let iface_ref = warmup_connection
+      .object_server()
+      .interface::<_, AppMenuDbusMenu>(warmup_names.menu_path.as_str())
+      .await
+      .log_expect("Failed to get reference to menu interface for warm-up");
+    let mut ticker = tokio::time::interval(constants::WARMUP_TICK);
+    loop {
+      ticker.tick().await;
+      let tool_tip = {
+        let mut iface = iface_ref.get_mut().await;
+        if !iface.warm_up() {
+          continue;
+        }
+        util::snapshot::stage(iface.snapshot());
+        iface.tool_tip()
+      };
+      let item_ref = match warmup_connection
+        .object_server()
+        .interface::<_, AppMenuStatusNotifierItem>(warmup_names.item_path.as_str())
+        .await
+      {
+        Ok(item_ref) => item_ref,
+        Err(_) => continue,
+      };
+      let mut item = item_ref.get_mut().await;
+      if item.tool_tip != tool_tip {
+        item.tool_tip = tool_tip;
+        if let Err(err) = AppMenuStatusNotifierItem::new_tool_tip(item_ref.signal_context()).await {
+          warn!("Failed to signal tooltip update: {}", err);
+        }
+      }
+    }
+  });
+
+  let recent_connection = connection.clone();
+  let recent_menu_path = names.menu_path.clone();
+  tokio::spawn(async move {
+    let iface_ref = recent_connection
+      .object_server()
+      .interface::<_, AppMenuDbusMenu>(recent_menu_path.as_str())
+      .await
+      .log_expect("Failed to get reference to menu interface for recent entry expiry");
+    let mut ticker = tokio::time::interval(constants::RECENT_EXPIRY_TICK);
+    loop {
+      ticker.tick().await;
+      let mut iface = iface_ref.get_mut().await;
+      iface.expire_recent(iface_ref.signal_context()).await;
+    }
+  });
+
+  if let Some(interval) = util::systemd::watchdog_interval() {
+    let watchdog_connection = connection.clone();
+    let watchdog_menu_path = names.menu_path.clone();
+    tokio::spawn(async move {
+      let iface_ref = watchdog_connection
+        .object_server()
+        .interface::<_, AppMenuDbusMenu>(watchdog_menu_path.as_str())
+        .await
+        .log_expect("Failed to get reference to menu interface for the watchdog");
+      let mut ticker = tokio::time::interval(interval);
+      loop {
+        ticker.tick().await;
+        // A menu stuck behind its lock stops the pings, so systemd restarts us.
+        drop(iface_ref.get().await);
+        util::systemd::notify("WATCHDOG=1");
+      }
+    });
+  }
+
+  let signal_connection = connection.clone();
+  let signal_events = tx.clone();
+  tokio::spawn(async move {
+    use tokio::signal::unix::{signal, SignalKind};
+    let signals = signal(SignalKind::terminate()).and_then(|terminate| {
+      Ok((
+        terminate,
+        signal(SignalKind::interrupt())?,
+        signal(SignalKind::hangup())?,
+        signal(SignalKind::user_defined1())?,
+      ))
+    });
+    let (mut terminate, mut interrupt, mut hangup, mut user1) = match signals {
+      Ok(signals) => signals,
+      Err(err) => {
+        warn!("Failed to listen for signals: {}", err);
+        return;
+      },
+    };
+    loop {
+      tokio::select! {
+        _ = terminate.recv() => break,
+        _ = interrupt.recv() => break,
+        _ = hangup.recv() => {
+          info!("Got SIGHUP, reloading the config and rescanning");
+          // Goes through the watcher loop so application directories are
+          // re-enumerated and watched as for a config change.
+          match config::user_config_path() {
+            Some(path) => {
+              if signal_events.send(notify::DebouncedEvent::Write(path)).is_err() {
+                warn!("File watcher stopped, not reloading");
+              }
+            },
+            None => refresh(signal_connection.clone()).await,
+          }
+        },
+        _ = user1.recv() => dump_state(&signal_connection).await,
+      }
+    }
+    info!("Terminating");
+    shut_down(signal_connection).await;
+  });
+
+  let usage_period = Duration::from_secs(config::get().usage_summary_secs);
+  if !usage_period.is_zero() {
+    let usage_connection = connection.clone();
+    let usage_menu_path = names.menu_path.clone();
+    tokio::spawn(async move {
+      let iface_ref = usage_connection
+        .object_server()
+        .interface::<_, AppMenuDbusMenu>(usage_menu_path.as_str())
+        .await
+        .log_expect("Failed to get reference to menu interface for usage summaries");
+      let mut ticker = tokio::time::interval(usage_period);
+      ticker.tick().await;
+      loop {
+        ticker.tick().await;
+        let counts = iface_ref.get().await.section_counts();
+        util::usage::log_summary(usage_period, &counts);
+      }
+    });
+  }
+
+  let try_exec_connection = connection.clone();
+  let try_exec_menu_path = names.menu_path.clone();
+  tokio::spawn(async move {
+    let iface_ref = try_exec_connection
+      .object_server()
+      .interface::<_, AppMenuDbusMenu>(try_exec_menu_path.as_str())
+      .await
+      .log_expect("Failed to get reference to menu interface for TryExec checks");
+    let mut fingerprint = util::launch::path_fingerprint();
+    let mut ticker = tokio::time::interval(constants::TRY_EXEC_POLL);
+    loop {
+      ticker.tick().await;
+      let current = util::launch::path_fingerprint();
+      if current == fingerprint {
+        continue;
+      }
+      fingerprint = current;
+      let mut iface = iface_ref.get_mut().await;
+      iface.revalidate_try_exec(iface_ref.signal_context()).await;
+    }
+  });
+
+  let config_dir = config_file.as_deref().and_then(std::path::Path::parent);
   loop {
     let evt = rx.recv();
+    if let Some(delay) = util::faults::watcher_delay() {
+      tokio::time::sleep(delay).await;
+    }
     use notify::DebouncedEvent::*;
     let mut iface = iface_ref.get_mut().await;
     match evt {
       Ok(event) => match event {
+        Create(path) | Write(path) | NoticeRemove(path) | Rename(_, path)
+          if config_file.as_ref() == Some(&path) =>
+        {
+          info!("Configuration changed, reloading");
+          config::reload();
+          let (added, removed) = iface.sync_app_dirs(iface_ref.signal_context()).await;
+          for dir in removed {
+            if let Err(err) = watcher.unwatch(&dir) {
+              warn!("Failed to stop watching {:?}: {}", dir, err);
+            }
+          }
+          for dir in added {
+            if let Err(err) = watcher.watch(&dir, RecursiveMode::Recursive) {
+              warn!("Failed to watch {:?}: {}", dir, err);
+            }
+          }
+          iface.apply_config(iface_ref.signal_context()).await;
+          drop(iface);
+          apply_tray_config(&connection, &names.item_path).await;
+        },
+        // Nothing else in the config directory is a launcher, such as the
+        // config file's editor backups or the file being removed.
+        Create(path) | Write(path) | NoticeRemove(path) | Remove(path) | Rename(_, path)
+          if config_dir.is_some_and(|dir| path.parent() == Some(dir)) =>
+        {
+          debug!("Ignoring change to {:?} in the config directory", path);
+        },
         Create(path) => {
           info!("New launcher at {:?}", path);
           iface
             .add_launcher_path(&path.to_string_lossy(), iface_ref.signal_context().clone())
             .await;
+          iface
+            .record_install(&path, iface_ref.signal_context())
+            .await;
         },
         Write(path) => {
           info!("Updated launcher at {:?}", path);
@@ -835,9 +4176,122 @@ async fn main() {
             .remove_launcher_path(&path.to_string_lossy(), iface_ref.signal_context().clone())
             .await;
         },
+        // Package managers write entries under a temporary name and rename
+        // them into place.
+        Rename(from, to) => {
+          info!("Launcher moved from {:?} to {:?}", from, to);
+          let is_entry = |p: &std::path::Path| p.extension().is_some_and(|ext| ext == "desktop");
+          if is_entry(&from) {
+            iface
+              .remove_launcher_path(&from.to_string_lossy(), iface_ref.signal_context().clone())
+              .await;
+          }
+          if is_entry(&to) {
+            iface
+              .add_launcher_path(&to.to_string_lossy(), iface_ref.signal_context().clone())
+              .await;
+            iface.record_install(&to, iface_ref.signal_context()).await;
+          }
+        },
         _ => {},
       },
       Err(e) => println!("Watcher error: {:?}", e),
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn submenu(id: i32) -> desktop::MenuProps {
+    desktop::MenuProps {
+      label: format!("Item {}", id),
+      visible: true,
+      enabled: true,
+      icon_name: String::new(),
+      icon_data: desktop::IconData::default(),
+      entry_type: "standard".to_string(),
+      children_display: "submenu".to_string(),
+      accessible_desc: String::new(),
+      x_categories: vec![],
+      toggle_type: String::new(),
+      toggle_state: -1,
+    }
+  }
+
+  /// A menu where each of `edges` makes the second item a child of the first.
+  fn menu(edges: &[(i32, i32)]) -> (HashMap<i32, Vec<i32>>, HashMap<i32, desktop::MenuProps>) {
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    let mut props = HashMap::from([(0, submenu(0))]);
+    for (parent, child) in edges {
+      children.entry(*parent).or_default().push(*child);
+      props.insert(*child, submenu(*child));
+    }
+    (children, props)
+  }
+
+  fn chain(len: i32) -> Vec<(i32, i32)> {
+    (0..len).map(|id| (id, id + 1)).collect()
+  }
+
+  fn child_entries(entry: &DbusMenuLayoutEntry) -> Vec<DbusMenuLayoutEntry> {
+    entry
+      .2
+      .iter()
+      .map(|child| {
+        DbusMenuLayoutEntry::try_from(zbus::zvariant::Value::from(child.clone()))
+          .expect("Layout child is not a layout entry")
+      })
+      .collect()
+  }
+
+  /// Item IDs of a layout, parents before their children.
+  fn entry_ids(entry: &DbusMenuLayoutEntry) -> Vec<i32> {
+    let mut ids = vec![entry.0];
+    for child in child_entries(entry) {
+      ids.extend(entry_ids(&child));
+    }
+    ids
+  }
+
+  fn nesting(entry: &DbusMenuLayoutEntry) -> usize {
+    1 + child_entries(entry).iter().map(nesting).max().unwrap_or(0)
+  }
+
+  #[test]
+  fn layout_skips_cycles() {
+    let (children, props) = menu(&[(0, 1), (1, 2), (2, 1)]);
+    let layout = get_layout(0, &children, &props, &[], -1);
+    assert_eq!(entry_ids(&layout), vec![0, 1, 2]);
+
+    let mut ids = layout_ids(0, -1, &children);
+    ids.sort();
+    assert_eq!(ids, vec![0, 1, 2]);
+  }
+
+  #[test]
+  fn layout_truncates_deep_chains() {
+    let (children, props) = menu(&chain(constants::MAX_LAYOUT_DEPTH as i32 * 2));
+    let layout = get_layout(0, &children, &props, &[], -1);
+    assert_eq!(nesting(&layout), constants::MAX_LAYOUT_DEPTH);
+  }
+
+  #[test]
+  fn layout_honors_recursion_depth() {
+    let (children, props) = menu(&[(0, 1), (0, 2), (1, 3), (3, 4)]);
+    let ids = |depth| entry_ids(&get_layout(0, &children, &props, &[], depth));
+    assert_eq!(ids(-1), vec![0, 1, 3, 4, 2]);
+    assert_eq!(ids(0), vec![0]);
+    assert_eq!(ids(1), vec![0, 1, 2]);
+
+    let sorted_ids = |depth| {
+      let mut ids = layout_ids(0, depth, &children);
+      ids.sort();
+      ids
+    };
+    assert_eq!(sorted_ids(-1), vec![0, 1, 2, 3, 4]);
+    assert_eq!(sorted_ids(0), vec![0]);
+    assert_eq!(sorted_ids(1), vec![0, 1, 2]);
+  }
+}
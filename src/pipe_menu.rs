@@ -0,0 +1,125 @@
+//! Openbox-style pipe menus: submenus whose items come from a script's JSON
+//! output, regenerated each time the submenu is about to be shown.
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use log::warn;
+use serde::Deserialize;
+
+use crate::{config, constants, desktop, util};
+
+/// One item printed by a pipe menu script. Items without `exec` or `argv` are
+/// shown as disabled labels, and items with `separator` set as a divider.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct PipeItem {
+  #[serde(default)]
+  pub label: String,
+  #[serde(default)]
+  pub separator: bool,
+  #[serde(default)]
+  pub icon: Option<String>,
+  /// Command line, quoted like a desktop file's `Exec`.
+  #[serde(default)]
+  pub exec: Option<String>,
+  /// Command and arguments, used instead of `exec` when set.
+  #[serde(default)]
+  pub argv: Vec<String>,
+  /// Variables added to the environment of the command.
+  #[serde(default)]
+  pub env: HashMap<String, String>,
+  /// Working directory of the command.
+  #[serde(default)]
+  pub cwd: Option<PathBuf>,
+}
+
+/// Runs the menu's command and parses its output as a JSON array of items.
+pub async fn run_script(menu: &config::PipeMenu) -> Result<Vec<PipeItem>, String> {
+  let args = util::xdg::exec_tokenize(&menu.command)
+    .filter(|args| !args.is_empty())
+    .ok_or_else(|| format!("Invalid command '{}'", menu.command))?;
+  let output = util::sandbox::output(
+    tokio::process::Command::new(&args[0]).args(&args[1..]),
+    constants::PIPE_MENU_TIMEOUT,
+  )
+  .await
+  .map_err(|e| format!("Failed to run '{}': {}", menu.command, e))?;
+  if !output.status.success() {
+    warn!("'{}' exited with {}", menu.command, output.status);
+  }
+  serde_json::from_slice(&output.stdout)
+    .map_err(|e| format!("Invalid output from '{}': {}", menu.command, e))
+}
+
+pub fn menu_props(menu: &config::PipeMenu) -> desktop::MenuProps {
+  desktop::MenuProps {
+    label: menu.label.clone(),
+    visible: true,
+    icon_name: menu.icon.clone().unwrap_or_default(),
+    entry_type: "standard".to_string(),
+    children_display: "submenu".to_string(),
+    icon_data: desktop::IconData::default(),
+    enabled: true,
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: String::new(),
+    toggle_state: -1,
+  }
+}
+
+pub fn item_props(item: &PipeItem) -> desktop::MenuProps {
+  if item.separator {
+    return desktop::separator_props();
+  }
+  desktop::MenuProps {
+    label: item.label.clone(),
+    visible: true,
+    icon_name: item.icon.clone().unwrap_or_default(),
+    entry_type: "standard".to_string(),
+    children_display: String::new(),
+    icon_data: desktop::IconData::default(),
+    enabled: item.exec.is_some() || !item.argv.is_empty(),
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: String::new(),
+    toggle_state: -1,
+  }
+}
+
+/// Launcher running the item's command when it is clicked.
+pub fn item_launcher(item: &PipeItem) -> Option<desktop::Launcher> {
+  if item.separator {
+    return None;
+  }
+  if item.exec.is_none() && item.argv.is_empty() {
+    return None;
+  }
+  let exec = util::xdg::command_argv(item.exec.as_deref().unwrap_or_default(), &item.argv);
+  if exec.is_none() {
+    warn!("Ignoring pipe menu item '{}' with invalid exec", item.label);
+  }
+  Some(desktop::Launcher {
+    path: config::user_config_path().unwrap_or_default(),
+    name: item.label.clone(),
+    generic_name: None,
+    comment: None,
+    keywords: vec![],
+    categories: vec![],
+    category_names: vec![],
+    exec: exec?,
+    file_code: None,
+    working_dir: item.cwd.clone(),
+    env: item
+      .env
+      .iter()
+      .map(|(k, v)| (k.clone(), v.clone()))
+      .collect(),
+    icon: item.icon.clone(),
+    display: true,
+    terminal: false,
+    runnable: true,
+    try_exec: None,
+    try_exec_missing: false,
+    tombstone: false,
+    deleted: false,
+  })
+}
@@ -1,4 +1,4 @@
-#![allow(non_snake_case)]
+#![allow(non_snake_case, clippy::too_many_arguments)]
 use zbus::dbus_proxy;
 
 #[dbus_proxy(
@@ -40,3 +40,42 @@ pub trait StatusNotifierWatcher {
   #[dbus_proxy(property)]
   fn registered_status_notifier_items(&self) -> zbus::Result<Vec<String>>;
 }
+
+#[dbus_proxy(
+  interface = "org.freedesktop.Notifications",
+  default_service = "org.freedesktop.Notifications",
+  default_path = "/org/freedesktop/Notifications"
+)]
+pub trait Notifications {
+  /// Notify method
+  fn notify(
+    &self,
+    app_name: &str,
+    replaces_id: u32,
+    app_icon: &str,
+    summary: &str,
+    body: &str,
+    actions: &[&str],
+    hints: std::collections::HashMap<&str, zbus::zvariant::Value<'_>>,
+    expire_timeout: i32,
+  ) -> zbus::Result<u32>;
+}
+
+#[dbus_proxy(
+  interface = "org.freedesktop.portal.Settings",
+  default_service = "org.freedesktop.portal.Desktop",
+  default_path = "/org/freedesktop/portal/desktop"
+)]
+pub trait Settings {
+  /// Read method
+  fn read(&self, namespace: &str, key: &str) -> zbus::Result<zbus::zvariant::OwnedValue>;
+
+  /// SettingChanged signal
+  #[dbus_proxy(signal)]
+  fn setting_changed(
+    &self,
+    namespace: &str,
+    key: &str,
+    value: zbus::zvariant::Value<'_>,
+  ) -> zbus::Result<()>;
+}
@@ -0,0 +1,273 @@
+use std::fmt;
+
+use futures_util::StreamExt;
+use log::{info, warn};
+
+use crate::{constants, control, proxy_types};
+
+/// Lifecycle of the StatusNotifierItem registration with the watcher.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationState {
+  /// Menu-only mode, no tray item is registered.
+  Disabled,
+  #[default]
+  WaitingForWatcher,
+  Registered,
+  /// Registered, but the watcher reports no host to display the item.
+  HostLost,
+  /// The watcher restarted and the item is being registered again.
+  ReRegistering,
+}
+
+impl RegistrationState {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      RegistrationState::Disabled => "disabled",
+      RegistrationState::WaitingForWatcher => "waiting-for-watcher",
+      RegistrationState::Registered => "registered",
+      RegistrationState::HostLost => "host-lost",
+      RegistrationState::ReRegistering => "re-registering",
+    }
+  }
+}
+
+impl fmt::Display for RegistrationState {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+/// Records a state change on the control object, logging and signalling it.
+pub async fn transition(connection: &zbus::Connection, to: RegistrationState) {
+  let control_ref = match connection
+    .object_server()
+    .interface::<_, control::AppMenuControl>(constants::CONTROL_PATH)
+    .await
+  {
+    Ok(control_ref) => control_ref,
+    Err(err) => {
+      warn!(
+        "Failed to get control interface for registration state: {}",
+        err
+      );
+      return;
+    },
+  };
+  let mut control = control_ref.get_mut().await;
+  if control.registration == to {
+    return;
+  }
+  info!("Tray registration: {} -> {}", control.registration, to);
+  control.registration = to;
+  control.registration_transitions += 1;
+  let ctxt = control_ref.signal_context();
+  if let Err(err) = control.registration_state_changed(ctxt).await {
+    warn!("Failed to signal registration state: {}", err);
+  }
+  if let Err(err) = control.registration_transitions_changed(ctxt).await {
+    warn!("Failed to signal registration transitions: {}", err);
+  }
+}
+
+/// Claims the per-instance name the spec has items register with, or returns
+/// `None` to register by object path.
+async fn claim_sni_name(connection: &zbus::Connection, item_path: &str) -> Option<String> {
+  if !crate::util::init::get_sni_unique_name() {
+    return None;
+  }
+  if item_path != constants::SNI_ITEM_PATH {
+    warn!(
+      "Registering by object path, as hosts only look for named items at {}",
+      constants::SNI_ITEM_PATH
+    );
+    return None;
+  }
+  let name = format!("{}-{}-1", constants::SNI_NAME_PREFIX, std::process::id());
+  match connection.request_name(name.as_str()).await {
+    Ok(()) => {
+      info!("Acquired {}", name);
+      Some(name)
+    },
+    Err(err) => {
+      warn!(
+        "Failed to acquire {}, registering by object path: {}",
+        name, err
+      );
+      None
+    },
+  }
+}
+
+/// Whether a watcher's item entry, a service name optionally followed by a
+/// path, refers to this item: our per-instance name when registered by it,
+/// otherwise our unique connection name with `item_path`.
+fn is_ours(
+  item: &str,
+  connection: &zbus::Connection,
+  item_path: &str,
+  service: Option<&str>,
+) -> bool {
+  let (name, path) = item.split_at(item.find('/').unwrap_or(item.len()));
+  match service {
+    Some(service) => name == service && (path.is_empty() || path == item_path),
+    None => {
+      path == item_path
+        && connection
+          .unique_name()
+          .is_some_and(|unique| unique.as_str() == name)
+    },
+  }
+}
+
+async fn watcher_present(bus: &zbus::fdo::DBusProxy<'_>, watcher_name: &str) -> bool {
+  match zbus::names::BusName::try_from(watcher_name) {
+    Ok(name) => bus.name_has_owner(name).await.unwrap_or(false),
+    Err(_) => false,
+  }
+}
+
+/// Whether the watcher still lists the item, assuming so if it can't be asked.
+async fn still_registered(
+  watcher: &proxy_types::StatusNotifierWatcherProxy<'_>,
+  connection: &zbus::Connection,
+  item_path: &str,
+  service: Option<&str>,
+) -> bool {
+  match watcher.registered_status_notifier_items().await {
+    Ok(items) => items
+      .iter()
+      .any(|item| is_ours(item, connection, item_path, service)),
+    Err(err) => {
+      warn!("Failed to list registered items: {}", err);
+      true
+    },
+  }
+}
+
+/// Keeps the tray item registered for as long as the process runs, following
+/// the watcher and hosts as they come and go.
+pub async fn maintain(connection: zbus::Connection, item_path: String) {
+  let watcher = match proxy_types::StatusNotifierWatcherProxy::builder(&connection)
+    .cache_properties(zbus::CacheProperties::No)
+    .build()
+    .await
+  {
+    Ok(watcher) => watcher,
+    Err(err) => {
+      warn!("Failed to create watcher proxy: {}", err);
+      return;
+    },
+  };
+  let bus = match zbus::fdo::DBusProxy::new(&connection).await {
+    Ok(bus) => bus,
+    Err(err) => {
+      warn!("Failed to create bus proxy: {}", err);
+      return;
+    },
+  };
+  let streams = futures_util::try_join!(
+    bus.receive_name_owner_changed(),
+    watcher.receive_status_notifier_host_registered(),
+    watcher.receive_status_notifier_host_unregistered(),
+    watcher.receive_status_notifier_item_unregistered(),
+  );
+  let (name_changes, mut hosts_added, mut hosts_removed, mut items_removed) = match streams {
+    Ok(streams) => streams,
+    Err(err) => {
+      warn!("Failed to subscribe to watcher signals: {}", err);
+      return;
+    },
+  };
+
+  let watcher_name = watcher.destination().to_string();
+  let mut owner_changes = name_changes.filter_map(|signal| {
+    let owner = signal
+      .args()
+      .ok()
+      .filter(|args| args.name().as_str() == watcher_name)
+      .map(|args| args.new_owner().is_some());
+    std::future::ready(owner)
+  });
+
+  let mut state = RegistrationState::WaitingForWatcher;
+  let mut was_registered = false;
+  let mut retry = constants::REGISTRATION_RETRY;
+  let mut service = claim_sni_name(&connection, &item_path).await;
+  transition(&connection, state).await;
+  loop {
+    state = match state {
+      RegistrationState::WaitingForWatcher | RegistrationState::ReRegistering => {
+        let target = service.as_deref().unwrap_or(&item_path);
+        match watcher.register_status_notifier_item(target).await {
+          Ok(()) => {
+            if !was_registered {
+              crate::util::systemd::notify("READY=1");
+            }
+            was_registered = true;
+            retry = constants::REGISTRATION_RETRY;
+            match watcher.is_status_notifier_host_registered().await {
+              Ok(false) => RegistrationState::HostLost,
+              _ => RegistrationState::Registered,
+            }
+          },
+          Err(err) if service.is_some() && watcher_present(&bus, &watcher_name).await => {
+            warn!(
+              "Watcher rejected {}, registering by object path instead: {}",
+              target, err
+            );
+            if let Err(err) = connection.release_name(target).await {
+              warn!("Failed to release {}: {}", target, err);
+            }
+            service = None;
+            RegistrationState::WaitingForWatcher
+          },
+          Err(err) => {
+            warn!(
+              "Failed to register with watcher, retrying in {:?} or once it appears: {}",
+              retry, err
+            );
+            transition(&connection, RegistrationState::WaitingForWatcher).await;
+            let delay = retry;
+            retry = (retry * 2).min(constants::REGISTRATION_RETRY_MAX);
+            tokio::select! {
+              owner = owner_changes.next() => match owner {
+                Some(true) if was_registered => RegistrationState::ReRegistering,
+                _ => RegistrationState::WaitingForWatcher,
+              },
+              _ = tokio::time::sleep(delay) => RegistrationState::WaitingForWatcher,
+            }
+          },
+        }
+      },
+      RegistrationState::Registered | RegistrationState::HostLost => tokio::select! {
+        owner = owner_changes.next() => match owner {
+          Some(true) => RegistrationState::ReRegistering,
+          _ => RegistrationState::WaitingForWatcher,
+        },
+        // A restarted host may come with a watcher that lost track of us.
+        _ = hosts_added.next() => {
+          match still_registered(&watcher, &connection, &item_path, service.as_deref()).await {
+            true => RegistrationState::Registered,
+            false => RegistrationState::ReRegistering,
+          }
+        },
+        Some(removed) = items_removed.next() => {
+          let service = service.as_deref();
+          let ours = removed
+            .args()
+            .is_ok_and(|args| is_ours(args.service(), &connection, &item_path, service));
+          match ours && !still_registered(&watcher, &connection, &item_path, service).await {
+            true => RegistrationState::ReRegistering,
+            false => state,
+          }
+        },
+        _ = hosts_removed.next() => match watcher.is_status_notifier_host_registered().await {
+          Ok(true) => RegistrationState::Registered,
+          _ => RegistrationState::HostLost,
+        },
+      },
+      RegistrationState::Disabled => return,
+    };
+    transition(&connection, state).await;
+  }
+}
@@ -0,0 +1,319 @@
+//! Top-level submenus. Entries are grouped by category, where the order,
+//! labels and categories of each submenu come from the config, defaulting to
+//! one per built-in category; by the kind of source they were installed
+//! from; or in one submenu per initial letter of their shown name.
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::{config, constants, desktop};
+
+/// How entries are grouped into top-level submenus.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupBy {
+  Category,
+  Source,
+  Initial,
+}
+
+/// Kinds of source shown when grouping by source, in display order.
+#[derive(Debug, Clone, Copy, PartialEq, enum_iterator::Sequence)]
+enum Source {
+  System,
+  Flatpak,
+  User,
+  Windows,
+}
+
+fn source_label(source: Source) -> (&'static str, &'static str) {
+  match source {
+    Source::System => ("System", "applications-system"),
+    Source::Flatpak => ("Flatpak", "package-x-generic"),
+    Source::User => ("User", "user-home"),
+    Source::Windows => ("Windows", "applications-other"),
+  }
+}
+
+/// Section labels when grouping by initial; names starting with anything
+/// but a letter go in the last.
+const INITIALS: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ#";
+
+#[derive(Debug)]
+pub struct Sections {
+  group_by: GroupBy,
+  /// Directories holding the user's own entries, when grouping by source.
+  user_dirs: Vec<PathBuf>,
+  /// Menu item IDs in display order, with their properties.
+  items: Vec<(i32, desktop::MenuProps)>,
+  by_category: HashMap<constants::Category, i32>,
+  /// Sections claiming raw `Categories` values that aren't built-in categories.
+  by_name: HashMap<String, i32>,
+  /// Section for entries no other section claims.
+  fallback: i32,
+  /// Launcher menu item IDs listed in each section, before any nesting.
+  members: HashMap<i32, Vec<i32>>,
+  /// Submenu IDs generated inside sections, by section and name, kept
+  /// stable across updates.
+  submenus: HashMap<(i32, String), i32>,
+}
+
+fn section_props(label: &str, icon: Option<&str>) -> desktop::MenuProps {
+  desktop::MenuProps {
+    label: label.to_string(),
+    visible: true,
+    icon_name: crate::util::appearance::themed_icon(icon.unwrap_or_default()),
+    entry_type: "standard".to_string(),
+    children_display: "submenu".to_string(),
+    icon_data: desktop::IconData::default(),
+    enabled: true,
+    accessible_desc: String::new(),
+    x_categories: vec![],
+    toggle_type: String::new(),
+    toggle_state: -1,
+  }
+}
+
+/// Icon name of a category submenu, configured or the standard theme icon.
+pub fn category_icon(category: constants::Category) -> String {
+  config::get()
+    .category_icons
+    .iter()
+    .find(|(name, _)| constants::category_by_name(name) == Some(category))
+    .map(|(_, icon)| icon.clone())
+    .unwrap_or_else(|| constants::category_icon(category).to_string())
+}
+
+/// Whether a launcher runs a Windows program through WSL interop.
+pub fn is_windows(launcher: &desktop::Launcher) -> bool {
+  launcher
+    .exec
+    .first()
+    .is_some_and(|program| program.starts_with("/mnt/") || program.to_lowercase().ends_with(".exe"))
+}
+
+impl Sections {
+  fn new(group_by: GroupBy) -> Sections {
+    Sections {
+      group_by,
+      user_dirs: Vec::new(),
+      items: Vec::new(),
+      by_category: HashMap::new(),
+      by_name: HashMap::new(),
+      fallback: constants::FIRST_SECTION_IDX,
+      members: HashMap::new(),
+      submenus: HashMap::new(),
+    }
+  }
+
+  pub fn from_config() -> Sections {
+    match crate::util::init::get_group_by() {
+      GroupBy::Category => Sections::by_category(),
+      GroupBy::Source => Sections::by_source(),
+      GroupBy::Initial => Sections::by_initial(),
+    }
+  }
+
+  fn by_source() -> Sections {
+    let mut sections = Sections::new(GroupBy::Source);
+    if let Ok(xdg_dirs) = xdg::BaseDirectories::new() {
+      sections.user_dirs = vec![xdg_dirs.get_data_home(), xdg_dirs.get_config_home()];
+    }
+    for (i, source) in enum_iterator::all::<Source>().enumerate() {
+      let (label, icon) = source_label(source);
+      sections.items.push((
+        constants::FIRST_SECTION_IDX + i as i32,
+        section_props(label, Some(icon)),
+      ));
+    }
+    sections
+  }
+
+  fn by_initial() -> Sections {
+    let mut sections = Sections::new(GroupBy::Initial);
+    for (i, initial) in INITIALS.chars().enumerate() {
+      sections.items.push((
+        constants::FIRST_SECTION_IDX + i as i32,
+        section_props(&initial.to_string(), None),
+      ));
+    }
+    sections
+  }
+
+  fn by_category() -> Sections {
+    let mut configured = config::get().categories.clone();
+    if configured.is_empty() {
+      configured = enum_iterator::all::<constants::Category>()
+        .map(|c| config::MenuCategory {
+          label: constants::category_string(c).to_string(),
+          icon: Some(category_icon(c)),
+          include: vec![constants::category_string(c).to_string()],
+        })
+        .collect();
+    }
+    let max = (constants::FIRST_LAUNCHER_IDX as i32 - constants::FIRST_SECTION_IDX) as usize;
+    if configured.len() > max {
+      log::warn!("Only the first {} configured categories are shown", max);
+      configured.truncate(max);
+    }
+    let mut sections = Sections::new(GroupBy::Category);
+    sections.fallback = constants::FIRST_SECTION_IDX + configured.len() as i32 - 1;
+    for (i, section) in configured.iter().enumerate() {
+      let idx = constants::FIRST_SECTION_IDX + i as i32;
+      // Sections without an icon of their own show that of the first
+      // built-in category they include.
+      let icon = section.icon.clone().or_else(|| {
+        section
+          .include
+          .iter()
+          .find_map(|name| constants::category_by_name(name))
+          .map(category_icon)
+      });
+      sections
+        .items
+        .push((idx, section_props(&section.label, icon.as_deref())));
+      for name in section.include.iter() {
+        match constants::category_by_name(name) {
+          Some(category) => {
+            sections.by_category.entry(category).or_insert(idx);
+          },
+          None => {
+            sections.by_name.entry(name.clone()).or_insert(idx);
+          },
+        }
+      }
+    }
+    if let Some(idx) = sections
+      .by_category
+      .get(&constants::Category::Uncategorized)
+    {
+      sections.fallback = *idx;
+    }
+    sections
+  }
+
+  /// Section menu item IDs in display order.
+  pub fn ids(&self) -> impl Iterator<Item = i32> + '_ {
+    self.items.iter().map(|(idx, _)| *idx)
+  }
+
+  pub fn items(&self) -> &[(i32, desktop::MenuProps)] {
+    &self.items
+  }
+
+  pub fn members(&self, section: i32) -> &[i32] {
+    self.members.get(&section).map_or(&[], Vec::as_slice)
+  }
+
+  /// Adds a launcher to a section, keeping its members sorted by label.
+  pub fn add_member(&mut self, section: i32, id: i32, props: &HashMap<i32, desktop::MenuProps>) {
+    let label = |k: &i32| props.get(k).map(|p| p.label.as_str());
+    let members = self.members.entry(section).or_default();
+    let pos = members.partition_point(|k| label(k) <= label(&id));
+    members.insert(pos, id);
+  }
+
+  /// Sorts the members of every section by label again.
+  pub fn sort_members(&mut self, props: &HashMap<i32, desktop::MenuProps>) {
+    let label = |k: &i32| props.get(k).map(|p| p.label.as_str());
+    for members in self.members.values_mut() {
+      members.sort_by(|a, b| label(a).cmp(&label(b)));
+    }
+  }
+
+  /// Empties every section.
+  pub fn clear_members(&mut self) {
+    self.members.clear();
+  }
+
+  pub fn remove_member(&mut self, section: i32, id: i32) {
+    if let Some(members) = self.members.get_mut(&section) {
+      members.retain(|i| *i != id);
+    }
+  }
+
+  /// ID of the submenu `name` inside `section`, handing out a new one the
+  /// first time it is asked for.
+  pub fn submenu_id(&mut self, section: i32, name: &str) -> i32 {
+    let next = constants::FIRST_SUBMENU_IDX + self.submenus.len() as i32;
+    *self
+      .submenus
+      .entry((section, name.to_string()))
+      .or_insert(next)
+  }
+
+  /// IDs of all submenus handed out so far.
+  pub fn submenu_ids(&self) -> impl Iterator<Item = i32> + '_ {
+    self.submenus.values().copied()
+  }
+
+  /// IDs of the submenus handed out inside `section`.
+  pub fn submenus_of(&self, section: i32) -> impl Iterator<Item = i32> + '_ {
+    self
+      .submenus
+      .iter()
+      .filter(move |((s, _), _)| *s == section)
+      .map(|(_, id)| *id)
+  }
+
+  /// Section the launcher with desktop-file ID `id` is listed in. By
+  /// initial, that of its shown name, overrides included.
+  pub fn section_of(&self, id: &std::ffi::OsStr, launcher: &desktop::Launcher) -> i32 {
+    match self.group_by {
+      GroupBy::Category => self.category_section(launcher),
+      GroupBy::Source => {
+        let source = self.source_of(launcher);
+        constants::FIRST_SECTION_IDX
+          + enum_iterator::all::<Source>()
+            .position(|s| s == source)
+            .unwrap_or_default() as i32
+      },
+      GroupBy::Initial => {
+        let initial = desktop::full_name(id, launcher)
+          .chars()
+          .next()
+          .and_then(|c| c.to_uppercase().next())
+          .and_then(|c| INITIALS.find(c))
+          .unwrap_or(INITIALS.len() - 1);
+        constants::FIRST_SECTION_IDX + initial as i32
+      },
+    }
+  }
+
+  fn source_of(&self, launcher: &desktop::Launcher) -> Source {
+    if is_windows(launcher) {
+      Source::Windows
+    } else if launcher
+      .path
+      .components()
+      .any(|c| c.as_os_str() == "flatpak")
+    {
+      Source::Flatpak
+    } else if self
+      .user_dirs
+      .iter()
+      .any(|dir| launcher.path.starts_with(dir))
+    {
+      Source::User
+    } else {
+      Source::System
+    }
+  }
+
+  /// The first of the launcher's raw categories a section claims, else the
+  /// section of its main category.
+  fn category_section(&self, launcher: &desktop::Launcher) -> i32 {
+    launcher
+      .category_names
+      .iter()
+      .find_map(|name| self.by_name.get(name))
+      .or_else(|| {
+        self.by_category.get(
+          launcher
+            .categories
+            .first()
+            .unwrap_or(&constants::Category::Uncategorized),
+        )
+      })
+      .copied()
+      .unwrap_or(self.fallback)
+  }
+}
@@ -0,0 +1,235 @@
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log_err::*;
+
+use crate::{config, constants, proxy_types, util};
+
+const UNIT_NAME: &str = "xdg-app-ksni.service";
+const AUTOSTART_NAME: &str = "xdg-app-ksni.desktop";
+
+enum InstallMode {
+  Systemd,
+  Autostart,
+  Skip,
+}
+
+fn prompt(question: &str, default: &str) -> String {
+  print!("{} [{}]: ", question, default);
+  let _ = std::io::stdout().flush();
+  let mut answer = String::new();
+  match std::io::stdin().lock().read_line(&mut answer) {
+    Ok(_) if !answer.trim().is_empty() => answer.trim().to_string(),
+    _ => default.to_string(),
+  }
+}
+
+/// Picks the desktop name entries are filtered against, preferring the first
+/// `XDG_CURRENT_DESKTOP` component.
+fn detect_desktop() -> String {
+  std::env::var("XDG_CURRENT_DESKTOP")
+    .ok()
+    .and_then(|d| d.split(':').next().map(str::to_string))
+    .filter(|d| !d.is_empty())
+    .or_else(|| {
+      std::env::var("DESKTOP_SESSION")
+        .ok()
+        .filter(|d| !d.is_empty())
+    })
+    .unwrap_or_else(|| "GNOME".to_string())
+}
+
+fn write_file(path: &Path, contents: &str) -> bool {
+  if let Some(parent) = path.parent() {
+    if let Err(err) = std::fs::create_dir_all(parent) {
+      eprintln!("Failed to create {:?}: {}", parent, err);
+      return false;
+    }
+  }
+  match std::fs::write(path, contents) {
+    Ok(()) => {
+      println!("Wrote {:?}", path);
+      true
+    },
+    Err(err) => {
+      eprintln!("Failed to write {:?}: {}", path, err);
+      false
+    },
+  }
+}
+
+fn install_systemd(exe: &Path) -> bool {
+  let xdg_dirs = xdg::BaseDirectories::new().log_expect("Failed to init XDG directories");
+  let unit = format!(
+    "[Unit]\n\
+     Description=XDG application menu tray item\n\
+     PartOf=graphical-session.target\n\
+     After=graphical-session.target\n\n\
+     [Service]\n\
+     Type=notify\n\
+     WatchdogSec=30\n\
+     ExecStart={}\n\
+     Restart=on-failure\n\
+     RestartPreventExitStatus=2 3\n\n\
+     [Install]\n\
+     WantedBy=graphical-session.target\n",
+    exe.display()
+  );
+  let unit_path = xdg_dirs
+    .get_config_home()
+    .join("systemd/user")
+    .join(UNIT_NAME);
+  if !write_file(&unit_path, &unit) {
+    return false;
+  }
+  for args in [vec!["daemon-reload"], vec!["enable", "--now", UNIT_NAME]] {
+    let status = std::process::Command::new("systemctl")
+      .arg("--user")
+      .args(&args)
+      .status();
+    match status {
+      Ok(status) if status.success() => {},
+      Ok(status) => {
+        eprintln!("systemctl --user {} exited with {}", args.join(" "), status);
+        return false;
+      },
+      Err(err) => {
+        eprintln!("Failed to run systemctl: {}", err);
+        return false;
+      },
+    }
+  }
+  true
+}
+
+fn install_autostart(exe: &Path) -> bool {
+  let xdg_dirs = xdg::BaseDirectories::new().log_expect("Failed to init XDG directories");
+  let entry = format!(
+    "[Desktop Entry]\n\
+     Type=Application\n\
+     Name=Application Menu\n\
+     Exec={}\n\
+     NoDisplay=true\n\
+     X-GNOME-Autostart-enabled=true\n",
+    exe.display()
+  );
+  let entry_path = xdg_dirs
+    .get_config_home()
+    .join("autostart")
+    .join(AUTOSTART_NAME);
+  if !write_file(&entry_path, &entry) {
+    return false;
+  }
+  println!("The menu will start with your next login");
+  true
+}
+
+/// Polls the StatusNotifierWatcher until an item owned by our bus name shows up.
+async fn verify_tray_icon() -> bool {
+  let connection = match zbus::Connection::session().await {
+    Ok(connection) => connection,
+    Err(err) => {
+      eprintln!("Failed to connect to the session bus: {}", err);
+      return false;
+    },
+  };
+  let dbus = zbus::fdo::DBusProxy::new(&connection)
+    .await
+    .log_expect("Failed to get bus proxy");
+  let watcher = proxy_types::StatusNotifierWatcherProxy::new(&connection)
+    .await
+    .log_expect("Failed to get watcher reference");
+  for _ in 0..10 {
+    let owner = dbus
+      .get_name_owner(
+        zbus::names::BusName::try_from(util::init::get_object_names().bus_name)
+          .log_expect("Failed to parse bus name"),
+      )
+      .await;
+    if let (Ok(owner), Ok(items)) = (owner, watcher.registered_status_notifier_items().await) {
+      for item in items {
+        let name = item.split('/').next().unwrap_or_default();
+        if name == owner.as_str() {
+          return true;
+        }
+        // Items registered by `sni_unique_name` list their per-instance name.
+        if name.starts_with(constants::SNI_NAME_PREFIX) {
+          let name_owner = match zbus::names::BusName::try_from(name) {
+            Ok(name) => dbus.get_name_owner(name).await.ok(),
+            Err(_) => None,
+          };
+          if name_owner.as_ref() == Some(&owner) {
+            return true;
+          }
+        }
+      }
+    }
+    tokio::time::sleep(Duration::from_secs(1)).await;
+  }
+  false
+}
+
+/// Interactive `setup` subcommand: writes a config suited to the current
+/// desktop, installs a service or autostart entry and checks that the tray
+/// item registers.
+pub async fn run() {
+  let exe = std::env::current_exe().log_expect("Failed to locate own binary");
+  if let Some(existing) = config::config_path() {
+    println!("Keeping existing configuration at {:?}", existing);
+  } else {
+    let desktop = prompt("Desktop to show entries for", &detect_desktop());
+    let xdg_dirs = xdg::BaseDirectories::with_prefix("xdg-app-ksni")
+      .log_expect("Failed to init XDG directories");
+    let config_file: PathBuf = xdg_dirs.get_config_home().join("config.toml");
+    if !write_file(
+      &config_file,
+      &format!(
+        "desktops = {}\nlog_level = \"INFO\"\n",
+        toml::Value::from(vec![desktop])
+      ),
+    ) {
+      std::process::exit(1);
+    }
+  }
+
+  let mode = loop {
+    match prompt(
+      "Install as a [s]ystemd user service, an [a]utostart entry, or [n]either?",
+      "s",
+    )
+    .to_lowercase()
+    .as_str()
+    {
+      "s" | "systemd" => break InstallMode::Systemd,
+      "a" | "autostart" => break InstallMode::Autostart,
+      "n" | "neither" => break InstallMode::Skip,
+      other => println!("Unrecognized choice '{}'", other),
+    }
+  };
+  match mode {
+    InstallMode::Systemd => {
+      if !install_systemd(&exe) {
+        std::process::exit(1);
+      }
+    },
+    InstallMode::Autostart => {
+      if !install_autostart(&exe) {
+        std::process::exit(1);
+      }
+      return;
+    },
+    InstallMode::Skip => return,
+  }
+
+  println!("Waiting for the tray icon to register...");
+  if verify_tray_icon().await {
+    println!("Tray icon registered, setup complete");
+  } else {
+    eprintln!(
+      "Tray icon did not register; check `journalctl --user -u {}`",
+      UNIT_NAME
+    );
+    std::process::exit(1);
+  }
+}
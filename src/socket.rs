@@ -0,0 +1,180 @@
+//! Local UNIX socket serving the menu as newline-delimited JSON, for status
+//! bars that can't easily speak D-Bus. Clients send one command per line:
+//!
+//! - `list`: replies with the menu tree.
+//! - `launch <id>`: launches a menu item ID or desktop-file ID, replying
+//!   `{"ok": true}` or `{"error": "..."}`.
+//! - `subscribe`: replies with the menu tree, then again whenever it changes,
+//!   until the client disconnects.
+use std::collections::HashSet;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::{constants, AppMenuDbusMenu};
+
+type MenuRef = zbus::InterfaceRef<AppMenuDbusMenu>;
+
+/// The visible menu below `id`, as nested objects.
+fn item_json(menu: &AppMenuDbusMenu, id: i32, on_path: &mut HashSet<i32>) -> Option<Value> {
+  if on_path.contains(&id) {
+    return None;
+  }
+  let props = menu.props.get(&id).filter(|p| p.visible)?;
+  let mut item = json!({
+    "id": id,
+    "label": props.label,
+    "enabled": props.enabled,
+  });
+  if props.entry_type == "separator" {
+    item["separator"] = json!(true);
+  }
+  if !props.icon_name.is_empty() {
+    item["icon"] = json!(props.icon_name);
+  }
+  if let Some(path) = menu
+    .counter
+    .get_path(&(constants::launcher_idx(id) as usize))
+  {
+    item["app"] = json!(path.to_string_lossy());
+  }
+  if props.children_display == "submenu" {
+    on_path.insert(id);
+    let children: Vec<Value> = if on_path.len() <= constants::MAX_LAYOUT_DEPTH {
+      crate::prune_separators(
+        menu.children.get(&id).map_or(&[], Vec::as_slice),
+        &menu.props,
+      )
+      .into_iter()
+      .filter_map(|child| item_json(menu, child, on_path))
+      .collect()
+    } else {
+      Vec::new()
+    };
+    on_path.remove(&id);
+    item["children"] = json!(children);
+  }
+  Some(item)
+}
+
+fn menu_json(menu: &AppMenuDbusMenu) -> Value {
+  json!({
+    "revision": menu.revision,
+    "menu": item_json(menu, 0, &mut HashSet::new()),
+  })
+}
+
+async fn launch(menu_ref: &MenuRef, target: &str) -> Value {
+  let menu = menu_ref.get().await;
+  if let Ok(id) = target.parse::<i32>() {
+    if !menu.props.contains_key(&id) {
+      return json!({ "error": format!("No menu item {}", id) });
+    }
+    let ctxt = menu_ref.signal_context().clone();
+    menu
+      .event(id, "clicked", zbus::zvariant::Value::new(0), 0, ctxt)
+      .await;
+    return json!({ "ok": true });
+  }
+  if menu.launch_app(menu_ref.signal_context().connection(), target, &[]) {
+    json!({ "ok": true })
+  } else {
+    json!({ "error": format!("No active entry for '{}'", target) })
+  }
+}
+
+async fn send(stream: &mut tokio::net::unix::OwnedWriteHalf, reply: &Value) -> bool {
+  let line = format!("{}\n", reply);
+  stream.write_all(line.as_bytes()).await.is_ok()
+}
+
+async fn handle_client(stream: UnixStream, menu_ref: Arc<MenuRef>) {
+  let (reader, mut writer) = stream.into_split();
+  let mut lines = BufReader::new(reader).lines();
+  while let Ok(Some(line)) = lines.next_line().await {
+    let mut words = line.split_whitespace();
+    let reply = match (words.next(), words.next()) {
+      (Some("list"), None) => menu_json(&*menu_ref.get().await),
+      (Some("launch"), Some(target)) => launch(&menu_ref, target).await,
+      (Some("subscribe"), None) => {
+        subscribe(&mut writer, &menu_ref).await;
+        return;
+      },
+      (None, _) => continue,
+      _ => json!({ "error": format!("Unknown command '{}'", line.trim()) }),
+    };
+    if !send(&mut writer, &reply).await {
+      return;
+    }
+  }
+}
+
+/// Sends the menu whenever its revision changes, until the client goes away.
+async fn subscribe(writer: &mut tokio::net::unix::OwnedWriteHalf, menu_ref: &MenuRef) {
+  let mut revision = None;
+  let mut ticker = tokio::time::interval(constants::SOCKET_SYNC_TICK);
+  loop {
+    ticker.tick().await;
+    let reply = {
+      let menu = menu_ref.get().await;
+      if revision == Some(menu.revision) {
+        continue;
+      }
+      revision = Some(menu.revision);
+      menu_json(&menu)
+    };
+    if !send(writer, &reply).await {
+      return;
+    }
+  }
+}
+
+fn bind(path: &PathBuf) -> std::io::Result<UnixListener> {
+  // A socket left behind by an instance that didn't shut down cleanly;
+  // the bus name is already ours, so no live instance owns it.
+  if path.exists() {
+    std::fs::remove_file(path)?;
+  }
+  let listener = UnixListener::bind(path)?;
+  std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+  Ok(listener)
+}
+
+/// Serves the menu socket at `path` until the process exits.
+pub async fn serve(connection: &zbus::Connection, menu_path: &str, path: PathBuf) {
+  let listener = match bind(&path) {
+    Ok(listener) => listener,
+    Err(err) => {
+      warn!("Failed to open menu socket at {:?}: {}", path, err);
+      return;
+    },
+  };
+  let menu_ref = match connection
+    .object_server()
+    .interface::<_, AppMenuDbusMenu>(menu_path)
+    .await
+  {
+    Ok(menu_ref) => Arc::new(menu_ref),
+    Err(err) => {
+      warn!("Failed to get menu interface for the menu socket: {}", err);
+      return;
+    },
+  };
+  info!("Serving the menu on {:?}", path);
+  tokio::spawn(async move {
+    loop {
+      match listener.accept().await {
+        Ok((stream, _)) => {
+          debug!("Menu socket client connected");
+          tokio::spawn(handle_client(stream, menu_ref.clone()));
+        },
+        Err(err) => warn!("Failed to accept menu socket client: {}", err),
+      }
+    }
+  });
+}
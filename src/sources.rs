@@ -0,0 +1,78 @@
+//! Directories entries are read from, in priority order: when the same ID is
+//! found in several of them, the one with the lowest priority wins. A
+//! directory's priority is its position in the configured order, so entries
+//! cached under priorities have to be renumbered when the set changes. Each
+//! directory also holds a slot for its "Sources" menu item, kept for as long
+//! as it is in the set and handed out again once it is removed.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default)]
+pub struct SourceSet {
+  /// Directories in priority order, highest first.
+  dirs: Vec<PathBuf>,
+  slots: bimap::BiMap<usize, PathBuf>,
+}
+
+impl SourceSet {
+  /// Builds a set from directories in priority order, highest first.
+  pub fn new(dirs: impl IntoIterator<Item = PathBuf>) -> SourceSet {
+    let mut set = SourceSet::default();
+    set.sync(dirs.into_iter().collect());
+    set
+  }
+
+  /// Makes `dirs` the directories in the set, in that priority order, with
+  /// duplicates dropped. Returns the new priority of each kept directory by
+  /// its old one, for renumbering entries cached under them.
+  pub fn sync(&mut self, dirs: Vec<PathBuf>) -> HashMap<usize, usize> {
+    let mut wanted: Vec<PathBuf> = Vec::with_capacity(dirs.len());
+    for dir in dirs {
+      if !wanted.contains(&dir) {
+        wanted.push(dir);
+      }
+    }
+    let old = std::mem::replace(&mut self.dirs, wanted);
+    self.slots.retain(|_, dir| self.dirs.contains(dir));
+    for dir in self.dirs.iter() {
+      if !self.slots.contains_right(dir) {
+        let slot = (0..)
+          .find(|slot| !self.slots.contains_left(slot))
+          .unwrap_or_default();
+        self.slots.insert(slot, dir.clone());
+      }
+    }
+    old
+      .iter()
+      .enumerate()
+      .filter_map(|(prio, dir)| Some((prio, self.dirs.iter().position(|d| d == dir)?)))
+      .collect()
+  }
+
+  /// Menu item slot of a directory in the set.
+  pub fn slot(&self, dir: &Path) -> Option<usize> {
+    self.slots.get_by_right(dir).copied()
+  }
+
+  /// Directory holding a menu item slot.
+  pub fn by_slot(&self, slot: usize) -> Option<&PathBuf> {
+    self.slots.get_by_left(&slot)
+  }
+
+  pub fn contains(&self, dir: &Path) -> bool {
+    self.dirs.iter().any(|d| d == dir)
+  }
+
+  /// Directories with their priorities, highest priority first.
+  pub fn iter(&self) -> impl Iterator<Item = (usize, &PathBuf)> {
+    self.dirs.iter().enumerate()
+  }
+
+  /// The innermost directory holding `p`, with its priority.
+  pub fn locate(&self, p: &Path) -> Option<(usize, &PathBuf)> {
+    self
+      .iter()
+      .filter(|(_, dir)| p.starts_with(dir))
+      .max_by_key(|(_, dir)| dir.components().count())
+  }
+}
@@ -0,0 +1,45 @@
+//! Failures worth the user's attention, such as unreadable application
+//! directories and launches that didn't start, collected for the tray item.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+#[derive(Debug)]
+pub enum Alert {
+  /// Something failed, with a one-line summary of what.
+  Failed(String),
+  /// The user has seen the failures, or they went away.
+  Resolved,
+}
+
+static SENDER: OnceLock<UnboundedSender<Alert>> = OnceLock::new();
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// Starts collecting alerts, returning where they arrive. Alerts raised
+/// before this, or with nobody listening, are dropped.
+pub fn subscribe() -> Option<UnboundedReceiver<Alert>> {
+  let (tx, rx) = unbounded_channel();
+  SENDER.set(tx).ok()?;
+  Some(rx)
+}
+
+/// Raises an alert for a failure.
+pub fn report(summary: String) {
+  FAILURES.fetch_add(1, Ordering::Relaxed);
+  if let Some(tx) = SENDER.get() {
+    let _ = tx.send(Alert::Failed(summary));
+  }
+}
+
+/// Clears the raised alerts.
+pub fn resolve() {
+  if let Some(tx) = SENDER.get() {
+    let _ = tx.send(Alert::Resolved);
+  }
+}
+
+/// Failures reported so far, to tell whether an operation ran cleanly.
+pub fn failures() -> u64 {
+  FAILURES.load(Ordering::Relaxed)
+}
@@ -0,0 +1,142 @@
+//! The desktop's light or dark color scheme preference, read from the
+//! settings portal, and the icon variants that go with it.
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use futures_util::StreamExt;
+use log::{debug, info, warn};
+use zbus::zvariant::Value;
+
+use crate::proxy_types::SettingsProxy;
+use crate::{config, constants, desktop};
+
+const NAMESPACE: &str = "org.freedesktop.appearance";
+const COLOR_SCHEME_KEY: &str = "color-scheme";
+/// Portal value for a preferred dark appearance; 0 is no preference and 2 light.
+const PREFER_DARK: u32 = 1;
+
+static COLOR_SCHEME: AtomicU32 = AtomicU32::new(0);
+
+/// Whether themed icons should be swapped for their dark variants.
+pub fn prefers_dark() -> bool {
+  config::get().follow_color_scheme && COLOR_SCHEME.load(Ordering::Relaxed) == PREFER_DARK
+}
+
+/// The icon shown for themed icon `name`: with a dark color scheme, its
+/// `-dark` or else `-symbolic` variant if the installed themes have one.
+pub fn themed_icon(name: &str) -> String {
+  if !prefers_dark() || name.is_empty() || name.contains('/') || name.ends_with("-symbolic") {
+    return name.to_string();
+  }
+  ["-dark", "-symbolic"]
+    .iter()
+    .map(|suffix| format!("{}{}", name, suffix))
+    .find(|variant| desktop::find_themed_icon(variant, constants::MENU_ICON_SIZE).is_some())
+    .unwrap_or_else(|| name.to_string())
+}
+
+/// Unwraps the color scheme from a portal reply, which older portals nest
+/// in an extra variant.
+fn color_scheme(value: &Value<'_>) -> Option<u32> {
+  match value {
+    Value::Value(inner) => color_scheme(inner),
+    Value::U32(scheme) => Some(*scheme),
+    _ => None,
+  }
+}
+
+/// Stores a color scheme read from the portal, calling `changed` when it
+/// differs from the last one while the option is on.
+async fn update<F, Fut>(scheme: u32, changed: &F)
+where
+  F: Fn() -> Fut,
+  Fut: std::future::Future<Output = ()>,
+{
+  if COLOR_SCHEME.swap(scheme, Ordering::Relaxed) != scheme {
+    info!("Color scheme preference changed to {}", scheme);
+    if config::get().follow_color_scheme {
+      changed().await;
+    }
+  }
+}
+
+/// Keeps track of the color scheme, calling `changed` every time it changes
+/// while the option is on. Without a settings portal, waits for one to show
+/// up, and reads the scheme again whenever the portal restarts.
+pub async fn follow<F, Fut>(connection: zbus::Connection, changed: F)
+where
+  F: Fn() -> Fut,
+  Fut: std::future::Future<Output = ()>,
+{
+  let settings = match SettingsProxy::new(&connection).await {
+    Ok(settings) => settings,
+    Err(err) => {
+      warn!("Failed to create settings portal proxy: {}", err);
+      return;
+    },
+  };
+  let bus = match zbus::fdo::DBusProxy::new(&connection).await {
+    Ok(bus) => bus,
+    Err(err) => {
+      warn!("Failed to create D-Bus proxy: {}", err);
+      return;
+    },
+  };
+  let streams = futures_util::try_join!(
+    bus.receive_name_owner_changed(),
+    settings.receive_setting_changed(),
+  );
+  let (name_changes, mut changes) = match streams {
+    Ok(streams) => streams,
+    Err(err) => {
+      warn!("Failed to subscribe to settings changes: {}", err);
+      return;
+    },
+  };
+  let portal_name = settings.destination().to_string();
+  let mut portal_started = name_changes.filter_map(|signal| {
+    let started = signal
+      .args()
+      .ok()
+      .filter(|args| args.name().as_str() == portal_name && args.new_owner().is_some())
+      .map(|_| ());
+    std::future::ready(started)
+  });
+  loop {
+    match settings.read(NAMESPACE, COLOR_SCHEME_KEY).await {
+      Ok(value) => {
+        let scheme = color_scheme(&value).unwrap_or_default();
+        debug!("Color scheme preference is {}", scheme);
+        update(scheme, &changed).await;
+      },
+      Err(err) => {
+        debug!("No color scheme from the settings portal: {}", err);
+        if portal_started.next().await.is_none() {
+          return;
+        }
+        continue;
+      },
+    }
+    loop {
+      tokio::select! {
+        signal = changes.next() => {
+          let signal = match signal {
+            Some(signal) => signal,
+            None => return,
+          };
+          let args = match signal.args() {
+            Ok(args) => args,
+            Err(_) => continue,
+          };
+          if *args.namespace() != NAMESPACE || *args.key() != COLOR_SCHEME_KEY {
+            continue;
+          }
+          update(color_scheme(args.value()).unwrap_or_default(), &changed).await;
+        },
+        started = portal_started.next() => match started {
+          Some(()) => break,
+          None => return,
+        },
+      }
+    }
+  }
+}
@@ -0,0 +1,52 @@
+//! Fault injection for integration tests, enabled through the undocumented
+//! `XDG_APP_KSNI_INJECT_FAULTS` variable, e.g. `spawn,icon,watcher-delay=5`.
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use log::warn;
+
+#[derive(Debug, Default)]
+struct Faults {
+  spawn: bool,
+  icon: bool,
+  watcher_delay: Option<Duration>,
+}
+
+static FAULTS: OnceLock<Faults> = OnceLock::new();
+
+fn faults() -> &'static Faults {
+  FAULTS.get_or_init(|| {
+    let mut faults = Faults::default();
+    let spec = std::env::var("XDG_APP_KSNI_INJECT_FAULTS").unwrap_or_default();
+    for fault in spec.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+      match fault.split_once('=').unwrap_or((fault, "")) {
+        ("spawn", _) => faults.spawn = true,
+        ("icon", _) => faults.icon = true,
+        ("watcher-delay", secs) => match secs.parse() {
+          Ok(secs) => faults.watcher_delay = Some(Duration::from_secs(secs)),
+          Err(_) => warn!("Invalid watcher delay '{}' for fault injection", secs),
+        },
+        _ => warn!("Unknown fault '{}' requested for injection", fault),
+      }
+    }
+    if faults.spawn || faults.icon || faults.watcher_delay.is_some() {
+      warn!("Fault injection active: {:?}", faults);
+    }
+    faults
+  })
+}
+
+/// Whether launching apps should fail.
+pub fn spawn_fails() -> bool {
+  faults().spawn
+}
+
+/// Whether icons should fail to render, as if corrupt.
+pub fn icon_corrupt() -> bool {
+  faults().icon
+}
+
+/// Extra delay before handling each file system event.
+pub fn watcher_delay() -> Option<Duration> {
+  faults().watcher_delay
+}
@@ -0,0 +1,63 @@
+//! Launch scores per desktop-file ID behind the "Frequently used" submenu,
+//! kept in the state directory. Each launch adds one to a score that halves
+//! every `frequent_half_life_days`, so applications no longer used drop off.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config, constants};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Score {
+  score: f64,
+  /// Unix time the score was last updated.
+  at: u64,
+}
+
+impl Score {
+  fn decayed(&self, now: u64) -> f64 {
+    let half_life = config::get().frequent_half_life_days * 24 * 60 * 60;
+    if half_life == 0 {
+      return self.score;
+    }
+    let age = now.saturating_sub(self.at) as f64;
+    self.score * 0.5f64.powf(age / half_life as f64)
+  }
+}
+
+/// Scores, read from the state file on first use.
+static SCORES: Mutex<Option<HashMap<String, Score>>> = Mutex::new(None);
+
+fn with_scores<R>(f: impl FnOnce(&mut HashMap<String, Score>) -> R) -> Option<R> {
+  let mut scores = SCORES.lock().ok()?;
+  let scores = scores
+    .get_or_insert_with(|| super::state::load(constants::FREQUENCY_STATE_FILE).unwrap_or_default());
+  Some(f(scores))
+}
+
+/// Counts a launch of `appid`, saving the scores.
+pub fn record(appid: &str) {
+  let now = super::state::unix_now();
+  with_scores(|scores| {
+    let score = scores.get(appid).map_or(0.0, |s| s.decayed(now)) + 1.0;
+    scores.insert(appid.to_string(), Score { score, at: now });
+    scores.retain(|_, s| s.decayed(now) >= constants::FREQUENT_MIN_SCORE);
+    super::state::save(constants::FREQUENCY_STATE_FILE, scores);
+  });
+}
+
+/// Desktop-file IDs by decayed score, highest first.
+pub fn ranked() -> Vec<String> {
+  let now = super::state::unix_now();
+  let mut ranked: Vec<(String, f64)> = with_scores(|scores| {
+    scores
+      .iter()
+      .map(|(appid, s)| (appid.clone(), s.decayed(now)))
+      .filter(|(_, score)| *score >= constants::FREQUENT_MIN_SCORE)
+      .collect()
+  })
+  .unwrap_or_default();
+  ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+  ranked.into_iter().map(|(appid, _)| appid).collect()
+}
@@ -1,14 +1,9 @@
 use log::{error, warn, LevelFilter};
 use log_err::*;
 
-fn env_or(name: &str, default: &str) -> String {
-  let var = std::env::var(name).unwrap_or_default();
-  if var.is_empty() {
-    default.to_string()
-  } else {
-    var
-  }
-}
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{config, constants};
 
 pub fn init_logging() {
   if systemd_journal_logger::connected_to_journal() {
@@ -16,19 +11,13 @@ pub fn init_logging() {
   } else {
     simple_logger::SimpleLogger::new().init().unwrap();
   }
-  let log_level = env_or("LOG_LEVEL", "INFO").to_uppercase();
-  match log_level.as_str() {
-    "ERROR" => log::set_max_level(LevelFilter::Error),
-    "DEBUG" => log::set_max_level(LevelFilter::Debug),
-    "TRACE" => log::set_max_level(LevelFilter::Trace),
-    "INFO" => log::set_max_level(LevelFilter::Info),
-    _ => {
-      log::set_max_level(LevelFilter::Info);
-      warn!(
-        "Unknown log level '{}' passed in, defaulting to info",
-        log_level
-      );
-    },
+  let log_level = config::get().log_level.clone();
+  if !set_log_level(&log_level) {
+    log::set_max_level(LevelFilter::Info);
+    warn!(
+      "Unknown log level '{}' passed in, defaulting to info",
+      log_level
+    );
   }
   let stock_hook = std::panic::take_hook();
   std::panic::set_hook(Box::new(move |info| {
@@ -51,10 +40,49 @@ pub fn init_logging() {
     for line in format!("Backtrace:\n{:?}", backtrace::Backtrace::new()).lines() {
       error!("{}", line);
     }
-    std::process::exit(1);
+    super::snapshot::save();
+    std::process::exit(crate::error::EXIT_RUNTIME);
   }));
 }
 
+/// Index of the active menu profile in the config.
+static PROFILE: AtomicUsize = AtomicUsize::new(0);
+
+/// The active menu profile, unless none are configured.
+pub fn get_profile() -> Option<config::MenuProfile> {
+  let profiles = &config::get().profiles;
+  let idx = PROFILE.load(Ordering::Relaxed) % profiles.len().max(1);
+  profiles.get(idx).cloned()
+}
+
+/// Moves `step` profiles on, wrapping around, returning the new one.
+pub fn cycle_profile(step: isize) -> Option<config::MenuProfile> {
+  let count = config::get().profiles.len() as isize;
+  if count == 0 {
+    return None;
+  }
+  let current = PROFILE.load(Ordering::Relaxed) as isize % count;
+  PROFILE.store(
+    (current + step).rem_euclid(count) as usize,
+    Ordering::Relaxed,
+  );
+  get_profile()
+}
+
+/// Switches the log level by name, returning whether the name was known.
+pub fn set_log_level(level: &str) -> bool {
+  let filter = match level.to_uppercase().as_str() {
+    "ERROR" => LevelFilter::Error,
+    "WARN" => LevelFilter::Warn,
+    "DEBUG" => LevelFilter::Debug,
+    "TRACE" => LevelFilter::Trace,
+    "INFO" => LevelFilter::Info,
+    _ => return false,
+  };
+  log::set_max_level(filter);
+  true
+}
+
 pub fn get_app_dirs() -> Vec<std::path::PathBuf> {
   let xdg_dirs = xdg::BaseDirectories::new().log_expect("Failed to init XDG directories");
   let mut dirs: std::collections::VecDeque<_> = xdg_dirs
@@ -62,12 +90,386 @@ pub fn get_app_dirs() -> Vec<std::path::PathBuf> {
     .drain(..)
     .map(|p| p.join("applications"))
     .collect();
+  for extra in config::get().app_dirs.iter() {
+    dirs.push_front(extra.clone());
+  }
   if let Ok(home_data) = xdg_dirs.create_data_directory("applications") {
     dirs.push_front(home_data);
   }
-  return dirs.drain(..).filter(|p| p.is_dir()).collect();
+  dirs.drain(..).filter(|p| p.is_dir()).collect()
+}
+
+/// Whether the application directory was switched off in the "Sources" submenu.
+pub fn is_dir_disabled(dir: &std::path::Path) -> bool {
+  config::get().disabled_dirs.iter().any(|d| d == dir)
+}
+
+/// Whether to add a "Sources" submenu for toggling application directories.
+pub fn get_show_sources() -> bool {
+  config::get().show_sources
+}
+
+/// Desktop names matched against `OnlyShowIn`/`NotShowIn`: the configured
+/// list, else `XDG_CURRENT_DESKTOP` (colon-separated), falling back to GNOME.
+pub fn get_current_desktops() -> Vec<String> {
+  let configured = &config::get().desktops;
+  if !configured.is_empty() {
+    return configured.clone();
+  }
+  let desktops = std::env::var("XDG_CURRENT_DESKTOP").unwrap_or_default();
+  let desktops: Vec<String> = desktops
+    .split(':')
+    .filter(|d| !d.is_empty())
+    .map(str::to_string)
+    .collect();
+  if desktops.is_empty() {
+    vec!["GNOME".to_string()]
+  } else {
+    desktops
+  }
+}
+
+/// Whether to serve only the dbusmenu object, skipping StatusNotifierItem registration.
+pub fn get_menu_only() -> bool {
+  config::get().menu_only
 }
 
-pub fn get_only_show() -> String {
-  env_or("ONLY_SHOW", "GNOME")
+pub fn get_launch_strictness() -> super::launch::Strictness {
+  use super::launch::Strictness;
+  let strictness = config::get().launch_strictness.to_uppercase();
+  match strictness.as_str() {
+    "OFF" => Strictness::Off,
+    "WARN" => Strictness::Warn,
+    "ENFORCE" => Strictness::Enforce,
+    _ => {
+      warn!(
+        "Unknown launch strictness '{}' passed in, defaulting to warn",
+        strictness
+      );
+      Strictness::Warn
+    },
+  }
+}
+
+/// What the tray item does when activated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ActivateAction {
+  /// Ask hosts to open the menu, leaving activation to them.
+  Menu,
+  /// Launch the top favorite.
+  Favorite,
+  /// Run the configured `activate_command`.
+  Command,
+  /// Do nothing.
+  Nothing,
+}
+
+pub fn get_activate_action() -> ActivateAction {
+  let action = config::get().activate.to_uppercase();
+  match action.as_str() {
+    "MENU" => ActivateAction::Menu,
+    "FAVORITE" => ActivateAction::Favorite,
+    "COMMAND" if config::get().activate_command.trim().is_empty() => {
+      warn!("No activate_command configured, defaulting to menu");
+      ActivateAction::Menu
+    },
+    "COMMAND" => ActivateAction::Command,
+    "NONE" => ActivateAction::Nothing,
+    _ => {
+      warn!(
+        "Unknown activate action '{}' passed in, defaulting to menu",
+        action
+      );
+      ActivateAction::Menu
+    },
+  }
+}
+
+pub fn get_group_by() -> crate::sections::GroupBy {
+  use crate::sections::GroupBy;
+  let group_by = config::get().group_by.to_uppercase();
+  match group_by.as_str() {
+    "CATEGORY" => GroupBy::Category,
+    "SOURCE" => GroupBy::Source,
+    "INITIAL" => GroupBy::Initial,
+    _ => {
+      warn!(
+        "Unknown grouping '{}' passed in, defaulting to category",
+        group_by
+      );
+      GroupBy::Category
+    },
+  }
+}
+
+/// Path of the menu socket, if enabled, named after the bus name so
+/// instances don't clash.
+pub fn get_socket_path() -> Option<std::path::PathBuf> {
+  if !config::get().socket {
+    return None;
+  }
+  let xdg_dirs = xdg::BaseDirectories::with_prefix("xdg-app-ksni").ok()?;
+  match xdg_dirs.place_runtime_file(format!("{}.sock", get_object_names().bus_name)) {
+    Ok(path) => Some(path),
+    Err(err) => {
+      warn!("No runtime directory for the menu socket: {}", err);
+      None
+    },
+  }
+}
+
+/// Path the SIGUSR1 state dump is written to, named like the socket.
+pub fn get_dump_path() -> Option<std::path::PathBuf> {
+  let xdg_dirs = xdg::BaseDirectories::with_prefix("xdg-app-ksni").ok()?;
+  match xdg_dirs.place_runtime_file(format!("{}.dump.json", get_object_names().bus_name)) {
+    Ok(path) => Some(path),
+    Err(err) => {
+      warn!("No runtime directory for the state dump: {}", err);
+      None
+    },
+  }
+}
+
+/// Name of the icon theme in use: the configured one, else the one set in
+/// the GTK settings, if any.
+pub fn get_icon_theme() -> Option<String> {
+  let configured = config::get().icon_theme.clone();
+  if !configured.is_empty() {
+    return Some(configured);
+  }
+  let xdg_dirs = xdg::BaseDirectories::new().ok()?;
+  ["gtk-4.0/settings.ini", "gtk-3.0/settings.ini"]
+    .iter()
+    .filter_map(|file| xdg_dirs.find_config_file(file))
+    .filter_map(|path| std::fs::read_to_string(path).ok())
+    .find_map(|settings| {
+      settings.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        let value = value.trim().trim_matches('"');
+        (key.trim() == "gtk-icon-theme-name" && !value.is_empty()).then(|| value.to_string())
+      })
+    })
+}
+
+static ICON_THEME_DIR: std::sync::OnceLock<Option<std::path::PathBuf>> = std::sync::OnceLock::new();
+
+/// Icon theme directory exported icons are rendered into, if enabled. It is
+/// created the first time it's asked for.
+pub fn get_icon_theme_dir() -> Option<std::path::PathBuf> {
+  if !config::get().export_icons {
+    return None;
+  }
+  ICON_THEME_DIR
+    .get_or_init(|| {
+      let xdg_dirs = xdg::BaseDirectories::with_prefix("xdg-app-ksni").ok()?;
+      match xdg_dirs.create_cache_directory("icon-theme") {
+        Ok(path) => Some(path),
+        Err(err) => {
+          warn!("No cache directory for exported icons: {}", err);
+          None
+        },
+      }
+    })
+    .clone()
+}
+
+static ICON_CACHE_DIR: std::sync::OnceLock<Option<std::path::PathBuf>> = std::sync::OnceLock::new();
+
+/// Directory rendered menu icons are cached in across restarts, if enabled.
+/// The first time it's asked for, renders unused for `ICON_CACHE_MAX_AGE`
+/// are removed from it.
+pub fn get_icon_cache_dir() -> Option<std::path::PathBuf> {
+  if !config::get().icon_cache {
+    return None;
+  }
+  ICON_CACHE_DIR
+    .get_or_init(|| {
+      let xdg_dirs = xdg::BaseDirectories::with_prefix("xdg-app-ksni").ok()?;
+      match xdg_dirs.create_cache_directory("icons") {
+        Ok(path) => {
+          prune_icon_cache(&path);
+          Some(path)
+        },
+        Err(err) => {
+          warn!("No cache directory for rendered icons: {}", err);
+          None
+        },
+      }
+    })
+    .clone()
+}
+
+/// Removes files in the icon cache `dir` last used over
+/// `ICON_CACHE_MAX_AGE` ago, including temporary files left by a crash.
+fn prune_icon_cache(dir: &std::path::Path) {
+  let entries = match std::fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(err) => {
+      warn!("Failed to list the icon cache {:?}: {}", dir, err);
+      return;
+    },
+  };
+  let now = std::time::SystemTime::now();
+  for entry in entries.flatten() {
+    let stale = entry
+      .metadata()
+      .and_then(|m| m.modified())
+      .ok()
+      .and_then(|modified| now.duration_since(modified).ok())
+      .is_some_and(|age| age > constants::ICON_CACHE_MAX_AGE);
+    if stale {
+      if let Err(err) = std::fs::remove_file(entry.path()) {
+        warn!(
+          "Failed to remove stale cached icon {:?}: {}",
+          entry.path(),
+          err
+        );
+      }
+    }
+  }
+}
+
+/// Edge length menu icons are rendered at, scaled for HiDPI panels, or
+/// `None` to keep their own size. Capped at `MAX_ICON_DIMENSION`.
+pub fn get_menu_icon_size() -> Option<u32> {
+  let config = config::get();
+  Some(config.icon_size.saturating_mul(config.icon_scale.max(1)))
+    .filter(|size| *size > 0)
+    .map(|size| size.min(constants::MAX_ICON_DIMENSION))
+}
+
+/// Edge lengths the tray icon is rendered at, with their scaled variants,
+/// capped at `MAX_ICON_DIMENSION`.
+pub fn get_tray_icon_sizes() -> Vec<u32> {
+  let config = config::get();
+  let scale = config.icon_scale.max(1);
+  let mut sizes: Vec<u32> = config
+    .tray_icon_sizes
+    .iter()
+    .flat_map(|size| [*size, size.saturating_mul(scale)])
+    .filter(|size| *size > 0)
+    .map(|size| size.min(constants::MAX_ICON_DIMENSION))
+    .collect();
+  sizes.sort_unstable();
+  sizes.dedup();
+  sizes
+}
+
+/// Whether to register the tray item by a per-instance service name.
+pub fn get_sni_unique_name() -> bool {
+  config::get().sni_unique_name
+}
+
+/// Whether the daemon should confine itself after startup.
+pub fn get_sandbox() -> bool {
+  config::get().sandbox
+}
+
+/// Whether to show the companion window for sessions without a tray.
+pub fn get_companion() -> bool {
+  config::get().companion
+}
+
+/// Whether to append an "About" item with build information to the root menu.
+pub fn get_show_about() -> bool {
+  config::get().show_about
+}
+
+/// Whether to end the root menu with "Options", "Refresh" and "Quit" items.
+pub fn get_show_controls() -> bool {
+  config::get().show_controls
+}
+
+/// Whether entries marked `NoDisplay` or meant for other desktops are listed.
+pub fn get_show_hidden() -> bool {
+  config::get().show_hidden
+}
+
+/// Whether entries run in a terminal are listed.
+pub fn get_show_terminal() -> bool {
+  config::get().show_terminal
+}
+
+/// Categories with fewer visible entries than this are folded into "Other"; 0 disables merging.
+pub fn get_merge_threshold() -> usize {
+  config::get().merge_threshold
+}
+
+/// Whether entries of merged categories go in the root menu instead of "Other".
+pub fn get_flatten_sparse() -> bool {
+  config::get().flatten_sparse
+}
+
+/// StatusNotifierItem category hosts may use to group the tray icon.
+pub fn get_sni_category() -> String {
+  let category = config::get().category.clone();
+  if constants::SNI_CATEGORIES.contains(&category.as_str()) {
+    category
+  } else {
+    warn!(
+      "Unknown tray category '{}' passed in, defaulting to ApplicationStatus",
+      category
+    );
+    "ApplicationStatus".to_string()
+  }
+}
+
+/// Whether web entries should be checked for network reachability before launching.
+pub fn get_network_guard() -> bool {
+  config::get().network_guard
+}
+
+/// Bus name and object paths the menu and tray item are served under.
+#[derive(Debug, Clone)]
+pub struct ObjectNames {
+  pub id: String,
+  pub bus_name: String,
+  pub item_path: String,
+  pub menu_path: String,
+}
+
+/// A custom Id gives its own bus name and item path so several instances can
+/// run side by side; the menu defaults to `Menu` below the item. Registering
+/// by service name puts the item where the spec says hosts look for it.
+pub fn get_object_names() -> ObjectNames {
+  let config = config::get();
+  let id = config.id.clone().filter(|id| !id.is_empty());
+  let element = id.as_deref().map(|id| {
+    let element: String = id
+      .chars()
+      .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+      .collect();
+    if element.starts_with(|c: char| c.is_ascii_digit()) {
+      format!("_{}", element)
+    } else {
+      element
+    }
+  });
+  let bus_name = config.bus_name.clone().unwrap_or_else(|| {
+    element
+      .as_ref()
+      .map_or(constants::DEFAULT_BUS_NAME.to_string(), |e| {
+        format!("{}.{}", constants::DEFAULT_BUS_NAME, e)
+      })
+  });
+  let item_path = config.item_path.clone().unwrap_or_else(|| {
+    if config.sni_unique_name {
+      return constants::SNI_ITEM_PATH.to_string();
+    }
+    element
+      .as_ref()
+      .map_or(constants::DEFAULT_ITEM_PATH.to_string(), |e| {
+        format!("{}/{}", constants::ITEM_PATH_PREFIX, e)
+      })
+  });
+  let menu_path = config
+    .menu_path
+    .clone()
+    .unwrap_or_else(|| format!("{}/Menu", item_path));
+  ObjectNames {
+    id: id.unwrap_or_else(|| constants::DEFAULT_ITEM_ID.to_string()),
+    bus_name,
+    item_path,
+    menu_path,
+  }
 }
@@ -0,0 +1,240 @@
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+/// How strictly Exec lines are vetted before spawning.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strictness {
+  /// Spawn everything without checks.
+  Off,
+  /// Log suspicious Exec lines but launch them anyway.
+  Warn,
+  /// Refuse to launch suspicious Exec lines.
+  Enforce,
+}
+
+const SHELL_METACHARACTERS: &[char] = &['|', '&', ';', '<', '>', '(', ')', '$', '`', '\n'];
+
+/// Interpreters whose `-c` argument is a script, where shell syntax is intended.
+const SHELLS: &[&str] = &["sh", "bash", "dash", "zsh", "ksh", "mksh", "fish"];
+
+/// Resolves a program name against `PATH`, or returns it as-is when it contains a slash.
+pub fn resolve_program(program: &str) -> Option<PathBuf> {
+  if program.contains('/') {
+    let path = PathBuf::from(program);
+    return path.is_file().then_some(path);
+  }
+  std::env::var_os("PATH").and_then(|paths| {
+    std::env::split_paths(&paths)
+      .map(|dir| dir.join(program))
+      .find(|candidate| candidate.is_file())
+  })
+}
+
+/// Arguments a terminal needs in front of the command it should run.
+fn terminal_exec_args(terminal: &Path) -> Vec<String> {
+  let name = terminal
+    .file_name()
+    .map(|n| n.to_string_lossy().to_string())
+    .unwrap_or_default();
+  let args: &[&str] = match name.as_str() {
+    "gnome-terminal" | "ptyxis" => &["--"],
+    "xfce4-terminal" | "mate-terminal" | "tilix" => &["-x"],
+    "wezterm" => &["start", "--"],
+    "foot" | "kitty" | "xdg-terminal-exec" => &[],
+    _ => &["-e"],
+  };
+  args.iter().map(|a| a.to_string()).collect()
+}
+
+fn terminal_invocation(terminal: &str) -> Option<Vec<String>> {
+  let resolved = resolve_program(terminal)?;
+  let mut prefix = vec![terminal.to_string()];
+  prefix.extend(terminal_exec_args(&resolved));
+  Some(prefix)
+}
+
+/// Terminals preferred by the running desktop environment, from `XDG_CURRENT_DESKTOP`.
+fn desktop_terminals() -> Vec<&'static str> {
+  let desktops = std::env::var("XDG_CURRENT_DESKTOP").unwrap_or_default();
+  desktops
+    .split(':')
+    .flat_map(|desktop| match desktop.to_uppercase().as_str() {
+      "GNOME" | "UNITY" | "UBUNTU" => vec!["kgx", "gnome-terminal"],
+      "KDE" => vec!["konsole"],
+      "XFCE" => vec!["xfce4-terminal"],
+      "MATE" => vec!["mate-terminal"],
+      "SWAY" | "WLROOTS" | "HYPRLAND" | "RIVER" => vec!["foot"],
+      _ => vec![],
+    })
+    .collect()
+}
+
+/// Per-app terminal choice from the `terminal_overrides` config table, keyed
+/// by desktop-file ID with or without the `.desktop` suffix.
+fn terminal_override(desktop_id: &str) -> Option<String> {
+  let overrides = &crate::config::get().terminal_overrides;
+  overrides
+    .get(desktop_id)
+    .or_else(|| overrides.get(desktop_id.strip_suffix(".desktop")?))
+    .cloned()
+}
+
+/// Finds a terminal emulator for a `Terminal=true` entry, returning the
+/// arguments to put in front of its command. Tried in order:
+///
+/// 1. the entry's configured terminal override,
+/// 2. `$TERMINAL`,
+/// 3. `xdg-terminal-exec`,
+/// 4. the desktop environment's own terminal (kgx, konsole, foot, ...),
+/// 5. the Debian `x-terminal-emulator` alternative, resolved through its symlinks,
+/// 6. a few common terminals, ending with xterm.
+pub fn terminal_prefix(desktop_id: &str) -> Option<Vec<String>> {
+  if let Some(terminal) = terminal_override(desktop_id) {
+    match terminal_invocation(&terminal) {
+      Some(prefix) => return Some(prefix),
+      None => warn!(
+        "Terminal override {} for {} not found, falling back",
+        terminal, desktop_id
+      ),
+    }
+  }
+  if let Some(prefix) = std::env::var("TERMINAL")
+    .ok()
+    .filter(|t| !t.is_empty())
+    .and_then(|t| terminal_invocation(&t))
+  {
+    return Some(prefix);
+  }
+  if let Some(prefix) = terminal_invocation("xdg-terminal-exec") {
+    return Some(prefix);
+  }
+  if let Some(prefix) = desktop_terminals()
+    .into_iter()
+    .find_map(terminal_invocation)
+  {
+    return Some(prefix);
+  }
+  if let Some(alternative) = resolve_program("x-terminal-emulator") {
+    let target = std::fs::canonicalize(&alternative).unwrap_or(alternative);
+    let mut prefix = vec!["x-terminal-emulator".to_string()];
+    prefix.extend(terminal_exec_args(&target));
+    return Some(prefix);
+  }
+  ["foot", "kitty", "alacritty", "xterm"]
+    .into_iter()
+    .find_map(terminal_invocation)
+}
+
+/// Whether the current user may execute `path`, honoring group membership and ACLs.
+pub fn executable_by_user(path: &Path) -> bool {
+  use std::os::unix::ffi::OsStrExt;
+  match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+    Ok(c_path) => unsafe { libc::access(c_path.as_ptr(), libc::X_OK) == 0 },
+    Err(_) => false,
+  }
+}
+
+/// Modification times of the `PATH` directories, which change whenever
+/// binaries are installed or removed.
+pub fn path_fingerprint() -> Vec<Option<std::time::SystemTime>> {
+  std::env::var_os("PATH")
+    .map(|paths| {
+      std::env::split_paths(&paths)
+        .map(|dir| std::fs::metadata(dir).and_then(|m| m.modified()).ok())
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+fn world_writable(path: &Path) -> bool {
+  std::fs::metadata(path)
+    .map(|meta| meta.permissions().mode() & 0o002 != 0)
+    .unwrap_or(false)
+}
+
+/// Whether `arg` is a whole URL like `https://example.com/?a=1&b=2`, whose
+/// query separators are not shell syntax once the Exec line is split.
+fn is_url(arg: &str) -> bool {
+  arg.split_once("://").is_some_and(|(scheme, _)| {
+    scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+      && scheme
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+  })
+}
+
+/// Index of the script in shell mode, an Exec line like `sh -c 'a; b'` whose
+/// options before the script include `-c`.
+fn shell_script_idx(exec: &[String]) -> Option<usize> {
+  let shell = Path::new(exec.first()?).file_name()?.to_str()?;
+  if !SHELLS.contains(&shell) {
+    return None;
+  }
+  let options = exec[1..]
+    .iter()
+    .take_while(|arg| arg.starts_with('-') && !arg.starts_with("--"))
+    .count();
+  exec[1..=options]
+    .iter()
+    .any(|arg| arg[1..].contains('c'))
+    .then_some(options + 1)
+}
+
+fn violations(exec: &[String], program: &str) -> Vec<String> {
+  let mut found = Vec::new();
+  let script = shell_script_idx(exec);
+  if exec
+    .iter()
+    .enumerate()
+    .any(|(idx, arg)| Some(idx) != script && !is_url(arg) && arg.contains(SHELL_METACHARACTERS))
+  {
+    found.push("contains shell metacharacters".to_string());
+  }
+  match resolve_program(program) {
+    Some(resolved) => {
+      if world_writable(&resolved) {
+        found.push(format!("{:?} is world-writable", resolved));
+      }
+      if let Some(parent) = resolved.parent() {
+        // Sticky directories like /tmp keep others from replacing a file, but
+        // anyone could have planted it, so only our own and root's files pass.
+        let sticky = std::fs::metadata(parent)
+          .map(|meta| meta.permissions().mode() & 0o1000 != 0)
+          .unwrap_or(false);
+        let trusted_owner = std::fs::metadata(&resolved)
+          .map(|meta| meta.uid() == 0 || meta.uid() == unsafe { libc::geteuid() })
+          .unwrap_or(false);
+        if world_writable(parent) && !(sticky && trusted_owner) {
+          found.push(format!(
+            "{:?} lives in a world-writable directory",
+            resolved
+          ));
+        }
+      }
+    },
+    None => found.push(format!("{} could not be resolved", program)),
+  }
+  found
+}
+
+/// Audits a launch request, returning whether it may proceed under `strictness`.
+pub fn permitted(exec: &[String], program: &str, source: &Path, strictness: Strictness) -> bool {
+  info!(target: "audit", "Launch requested by {:?}: {}", source, exec.join(" "));
+  if strictness == Strictness::Off {
+    return true;
+  }
+  let found = violations(exec, program);
+  if found.is_empty() {
+    return true;
+  }
+  let summary = found.join(", ");
+  if strictness == Strictness::Enforce {
+    warn!(target: "audit", "Refusing to launch {:?}: {}", source, summary);
+    false
+  } else {
+    warn!(target: "audit", "Launching {:?} despite: {}", source, summary);
+    true
+  }
+}
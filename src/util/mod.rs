@@ -1,2 +1,14 @@
+pub mod alerts;
+pub mod appearance;
+pub mod faults;
+pub mod frequency;
 pub mod init;
+pub mod launch;
+pub mod network;
+pub mod sandbox;
+pub mod snapshot;
+pub mod state;
+pub mod systemd;
+pub mod usage;
 pub mod xdg;
+pub mod xpm;
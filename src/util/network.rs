@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+/// How long a reachability result is reused before resolving the host again.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+/// Upper bound on a single DNS lookup so a dead resolver can't stall launches.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Extracts the host of the first http(s) URL in `args`, including URLs
+/// embedded in flags like `--app=https://example.com`.
+pub fn url_host<S: AsRef<str>>(args: &[S]) -> Option<String> {
+  args.iter().find_map(|arg| {
+    let arg = arg.as_ref();
+    let start = arg.find("https://").or_else(|| arg.find("http://"))?;
+    let rest = &arg[start..];
+    let rest = &rest[rest.find("://")? + 3..];
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = if host.starts_with('[') {
+      host.split(']').next()?.trim_start_matches('[')
+    } else {
+      host.split(':').next()?
+    };
+    (!host.is_empty()).then(|| host.to_string())
+  })
+}
+
+/// Caches whether hosts of web entries currently resolve.
+#[derive(Debug, Default)]
+pub struct ReachabilityCache {
+  results: HashMap<String, (Instant, bool)>,
+}
+
+impl ReachabilityCache {
+  /// Resolves `host`, reusing a recent answer when there is one.
+  pub async fn reachable(&mut self, host: &str) -> bool {
+    if let Some((checked, reachable)) = self.results.get(host) {
+      if checked.elapsed() < CACHE_TTL {
+        return *reachable;
+      }
+    }
+    let lookup = tokio::time::timeout(LOOKUP_TIMEOUT, tokio::net::lookup_host((host, 443))).await;
+    let reachable = match lookup {
+      Ok(Ok(mut addrs)) => addrs.next().is_some(),
+      Ok(Err(err)) => {
+        warn!("Failed to resolve {}: {}", host, err);
+        false
+      },
+      Err(_) => {
+        warn!("Timed out resolving {}", host);
+        false
+      },
+    };
+    debug!("Host {} reachable: {}", host, reachable);
+    self
+      .results
+      .insert(host.to_string(), (Instant::now(), reachable));
+    reachable
+  }
+}
@@ -0,0 +1,519 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use log::{debug, error, warn};
+use tokio::sync::oneshot;
+
+/// Argument that makes the binary act as the unconfined launch broker.
+pub const BROKER_ARG: &str = "--launch-broker";
+
+/// Outcome of a broker request: the command's exit status and output when
+/// it was run to completion, or why it couldn't be started.
+type Reply = Result<std::process::Output, String>;
+
+/// Connection to the broker, present once the daemon has confined itself.
+/// Requests and replies travel over a socket the broker has as its stdin.
+struct Broker {
+  requests: Mutex<UnixStream>,
+  pending: Mutex<HashMap<u64, oneshot::Sender<Reply>>>,
+  next_id: AtomicU64,
+}
+
+static BROKER: OnceLock<Broker> = OnceLock::new();
+
+impl Broker {
+  /// Sends `cmd` to the broker and waits for it to report whether it
+  /// started, or for its output if `capture` gives how long it may run.
+  async fn request(
+    &self,
+    cmd: &Command,
+    capture: Option<Duration>,
+  ) -> std::io::Result<std::process::Output> {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = oneshot::channel();
+    self
+      .pending
+      .lock()
+      .map_err(|_| std::io::Error::other("launch broker lock poisoned"))?
+      .insert(id, tx);
+    let sent = self
+      .requests
+      .lock()
+      .map_err(|_| std::io::Error::other("launch broker lock poisoned"))
+      .and_then(|mut requests| {
+        requests.write_all(&request_record(id, cmd, capture))?;
+        requests.flush()
+      });
+    if let Err(err) = sent {
+      if let Ok(mut pending) = self.pending.lock() {
+        pending.remove(&id);
+      }
+      return Err(err);
+    }
+    match rx.await {
+      Ok(reply) => reply.map_err(std::io::Error::other),
+      Err(_) => Err(std::io::Error::other("launch broker went away")),
+    }
+  }
+
+  /// Hands replies from the broker to the requests waiting for them, until
+  /// the broker goes away.
+  #[cfg(feature = "sandbox")]
+  fn read_replies(&self, stream: UnixStream) {
+    let mut input = std::io::BufReader::new(stream);
+    while let Some((id, reply)) = read_reply(&mut input) {
+      let waiting = self.pending.lock().ok().and_then(|mut p| p.remove(&id));
+      match waiting {
+        Some(tx) => {
+          let _ = tx.send(reply);
+        },
+        None => warn!("Launch broker replied to unknown request {}", id),
+      }
+    }
+    error!("Launch broker went away, launches will fail");
+    if let Ok(mut pending) = self.pending.lock() {
+      pending.clear();
+    }
+  }
+}
+
+/// Spawns `cmd` detached, routing it through the launch broker when sandboxed
+/// so the launched app doesn't inherit the daemon's restrictions. Either way
+/// the result says whether the command was started.
+pub async fn spawn(cmd: &mut tokio::process::Command) -> std::io::Result<()> {
+  if super::faults::spawn_fails() {
+    return Err(std::io::Error::other("injected spawn failure"));
+  }
+  if let Some(broker) = BROKER.get() {
+    return broker.request(cmd.as_std(), None).await.map(|_| ());
+  }
+  let mut child = cmd.spawn()?;
+  tokio::spawn(async move {
+    match child.wait().await {
+      Ok(status) => debug!("Launched app exited with {}", status),
+      Err(err) => warn!("Failed to wait for launched app: {}", err),
+    }
+  });
+  Ok(())
+}
+
+/// Runs `cmd` to completion with its output captured, killing it once
+/// `timeout` is up. Goes through the launch broker when sandboxed, like
+/// `spawn`.
+pub async fn output(
+  cmd: &mut tokio::process::Command,
+  timeout: Duration,
+) -> std::io::Result<std::process::Output> {
+  if let Some(broker) = BROKER.get() {
+    return broker.request(cmd.as_std(), Some(timeout)).await;
+  }
+  let output = cmd
+    .stdin(std::process::Stdio::null())
+    .kill_on_drop(true)
+    .output();
+  tokio::time::timeout(timeout, output)
+    .await
+    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out"))?
+}
+
+/// Encodes a request as NUL-separated fields: the request ID, how many
+/// milliseconds the command may run with its output captured or 0 to leave
+/// it running detached, the argument count, the working directory, the
+/// count of `KEY=VALUE` environment pairs, the pairs, then the arguments.
+fn request_record(id: u64, cmd: &Command, capture: Option<Duration>) -> Vec<u8> {
+  use std::os::unix::ffi::OsStrExt;
+  let mut fields = vec![cmd.get_program().to_os_string()];
+  fields.extend(cmd.get_args().map(|a| a.to_os_string()));
+  // An empty working directory means the broker's own.
+  let cwd = cmd
+    .get_current_dir()
+    .map(|dir| dir.as_os_str().to_os_string())
+    .unwrap_or_default();
+  let env: Vec<std::ffi::OsString> = cmd
+    .get_envs()
+    .filter_map(|(key, value)| {
+      let mut pair = key.to_os_string();
+      pair.push("=");
+      pair.push(value?);
+      Some(pair)
+    })
+    .collect();
+  let capture = capture.map_or(0, |timeout| timeout.as_millis().max(1));
+  let mut record = format!("{}\0{}\0{}\0", id, capture, fields.len()).into_bytes();
+  let env_count = std::ffi::OsString::from(env.len().to_string());
+  for field in [cwd, env_count].into_iter().chain(env).chain(fields) {
+    record.extend_from_slice(field.as_bytes());
+    record.push(0);
+  }
+  record
+}
+
+fn read_field(input: &mut impl BufRead) -> Option<std::ffi::OsString> {
+  use std::os::unix::ffi::OsStringExt;
+  let mut buf = Vec::new();
+  match input.read_until(0, &mut buf) {
+    Ok(0) => None,
+    Ok(_) => {
+      buf.pop();
+      Some(std::ffi::OsString::from_vec(buf))
+    },
+    Err(err) => {
+      error!("Launch broker failed to read: {}", err);
+      None
+    },
+  }
+}
+
+/// Reads one request, returning its ID, how long it may run if its output
+/// is wanted, and the command it asks for.
+fn read_request(input: &mut impl BufRead) -> Option<(String, Option<Duration>, Command)> {
+  let id = read_field(input)?.to_string_lossy().to_string();
+  let capture = read_field(input)?
+    .to_string_lossy()
+    .parse()
+    .ok()
+    .filter(|ms| *ms > 0)
+    .map(Duration::from_millis);
+  let count: usize = match read_field(input)?.to_string_lossy().parse() {
+    Ok(count) => count,
+    Err(err) => {
+      error!("Launch broker got malformed request: {}", err);
+      return None;
+    },
+  };
+  let cwd = read_field(input).unwrap_or_default();
+  let env_count: usize = read_field(input)
+    .and_then(|count| count.to_string_lossy().parse().ok())
+    .unwrap_or_default();
+  let env: Option<Vec<_>> = (0..env_count).map(|_| read_field(input)).collect();
+  let fields: Option<Vec<_>> = (0..count).map(|_| read_field(input)).collect();
+  let (env, mut fields) = match (env, fields) {
+    (Some(env), Some(fields)) if !fields.is_empty() => (env, fields.into_iter()),
+    _ => {
+      error!("Launch broker got truncated request");
+      return None;
+    },
+  };
+  let mut cmd = Command::new(fields.next().unwrap_or_default());
+  if !cwd.is_empty() {
+    cmd.current_dir(cwd);
+  }
+  for pair in env {
+    let pair = pair.to_string_lossy();
+    if let Some((key, value)) = pair.split_once('=') {
+      cmd.env(key, value);
+    }
+  }
+  // Launched apps must not get hold of the request socket.
+  cmd.args(fields).stdin(std::process::Stdio::null());
+  Some((id, capture, cmd))
+}
+
+/// Reads one reply from the broker: the request ID, an error message, empty
+/// if the command started, its raw exit status, and the length and bytes of
+/// its output.
+#[cfg(feature = "sandbox")]
+fn read_reply(input: &mut impl BufRead) -> Option<(u64, Reply)> {
+  use std::os::unix::process::ExitStatusExt;
+  let id = read_field(input)?
+    .to_string_lossy()
+    .parse()
+    .unwrap_or(u64::MAX);
+  let error = read_field(input)?;
+  let status: i32 = read_field(input)?.to_string_lossy().parse().ok()?;
+  let len: usize = read_field(input)?.to_string_lossy().parse().ok()?;
+  let mut stdout = vec![0; len];
+  input.read_exact(&mut stdout).ok()?;
+  let reply = match error.is_empty() {
+    true => Ok(std::process::Output {
+      status: std::process::ExitStatus::from_raw(status),
+      stdout,
+      stderr: vec![],
+    }),
+    false => Err(error.to_string_lossy().to_string()),
+  };
+  Some((id, reply))
+}
+
+/// Runs `cmd` for the broker with its output captured, killing it once
+/// `timeout` is up.
+fn capture(mut cmd: Command, timeout: Duration) -> Reply {
+  let mut child = cmd
+    .stdout(std::process::Stdio::piped())
+    .spawn()
+    .map_err(|err| err.to_string())?;
+  let mut stdout = child.stdout.take().ok_or("no output pipe")?;
+  let (tx, rx) = std::sync::mpsc::channel();
+  std::thread::spawn(move || {
+    let mut out = Vec::new();
+    let _ = tx.send(stdout.read_to_end(&mut out).map(|_| out));
+  });
+  match rx.recv_timeout(timeout) {
+    Ok(Ok(stdout)) => {
+      let status = child.wait().map_err(|err| err.to_string())?;
+      Ok(std::process::Output {
+        status,
+        stdout,
+        stderr: vec![],
+      })
+    },
+    Ok(Err(err)) => {
+      let _ = child.kill();
+      let _ = child.wait();
+      Err(err.to_string())
+    },
+    Err(_) => {
+      let _ = child.kill();
+      let _ = child.wait();
+      Err("timed out".to_string())
+    },
+  }
+}
+
+/// Answers request `id` with `reply`, see `read_reply`.
+fn send_reply(replies: &Mutex<UnixStream>, id: &str, reply: Reply) -> std::io::Result<()> {
+  use std::os::unix::process::ExitStatusExt;
+  let (error, status, stdout) = match reply {
+    Ok(output) => (String::new(), output.status.into_raw(), output.stdout),
+    Err(error) => (error, 0, vec![]),
+  };
+  let mut record = format!(
+    "{}\0{}\0{}\0{}\0",
+    id,
+    error.replace('\0', " "),
+    status,
+    stdout.len()
+  )
+  .into_bytes();
+  record.extend_from_slice(&stdout);
+  let mut replies = replies
+    .lock()
+    .map_err(|_| std::io::Error::other("reply lock poisoned"))?;
+  replies.write_all(&record)?;
+  replies.flush()
+}
+
+/// Serves launch requests from the daemon on the socket it passes as stdin
+/// until it goes away, replying to each on the same socket. Commands whose
+/// output is wanted run on their own thread, so slow ones don't hold up
+/// launches.
+pub fn run_broker() {
+  use std::os::fd::AsFd;
+  let replies = match std::io::stdin().as_fd().try_clone_to_owned() {
+    Ok(fd) => Arc::new(Mutex::new(UnixStream::from(fd))),
+    Err(err) => {
+      error!("Launch broker failed to open its reply socket: {}", err);
+      return;
+    },
+  };
+  let stdin = std::io::stdin();
+  let mut input = stdin.lock();
+  while let Some((id, capture_for, mut cmd)) = read_request(&mut input) {
+    if let Some(timeout) = capture_for {
+      let replies = replies.clone();
+      std::thread::spawn(move || {
+        if let Err(err) = send_reply(&replies, &id, capture(cmd, timeout)) {
+          error!("Launch broker failed to reply: {}", err);
+        }
+      });
+      continue;
+    }
+    let reply = match cmd.spawn() {
+      Ok(mut child) => {
+        std::thread::spawn(move || child.wait());
+        Ok(std::process::Output {
+          status: std::process::ExitStatus::default(),
+          stdout: vec![],
+          stderr: vec![],
+        })
+      },
+      Err(err) => {
+        error!(
+          "Launch broker failed to exec {:?}: {}",
+          cmd.get_program(),
+          err
+        );
+        Err(err.to_string())
+      },
+    };
+    if let Err(err) = send_reply(&replies, &id, reply) {
+      error!("Launch broker failed to reply: {}", err);
+      return;
+    }
+  }
+}
+
+/// Directories the daemon may write to once sandboxed, created if missing:
+/// its cache, state and runtime directories, the latter holding the menu
+/// socket and state dumps, and the user's applications directory, where
+/// HideApp writes its overrides.
+#[cfg(feature = "sandbox")]
+fn writable_dirs() -> Vec<std::path::PathBuf> {
+  use log_err::LogErrResult;
+  let xdg_dirs =
+    xdg::BaseDirectories::with_prefix("xdg-app-ksni").log_expect("Failed to init XDG directories");
+  let runtime = match xdg_dirs.has_runtime_directory() {
+    true => Some(xdg_dirs.create_runtime_directory("")),
+    false => None,
+  };
+  let applications = xdg::BaseDirectories::new()
+    .log_expect("Failed to init XDG directories")
+    .create_data_directory("applications");
+  [
+    Some(xdg_dirs.create_cache_directory("")),
+    Some(xdg_dirs.create_state_directory("")),
+    runtime,
+    Some(applications),
+  ]
+  .into_iter()
+  .flatten()
+  .filter_map(|dir| match dir {
+    Ok(dir) => Some(dir),
+    Err(err) => {
+      warn!("Failed to create writable directory: {}", err);
+      None
+    },
+  })
+  .collect()
+}
+
+/// Warns about any of `writable` the daemon can't create files in after
+/// confining itself.
+#[cfg(feature = "sandbox")]
+fn check_writable(writable: &[std::path::PathBuf]) {
+  for dir in writable {
+    let probe = dir.join(format!(".sandbox-probe-{}", std::process::id()));
+    match std::fs::write(&probe, b"").and_then(|_| std::fs::remove_file(&probe)) {
+      Ok(()) => debug!("Sandbox allows writing to {:?}", dir),
+      Err(err) => warn!("Sandbox blocks writing to {:?}: {}", dir, err),
+    }
+  }
+}
+
+#[cfg(feature = "sandbox")]
+fn restrict_filesystem(writable: &[std::path::PathBuf]) -> Result<(), landlock::RulesetError> {
+  use landlock::{
+    path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+    ABI,
+  };
+  let abi = ABI::V2;
+  let status = Ruleset::default()
+    .handle_access(AccessFs::from_all(abi))?
+    .create()?
+    .add_rules(path_beneath_rules(&["/"], AccessFs::from_read(abi)))?
+    .add_rules(path_beneath_rules(
+      writable,
+      AccessFs::from_all(abi) & !AccessFs::Execute,
+    ))?
+    .restrict_self()?;
+  match status.ruleset {
+    RulesetStatus::FullyEnforced => log::info!("Filesystem sandbox fully enforced"),
+    RulesetStatus::PartiallyEnforced => log::info!("Filesystem sandbox partially enforced"),
+    RulesetStatus::NotEnforced => warn!("Kernel lacks Landlock support, filesystem not sandboxed"),
+  }
+  Ok(())
+}
+
+#[cfg(feature = "sandbox")]
+fn restrict_syscalls() -> Result<(), seccompiler::Error> {
+  use seccompiler::{BpfProgram, SeccompAction, SeccompFilter};
+  let denied = [
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_execve,
+    libc::SYS_execveat,
+  ];
+  let filter = SeccompFilter::new(
+    denied.iter().map(|nr| (*nr, vec![])).collect(),
+    SeccompAction::Allow,
+    SeccompAction::Errno(libc::EPERM as u32),
+    std::env::consts::ARCH
+      .try_into()
+      .map_err(seccompiler::Error::Backend)?,
+  )
+  .map_err(seccompiler::Error::Backend)?;
+  let program: BpfProgram = filter.try_into().map_err(seccompiler::Error::Backend)?;
+  seccompiler::apply_filter_all_threads(&program)?;
+  log::info!("Syscall sandbox enforced");
+  Ok(())
+}
+
+/// Confines the daemon when `SANDBOX` is set: reads anywhere, writes only to
+/// `writable_dirs`, and never execs itself — launches go through a broker
+/// started beforehand. Must run before any other threads exist.
+pub fn enter() {
+  if !super::init::get_sandbox() {
+    return;
+  }
+  #[cfg(not(feature = "sandbox"))]
+  warn!("SANDBOX requested but this build lacks the sandbox feature, running unconfined");
+  #[cfg(feature = "sandbox")]
+  {
+    let exe = match std::env::current_exe() {
+      Ok(exe) => exe,
+      Err(err) => {
+        warn!(
+          "Failed to locate own binary for launch broker, running unconfined: {}",
+          err
+        );
+        return;
+      },
+    };
+    let (daemon_end, broker_end) = match UnixStream::pair() {
+      Ok(pair) => pair,
+      Err(err) => {
+        warn!(
+          "Failed to create launch broker socket, running unconfined: {}",
+          err
+        );
+        return;
+      },
+    };
+    let broker = Command::new(exe)
+      .arg(BROKER_ARG)
+      .stdin(std::os::fd::OwnedFd::from(broker_end))
+      .spawn();
+    let replies = match (broker, daemon_end.try_clone()) {
+      (Ok(_), Ok(replies)) => replies,
+      (Err(err), _) | (_, Err(err)) => {
+        warn!("Failed to start launch broker, running unconfined: {}", err);
+        return;
+      },
+    };
+    let _ = BROKER.set(Broker {
+      requests: Mutex::new(daemon_end),
+      pending: Mutex::new(HashMap::new()),
+      next_id: AtomicU64::new(0),
+    });
+
+    let writable = writable_dirs();
+    if let Err(err) = restrict_filesystem(&writable) {
+      warn!("Failed to sandbox filesystem access: {}", err);
+    }
+    if let Err(err) = restrict_syscalls() {
+      warn!("Failed to sandbox syscalls: {}", err);
+    }
+    check_writable(&writable);
+    // Started only now so the reader is confined like the rest of the daemon.
+    if let Some(broker) = BROKER.get() {
+      std::thread::spawn(move || broker.read_replies(replies));
+    }
+  }
+}
@@ -0,0 +1,101 @@
+//! Runtime menu state written to the state directory when the daemon panics
+//! or is terminated, and read back on the next start before the first scan,
+//! so launchers keep their menu item IDs (and with them pinned, recent and
+//! frequent copies), revisions keep counting up and the last menu is served
+//! until the scan finishes. Favorites and install times are saved as they
+//! change and need no snapshot.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{constants, desktop};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+  pub revision: u32,
+  /// Menu item ID of each desktop-file ID.
+  pub ids: HashMap<String, usize>,
+  /// Next menu item ID to hand out.
+  pub next_id: usize,
+  /// Properties of each launcher menu item, by ID.
+  #[serde(default)]
+  pub props: HashMap<i32, Props>,
+  /// Launcher menu item IDs listed in each section, by section ID.
+  #[serde(default)]
+  pub children: HashMap<i32, Vec<i32>>,
+}
+
+/// The properties of a launcher menu item kept in a snapshot. Icon data is
+/// left out, as it is rendered again once the scan finishes.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Props {
+  pub label: String,
+  pub visible: bool,
+  pub enabled: bool,
+  pub icon_name: String,
+  pub accessible_desc: String,
+  pub x_categories: Vec<String>,
+}
+
+impl From<&desktop::MenuProps> for Props {
+  fn from(props: &desktop::MenuProps) -> Self {
+    Props {
+      label: props.label.clone(),
+      visible: props.visible,
+      enabled: props.enabled,
+      icon_name: props.icon_name.clone(),
+      accessible_desc: props.accessible_desc.clone(),
+      x_categories: props.x_categories.clone(),
+    }
+  }
+}
+
+impl From<Props> for desktop::MenuProps {
+  fn from(props: Props) -> Self {
+    desktop::MenuProps {
+      label: props.label,
+      visible: props.visible,
+      enabled: props.enabled,
+      icon_name: props.icon_name,
+      icon_data: desktop::IconData::default(),
+      entry_type: "standard".to_string(),
+      children_display: String::new(),
+      accessible_desc: props.accessible_desc,
+      x_categories: props.x_categories,
+      toggle_type: String::new(),
+      toggle_state: -1,
+    }
+  }
+}
+
+/// Latest snapshot, kept up to date while running so it can be written out
+/// without touching the menu.
+static LATEST: Mutex<Option<Snapshot>> = Mutex::new(None);
+
+/// Replaces the snapshot written out on exit.
+pub fn stage(snapshot: Snapshot) {
+  if let Ok(mut latest) = LATEST.lock() {
+    *latest = Some(snapshot);
+  }
+}
+
+/// Writes the staged snapshot, if any. Gives up rather than wait if another
+/// thread holds it, as this runs from the panic hook.
+pub fn save() {
+  if let Ok(latest) = LATEST.try_lock() {
+    if let Some(snapshot) = latest.as_ref() {
+      super::state::save(constants::SNAPSHOT_STATE_FILE, snapshot);
+      info!(
+        "Saved menu snapshot at revision {} with {} entries",
+        snapshot.revision,
+        snapshot.ids.len()
+      );
+    }
+  }
+}
+
+pub fn load() -> Option<Snapshot> {
+  super::state::load(constants::SNAPSHOT_STATE_FILE)
+}
@@ -0,0 +1,65 @@
+//! JSON files in `$XDG_STATE_HOME/xdg-app-ksni` holding what should survive a
+//! restart without being configuration.
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Seconds since the epoch, the form timestamps are kept in.
+pub fn unix_now() -> u64 {
+  std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs())
+}
+
+/// A Unix time as local `HH:MM`, or `None` if it can't be converted.
+pub fn local_clock(secs: u64) -> Option<String> {
+  let time = libc::time_t::try_from(secs).ok()?;
+  // SAFETY: localtime_r only writes to the zeroed struct passed in.
+  let tm = unsafe {
+    let mut tm: libc::tm = std::mem::zeroed();
+    if libc::localtime_r(&time, &mut tm).is_null() {
+      return None;
+    }
+    tm
+  };
+  Some(format!("{:02}:{:02}", tm.tm_hour, tm.tm_min))
+}
+
+fn state_dirs() -> Option<xdg::BaseDirectories> {
+  xdg::BaseDirectories::with_prefix("xdg-app-ksni")
+    .map_err(|err| warn!("Failed to locate the state directory: {}", err))
+    .ok()
+}
+
+/// Reads state file `name`, or `None` if it doesn't exist or can't be parsed.
+pub fn load<T: DeserializeOwned>(name: &str) -> Option<T> {
+  let path = state_dirs()?.find_state_file(name)?;
+  let data = std::fs::read(&path)
+    .map_err(|err| warn!("Failed to read {:?}: {}", path, err))
+    .ok()?;
+  serde_json::from_slice(&data)
+    .map_err(|err| warn!("Ignoring invalid state file {:?}: {}", path, err))
+    .ok()
+}
+
+/// Replaces state file `name`, writing a temporary file first so readers
+/// never see it half written.
+pub fn save<T: Serialize>(name: &str, value: &T) {
+  if let Err(err) = store(name, value) {
+    warn!("{}", err);
+  }
+}
+
+/// Like `save`, but returns the failure instead of logging it.
+pub fn store<T: Serialize>(name: &str, value: &T) -> Result<(), String> {
+  let path = state_dirs()
+    .ok_or("Failed to locate the state directory")?
+    .place_state_file(name)
+    .map_err(|err| format!("Failed to create the state directory: {}", err))?;
+  let data =
+    serde_json::to_vec(value).map_err(|err| format!("Failed to serialize {}: {}", name, err))?;
+  let tmp = path.with_extension("tmp");
+  std::fs::write(&tmp, data)
+    .and_then(|_| std::fs::rename(&tmp, &path))
+    .map_err(|err| format!("Failed to write {:?}: {}", path, err))
+}
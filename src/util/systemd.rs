@@ -0,0 +1,63 @@
+//! The `sd_notify` protocol, so the daemon can run as a `Type=notify` user
+//! service with a watchdog.
+use std::ffi::OsString;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use log::{debug, warn};
+
+/// The service manager's variables, in the order of `VARS`.
+static ENV: OnceLock<[Option<OsString>; 3]> = OnceLock::new();
+const VARS: [&str; 3] = ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"];
+
+/// Takes the service manager's variables out of the environment, so the
+/// applications launched don't inherit them and notify or get watched in
+/// the daemon's place. Must run before any thread is started.
+pub fn take_env() {
+  ENV.get_or_init(|| {
+    VARS.map(|name| {
+      let value = std::env::var_os(name);
+      std::env::remove_var(name);
+      value
+    })
+  });
+}
+
+fn var(name: &str) -> Option<&'static OsString> {
+  let i = VARS.iter().position(|var| *var == name)?;
+  ENV.get()?[i].as_ref()
+}
+
+/// Sends a state such as `READY=1` to the service manager, if it asked for
+/// notifications through `$NOTIFY_SOCKET`.
+pub fn notify(state: &str) {
+  let socket = match var("NOTIFY_SOCKET") {
+    Some(socket) => socket,
+    None => return,
+  };
+  let addr = match socket.to_string_lossy().strip_prefix('@') {
+    Some(name) => {
+      use std::os::linux::net::SocketAddrExt;
+      SocketAddr::from_abstract_name(name.as_bytes())
+    },
+    None => SocketAddr::from_pathname(socket),
+  };
+  let sent = addr.and_then(|addr| UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr));
+  match sent {
+    Ok(_) => debug!("Notified service manager: {}", state),
+    Err(err) => warn!("Failed to notify service manager of {}: {}", state, err),
+  }
+}
+
+/// How often to send `WATCHDOG=1`: half the interval the service manager
+/// expects, or `None` if the watchdog isn't enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+  if let Some(pid) = var("WATCHDOG_PID") {
+    if pid.to_string_lossy().parse() != Ok(std::process::id()) {
+      return None;
+    }
+  }
+  let usec: u64 = var("WATCHDOG_USEC")?.to_string_lossy().parse().ok()?;
+  Some(Duration::from_micros(usec / 2)).filter(|interval| !interval.is_zero())
+}
@@ -0,0 +1,91 @@
+//! Counters behind the opt-in usage summary. Only counts are kept, never
+//! which applications were launched.
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::info;
+
+struct Counters {
+  launches: u64,
+  scans: u64,
+  last_scan: Duration,
+  /// When the last scan finished, in seconds since the epoch.
+  last_scan_at: u64,
+  max_scan: Duration,
+}
+
+static COUNTERS: Mutex<Counters> = Mutex::new(Counters {
+  launches: 0,
+  scans: 0,
+  last_scan: Duration::ZERO,
+  last_scan_at: 0,
+  max_scan: Duration::ZERO,
+});
+
+pub fn record_launch() {
+  if let Ok(mut counters) = COUNTERS.lock() {
+    counters.launches += 1;
+  }
+}
+
+pub fn record_scan(elapsed: Duration) {
+  if let Ok(mut counters) = COUNTERS.lock() {
+    counters.scans += 1;
+    counters.last_scan = elapsed;
+    counters.last_scan_at = super::state::unix_now();
+    counters.max_scan = counters.max_scan.max(elapsed);
+  }
+}
+
+/// When the last scan finished, in seconds since the epoch, and how long it
+/// took. Unaffected by the summary resetting the counters.
+pub fn last_scan() -> (u64, Duration) {
+  COUNTERS.lock().map_or((0, Duration::ZERO), |counters| {
+    (counters.last_scan_at, counters.last_scan)
+  })
+}
+
+/// Logs the summary for the `period` since the last one, with the number of
+/// entries per category, as journal fields, then resets the counters.
+pub fn log_summary(period: Duration, categories: &[(String, usize)]) {
+  let (launches, scans, last_scan, max_scan) = match COUNTERS.lock() {
+    Ok(mut counters) => {
+      let taken = (
+        counters.launches,
+        counters.scans,
+        counters.last_scan,
+        counters.max_scan,
+      );
+      counters.launches = 0;
+      counters.scans = 0;
+      counters.max_scan = Duration::ZERO;
+      taken
+    },
+    Err(_) => return,
+  };
+  let entries: usize = categories.iter().map(|(_, count)| count).sum();
+  let per_category = categories
+    .iter()
+    .map(|(label, count)| format!("{}={}", label, count))
+    .collect::<Vec<_>>()
+    .join(" ");
+  let launches_per_day = launches as f64 * 86400.0 / period.as_secs_f64().max(1.0);
+  info!(
+    target: "usage",
+    entries = entries,
+    categories = per_category.as_str(),
+    launches = launches,
+    launches_per_day = launches_per_day,
+    scans = scans,
+    last_scan_ms = last_scan.as_millis() as u64,
+    max_scan_ms = max_scan.as_millis() as u64;
+    "Usage summary: {} entries ({}), {} launches ({:.1}/day), {} scans (last {} ms, max {} ms)",
+    entries,
+    per_category,
+    launches,
+    launches_per_day,
+    scans,
+    last_scan.as_millis(),
+    max_scan.as_millis()
+  );
+}
@@ -1,4 +1,3 @@
-use log_err::LogErrResult;
 pub fn unescape(input: &str, multi: bool) -> String {
   let mut out = String::new();
   let mut control = false;
@@ -60,34 +59,211 @@ pub fn split(input: &str) -> Vec<String> {
   out
 }
 
-pub fn exec_substitute(
-  input: &str,
-  icon: Option<String>,
+/// Command line given either as an argv array, used as-is, or as an
+/// `Exec`-style string. Returns `None` if both are empty or the string is
+/// invalid.
+pub fn command_argv(exec: &str, argv: &[String]) -> Option<Vec<String>> {
+  if !argv.is_empty() {
+    return Some(argv.to_vec());
+  }
+  exec_tokenize(exec).filter(|exec| !exec.is_empty())
+}
+
+/// Splits an (already unescaped) Exec value into arguments following the
+/// Desktop Entry spec quoting rules. Returns `None` for unterminated quotes.
+pub fn exec_tokenize(input: &str) -> Option<Vec<String>> {
+  let mut out = Vec::new();
+  let mut current = String::new();
+  let mut in_arg = false;
+  let mut quoted = false;
+  let mut chars = input.chars();
+  while let Some(c) = chars.next() {
+    if quoted {
+      match c {
+        '"' => quoted = false,
+        '\\' => match chars.next() {
+          Some(e @ ('"' | '`' | '$' | '\\')) => current.push(e),
+          Some(e) => {
+            current.push('\\');
+            current.push(e);
+          },
+          None => return None,
+        },
+        _ => current.push(c),
+      }
+    } else {
+      match c {
+        ' ' | '\t' | '\n' => {
+          if in_arg {
+            out.push(std::mem::take(&mut current));
+            in_arg = false;
+          }
+        },
+        '"' => {
+          quoted = true;
+          in_arg = true;
+        },
+        _ => {
+          current.push(c);
+          in_arg = true;
+        },
+      }
+    }
+  }
+  if quoted {
+    return None;
+  }
+  if in_arg {
+    out.push(current);
+  }
+  Some(out)
+}
+
+/// Expands field codes in tokenized Exec arguments. `%i` becomes the
+/// `--icon <icon>` pair, file and URL codes are dropped since the menu never
+/// passes files, and `%%` yields a literal percent sign.
+pub fn exec_expand(
+  args: Vec<String>,
+  icon: Option<&str>,
   name: &str,
-  path: &std::path::PathBuf,
-) -> String {
-  let re = regex::Regex::new(r"(%f|%F|%u|%U|%d|%D|%n|%N|%i|%c|%k|%v|%m)")
-    .log_expect("Failed to instantiate exec regex");
+  path: &std::path::Path,
+) -> Vec<String> {
   let path_lossy = path.to_string_lossy();
-  let icon = icon.clone().unwrap_or_default();
-  re.replace_all(input, |cap: &regex::Captures| {
-    match &cap[0] {
-      "%f" => "",
-      "%F" => "",
-      "%u" => "",
-      "%U" => "",
-      "%d" => "",
-      "%D" => "",
-      "%n" => "",
-      "%N" => "",
-      "%i" => &icon,
-      "%c" => name,
-      "%k" => &path_lossy,
-      "%v" => "",
-      "%m" => "",
-      _ => unreachable!("Non exhaustive regex!"),
+  let mut out = Vec::new();
+  for arg in args {
+    match arg.as_str() {
+      "%f" | "%F" | "%u" | "%U" | "%d" | "%D" | "%n" | "%N" | "%v" | "%m" => continue,
+      "%i" => {
+        if let Some(icon) = icon {
+          out.push("--icon".to_string());
+          out.push(icon.to_string());
+        }
+        continue;
+      },
+      _ => {},
+    }
+    let mut expanded = String::new();
+    let mut chars = arg.chars();
+    while let Some(c) = chars.next() {
+      if c != '%' {
+        expanded.push(c);
+        continue;
+      }
+      match chars.next() {
+        Some('%') => expanded.push('%'),
+        Some('c') => expanded.push_str(name),
+        Some('k') => expanded.push_str(&path_lossy),
+        Some('i') => expanded.push_str(icon.unwrap_or_default()),
+        Some(_) | None => {},
+      }
+    }
+    out.push(expanded);
+  }
+  out
+}
+
+/// The file or URL field code (`%f`, `%F`, `%u` or `%U`) among tokenized
+/// Exec arguments, with its index.
+pub fn file_code(args: &[String]) -> Option<(char, usize)> {
+  args
+    .iter()
+    .enumerate()
+    .find_map(|(i, arg)| match arg.as_str() {
+      "%f" | "%F" | "%u" | "%U" => arg.chars().nth(1).map(|code| (code, i)),
+      _ => None,
+    })
+}
+
+/// Local path of a `file://` URI or plain path, `None` for other URIs.
+pub fn uri_path(uri: &str) -> Option<String> {
+  let encoded = match uri.strip_prefix("file://") {
+    Some(rest) => rest.trim_start_matches(|c| c != '/'),
+    None if uri.contains("://") => return None,
+    None => return Some(uri.to_string()),
+  };
+  let mut bytes = Vec::new();
+  let mut rest = encoded.as_bytes();
+  while let Some((&b, tail)) = rest.split_first() {
+    let hex = tail
+      .get(..2)
+      .and_then(|h| std::str::from_utf8(h).ok())
+      .and_then(|h| u8::from_str_radix(h, 16).ok());
+    match hex {
+      Some(decoded) if b == b'%' => {
+        bytes.push(decoded);
+        rest = &tail[2..];
+      },
+      _ => {
+        bytes.push(b);
+        rest = tail;
+      },
     }
-    .to_string()
-  })
-  .to_string()
+  }
+  String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+  }
+
+  fn expand(exec: &[&str], icon: Option<&str>) -> Vec<String> {
+    exec_expand(
+      args(exec),
+      icon,
+      "App",
+      std::path::Path::new("/usr/share/applications/app.desktop"),
+    )
+  }
+
+  #[test]
+  fn tokenize_quoted_program() {
+    assert_eq!(
+      exec_tokenize(r#""/opt/My App/run" --flag"#),
+      Some(args(&["/opt/My App/run", "--flag"]))
+    );
+  }
+
+  #[test]
+  fn tokenize_escapes_in_quotes() {
+    assert_eq!(
+      exec_tokenize(r#"echo "say \"hi\"" "\`cmd\`" "\$HOME""#),
+      Some(args(&["echo", r#"say "hi""#, "`cmd`", "$HOME"]))
+    );
+    // The file's `\\\\` is `\\` after string unescaping, a single backslash in quotes.
+    assert_eq!(
+      exec_tokenize(&unescape(r#"printf "a\\\\b""#, false)),
+      Some(args(&["printf", r"a\b"]))
+    );
+  }
+
+  #[test]
+  fn tokenize_unterminated_quote() {
+    assert_eq!(exec_tokenize(r#"app "oops"#), None);
+  }
+
+  #[test]
+  fn expand_percent() {
+    assert_eq!(expand(&["echo", "100%%"], None), args(&["echo", "100%"]));
+  }
+
+  #[test]
+  fn quoted_file_code() {
+    let exec = exec_tokenize(r#"viewer "%f""#).unwrap();
+    assert_eq!(exec, args(&["viewer", "%f"]));
+    assert_eq!(file_code(&exec), Some(('f', 1)));
+    assert_eq!(expand(&["viewer", "%f"], None), args(&["viewer"]));
+  }
+
+  #[test]
+  fn expand_icon() {
+    assert_eq!(expand(&["app", "%i"], None), args(&["app"]));
+    assert_eq!(
+      expand(&["app", "%i"], Some("app-icon")),
+      args(&["app", "--icon", "app-icon"])
+    );
+  }
 }
@@ -0,0 +1,144 @@
+//! Decoder for XPM images, the C-source icon format older packages still
+//! install to `pixmaps`. Covers XPM3: a header string, one string per color
+//! and one per row of pixels.
+use std::collections::HashMap;
+
+use crate::constants;
+
+/// Decodes the text of an XPM file.
+pub fn decode(source: &str) -> Result<image::RgbaImage, String> {
+  let strings = quoted_strings(source);
+  let mut strings = strings.iter();
+  let header = strings.next().ok_or("no header")?;
+  let values: Vec<u32> = header
+    .split_whitespace()
+    .take(4)
+    .map(str::parse)
+    .collect::<Result<_, _>>()
+    .map_err(|_| format!("invalid header '{}'", header))?;
+  let (width, height, colors, cpp) = match values[..] {
+    [width, height, colors, cpp] if cpp > 0 => (width, height, colors, cpp as usize),
+    _ => return Err(format!("invalid header '{}'", header)),
+  };
+  if width > constants::MAX_ICON_DIMENSION || height > constants::MAX_ICON_DIMENSION {
+    return Err(format!("{}x{} is too large", width, height));
+  }
+
+  let mut palette: HashMap<&str, [u8; 4]> = HashMap::new();
+  for _ in 0..colors {
+    let line = strings.next().ok_or("color table cut short")?;
+    let key = line
+      .get(..cpp)
+      .ok_or_else(|| format!("invalid color '{}'", line))?;
+    palette.insert(key, color_of(&line[cpp..])?);
+  }
+
+  let mut image = image::RgbaImage::new(width, height);
+  for y in 0..height {
+    let row = strings.next().ok_or("pixels cut short")?;
+    for x in 0..width {
+      let at = x as usize * cpp;
+      let color = row
+        .get(at..at + cpp)
+        .and_then(|key| palette.get(key))
+        .ok_or_else(|| format!("invalid pixel at {},{}", x, y))?;
+      image.put_pixel(x, y, image::Rgba(*color));
+    }
+  }
+  Ok(image)
+}
+
+/// Contents of the string literals in C source, skipping comments.
+fn quoted_strings(source: &str) -> Vec<String> {
+  let mut strings = Vec::new();
+  let mut chars = source.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '/' if chars.peek() == Some(&'*') => {
+        chars.next();
+        let mut prev = ' ';
+        for c in chars.by_ref() {
+          if prev == '*' && c == '/' {
+            break;
+          }
+          prev = c;
+        }
+      },
+      '"' => strings.push(chars.by_ref().take_while(|c| *c != '"').collect()),
+      _ => {},
+    }
+  }
+  strings
+}
+
+/// Color of a color table entry such as `c #FF0000` or `s mask c None`,
+/// preferring the color visual over the grayscale and mono ones.
+fn color_of(spec: &str) -> Result<[u8; 4], String> {
+  let mut values: Vec<(&str, String)> = Vec::new();
+  for word in spec.split_whitespace() {
+    match (word, values.last_mut()) {
+      ("c" | "g" | "g4" | "m" | "s", _) => values.push((word, String::new())),
+      (_, Some((_, value))) => {
+        if !value.is_empty() {
+          value.push(' ');
+        }
+        value.push_str(word);
+      },
+      (_, None) => return Err(format!("invalid color '{}'", spec)),
+    }
+  }
+  let value = ["c", "g", "g4", "m"]
+    .iter()
+    .find_map(|key| values.iter().find(|(k, _)| k == key))
+    .map(|(_, value)| value.as_str())
+    .ok_or_else(|| format!("invalid color '{}'", spec))?;
+  parse_color(value).ok_or_else(|| format!("unknown color '{}'", value))
+}
+
+/// Parses `None`, hex colors with 1 to 4 digits per channel, and common X11
+/// color names.
+fn parse_color(value: &str) -> Option<[u8; 4]> {
+  if let Some(hex) = value.strip_prefix('#') {
+    let digits = hex.len() / 3;
+    if hex.len() % 3 != 0 || !(1..=4).contains(&digits) {
+      return None;
+    }
+    let channel = |i: usize| -> Option<u8> {
+      let value = u32::from_str_radix(hex.get(i * digits..(i + 1) * digits)?, 16).ok()?;
+      let max = (1u32 << (4 * digits)) - 1;
+      Some((value * 255 / max) as u8)
+    };
+    return Some([channel(0)?, channel(1)?, channel(2)?, 255]);
+  }
+  let name = value.to_lowercase().replace(' ', "");
+  if name == "none" {
+    return Some([0, 0, 0, 0]);
+  }
+  if let Some(level) = name
+    .strip_prefix("gray")
+    .or_else(|| name.strip_prefix("grey"))
+    .and_then(|level| level.parse::<u32>().ok())
+    .filter(|level| *level <= 100)
+  {
+    let v = (level * 255 / 100) as u8;
+    return Some([v, v, v, 255]);
+  }
+  let [r, g, b] = match name.as_str() {
+    "black" => [0, 0, 0],
+    "white" => [255, 255, 255],
+    "red" => [255, 0, 0],
+    "green" => [0, 255, 0],
+    "blue" => [0, 0, 255],
+    "yellow" => [255, 255, 0],
+    "cyan" => [0, 255, 255],
+    "magenta" => [255, 0, 255],
+    "gray" | "grey" => [190, 190, 190],
+    "darkgray" | "darkgrey" => [169, 169, 169],
+    "lightgray" | "lightgrey" => [211, 211, 211],
+    "orange" => [255, 165, 0],
+    "brown" => [165, 42, 42],
+    "navy" => [0, 0, 128],
+    _ => return None,
+  };
+  Some([r, g, b, 255])
+}